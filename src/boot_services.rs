@@ -2,12 +2,13 @@ use ffi::{
     boot_services::EFI_LOCATE_SEARCH_TYPE,
     EFI_HANDLE,
     EFI_GUID,
+    EFI_STATUS,
     EFI_NOT_FOUND,
     VOID,
     UINTN,
 };
 use crate::{Result, system_table};
-use core::{ptr};
+use core::{ptr, mem};
 use alloc::{vec::Vec, boxed::Box};
 
 // TODO: this guy should return an iterator to avoid allocations
@@ -35,3 +36,31 @@ pub (crate) fn locate_handles(protocol_guid: &EFI_GUID) -> Result<Vec<EFI_HANDLE
 
     Ok(handles)
 }
+
+// efi_ffi doesn't define the signature of LocateHandle yet
+type EfiLocateHandle = extern "win64" fn(
+    search_type: EFI_LOCATE_SEARCH_TYPE,
+    protocol: *const EFI_GUID,
+    search_key: *const VOID,
+    buffer_size: *mut UINTN,
+    buffer: *mut EFI_HANDLE
+) -> EFI_STATUS;
+
+/// Next handle on which the protocol of a RegisterProtocolNotify registration got installed.
+/// The firmware hands out each handle once. `None` means there are no more for now.
+pub (crate) fn locate_next_notified_handle(registration: *const VOID) -> Result<Option<EFI_HANDLE>> {
+    let bs = system_table().BootServices;
+    let mut handle: EFI_HANDLE = ptr::null_mut();
+    let mut buffer_size: UINTN = mem::size_of::<EFI_HANDLE>();
+
+    let status = unsafe {
+        let locate_handle: EfiLocateHandle = mem::transmute((*bs).LocateHandle);
+        locate_handle(EFI_LOCATE_SEARCH_TYPE::ByRegisterNotify, ptr::null(), registration, &mut buffer_size, &mut handle)
+    };
+    if status == EFI_NOT_FOUND {
+        return Ok(None);
+    }
+
+    ret_on_err!(status);
+    Ok(Some(handle))
+}
@@ -0,0 +1,451 @@
+//! Interpretation of ANSI/VT100 escape sequences written to the console.
+//!
+//! Supports the subset terminal-oriented code commonly relies on:
+//!
+//! - SGR colors (`ESC[...m`): reset, bold/bright, the 8 standard and 8 bright foreground colors,
+//!   the 8 background colors and the first 16 of the 256 colors. Bright backgrounds are shown
+//!   as their normal counterparts since UEFI only has 8 background colors.
+//! - Cursor positioning: `H`/`f`, `A`/`B`/`C`/`D`, `G`, saving and restoring (`s`/`u`, `ESC 7`/`ESC 8`)
+//!   and showing and hiding it (`ESC[?25h`/`ESC[?25l`)
+//! - Erasing the screen (`J`) and line (`K`)
+//!
+//! Other sequences are swallowed so that at least they don't show up as garbage.
+
+use super::{Console, ForeColor, BackColor, Position};
+use crate::Result;
+use alloc::{vec::Vec, string::String};
+use core::cmp;
+
+const ESC: char = '\u{1B}';
+const MAX_PARAMS: usize = 16;
+
+// UEFI color numbers of the ANSI colors black, red, green, yellow, blue, magenta, cyan and white.
+// Adding 8 gives the bright variant.
+const ANSI_TO_EFI_COLOR: [usize; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Action {
+    Print(char),
+    Command(Command),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Command {
+    Sgr(Vec<u16>),
+    /// Zero-based, unlike in the escape sequence
+    CursorPosition { row: usize, col: usize },
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBack(usize),
+    /// Zero-based, unlike in the escape sequence
+    CursorColumn(usize),
+    EraseDisplay(u16),
+    EraseLine(u16),
+    ShowCursor(bool),
+    SaveCursor,
+    RestoreCursor,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Splits the written chars into text to print and commands. Keeps its state between writes
+/// so that sequences split across them are still recognized.
+pub(super) struct Parser {
+    state: State,
+    params: Vec<u16>,
+    current: Option<u16>,
+    private: bool,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self { state: State::Ground, params: Vec::new(), current: None, private: false }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => {
+                if c == ESC {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Action::Print(c))
+                }
+            },
+            State::Escape => {
+                self.state = State::Ground;
+                match c {
+                    '[' => {
+                        self.state = State::Csi;
+                        self.params.clear();
+                        self.current = None;
+                        self.private = false;
+                        None
+                    },
+                    '7' => Some(Action::Command(Command::SaveCursor)),
+                    '8' => Some(Action::Command(Command::RestoreCursor)),
+                    ESC | ' '..='/' => { // Intermediate bytes like the '(' in ESC(B are followed by the final char
+                        self.state = State::Escape;
+                        None
+                    },
+                    _ => None,
+                }
+            },
+            State::Csi => match c {
+                '0'..='9' => {
+                    let digit = c as u16 - '0' as u16;
+                    self.current = Some(self.current.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                    None
+                },
+                ';' => {
+                    self.push_param();
+                    None
+                },
+                '?' => {
+                    self.private = true;
+                    None
+                },
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                },
+                '\u{40}'..='\u{7E}' => {
+                    self.state = State::Ground;
+                    if self.current.is_some() || !self.params.is_empty() {
+                        self.push_param();
+                    }
+                    self.dispatch(c).map(Action::Command)
+                },
+                _ => None, // Intermediate bytes and stray control chars
+            },
+        }
+    }
+
+    fn push_param(&mut self) {
+        let param = self.current.take().unwrap_or(0);
+        if self.params.len() < MAX_PARAMS {
+            self.params.push(param);
+        }
+    }
+
+    /// The param at `index` with 0 or a missing param meaning `default`, as is the convention for counts and positions
+    fn param(&self, index: usize, default: u16) -> usize {
+        match self.params.get(index) {
+            Some(&p) if p != 0 => p as usize,
+            _ => default as usize,
+        }
+    }
+
+    fn dispatch(&mut self, final_char: char) -> Option<Command> {
+        if self.private {
+            return match final_char {
+                'h' | 'l' if self.params.contains(&25) => Some(Command::ShowCursor(final_char == 'h')),
+                _ => None,
+            };
+        }
+
+        let command = match final_char {
+            'm' if self.params.is_empty() => Command::Sgr(vec![0]),
+            'm' => Command::Sgr(core::mem::take(&mut self.params)),
+            'H' | 'f' => Command::CursorPosition { row: self.param(0, 1) - 1, col: self.param(1, 1) - 1 },
+            'A' => Command::CursorUp(self.param(0, 1)),
+            'B' => Command::CursorDown(self.param(0, 1)),
+            'C' => Command::CursorForward(self.param(0, 1)),
+            'D' => Command::CursorBack(self.param(0, 1)),
+            'G' => Command::CursorColumn(self.param(0, 1) - 1),
+            'J' => Command::EraseDisplay(self.params.first().cloned().unwrap_or(0)),
+            'K' => Command::EraseLine(self.params.first().cloned().unwrap_or(0)),
+            's' => Command::SaveCursor,
+            'u' => Command::RestoreCursor,
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
+
+/// The colors selected by SGR sequences so far
+pub(super) struct Graphics {
+    default_fore: ForeColor,
+    default_back: BackColor,
+    /// Index into `ANSI_TO_EFI_COLOR`, plus 8 if bright. `None` means the default color.
+    fore: Option<usize>,
+    back: Option<usize>,
+    bold: bool,
+}
+
+impl Graphics {
+    pub fn new(default_fore: ForeColor, default_back: BackColor) -> Self {
+        Self { default_fore, default_back, fore: None, back: None, bold: false }
+    }
+
+    pub fn apply(&mut self, params: &[u16]) {
+        let mut params = params.iter().map(|&p| p as usize);
+        while let Some(param) = params.next() {
+            match param {
+                0 => {
+                    self.fore = None;
+                    self.back = None;
+                    self.bold = false;
+                },
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.fore = Some(param - 30),
+                39 => self.fore = None,
+                40..=47 => self.back = Some(param - 40),
+                49 => self.back = None,
+                90..=97 => self.fore = Some(param - 90 + 8),
+                100..=107 => self.back = Some(param - 100 + 8),
+                38 | 48 => {
+                    // Extended colors. Only the first 16 of the 256 colors map onto UEFI's.
+                    let color = match params.next() {
+                        Some(5) => params.next().filter(|&c| c < 16),
+                        Some(2) => {
+                            params.by_ref().take(3).for_each(drop); // Can't show RGB colors
+                            None
+                        },
+                        _ => None,
+                    };
+
+                    if let Some(color) = color {
+                        if param == 38 { self.fore = Some(color) } else { self.back = Some(color) }
+                    }
+                },
+                _ => {}, // Attributes UEFI can't show like underline and blink
+            }
+        }
+    }
+
+    pub fn colors(&self) -> (ForeColor, BackColor) {
+        let fore = match self.fore {
+            Some(index) => ANSI_TO_EFI_COLOR[index % 8] | (index & 8),
+            None => usize::from(self.default_fore),
+        };
+        let fore = if self.bold { fore | 8 } else { fore };
+
+        let back = match self.back {
+            Some(index) => ANSI_TO_EFI_COLOR[index % 8] << 4,
+            None => usize::from(self.default_back),
+        };
+
+        (ForeColor::from(fore), BackColor::from(back))
+    }
+}
+
+/// What a console needs to keep between writes to interpret escape sequences
+pub(super) struct Ansi {
+    parser: Parser,
+    graphics: Graphics,
+    saved_cursor: Option<Position>,
+}
+
+impl Ansi {
+    pub fn new(default_fore: ForeColor, default_back: BackColor) -> Self {
+        Self { parser: Parser::new(), graphics: Graphics::new(default_fore, default_back), saved_cursor: None }
+    }
+}
+
+impl Console {
+    pub fn ansi_enabled(&self) -> bool {
+        self.ansi.is_some()
+    }
+
+    /// Sets whether ANSI escape sequences written through `io::Write` are interpreted.
+    /// The colors at the time it's enabled become the defaults that sequences reset to.
+    pub fn set_ansi(&mut self, enabled: bool) {
+        self.ansi = if enabled {
+            self.ansi.take().or_else(|| Some(Ansi::new(self.fore_color(), self.back_color())))
+        } else {
+            None
+        };
+    }
+
+    pub(super) fn write_ansi(&mut self, s: &str) -> Result<()> {
+        let mut ansi = match self.ansi.take() {
+            Some(ansi) => ansi,
+            None => return self.write_text(s),
+        };
+
+        let result = self.write_ansi_with(&mut ansi, s);
+        self.ansi = Some(ansi);
+        result
+    }
+
+    fn write_ansi_with(&mut self, ansi: &mut Ansi, s: &str) -> Result<()> {
+        let mut text = String::new();
+        for c in s.chars() {
+            match ansi.parser.advance(c) {
+                Some(Action::Print(c)) => text.push(c),
+                Some(Action::Command(command)) => {
+                    if !text.is_empty() {
+                        self.write_text(&text)?;
+                        text.clear();
+                    }
+                    self.execute(ansi, command)?;
+                },
+                None => {},
+            }
+        }
+
+        if !text.is_empty() {
+            self.write_text(&text)?;
+        }
+
+        Ok(())
+    }
+
+    fn execute(&mut self, ansi: &mut Ansi, command: Command) -> Result<()> {
+        let pos = self.cursor_pos();
+        let (row, col) = (pos.row as usize, pos.col as usize);
+
+        match command {
+            Command::Sgr(params) => {
+                ansi.graphics.apply(&params);
+                let (fore, back) = ansi.graphics.colors();
+                self.set_fore_color(fore)?;
+                self.set_back_color(back)
+            },
+            Command::CursorPosition { row, col } => self.move_cursor(row, col),
+            Command::CursorUp(n) => self.move_cursor(row.saturating_sub(n), col),
+            Command::CursorDown(n) => self.move_cursor(row.saturating_add(n), col),
+            Command::CursorForward(n) => self.move_cursor(row, col.saturating_add(n)),
+            Command::CursorBack(n) => self.move_cursor(row, col.saturating_sub(n)),
+            Command::CursorColumn(col) => self.move_cursor(row, col),
+            Command::EraseDisplay(mode) => {
+                let (columns, rows) = self.size()?;
+                match mode {
+                    0 => {
+                        self.blank(row, col, columns, rows)?;
+                        for r in row + 1..rows {
+                            self.blank(r, 0, columns, rows)?;
+                        }
+                    },
+                    1 => {
+                        for r in 0..row {
+                            self.blank(r, 0, columns, rows)?;
+                        }
+                        self.blank(row, 0, col + 1, rows)?;
+                    },
+                    _ => self.clear_screen()?,
+                }
+                self.set_cursor_pos(pos)
+            },
+            Command::EraseLine(mode) => {
+                let (columns, rows) = self.size()?;
+                let (from, to) = match mode {
+                    0 => (col, columns),
+                    1 => (0, col + 1),
+                    _ => (0, columns),
+                };
+                self.blank(row, from, to, rows)?;
+                self.set_cursor_pos(pos)
+            },
+            Command::ShowCursor(true) => self.enable_cursor(),
+            Command::ShowCursor(false) => self.disable_cursor(),
+            Command::SaveCursor => {
+                ansi.saved_cursor = Some(pos);
+                Ok(())
+            },
+            Command::RestoreCursor => match ansi.saved_cursor {
+                Some(saved) => self.set_cursor_pos(saved),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Moves the cursor, keeping it on the screen
+    fn move_cursor(&self, row: usize, col: usize) -> Result<()> {
+        let (columns, rows) = self.size()?;
+        let row = cmp::min(row, rows.saturating_sub(1));
+        let col = cmp::min(col, columns.saturating_sub(1));
+        self.set_cursor_pos(Position { row: row as u32, col: col as u32 })
+    }
+
+    /// Overwrites columns `from..to` of `row` with spaces in the current colors
+    fn blank(&self, row: usize, from: usize, to: usize, rows: usize) -> Result<()> {
+        let (columns, _) = self.size()?;
+        // Writing the very last cell of the screen makes the console scroll so stop one short of it
+        let last = if row + 1 >= rows { columns.saturating_sub(1) } else { columns };
+        let to = cmp::min(to, last);
+        if from >= to {
+            return Ok(());
+        }
+
+        self.set_cursor_pos(Position { row: row as u32, col: from as u32 })?;
+        self.write_str(&" ".repeat(to - from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Vec<Action> {
+        let mut parser = Parser::new();
+        s.chars().filter_map(|c| parser.advance(c)).collect()
+    }
+
+    #[test]
+    fn parses_text_and_commands() {
+        use self::Action::*;
+        use self::Command::*;
+
+        assert_eq!(parse("a\x1b[1;31mb\x1b[0m"), vec![Print('a'), Command(Sgr(vec![1, 31])), Print('b'), Command(Sgr(vec![0]))]);
+        assert_eq!(parse("\x1b[m\x1b[H\x1b[5;10f"), vec![
+            Command(Sgr(vec![0])),
+            Command(CursorPosition { row: 0, col: 0 }),
+            Command(CursorPosition { row: 4, col: 9 }),
+        ]);
+        assert_eq!(parse("\x1b[A\x1b[3B\x1b[0C\x1b[2D\x1b[7G"), vec![
+            Command(CursorUp(1)),
+            Command(CursorDown(3)),
+            Command(CursorForward(1)),
+            Command(CursorBack(2)),
+            Command(CursorColumn(6)),
+        ]);
+        assert_eq!(parse("\x1b[2J\x1b[K\x1b[?25l\x1b7\x1b8"), vec![
+            Command(EraseDisplay(2)),
+            Command(EraseLine(0)),
+            Command(ShowCursor(false)),
+            Command(SaveCursor),
+            Command(RestoreCursor),
+        ]);
+    }
+
+    #[test]
+    fn swallows_unknown_sequences_and_resumes_across_writes() {
+        use self::Action::*;
+
+        assert_eq!(parse("x\x1b[?1049hy\x1b[6nz\x1b(B!"), vec![Print('x'), Print('y'), Print('z'), Print('!')]);
+
+        let mut parser = Parser::new();
+        let first = "\x1b[3".chars().filter_map(|c| parser.advance(c)).collect::<Vec<_>>();
+        let second = "2mok".chars().filter_map(|c| parser.advance(c)).collect::<Vec<_>>();
+        assert!(first.is_empty());
+        assert_eq!(second, vec![Command(self::Command::Sgr(vec![32])), Print('o'), Print('k')]);
+    }
+
+    #[test]
+    fn maps_sgr_to_efi_colors() {
+        let colors = |params: &[u16]| {
+            let mut graphics = Graphics::new(ForeColor::LightGray, BackColor::Black);
+            graphics.apply(params);
+            let (fore, back) = graphics.colors();
+            (fore as usize, back as usize)
+        };
+
+        assert_eq!(colors(&[31, 44]), (ForeColor::Red as usize, BackColor::Blue as usize));
+        assert_eq!(colors(&[1, 33]), (ForeColor::Yellow as usize, BackColor::Black as usize));
+        assert_eq!(colors(&[33]), (ForeColor::Brown as usize, BackColor::Black as usize));
+        assert_eq!(colors(&[96, 107]), (ForeColor::LightCyan as usize, BackColor::LightGray as usize));
+        assert_eq!(colors(&[38, 5, 9, 48, 2, 1, 2, 3]), (ForeColor::LightRed as usize, BackColor::Black as usize));
+        assert_eq!(colors(&[31, 42, 0]), (ForeColor::LightGray as usize, BackColor::Black as usize));
+        assert_eq!(colors(&[1]), (ForeColor::White as usize, BackColor::Black as usize));
+    }
+}
@@ -0,0 +1,398 @@
+//! Interactive line editing for shells and prompts.
+//!
+//! Supports cursor movement (Left/Right, Home/End, Ctrl-A/Ctrl-E), deleting (Backspace, Delete,
+//! Ctrl-K, Ctrl-U), history (Up/Down) and tab completion through a `Completer`. Lines longer
+//! than the console is wide wrap onto the following rows and are redrawn correctly as they change.
+//!
+//! ```ignore
+//! let mut editor = LineEditor::new().with_completer(|line: &str, pos: usize| complete_command(line, pos));
+//! while let Some(line) = editor.read_line(&mut console(), "> ")? {
+//!     run(&line)?;
+//! }
+//! ```
+
+use super::{Console, Key, KeyPress, Position};
+use crate::Result;
+use alloc::{boxed::Box, vec::Vec, string::String};
+use core::cmp;
+
+const DEFAULT_MAX_HISTORY: usize = 100;
+
+/// Supplies completions when Tab is pressed
+pub trait Completer {
+    /// Gets the line and the cursor's position in it (in chars) and returns the char index at which
+    /// the word being completed starts along with the candidates to replace that word with.
+    fn complete(&mut self, line: &str, pos: usize) -> (usize, Vec<String>);
+}
+
+impl<F: FnMut(&str, usize) -> (usize, Vec<String>)> Completer for F {
+    fn complete(&mut self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        self(line, pos)
+    }
+}
+
+/// Reads lines from the console with editing, history and completion
+pub struct LineEditor {
+    history: Vec<String>,
+    max_history: usize,
+    completer: Option<Box<dyn Completer>>,
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self { history: Vec::new(), max_history: DEFAULT_MAX_HISTORY, completer: None }
+    }
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_completer<C: Completer + 'static>(mut self, completer: C) -> Self {
+        self.completer = Some(Box::new(completer));
+        self
+    }
+
+    /// Limits the number of lines kept in history. The oldest lines go first.
+    pub fn set_max_history(&mut self, max_history: usize) {
+        self.max_history = max_history;
+        self.trim_history();
+    }
+
+    /// Adds a line to the history. Lines that repeat the previous one are skipped.
+    pub fn add_history<S: Into<String>>(&mut self, line: S) {
+        let line = line.into();
+        if line.is_empty() || self.history.last() == Some(&line) {
+            return;
+        }
+
+        self.history.push(line);
+        self.trim_history();
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    fn trim_history(&mut self) {
+        if self.history.len() > self.max_history {
+            let excess = self.history.len() - self.max_history;
+            self.history.drain(..excess);
+        }
+    }
+
+    /// Shows `prompt` and reads a line, which is added to the history.
+    /// Returns `None` if Ctrl-D is pressed on an empty line.
+    pub fn read_line(&mut self, console: &mut Console, prompt: &str) -> Result<Option<String>> {
+        let mut view = View::new(console, prompt)?;
+        let mut buf = LineBuffer::default();
+        let mut history_index = self.history.len();
+        let mut edited_line = None; // What was typed before going back in history
+
+        view.draw(console, &buf)?;
+        loop {
+            let key_press = console.read_key()?;
+            match (key_press.key, control_letter(&key_press)) {
+                (_, Some('a')) | (Key::Home, _) => buf.move_home(),
+                (_, Some('e')) | (Key::End, _) => buf.move_end(),
+                (_, Some('k')) => buf.kill_to_end(),
+                (_, Some('u')) => buf.kill_to_start(),
+                (_, Some('d')) => {
+                    if buf.is_empty() {
+                        view.finish(console, &buf)?;
+                        return Ok(None);
+                    }
+                    buf.delete();
+                },
+                (_, Some(_)) => continue, // Other control keys do nothing
+                (Key::Enter, _) => {
+                    view.finish(console, &buf)?;
+                    let line = buf.line();
+                    self.add_history(line.clone());
+                    return Ok(Some(line));
+                },
+                (Key::Backspace, _) => buf.backspace(),
+                (Key::Delete, _) => buf.delete(),
+                (Key::Left, _) => buf.move_left(),
+                (Key::Right, _) => buf.move_right(),
+                (Key::Up, _) => {
+                    if history_index == 0 {
+                        continue;
+                    }
+                    if history_index == self.history.len() {
+                        edited_line = Some(buf.line());
+                    }
+                    history_index -= 1;
+                    buf = LineBuffer::from(self.history[history_index].as_str());
+                },
+                (Key::Down, _) => {
+                    if history_index == self.history.len() {
+                        continue;
+                    }
+                    history_index += 1;
+                    buf = match self.history.get(history_index) {
+                        Some(line) => LineBuffer::from(line.as_str()),
+                        None => LineBuffer::from(edited_line.take().unwrap_or_default().as_str()),
+                    };
+                },
+                (Key::Tab, _) => self.complete(console, &mut view, &mut buf)?,
+                (Key::Char(c), None) => buf.insert(c),
+                _ => continue,
+            }
+
+            view.draw(console, &buf)?;
+        }
+    }
+
+    fn complete(&mut self, console: &mut Console, view: &mut View, buf: &mut LineBuffer) -> Result<()> {
+        let completer = match self.completer {
+            Some(ref mut completer) => completer,
+            None => return Ok(()),
+        };
+
+        let (start, candidates) = completer.complete(&buf.line(), buf.cursor);
+        let start = cmp::min(start, buf.cursor);
+        match candidates.len() {
+            0 => (),
+            1 => buf.replace(start, &candidates[0]),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.chars().count() > buf.cursor - start {
+                    buf.replace(start, prefix);
+                } else {
+                    // Nothing more to fill in so list the candidates below the line and start over under them
+                    view.finish(console, buf)?;
+                    console.write_str(&candidates.join("  "))?;
+                    console.write_str("\r\n")?;
+                    *view = View::new(console, view.prompt)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+}
+
+/// Ctrl combinations come either as the letter with Ctrl held or as an ASCII control character
+pub(super) fn control_letter(key_press: &KeyPress) -> Option<char> {
+    match key_press.key {
+        Key::Char(c) if key_press.modifiers.ctrl && c.is_ascii_alphabetic() => Some(c.to_ascii_lowercase()),
+        Key::Char(c) if ('\u{1}'..='\u{1A}').contains(&c) => Some((b'a' + c as u8 - 1) as char),
+        _ => None,
+    }
+}
+
+fn common_prefix(candidates: &[String]) -> &str {
+    let first = &candidates[0];
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first.char_indices()
+            .zip(candidate.chars())
+            .take_while(|((i, a), b)| *i < len && a == b)
+            .last()
+            .map(|((i, a), _)| i + a.len_utf8())
+            .unwrap_or(0);
+    }
+
+    &first[..len]
+}
+
+/// The line being edited and the cursor position in it, both in chars
+#[derive(Default)]
+struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl<'a> From<&'a str> for LineBuffer {
+    fn from(line: &'a str) -> Self {
+        let chars = line.chars().collect::<Vec<_>>();
+        Self { cursor: chars.len(), chars }
+    }
+}
+
+impl LineBuffer {
+    fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    fn line(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn move_right(&mut self) {
+        self.cursor = cmp::min(self.cursor + 1, self.chars.len());
+    }
+
+    fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn move_end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+
+    fn kill_to_end(&mut self) {
+        self.chars.truncate(self.cursor);
+    }
+
+    fn kill_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+
+    /// Replaces the chars from `start` up to the cursor with `text` and puts the cursor after it
+    fn replace(&mut self, start: usize, text: &str) {
+        let end = self.cursor;
+        self.chars.splice(start..end, text.chars());
+        self.cursor = start + text.chars().count();
+    }
+}
+
+/// Where the prompt and line are on screen
+struct View<'p> {
+    prompt: &'p str,
+    prompt_len: usize,
+    origin: Position, // Where the prompt starts
+    columns: usize,
+    rows: usize,
+    drawn_len: usize, // Chars drawn last time including the prompt. Anything beyond the new length must be erased.
+}
+
+impl<'p> View<'p> {
+    fn new(console: &Console, prompt: &'p str) -> Result<Self> {
+        let (columns, rows) = console.size()?;
+        Ok(Self {
+            prompt,
+            prompt_len: prompt.chars().count(),
+            origin: console.cursor_pos(),
+            columns: cmp::max(columns, 1),
+            rows: cmp::max(rows, 1),
+            drawn_len: 0,
+        })
+    }
+
+    fn draw(&mut self, console: &mut Console, buf: &LineBuffer) -> Result<()> {
+        let len = self.prompt_len + buf.len();
+        let mut text = String::with_capacity(cmp::max(len, self.drawn_len));
+        text.push_str(self.prompt);
+        text.extend(buf.chars.iter());
+        text.extend((len..self.drawn_len).map(|_| ' '));
+
+        console.set_cursor_pos(self.origin)?;
+        console.write_str(&text)?;
+
+        // Writing past the bottom row scrolls the screen up and the prompt with it
+        let (end_row, _) = offset_to_screen(self.origin, cmp::max(len, self.drawn_len), self.columns);
+        if end_row >= self.rows {
+            self.origin.row -= cmp::min((end_row - self.rows + 1) as u32, self.origin.row);
+        }
+        self.drawn_len = len;
+
+        let (row, col) = offset_to_screen(self.origin, self.prompt_len + buf.cursor, self.columns);
+        console.set_cursor_pos(Position { row: row as u32, col: col as u32 })
+    }
+
+    /// Moves the cursor to the start of the row after the line
+    fn finish(&mut self, console: &mut Console, buf: &LineBuffer) -> Result<()> {
+        let (row, col) = offset_to_screen(self.origin, self.prompt_len + buf.len(), self.columns);
+        let row = cmp::min(row, self.rows - 1); // An exactly full last row already scrolled the cursor onto a new one
+        console.set_cursor_pos(Position { row: row as u32, col: col as u32 })?;
+        if col != 0 || self.prompt_len + buf.len() == 0 {
+            console.write_str("\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Screen row and column of the char `offset` chars after `origin` when lines wrap at `columns`
+fn offset_to_screen(origin: Position, offset: usize, columns: usize) -> (usize, usize) {
+    let linear = origin.col as usize + offset;
+    (origin.row as usize + linear / columns, linear % columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{Modifiers, Toggles};
+
+    #[test]
+    fn edits_the_line_buffer() {
+        let mut buf = LineBuffer::from("boot hd0");
+        buf.move_home();
+        buf.delete();
+        buf.insert('B');
+        buf.move_end();
+        buf.backspace();
+        buf.insert('1');
+        assert_eq!(buf.line(), "Boot hd1");
+
+        for _ in 0..4 {
+            buf.move_left();
+        }
+        buf.kill_to_end();
+        assert_eq!(buf.line(), "Boot");
+
+        buf.move_left();
+        buf.kill_to_start();
+        assert_eq!((buf.line().as_str(), buf.cursor), ("t", 0));
+    }
+
+    #[test]
+    fn completes_the_word_before_the_cursor() {
+        let mut buf = LineBuffer::from("ls fs0:\\EF");
+        buf.replace(3, "fs0:\\EFI\\");
+        assert_eq!(buf.line(), "ls fs0:\\EFI\\");
+        assert_eq!(buf.cursor, buf.len());
+
+        let candidates = ["reboot".into(), "reset".into(), "resize".into()];
+        assert_eq!(common_prefix(&candidates), "re");
+        assert_eq!(common_prefix(&candidates[1..]), "res");
+        assert_eq!(common_prefix(&["über".into(), "übel".into()]), "übe");
+        assert_eq!(common_prefix(&["a".into(), "b".into()]), "");
+    }
+
+    #[test]
+    fn wraps_offsets_across_rows() {
+        let origin = Position { row: 3, col: 70 };
+        assert_eq!(offset_to_screen(origin, 5, 80), (3, 75));
+        assert_eq!(offset_to_screen(origin, 10, 80), (4, 0));
+        assert_eq!(offset_to_screen(origin, 95, 80), (5, 5));
+    }
+
+    #[test]
+    fn recognizes_control_keys() {
+        let press = |key, ctrl| KeyPress { key, modifiers: Modifiers { ctrl, ..Modifiers::default() }, toggles: Toggles::default() };
+        assert_eq!(control_letter(&press(Key::Char('A'), true)), Some('a'));
+        assert_eq!(control_letter(&press(Key::Char('\u{5}'), false)), Some('e'));
+        assert_eq!(control_letter(&press(Key::Char('k'), false)), None);
+        assert_eq!(control_letter(&press(Key::Left, true)), None);
+    }
+}
@@ -6,8 +6,18 @@ use ffi::{
         EFI_KEY_DATA,
         EFI_INPUT_KEY,
         EFI_SHIFT_STATE_VALID,
+        EFI_LEFT_SHIFT_PRESSED,
+        EFI_RIGHT_SHIFT_PRESSED,
         EFI_LEFT_CONTROL_PRESSED,
         EFI_RIGHT_CONTROL_PRESSED,
+        EFI_LEFT_ALT_PRESSED,
+        EFI_RIGHT_ALT_PRESSED,
+        EFI_LEFT_LOGO_PRESSED,
+        EFI_RIGHT_LOGO_PRESSED,
+        EFI_TOGGLE_STATE_VALID,
+        EFI_SCROLL_LOCK_ACTIVE,
+        EFI_NUM_LOCK_ACTIVE,
+        EFI_CAPS_LOCK_ACTIVE,
         EFI_BLACK,
         EFI_BLUE,
         EFI_GREEN,
@@ -34,17 +44,28 @@ use ffi::{
         EFI_BACKGROUND_LIGHTGRAY,
     }, 
     IsSuccess, 
+    EFI_EVENT,
+    EFI_NOT_READY,
     UINTN,
+    UINT32,
     TRUE,
     FALSE,
 };
-use core::{cmp, mem::transmute};
+use core::{cmp, mem::transmute, time::Duration};
 use crate::{SystemTable, io::{self, Write, Cursor, BufRead, BufReader, LineWriter}};
+use crate::events::{self, AsRawEvt, Timer, TimerSchedule, TimerState, EventTpl};
 use crate::Result;
 use crate::system_table;
 use crate::TextInputProcolPtr;
 use alloc::{vec::Vec, string::String, str, fmt};
 
+mod ansi;
+mod line_editor;
+mod password;
+
+pub use self::line_editor::{LineEditor, Completer};
+pub use self::password::Password;
+
 // TODO: This whole module has gotten ugly. Needs cleanup.
 // TODO: Should we replace Console with two structs, StdIn and StdOut, corresponding to input and output? This is more in line with Rust stdlib.
 
@@ -112,10 +133,142 @@ impl From<BackColor> for UINTN {
     }
 }
 
+/// A key on the keyboard
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Key {
+    /// A printable character
+    Char(char),
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// A function key. F1 is `Function(1)`.
+    Function(u8),
+    /// Any other scan code
+    Unknown(u16),
+}
+
+// Scan codes from the UEFI spec. Not in efi_ffi yet.
+const SCAN_UP: u16 = 0x01;
+const SCAN_DOWN: u16 = 0x02;
+const SCAN_RIGHT: u16 = 0x03;
+const SCAN_LEFT: u16 = 0x04;
+const SCAN_HOME: u16 = 0x05;
+const SCAN_END: u16 = 0x06;
+const SCAN_INSERT: u16 = 0x07;
+const SCAN_DELETE: u16 = 0x08;
+const SCAN_PAGE_UP: u16 = 0x09;
+const SCAN_PAGE_DOWN: u16 = 0x0A;
+const SCAN_F1: u16 = 0x0B;
+const SCAN_F12: u16 = 0x16;
+const SCAN_ESC: u16 = 0x17;
+
+const TAB: u16 = 9;
+
+/// Modifier keys held down during a key press.
+/// All false if the firmware doesn't report them (i.e. no Simple Text Input Ex protocol).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub logo: bool,
+}
+
+/// Lock key states during a key press. All false if the firmware doesn't report them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Toggles {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// A key press along with the modifier and toggle state at the time
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyPress {
+    pub key: Key,
+    pub modifiers: Modifiers,
+    pub toggles: Toggles,
+}
+
+impl KeyPress {
+    /// Converts what the input protocols report. Returns `None` for reports that aren't a key
+    /// press, e.g. when only a modifier key went down.
+    fn from_raw(scan_code: u16, unicode_char: u16, shift_state: UINT32, toggle_state: u8) -> Option<Self> {
+        let key = match (scan_code, unicode_char) {
+            (0, 0) => return None,
+            (0, CR) => Key::Enter,
+            (0, BS) => Key::Backspace,
+            (0, TAB) => Key::Tab,
+            (0, c) => Key::Char(char::from_u32(c as u32)?), // Lone surrogates can't be turned into a char
+            (SCAN_UP, _) => Key::Up,
+            (SCAN_DOWN, _) => Key::Down,
+            (SCAN_RIGHT, _) => Key::Right,
+            (SCAN_LEFT, _) => Key::Left,
+            (SCAN_HOME, _) => Key::Home,
+            (SCAN_END, _) => Key::End,
+            (SCAN_INSERT, _) => Key::Insert,
+            (SCAN_DELETE, _) => Key::Delete,
+            (SCAN_PAGE_UP, _) => Key::PageUp,
+            (SCAN_PAGE_DOWN, _) => Key::PageDown,
+            (SCAN_F1..=SCAN_F12, _) => Key::Function((scan_code - SCAN_F1 + 1) as u8),
+            (SCAN_ESC, _) => Key::Escape,
+            (code, _) => Key::Unknown(code),
+        };
+
+        let mut modifiers = Modifiers::default();
+        if shift_state & EFI_SHIFT_STATE_VALID != 0 {
+            modifiers.shift = shift_state & (EFI_LEFT_SHIFT_PRESSED | EFI_RIGHT_SHIFT_PRESSED) != 0;
+            modifiers.ctrl = shift_state & (EFI_LEFT_CONTROL_PRESSED | EFI_RIGHT_CONTROL_PRESSED) != 0;
+            modifiers.alt = shift_state & (EFI_LEFT_ALT_PRESSED | EFI_RIGHT_ALT_PRESSED) != 0;
+            modifiers.logo = shift_state & (EFI_LEFT_LOGO_PRESSED | EFI_RIGHT_LOGO_PRESSED) != 0;
+        }
+
+        let mut toggles = Toggles::default();
+        if toggle_state & EFI_TOGGLE_STATE_VALID != 0 {
+            toggles.caps_lock = toggle_state & EFI_CAPS_LOCK_ACTIVE != 0;
+            toggles.num_lock = toggle_state & EFI_NUM_LOCK_ACTIVE != 0;
+            toggles.scroll_lock = toggle_state & EFI_SCROLL_LOCK_ACTIVE != 0;
+        }
+
+        Some(KeyPress { key, modifiers, toggles })
+    }
+}
+
+/// The input protocol's wait event so it can be passed to `events::wait_any`
+struct KeyEvent(EFI_EVENT);
+
+impl AsRawEvt for KeyEvent {
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0
+    }
+}
+
 pub struct Console {
     pub input: TextInputProcolPtr,
     pub output: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
-    utf8_buf: io::Cursor<Vec<u8>>
+    utf8_buf: io::Cursor<Vec<u8>>,
+    echo: Echo,
+    ansi: Option<ansi::Ansi>,
+}
+
+/// How characters typed while reading from the console are echoed back to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Echo {
+    On,
+    Off,
+    /// Echo this character in place of each one typed
+    Mask(char),
 }
 
 const LF: u16 = 10;
@@ -124,7 +277,16 @@ const BS: u16 = 8;
 
 impl Console {
     pub fn new(input: TextInputProcolPtr, output: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL) -> Self {
-        Self { input, output, utf8_buf: Cursor::new(Vec::new()) }
+        Self { input, output, utf8_buf: Cursor::new(Vec::new()), echo: Echo::On, ansi: None }
+    }
+
+    pub fn echo(&self) -> Echo {
+        self.echo
+    }
+
+    /// Sets how typed characters are echoed by `read` and friends. Line endings are always echoed.
+    pub fn set_echo(&mut self, echo: Echo) {
+        self.echo = echo;
     }
 
     pub fn cursor_pos(&self) -> Position {
@@ -140,6 +302,10 @@ impl Console {
         Ok(())
     }
 
+    pub fn cursor_visible(&self) -> bool {
+        unsafe { (*(*(*self).output).Mode).CursorVisible != FALSE }
+    }
+
     pub fn enable_cursor(&mut self) -> Result<()> {
         unsafe {
             ret_on_err!(((*(*self).output).EnableCursor)(self.output, TRUE));
@@ -164,9 +330,6 @@ impl Console {
         Ok(())
     }
 
-    // TODO: instead of u32 use a strong type like 'ConsoleMode'
-    // It may simply be a newtype around u32 and have methods
-    // 'resolution()' which return something like 80x25, 80x50, Custom etc.
     pub fn max_supported_mode(&mut self) -> u32 {
         unsafe { (*(*(*self).output).Mode).MaxMode  as u32 } // Cast from i32 to u32 to is safe
     }
@@ -179,6 +342,38 @@ impl Console {
         Ok(())
     }
 
+    /// The text modes the console supports. Modes the console reports as unsupported are skipped.
+    pub fn modes(&self) -> Modes<'_> {
+        let max_mode = unsafe { (*(*(*self).output).Mode).MaxMode } as u32; // Cast from i32 to u32 to is safe
+        Modes { console: self, next: 0, max_mode }
+    }
+
+    pub fn current_mode(&self) -> Result<ConsoleMode> {
+        let number = unsafe { (*(*(*self).output).Mode).Mode } as u32; // Cast from i32 to u32 to is safe
+        self.query_mode(number)
+    }
+
+    /// Number of columns and rows in the current text mode
+    pub fn size(&self) -> Result<(usize, usize)> {
+        let mode = self.current_mode()?;
+        Ok((mode.columns, mode.rows))
+    }
+
+    /// The supported mode with the most character cells, if any
+    pub fn largest_mode(&self) -> Option<ConsoleMode> {
+        largest(self.modes())
+    }
+
+    fn query_mode(&self, number: u32) -> Result<ConsoleMode> {
+        let mut columns: UINTN = 0;
+        let mut rows: UINTN = 0;
+        unsafe {
+            ret_on_err!(((*self.output).QueryMode)(self.output, number as UINTN, &mut columns, &mut rows));
+        }
+
+        Ok(ConsoleMode { number, columns, rows })
+    }
+
     pub fn fore_color(&mut self) -> ForeColor {
         let attribute = unsafe { (*(*(*self).output).Mode).Attribute } as UINTN; // TODO: Cast should be safe on patforms with 32 and 64 ptr widths. Do we need to worry about other platforms?
         let fore_color_num = attribute & 0b1111; // Bits 0..3 are fore color, 4..6 are back color
@@ -225,6 +420,107 @@ impl Console {
         Ok(())
     }
 
+    /// Blocks until a key is pressed and returns it. Unlike reading through `io::Read`
+    /// nothing is echoed and keys without a character such as arrows are reported too.
+    pub fn read_key(&mut self) -> Result<KeyPress> {
+        loop {
+            if let Some(key_press) = self.poll_key()? {
+                return Ok(key_press);
+            }
+
+            let mut evt_index: UINTN = 0;
+            let evt_list = [self.wait_for_key_event()];
+            unsafe {
+                ret_on_err!(((*system_table().BootServices).WaitForEvent)(evt_list.len(), evt_list.as_ptr(), &mut evt_index));
+            }
+        }
+    }
+
+    /// Same as `read_key` but gives up after `timeout` and returns `None`.
+    /// E.g. for a "press any key to enter setup" countdown.
+    pub fn read_key_timeout(&mut self, timeout: Duration) -> Result<Option<KeyPress>> {
+        let timer = Timer::create(timeout, TimerSchedule::Relative, TimerState::Active, EventTpl::Callback)?;
+        let key_event = KeyEvent(self.wait_for_key_event());
+        loop {
+            if let Some(key_press) = self.poll_key()? {
+                return Ok(Some(key_press));
+            }
+
+            if events::wait_any(&[&key_event, &timer], None)? == Some(1) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns the next key press if there is one without blocking
+    pub fn poll_key(&mut self) -> Result<Option<KeyPress>> {
+        loop {
+            let (scan_code, unicode_char, shift_state, toggle_state) = match self.input {
+                TextInputProcolPtr::Input(input) => {
+                    let mut key = EFI_INPUT_KEY::default();
+                    let status = unsafe { ((*input).ReadKeyStroke)(input, &mut key) };
+                    if status == EFI_NOT_READY {
+                        return Ok(None);
+                    }
+                    ret_on_err!(status);
+                    (key.ScanCode, key.UnicodeChar, 0, 0)
+                },
+                TextInputProcolPtr::InputEx(input_ex) => {
+                    let mut key_data = EFI_KEY_DATA::default();
+                    let status = unsafe { ((*input_ex).ReadKeyStrokeEx)(input_ex, &mut key_data) };
+                    if status == EFI_NOT_READY {
+                        return Ok(None);
+                    }
+                    ret_on_err!(status);
+                    (key_data.Key.ScanCode, key_data.Key.UnicodeChar, key_data.KeyState.KeyShiftState, key_data.KeyState.KeyToggleState)
+                },
+            };
+
+            // Reports that aren't key presses are skipped in favour of whatever comes after them
+            if let Some(key_press) = KeyPress::from_raw(scan_code, unicode_char, shift_state, toggle_state) {
+                return Ok(Some(key_press));
+            }
+        }
+    }
+
+    fn wait_for_key_event(&self) -> EFI_EVENT {
+        match self.input {
+            TextInputProcolPtr::Input(input) => unsafe { (*input).WaitForKey },
+            TextInputProcolPtr::InputEx(input_ex) => unsafe { (*input_ex).WaitForKeyEx },
+        }
+    }
+
+    /// Writes `utf8_buf` translating LF's to CRLF's
+    fn write_text(&self, utf8_buf: &str) -> Result<()> {
+        // Convert to UTF16, normalizing all LF's to CRLF's (if present)
+        // because UEFI console doesn't automatically perform carriage upon seeing LF's
+        let utf16_iter = utf8_buf.encode_utf16();
+        let mut expected_utf16_buf_size = utf16_iter.size_hint().1.unwrap_or(utf8_buf.len()); // Guessing the capacity of utf16 buffer.
+        let five_percent = (expected_utf16_buf_size as f32 * 0.05) as usize;
+        let extra_size_for_line_endings = cmp::max(5, five_percent); // Least of 5 chars worth of space will come into play for very small writes. Without min limit extra could come out to be zero.
+        expected_utf16_buf_size += extra_size_for_line_endings; // Extra added in case we have to normalize line endings
+        let mut utf16_buf = Vec::with_capacity(expected_utf16_buf_size);
+
+        let mut last_c = 0_u16;
+        for (i, c) in utf16_iter.enumerate() {
+            if c == LF && (i == 0 || last_c != CR) { // Normalizing LF's
+                utf16_buf.push(CR);
+            }
+            utf16_buf.push(c);
+            last_c = c;
+        }
+
+        utf16_buf.push(0); // Appending the null terminator
+
+        self.write_to_efi(&utf16_buf)
+    }
+
+    /// Writes `s` as is, i.e. without translating line endings
+    pub(crate) fn write_str(&self, s: &str) -> Result<()> {
+        let utf16 = s.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+        self.write_to_efi(&utf16)
+    }
+
     fn write_to_efi(&self, buf: &[u16]) -> Result<()> {
         unsafe {
             let (ptr, _) = to_ptr(buf);
@@ -276,7 +572,7 @@ impl Console {
                     BS => {
                         if bytes_read > 0 {
                             bytes_read -= 1;
-                            self.write_to_efi(&[BS, 0])?; // 0 is for null termination
+                            self.echo_char(BS)?;
                         }
                     },
                     c => {
@@ -290,7 +586,7 @@ impl Console {
                                 self.write_to_efi(&[CR, LF, 0])?; // Must echo both CR and LF because other wise it fucks up the cursor position.
                                 break;
                             } else {
-                                self.write_to_efi(&[c, 0])?;
+                                self.echo_char(c)?;
                             }
                         }
                     }
@@ -305,6 +601,19 @@ impl Console {
         Ok(bytes_read)
     }
 
+    fn echo_char(&self, c: u16) -> Result<()> {
+        match self.echo {
+            Echo::On => self.write_to_efi(&[c, 0]), // 0 is for null termination
+            Echo::Off => Ok(()),
+            Echo::Mask(_) if c == BS => self.write_to_efi(&[BS, 0]),
+            Echo::Mask(mask) => {
+                let mut mask_buf = [0u16; 3];
+                let len = mask.encode_utf16(&mut mask_buf).len();
+                self.write_to_efi(&mask_buf[..len + 1])
+            }
+        }
+    }
+
     fn read_from_efi_input(&self, buf: &mut [u16], input: *mut EFI_SIMPLE_TEXT_INPUT_PROTOCOL) -> Result<usize> {
         let mut bytes_read = 0;
 
@@ -334,7 +643,7 @@ impl Console {
                     BS => {
                         if bytes_read > 0 {
                             bytes_read -= 1;
-                            self.write_to_efi(&[BS, 0])?; // 0 is for null termination
+                            self.echo_char(BS)?;
                         }
                     },
                     c => {
@@ -345,7 +654,7 @@ impl Console {
                             self.write_to_efi(&[CR, LF, 0])?; // Must echo both CR and LF because other wise it fucks up the cursor position.
                             break;
                         } else {
-                            self.write_to_efi(&[c, 0])?;
+                            self.echo_char(c)?;
                         }
                     }
                 };
@@ -375,28 +684,8 @@ impl io::Write for Console {
             Err(e) => str::from_utf8(&buf[..e.valid_up_to()]).unwrap(), // At least write those that are valid
         };
 
-        // Convert to UTF16, normalizing all LF's to CRLF's (if present)
-        // because UEFI console doesn't automatically perform carriage upon seeing LF's
-        let utf16_iter = utf8_buf.encode_utf16();
-        let mut expected_utf16_buf_size = utf16_iter.size_hint().1.unwrap_or(utf8_buf.len()); // Guessing the capacity of utf16 buffer.
-        let five_percent = (expected_utf16_buf_size as f32 * 0.05) as usize;
-        let extra_size_for_line_endings = cmp::max(5, five_percent); // Least of 5 chars worth of space will come into play for very small writes. Without min limit extra could come out to be zero.
-        expected_utf16_buf_size += extra_size_for_line_endings; // Extra added in case we have to normalize line endings
-        let mut utf16_buf = Vec::with_capacity(expected_utf16_buf_size);
-
-        let mut last_c = 0_u16;
-        for (i, c) in utf16_iter.enumerate() {
-            if c == LF && (i == 0 || last_c != CR) { // Normalizing LF's
-                utf16_buf.push(CR);
-            }
-            utf16_buf.push(c);
-            last_c = c;
-        }
-
-        utf16_buf.push(0); // Appending the null terminator
-
-        self.write_to_efi(&utf16_buf)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to write to EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL"))?; // TODO: Don't swallaow EFI status like this. Error handling in this whole crate needs fixing
+        let result = if self.ansi.is_some() { self.write_ansi(utf8_buf) } else { self.write_text(utf8_buf) };
+        result.map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to write to EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL"))?; // TODO: Don't swallaow EFI status like this. Error handling in this whole crate needs fixing
 
         Ok(utf8_buf.len())
     }
@@ -466,6 +755,42 @@ impl io::Write for StdOut {
     }
 }
 
+/// A text mode along with its geometry
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConsoleMode {
+    /// The number to pass to `Console::set_mode`
+    pub number: u32,
+    pub columns: usize,
+    pub rows: usize,
+}
+
+/// Iterator over the supported text modes returned by `Console::modes`
+pub struct Modes<'a> {
+    console: &'a Console,
+    next: u32,
+    max_mode: u32,
+}
+
+impl<'a> Iterator for Modes<'a> {
+    type Item = ConsoleMode;
+
+    fn next(&mut self) -> Option<ConsoleMode> {
+        while self.next < self.max_mode {
+            let number = self.next;
+            self.next += 1;
+            if let Ok(mode) = self.console.query_mode(number) {
+                return Some(mode);
+            }
+        }
+
+        None
+    }
+}
+
+fn largest<I: IntoIterator<Item=ConsoleMode>>(modes: I) -> Option<ConsoleMode> {
+    modes.into_iter().max_by_key(|m| (m.columns * m.rows, m.columns))
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Position {
     pub row: u32,
@@ -513,3 +838,45 @@ fn to_ptr<T>(slice: &[T]) -> (*const T, usize) {
         transmute(slice)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_raw_keys() {
+        let key = |scan_code, unicode_char| KeyPress::from_raw(scan_code, unicode_char, 0, 0).map(|k| k.key);
+        assert_eq!(key(0, 'a' as u16), Some(Key::Char('a')));
+        assert_eq!(key(0, CR), Some(Key::Enter));
+        assert_eq!(key(SCAN_LEFT, 0), Some(Key::Left));
+        assert_eq!(key(SCAN_F1, 0), Some(Key::Function(1)));
+        assert_eq!(key(SCAN_F12, 0), Some(Key::Function(12)));
+        assert_eq!(key(SCAN_ESC, 0), Some(Key::Escape));
+        assert_eq!(key(0x48, 0), Some(Key::Unknown(0x48)));
+        assert_eq!(key(0, 0), None);
+        assert_eq!(key(0, 0xD800), None);
+    }
+
+    #[test]
+    fn converts_modifier_and_toggle_state() {
+        let shift_state = EFI_SHIFT_STATE_VALID | EFI_LEFT_CONTROL_PRESSED | EFI_RIGHT_ALT_PRESSED;
+        let toggle_state = EFI_TOGGLE_STATE_VALID | EFI_CAPS_LOCK_ACTIVE;
+        let key_press = KeyPress::from_raw(0, 'c' as u16, shift_state, toggle_state).unwrap();
+        assert_eq!(key_press.modifiers, Modifiers { shift: false, ctrl: true, alt: true, logo: false });
+        assert_eq!(key_press.toggles, Toggles { caps_lock: true, num_lock: false, scroll_lock: false });
+
+        // Without the valid bits the state is unknown and reported as nothing pressed
+        let key_press = KeyPress::from_raw(0, 'c' as u16, EFI_LEFT_CONTROL_PRESSED, EFI_CAPS_LOCK_ACTIVE).unwrap();
+        assert_eq!(key_press.modifiers, Modifiers::default());
+        assert_eq!(key_press.toggles, Toggles::default());
+    }
+
+    #[test]
+    fn picks_mode_with_most_cells() {
+        let mode = |number, columns, rows| ConsoleMode { number, columns, rows };
+        assert_eq!(largest(vec![mode(0, 80, 25), mode(2, 100, 31), mode(1, 80, 50)]), Some(mode(1, 80, 50)));
+        assert_eq!(largest(vec![mode(0, 80, 50), mode(1, 100, 40)]), Some(mode(1, 100, 40))); // Same cell count, wider wins
+        assert_eq!(largest(vec![]), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Reading passphrases and other secrets from the console.
+//!
+//! The typed characters are masked rather than echoed and the buffer holding them is zeroed
+//! once it's no longer needed, including when the returned `Password` is dropped.
+//!
+//! ```ignore
+//! if let Some(passphrase) = console().read_password("Passphrase: ")? {
+//!     unlock_disk(passphrase.as_bytes())?;
+//! }
+//! ```
+
+use super::{Console, Echo, Key, line_editor::control_letter};
+use crate::Result;
+use alloc::string::String;
+use core::{fmt, mem, ops::Deref, ptr, sync::atomic::{compiler_fence, Ordering}};
+
+const INITIAL_CAPACITY: usize = 128;
+
+/// A string that's zeroed when it's dropped
+pub struct Password(String);
+
+impl Password {
+    fn new() -> Self {
+        Password(String::with_capacity(INITIAL_CAPACITY))
+    }
+
+    fn push(&mut self, c: char) {
+        // Growing the string in place would leave a copy of it behind in the old allocation
+        if self.0.len() + c.len_utf8() > self.0.capacity() {
+            let mut grown = String::with_capacity(self.0.capacity() * 2);
+            grown.push_str(&self.0);
+            let mut old = mem::replace(&mut self.0, grown);
+            old.clear();
+            zero_string(&mut old);
+        }
+
+        self.0.push(c);
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        let c = self.0.pop();
+        zero_string(&mut self.0); // Also zeroes the popped char which is now past the end
+        c
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+        zero_string(&mut self.0);
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Password {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+// Deliberately doesn't print the password so it can't end up in logs by accident
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Zeroes the spare capacity of the string, i.e. everything after its current length
+fn zero_string(s: &mut String) {
+    let vec = unsafe { s.as_mut_vec() };
+    for byte in vec.spare_capacity_mut() {
+        unsafe { ptr::write_volatile(byte.as_mut_ptr(), 0) };
+    }
+    compiler_fence(Ordering::SeqCst); // Keep the writes from being optimized away as dead stores
+}
+
+impl Console {
+    /// Prints `prompt` and reads a line without echoing it, showing a `*` for each typed
+    /// character unless echo has been set to `Echo::Off` or a different mask.
+    /// Returns `None` if the user presses Escape.
+    pub fn read_password(&mut self, prompt: &str) -> Result<Option<Password>> {
+        let mask = match self.echo {
+            Echo::On => Some('*'),
+            Echo::Off => None,
+            Echo::Mask(mask) => Some(mask),
+        };
+
+        let mut mask_buf = [0u8; 4];
+        let mask = mask.map(|m| &*m.encode_utf8(&mut mask_buf));
+
+        self.write_str(prompt)?;
+
+        let mut password = Password::new();
+        loop {
+            let key_press = self.read_key()?;
+            match (key_press.key, control_letter(&key_press)) {
+                (Key::Enter, _) => break,
+                (Key::Escape, _) => {
+                    self.write_str("\r\n")?;
+                    return Ok(None);
+                },
+                (Key::Backspace, _) => {
+                    let popped = password.pop();
+                    if popped.is_some() && mask.is_some() {
+                        self.write_str("\u{8}")?;
+                    }
+                },
+                (_, Some('u')) => {
+                    if mask.is_some() {
+                        for _ in password.chars() {
+                            self.write_str("\u{8}")?;
+                        }
+                    }
+                    password.clear();
+                },
+                (Key::Char(c), None) if !c.is_control() && !key_press.modifiers.ctrl && !key_press.modifiers.alt => {
+                    password.push(c);
+                    if let Some(mask) = mask {
+                        self.write_str(mask)?;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        self.write_str("\r\n")?;
+        Ok(Some(password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_survives_growing_past_initial_capacity() {
+        let mut password = Password::new();
+        let expected = "pässwörd".repeat(INITIAL_CAPACITY / 4);
+        for c in expected.chars() {
+            password.push(c);
+        }
+
+        assert_eq!(password.as_str(), expected);
+        assert!(password.0.capacity() > INITIAL_CAPACITY);
+    }
+
+    #[test]
+    fn pop_zeroes_removed_bytes() {
+        let mut password = Password::new();
+        password.push('a');
+        password.push('ß');
+        assert_eq!(password.pop(), Some('ß'));
+        assert_eq!(&*password, "a");
+
+        let vec = unsafe { password.0.as_mut_vec() };
+        let spare = vec.spare_capacity_mut();
+        assert!(spare[..2].iter().all(|b| unsafe { b.assume_init() } == 0));
+    }
+}
@@ -0,0 +1,181 @@
+//! Fluent construction of device paths from typed nodes.
+//!
+//! ```ignore
+//! let path = DevicePathBuilder::new()
+//!     .hard_drive(1, &esp)
+//!     .file_path(r"\EFI\BOOT\BOOTX64.EFI")
+//!     .build()?;
+//! ```
+
+use super::{DevicePath, node::{Node, PartitionSignature, VendorKind}};
+use crate::{Guid, Result, EfiErrorKind, disk::gpt::GptPartition, net::{Ipv4Addr, Ipv6Addr}, utils::clone_guid};
+use alloc::{vec::Vec, string::String};
+
+const ETHERNET_IF_TYPE: u8 = 1; // From RFC 1700 as the spec asks
+const TCP: u16 = 6;
+
+/// Builds a device path node by node.
+///
+/// The final end node is added by `build` so it never needs to be added by hand.
+#[derive(Clone, Default)]
+pub struct DevicePathBuilder {
+    nodes: Vec<Node>,
+}
+
+impl DevicePathBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from the nodes of an existing path, e.g. the path of a device handle
+    pub fn from_path(path: &DevicePath) -> Self {
+        Self { nodes: path.nodes().filter(|n| *n != Node::End).collect() }
+    }
+
+    /// Appends any node. `Node::End` is ignored because `build` terminates the path anyway.
+    pub fn node(mut self, node: Node) -> Self {
+        if node != Node::End {
+            self.nodes.push(node);
+        }
+        self
+    }
+
+    /// A GPT partition. `partition_number` is the 1-based index of its entry in the partition table.
+    pub fn hard_drive(self, partition_number: u32, partition: &GptPartition) -> Self {
+        self.node(Node::HardDrive {
+            partition_number,
+            start: partition.starting_lba,
+            size: partition.ending_lba - partition.starting_lba + 1,
+            signature: PartitionSignature::Gpt(clone_guid(&partition.unique_guid)),
+        })
+    }
+
+    /// A file relative to the device before it. Path separators are backslashes.
+    pub fn file_path<P: AsRef<str>>(self, path: P) -> Self {
+        self.node(Node::FilePath(path.as_ref().into()))
+    }
+
+    /// An Ethernet MAC address
+    pub fn mac(self, address: [u8; 6]) -> Self {
+        let mut padded = [0u8; 32];
+        padded[..6].copy_from_slice(&address);
+        self.node(Node::Mac { address: padded, if_type: ETHERNET_IF_TYPE })
+    }
+
+    /// An IPv4 node as used for HTTP boot: TCP with DHCP assigned addresses.
+    /// Use `node` with a `Node::Ipv4` for anything else.
+    pub fn ipv4(self, local: Ipv4Addr, remote: Ipv4Addr) -> Self {
+        self.node(Node::Ipv4 {
+            local,
+            remote,
+            local_port: 0,
+            remote_port: 0,
+            protocol: TCP,
+            static_ip: false,
+            gateway: Ipv4Addr::unspecified(),
+            subnet_mask: Ipv4Addr::unspecified(),
+        })
+    }
+
+    /// Same as `ipv4` for IPv6. The address origin is left as static.
+    pub fn ipv6(self, local: Ipv6Addr, remote: Ipv6Addr) -> Self {
+        self.node(Node::Ipv6 {
+            local,
+            remote,
+            local_port: 0,
+            remote_port: 0,
+            protocol: TCP,
+            origin: 0,
+            prefix_length: 0,
+            gateway: Ipv6Addr::unspecified(),
+        })
+    }
+
+    pub fn uri<U: Into<String>>(self, uri: U) -> Self {
+        self.node(Node::Uri(uri.into()))
+    }
+
+    pub fn vendor(self, kind: VendorKind, guid: &Guid, data: &[u8]) -> Self {
+        self.node(Node::Vendor { kind, guid: clone_guid(guid), data: data.to_vec() })
+    }
+
+    /// Ends the current instance. Nodes added after this go into a new instance.
+    pub fn end_instance(self) -> Self {
+        self.node(Node::EndInstance)
+    }
+
+    /// The binary form of the path including the final end node.
+    ///
+    /// Fails if a node doesn't fit the 16-bit length field of the node header.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for node in self.nodes.iter().chain(Some(&Node::End)) {
+            let encoded = node.to_bytes();
+            if encoded.len() > u16::MAX as usize {
+                return Err(EfiErrorKind::InvalidParameter.into());
+            }
+            bytes.extend_from_slice(&encoded);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Copies the path into firmware memory
+    pub fn build(&self) -> Result<DevicePath> {
+        DevicePath::from_bytes(&self.to_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_path::{text::path_to_text, node::Nodes};
+    use ffi::EFI_GUID;
+
+    #[test]
+    fn builds_a_boot_option_path() {
+        let esp = GptPartition::new(
+            crate::disk::gpt::EFI_SYSTEM_PARTITION_GUID,
+            EFI_GUID(0x6A4E1F9D, 0x1234, 0x4ABC, [0x8D, 0x2E, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55]),
+            2048,
+            411_647,
+            "EFI system partition",
+        );
+
+        let bytes = DevicePathBuilder::new()
+            .hard_drive(1, &esp)
+            .file_path(r"\EFI\BOOT\BOOTX64.EFI")
+            .to_bytes()
+            .unwrap();
+
+        assert_eq!(path_to_text(&bytes), r"HD(1,GPT,6A4E1F9D-1234-4ABC-8D2E-001122334455,0x800,0x64000)/\EFI\BOOT\BOOTX64.EFI");
+    }
+
+    #[test]
+    fn builds_an_http_boot_path() {
+        let bytes = DevicePathBuilder::new()
+            .mac([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+            .ipv4(Ipv4Addr::unspecified(), Ipv4Addr::unspecified())
+            .uri("http://boot.example.com/x64/grub.efi")
+            .end_instance()
+            .mac([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+            .ipv6(Ipv6Addr::unspecified(), Ipv6Addr::unspecified())
+            .uri("http://boot.example.com/x64/grub.efi")
+            .node(Node::End)
+            .to_bytes()
+            .unwrap();
+
+        assert_eq!(
+            path_to_text(&bytes),
+            "MAC(525400123456,0x1)/IPv4(0.0.0.0,TCP,DHCP,0.0.0.0,0.0.0.0,0.0.0.0)/Uri(http://boot.example.com/x64/grub.efi),\
+             MAC(525400123456,0x1)/IPv6(::,TCP,Static,::,::,0x0)/Uri(http://boot.example.com/x64/grub.efi)"
+        );
+        assert_eq!(Nodes::new(&bytes).filter(|n| *n == Node::End).count(), 1);
+    }
+
+    #[test]
+    fn rejects_oversized_nodes() {
+        let builder = DevicePathBuilder::new().uri(String::from_utf8(vec![b'a'; 70_000]).unwrap());
+        assert!(builder.to_bytes().is_err());
+    }
+}
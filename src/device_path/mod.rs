@@ -1,3 +1,7 @@
+pub mod node;
+pub mod text;
+pub mod builder;
+
 use ffi::{
     IsSuccess,
     CHAR16,
@@ -10,14 +14,20 @@ use ffi::{
         EFI_DEVICE_PATH_UTILITIES_PROTOCOL_GUID,
         EFI_DEVICE_PATH_TO_TEXT_PROTOCOL,
         EFI_DEVICE_PATH_TO_TEXT_PROTOCOL_GUID,
+        END_DEVICE_PATH_TYPE,
+        END_ENTIRE_DEVICE_PATH_SUBTYPE,
     },
     UINT16,
 };
 
 use crate::{EfiError, EfiErrorKind, Result, utils::as_slice};
-use core::{mem, ptr, fmt, slice};
+use core::{mem, ptr, fmt, slice, str::FromStr};
 use crate::system_table;
 use alloc::{string::String, boxed::Box, vec::Vec};
+use self::node::{NODE_HEADER_SIZE, node_len};
+
+pub use self::node::{Node, Nodes, PartitionSignature, VendorKind};
+pub use self::builder::DevicePathBuilder;
 
 // TODO: the whole concept of wrapping device path pointers like
 // this is not safe. We need to analyze memory lifetimes etc.
@@ -55,6 +65,19 @@ impl DeviceNode {
         self.inner
     }
 
+    /// The raw bytes of this node, header included
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let header = slice::from_raw_parts(self.inner as *const u8, NODE_HEADER_SIZE);
+            slice::from_raw_parts(self.inner as *const u8, u16::from_le_bytes([header[2], header[3]]) as usize)
+        }
+    }
+
+    /// Decodes this node
+    pub fn node(&self) -> Node {
+        Node::from_bytes(self.as_bytes())
+    }
+
     pub fn into_path(self) -> DevicePath {
         let path = unsafe {
             ((*self.path_utils).AppendDeviceNode)(ptr::null(), self.inner)
@@ -77,9 +100,11 @@ impl Clone for DeviceNode {
 
 impl fmt::Display for DeviceNode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let display = to_string(self.inner, false).map_err(|_| fmt::Error)?; // TODO: don't swallow lower level I/O
-        write!(f, "{}", display)?;
-        Ok(())
+        // Fall back to our own formatter on firmware without the device path to text protocol
+        match to_string(self.inner, true) {
+            Ok(display) => write!(f, "{}", display),
+            Err(_) => write!(f, "{}", self.node()),
+        }
     }
 }
 
@@ -99,10 +124,84 @@ impl DevicePath {
         Ok(Self { inner: ptr, path_utils: path_utils()? })
     }
 
+    /// Copies a path in its binary form into firmware memory.
+    ///
+    /// `bytes` must be a well formed path terminated by an end node.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut offset = 0;
+        loop {
+            let len = node_len(&bytes[offset..]).ok_or_else(|| EfiError::from(EfiErrorKind::InvalidParameter))?;
+            let node = &bytes[offset..offset + len];
+            offset += len;
+            if node[0] == END_DEVICE_PATH_TYPE && node[1] == END_ENTIRE_DEVICE_PATH_SUBTYPE {
+                break;
+            }
+        }
+
+        let path_utils = path_utils()?;
+        let inner = unsafe {
+            ((*path_utils).DuplicateDevicePath)(bytes.as_ptr() as *const EFI_DEVICE_PATH_PROTOCOL)
+        };
+
+        if inner.is_null() {
+            return Err(EfiErrorKind::OutOfResources.into());
+        }
+
+        Ok(Self { inner, path_utils })
+    }
+
     pub fn as_ptr(&self) -> *const EFI_DEVICE_PATH_PROTOCOL {
         self.inner
     }
 
+    /// The raw bytes of the whole path including the final end node
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { path_bytes(self.inner) }
+    }
+
+    /// Iterates over the decoded nodes of this path
+    pub fn nodes(&self) -> Nodes<'_> {
+        Nodes::new(self.as_bytes())
+    }
+
+    /// True if the nodes of `prefix` are the first nodes of this path
+    pub fn starts_with(&self, prefix: &DevicePath) -> bool {
+        self.as_bytes().starts_with(without_end(prefix.as_bytes()))
+    }
+
+    /// What is left of this path after `prefix`, or `None` if this path doesn't start with it.
+    ///
+    /// This is how you get the file path part of a path relative to its device's path.
+    pub fn strip_prefix(&self, prefix: &DevicePath) -> Result<Option<DevicePath>> {
+        if !self.starts_with(prefix) {
+            return Ok(None);
+        }
+
+        let rest = &self.as_bytes()[without_end(prefix.as_bytes()).len()..];
+        Self::from_bytes(rest).map(Some)
+    }
+
+    /// The path without its last node, or `None` if it has no nodes.
+    ///
+    /// For multi-instance paths this works on the last instance. An instance left empty is dropped.
+    pub fn parent(&self) -> Result<Option<DevicePath>> {
+        match parent_bytes(self.as_bytes()) {
+            Some(bytes) => Self::from_bytes(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_multi_instance(&self) -> bool {
+        self.nodes().any(|n| n == Node::EndInstance)
+    }
+
+    /// Splits a multi-instance path (such as the `ConOut` variable) into single-instance paths
+    pub fn instances(&self) -> Result<Vec<DevicePath>> {
+        split_instances(self.as_bytes()).iter()
+            .map(|bytes| Self::from_bytes(bytes))
+            .collect()
+    }
+
     pub fn try_clone(&self) -> Result<Self> {
         let path = unsafe {
             ((*self.path_utils).DuplicateDevicePath)(self.inner)
@@ -124,12 +223,111 @@ impl Clone for DevicePath {
 
 impl fmt::Display for DevicePath {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let display = to_string(self.inner, false).map_err(|_| fmt::Error)?; // TODO: don't swallow lower level I/O
-        write!(f, "{}", display)?;
-        Ok(())
+        // Fall back to our own formatter on firmware without the device path to text protocol
+        match to_string(self.inner, false) {
+            Ok(display) => write!(f, "{}", display),
+            Err(_) => write!(f, "{}", text::path_to_text(self.as_bytes())),
+        }
     }
 }
 
+impl FromStr for DevicePath {
+    type Err = EfiError;
+
+    /// Parses the UEFI text form of a path. Doesn't need the device path from text protocol.
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_bytes(&text::text_to_path(s)?)
+    }
+}
+
+// Walks the nodes up to and including the end node to find out how long the path is
+unsafe fn path_bytes<'a>(path: *const EFI_DEVICE_PATH_PROTOCOL) -> &'a [u8] {
+    if path.is_null() {
+        return &[];
+    }
+
+    let start = path as *const u8;
+    let mut len = 0;
+    loop {
+        let header = slice::from_raw_parts(start.add(len), NODE_HEADER_SIZE);
+        let node_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if node_len < NODE_HEADER_SIZE {
+            break; // Malformed. Stop rather than run off into the weeds.
+        }
+
+        len += node_len;
+        if header[0] == END_DEVICE_PATH_TYPE && header[1] == END_ENTIRE_DEVICE_PATH_SUBTYPE {
+            break;
+        }
+    }
+
+    slice::from_raw_parts(start, len)
+}
+
+// Offsets at which the nodes of a binary path start. Includes the offset of the end node.
+fn node_offsets(bytes: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while let Some(len) = node_len(&bytes[offset..]) {
+        offsets.push(offset);
+        if bytes[offset] == END_DEVICE_PATH_TYPE && bytes[offset + 1] == END_ENTIRE_DEVICE_PATH_SUBTYPE {
+            break;
+        }
+        offset += len;
+    }
+
+    offsets
+}
+
+// The path minus its final end node
+fn without_end(bytes: &[u8]) -> &[u8] {
+    match node_offsets(bytes).last() {
+        Some(end) => &bytes[..*end],
+        None => bytes,
+    }
+}
+
+fn end_node() -> [u8; NODE_HEADER_SIZE] {
+    [END_DEVICE_PATH_TYPE, END_ENTIRE_DEVICE_PATH_SUBTYPE, NODE_HEADER_SIZE as u8, 0]
+}
+
+fn parent_bytes(bytes: &[u8]) -> Option<Vec<u8>> {
+    let offsets = node_offsets(bytes);
+    if offsets.len() < 2 {
+        return None; // Nothing but the end node
+    }
+
+    // Drop the last node and then an instance separator if it has become the last node
+    let mut cut = offsets[offsets.len() - 2];
+    if offsets.len() >= 3 {
+        let prev = offsets[offsets.len() - 3];
+        if bytes[prev] == END_DEVICE_PATH_TYPE {
+            cut = prev;
+        }
+    }
+
+    let mut parent = bytes[..cut].to_vec();
+    parent.extend_from_slice(&end_node());
+    Some(parent)
+}
+
+fn split_instances(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut instances = Vec::new();
+    let mut instance = Vec::new();
+    let offsets = node_offsets(bytes);
+    for (i, offset) in offsets.iter().enumerate() {
+        if bytes[*offset] == END_DEVICE_PATH_TYPE {
+            instance.extend_from_slice(&end_node());
+            instances.push(mem::take(&mut instance));
+        } else {
+            let next = offsets.get(i + 1).cloned().unwrap_or(bytes.len());
+            instance.extend_from_slice(&bytes[*offset..next]);
+        }
+    }
+
+    instances
+}
+
 fn to_string(path: *const EFI_DEVICE_PATH_PROTOCOL, is_single_node: bool) -> Result<String> {
     let bs = (*system_table()).BootServices;
 
@@ -212,4 +410,48 @@ pub fn append_path(path1: &DevicePath, path2: &DevicePath) -> Result<DevicePath>
     };
 
     DevicePath::from_ptr(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(text: &str) -> Vec<u8> {
+        text::text_to_path(text).unwrap()
+    }
+
+    #[test]
+    fn prefixes_are_matched_on_whole_nodes() {
+        let full = path(r"PciRoot(0x0)/Pci(0x1F,0x2)/Sata(0x0,0xFFFF,0x0)/\EFI\BOOT\BOOTX64.EFI");
+        let prefix = path("PciRoot(0x0)/Pci(0x1F,0x2)");
+
+        assert!(full.starts_with(without_end(&prefix)));
+        assert!(!full.starts_with(without_end(&path("PciRoot(0x0)/Pci(0x1F,0x3)"))));
+
+        let rest = &full[without_end(&prefix).len()..];
+        assert_eq!(text::path_to_text(rest), r"Sata(0x0,0xFFFF,0x0)/\EFI\BOOT\BOOTX64.EFI");
+    }
+
+    #[test]
+    fn parent_drops_the_last_node() {
+        let parent = parent_bytes(&path("PciRoot(0x0)/Pci(0x1,0x0)")).unwrap();
+        assert_eq!(text::path_to_text(&parent), "PciRoot(0x0)");
+
+        let parent = parent_bytes(&parent).unwrap();
+        assert_eq!(parent, end_node());
+        assert!(parent_bytes(&parent).is_none());
+
+        let parent = parent_bytes(&path("PciRoot(0x0)/Pci(0x1,0x0),Pci(0x2,0x0)")).unwrap();
+        assert_eq!(text::path_to_text(&parent), "PciRoot(0x0)/Pci(0x1,0x0)");
+    }
+
+    #[test]
+    fn splits_instances() {
+        let instances = split_instances(&path("PciRoot(0x0)/Pci(0x2,0x0),PciRoot(0x0)/Pci(0x1F,0x0)/Serial(0x0)"));
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0], path("PciRoot(0x0)/Pci(0x2,0x0)"));
+        assert_eq!(instances[1], path("PciRoot(0x0)/Pci(0x1F,0x0)/Serial(0x0)"));
+
+        assert_eq!(split_instances(&path("Pci(0x1,0x0)")), vec![path("Pci(0x1,0x0)")]);
+    }
 }
\ No newline at end of file
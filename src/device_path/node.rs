@@ -0,0 +1,578 @@
+//! Typed decoding of device path nodes.
+//!
+//! Works purely on bytes so it doesn't need the firmware. `DevicePath::nodes()`
+//! and `DeviceNode::node()` are the usual entry points.
+
+use ffi::device_path::{
+    HARDWARE_DEVICE_PATH,
+    HW_PCI_DP,
+    HW_VENDOR_DP,
+    ACPI_DEVICE_PATH,
+    ACPI_DP,
+    MESSAGING_DEVICE_PATH,
+    MSG_USB_DP,
+    MSG_MAC_ADDR_DP,
+    MSG_IPv4_DP as MSG_IPV4_DP,
+    MSG_IPv6_DP as MSG_IPV6_DP,
+    MSG_SATA_DP,
+    MSG_NVME_NAMESPACE_DP,
+    MSG_VENDOR_DP,
+    MEDIA_DEVICE_PATH,
+    MEDIA_HARDDRIVE_DP,
+    MEDIA_CDROM_DP,
+    MEDIA_VENDOR_DP,
+    MEDIA_FILEPATH_DP,
+    END_DEVICE_PATH_TYPE,
+    END_ENTIRE_DEVICE_PATH_SUBTYPE,
+    END_INSTANCE_DEVICE_PATH_SUBTYPE,
+};
+use ffi::EFI_GUID;
+use crate::{Guid, net::{Ipv4Addr, Ipv6Addr}, utils::{guid_from_bytes, guid_to_bytes, clone_guid}};
+use alloc::{vec::Vec, string::String};
+
+pub const MSG_URI_DP: u8 = 0x18; // Not in efi_ffi yet
+
+pub (crate) const NODE_HEADER_SIZE: usize = 4;
+
+/// The kind of vendor defined node. Vendor nodes exist under three different node types.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VendorKind {
+    Hardware,
+    Messaging,
+    Media,
+}
+
+/// The signature of the partition in a `HardDrive` node
+#[derive(Debug, PartialEq)]
+pub enum PartitionSignature {
+    None,
+    Mbr(u32),
+    Gpt(Guid),
+}
+
+impl Clone for PartitionSignature {
+    fn clone(&self) -> Self {
+        match self {
+            PartitionSignature::None => PartitionSignature::None,
+            PartitionSignature::Mbr(sig) => PartitionSignature::Mbr(*sig),
+            PartitionSignature::Gpt(guid) => PartitionSignature::Gpt(clone_guid(guid)),
+        }
+    }
+}
+
+/// A decoded device path node.
+///
+/// Node types this crate doesn't know about come back as `Unknown` with their raw bytes.
+/// Nodes that are too short for their type are treated the same way.
+#[derive(Debug, PartialEq)]
+pub enum Node {
+    Pci { function: u8, device: u8 },
+    Acpi { hid: u32, uid: u32 },
+    Usb { parent_port: u8, interface: u8 },
+    Sata { hba_port: u16, port_multiplier_port: u16, lun: u16 },
+    Nvme { namespace_id: u32, eui64: u64 },
+    Mac { address: [u8; 32], if_type: u8 },
+    Ipv4 {
+        local: Ipv4Addr,
+        remote: Ipv4Addr,
+        local_port: u16,
+        remote_port: u16,
+        protocol: u16,
+        static_ip: bool,
+        gateway: Ipv4Addr,
+        subnet_mask: Ipv4Addr,
+    },
+    Ipv6 {
+        local: Ipv6Addr,
+        remote: Ipv6Addr,
+        local_port: u16,
+        remote_port: u16,
+        protocol: u16,
+        origin: u8, // 0 for static, 1 for stateless auto configuration, 2 for stateful
+        prefix_length: u8,
+        gateway: Ipv6Addr,
+    },
+    Uri(String),
+    HardDrive { partition_number: u32, start: u64, size: u64, signature: PartitionSignature },
+    CdRom { boot_entry: u32, start: u64, size: u64 },
+    FilePath(String),
+    Vendor { kind: VendorKind, guid: Guid, data: Vec<u8> },
+    /// Separates the instances of a multi-instance path
+    EndInstance,
+    /// Terminates the path
+    End,
+    Unknown { node_type: u8, sub_type: u8, data: Vec<u8> },
+}
+
+impl Node {
+    /// Decodes a single node. `bytes` must span exactly the node, header included.
+    /// Anything shorter than the node header comes back as `Node::Unknown`.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.len() < NODE_HEADER_SIZE {
+            let (node_type, sub_type) = (bytes.first().copied().unwrap_or(0), bytes.get(1).copied().unwrap_or(0));
+            return Node::Unknown { node_type, sub_type, data: Vec::new() };
+        }
+
+        let (node_type, sub_type) = (bytes[0], bytes[1]);
+        let data = &bytes[NODE_HEADER_SIZE..];
+        Self::decode(node_type, sub_type, data).unwrap_or_else(|| Node::Unknown { node_type, sub_type, data: data.to_vec() })
+    }
+
+    fn decode(node_type: u8, sub_type: u8, data: &[u8]) -> Option<Self> {
+        let node = match (node_type, sub_type) {
+            (HARDWARE_DEVICE_PATH, HW_PCI_DP) if data.len() >= 2 => Node::Pci { function: data[0], device: data[1] },
+            (ACPI_DEVICE_PATH, ACPI_DP) if data.len() >= 8 => Node::Acpi { hid: le_u32(&data[0..]), uid: le_u32(&data[4..]) },
+            (MESSAGING_DEVICE_PATH, MSG_USB_DP) if data.len() >= 2 => Node::Usb { parent_port: data[0], interface: data[1] },
+            (MESSAGING_DEVICE_PATH, MSG_SATA_DP) if data.len() >= 6 => Node::Sata {
+                hba_port: le_u16(&data[0..]),
+                port_multiplier_port: le_u16(&data[2..]),
+                lun: le_u16(&data[4..]),
+            },
+            (MESSAGING_DEVICE_PATH, MSG_NVME_NAMESPACE_DP) if data.len() >= 12 => Node::Nvme {
+                namespace_id: le_u32(&data[0..]),
+                eui64: le_u64(&data[4..]),
+            },
+            (MESSAGING_DEVICE_PATH, MSG_MAC_ADDR_DP) if data.len() >= 33 => {
+                let mut address = [0u8; 32];
+                address.copy_from_slice(&data[..32]);
+                Node::Mac { address, if_type: data[32] }
+            },
+            (MESSAGING_DEVICE_PATH, MSG_IPV4_DP) if data.len() >= 15 => {
+                // Gateway and subnet mask were added in UEFI 2.4. Older firmware produces shorter nodes.
+                let (gateway, subnet_mask) = if data.len() >= 23 {
+                    (ipv4(&data[15..]), ipv4(&data[19..]))
+                } else {
+                    (Ipv4Addr::unspecified(), Ipv4Addr::unspecified())
+                };
+
+                Node::Ipv4 {
+                    local: ipv4(&data[0..]),
+                    remote: ipv4(&data[4..]),
+                    local_port: le_u16(&data[8..]),
+                    remote_port: le_u16(&data[10..]),
+                    protocol: le_u16(&data[12..]),
+                    static_ip: data[14] != 0,
+                    gateway,
+                    subnet_mask,
+                }
+            },
+            (MESSAGING_DEVICE_PATH, MSG_IPV6_DP) if data.len() >= 39 => {
+                // Same story as IPv4. Prefix length and gateway came later.
+                let (origin, prefix_length, gateway) = if data.len() >= 56 {
+                    (data[38], data[39], ipv6(&data[40..]))
+                } else {
+                    (data[38], 0, Ipv6Addr::unspecified())
+                };
+
+                Node::Ipv6 {
+                    local: ipv6(&data[0..]),
+                    remote: ipv6(&data[16..]),
+                    local_port: le_u16(&data[32..]),
+                    remote_port: le_u16(&data[34..]),
+                    protocol: le_u16(&data[36..]),
+                    origin,
+                    prefix_length,
+                    gateway,
+                }
+            },
+            (MESSAGING_DEVICE_PATH, MSG_URI_DP) => Node::Uri(String::from_utf8_lossy(data).into()),
+            (MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP) if data.len() >= 38 => {
+                let signature = match data[37] {
+                    1 => PartitionSignature::Mbr(le_u32(&data[20..])),
+                    2 => PartitionSignature::Gpt(guid(&data[20..])),
+                    _ => PartitionSignature::None,
+                };
+
+                Node::HardDrive {
+                    partition_number: le_u32(&data[0..]),
+                    start: le_u64(&data[4..]),
+                    size: le_u64(&data[12..]),
+                    signature,
+                }
+            },
+            (MEDIA_DEVICE_PATH, MEDIA_CDROM_DP) if data.len() >= 20 => Node::CdRom {
+                boot_entry: le_u32(&data[0..]),
+                start: le_u64(&data[4..]),
+                size: le_u64(&data[12..]),
+            },
+            (MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP) => {
+                let utf16 = data.chunks(2)
+                    .filter(|c| c.len() == 2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect::<Vec<_>>();
+                Node::FilePath(String::from_utf16_lossy(&utf16))
+            },
+            (HARDWARE_DEVICE_PATH, HW_VENDOR_DP) |
+            (MESSAGING_DEVICE_PATH, MSG_VENDOR_DP) |
+            (MEDIA_DEVICE_PATH, MEDIA_VENDOR_DP) if data.len() >= 16 => {
+                let kind = match node_type {
+                    HARDWARE_DEVICE_PATH => VendorKind::Hardware,
+                    MESSAGING_DEVICE_PATH => VendorKind::Messaging,
+                    _ => VendorKind::Media,
+                };
+                Node::Vendor { kind, guid: guid(data), data: data[16..].to_vec() }
+            },
+            (END_DEVICE_PATH_TYPE, END_INSTANCE_DEVICE_PATH_SUBTYPE) => Node::EndInstance,
+            (END_DEVICE_PATH_TYPE, END_ENTIRE_DEVICE_PATH_SUBTYPE) => Node::End,
+            _ => return None,
+        };
+
+        Some(node)
+    }
+
+    /// True for both kinds of end node
+    pub fn is_end(&self) -> bool {
+        matches!(self, Node::End | Node::EndInstance)
+    }
+
+    /// Encodes the node into its binary form, header included. The inverse of `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        let (node_type, sub_type) = match self {
+            Node::Pci { function, device } => {
+                data.extend_from_slice(&[*function, *device]);
+                (HARDWARE_DEVICE_PATH, HW_PCI_DP)
+            },
+            Node::Acpi { hid, uid } => {
+                data.extend_from_slice(&hid.to_le_bytes());
+                data.extend_from_slice(&uid.to_le_bytes());
+                (ACPI_DEVICE_PATH, ACPI_DP)
+            },
+            Node::Usb { parent_port, interface } => {
+                data.extend_from_slice(&[*parent_port, *interface]);
+                (MESSAGING_DEVICE_PATH, MSG_USB_DP)
+            },
+            Node::Sata { hba_port, port_multiplier_port, lun } => {
+                data.extend_from_slice(&hba_port.to_le_bytes());
+                data.extend_from_slice(&port_multiplier_port.to_le_bytes());
+                data.extend_from_slice(&lun.to_le_bytes());
+                (MESSAGING_DEVICE_PATH, MSG_SATA_DP)
+            },
+            Node::Nvme { namespace_id, eui64 } => {
+                data.extend_from_slice(&namespace_id.to_le_bytes());
+                data.extend_from_slice(&eui64.to_le_bytes());
+                (MESSAGING_DEVICE_PATH, MSG_NVME_NAMESPACE_DP)
+            },
+            Node::Mac { address, if_type } => {
+                data.extend_from_slice(address);
+                data.push(*if_type);
+                (MESSAGING_DEVICE_PATH, MSG_MAC_ADDR_DP)
+            },
+            Node::Ipv4 { local, remote, local_port, remote_port, protocol, static_ip, gateway, subnet_mask } => {
+                data.extend_from_slice(&local.octets());
+                data.extend_from_slice(&remote.octets());
+                data.extend_from_slice(&local_port.to_le_bytes());
+                data.extend_from_slice(&remote_port.to_le_bytes());
+                data.extend_from_slice(&protocol.to_le_bytes());
+                data.push(*static_ip as u8);
+                data.extend_from_slice(&gateway.octets());
+                data.extend_from_slice(&subnet_mask.octets());
+                (MESSAGING_DEVICE_PATH, MSG_IPV4_DP)
+            },
+            Node::Ipv6 { local, remote, local_port, remote_port, protocol, origin, prefix_length, gateway } => {
+                data.extend_from_slice(&local.octets());
+                data.extend_from_slice(&remote.octets());
+                data.extend_from_slice(&local_port.to_le_bytes());
+                data.extend_from_slice(&remote_port.to_le_bytes());
+                data.extend_from_slice(&protocol.to_le_bytes());
+                data.extend_from_slice(&[*origin, *prefix_length]);
+                data.extend_from_slice(&gateway.octets());
+                (MESSAGING_DEVICE_PATH, MSG_IPV6_DP)
+            },
+            Node::Uri(uri) => {
+                data.extend_from_slice(uri.as_bytes());
+                (MESSAGING_DEVICE_PATH, MSG_URI_DP)
+            },
+            Node::HardDrive { partition_number, start, size, signature } => {
+                data.extend_from_slice(&partition_number.to_le_bytes());
+                data.extend_from_slice(&start.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+                let (raw, format, signature_type) = match signature {
+                    PartitionSignature::None => ([0u8; 16], 1, 0),
+                    PartitionSignature::Mbr(sig) => {
+                        let mut raw = [0u8; 16];
+                        raw[..4].copy_from_slice(&sig.to_le_bytes());
+                        (raw, 1, 1)
+                    },
+                    PartitionSignature::Gpt(guid) => (guid_to_bytes(guid), 2, 2),
+                };
+                data.extend_from_slice(&raw);
+                data.extend_from_slice(&[format, signature_type]);
+                (MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP)
+            },
+            Node::CdRom { boot_entry, start, size } => {
+                data.extend_from_slice(&boot_entry.to_le_bytes());
+                data.extend_from_slice(&start.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+                (MEDIA_DEVICE_PATH, MEDIA_CDROM_DP)
+            },
+            Node::FilePath(path) => {
+                for c in path.encode_utf16().chain(Some(0)) {
+                    data.extend_from_slice(&c.to_le_bytes());
+                }
+                (MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP)
+            },
+            Node::Vendor { kind, guid, data: vendor_data } => {
+                data.extend_from_slice(&guid_to_bytes(guid));
+                data.extend_from_slice(vendor_data);
+                match kind {
+                    VendorKind::Hardware => (HARDWARE_DEVICE_PATH, HW_VENDOR_DP),
+                    VendorKind::Messaging => (MESSAGING_DEVICE_PATH, MSG_VENDOR_DP),
+                    VendorKind::Media => (MEDIA_DEVICE_PATH, MEDIA_VENDOR_DP),
+                }
+            },
+            Node::EndInstance => (END_DEVICE_PATH_TYPE, END_INSTANCE_DEVICE_PATH_SUBTYPE),
+            Node::End => (END_DEVICE_PATH_TYPE, END_ENTIRE_DEVICE_PATH_SUBTYPE),
+            Node::Unknown { node_type, sub_type, data: raw } => {
+                data.extend_from_slice(raw);
+                (*node_type, *sub_type)
+            },
+        };
+
+        let mut bytes = vec![node_type, sub_type];
+        bytes.extend_from_slice(&((data.len() + NODE_HEADER_SIZE) as u16).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Self {
+        // Written by hand because EFI_GUID isn't Clone
+        match self {
+            Node::Pci { function, device } => Node::Pci { function: *function, device: *device },
+            Node::Acpi { hid, uid } => Node::Acpi { hid: *hid, uid: *uid },
+            Node::Usb { parent_port, interface } => Node::Usb { parent_port: *parent_port, interface: *interface },
+            Node::Sata { hba_port, port_multiplier_port, lun } => Node::Sata { hba_port: *hba_port, port_multiplier_port: *port_multiplier_port, lun: *lun },
+            Node::Nvme { namespace_id, eui64 } => Node::Nvme { namespace_id: *namespace_id, eui64: *eui64 },
+            Node::Mac { address, if_type } => Node::Mac { address: *address, if_type: *if_type },
+            Node::Ipv4 { local, remote, local_port, remote_port, protocol, static_ip, gateway, subnet_mask } => Node::Ipv4 {
+                local: *local,
+                remote: *remote,
+                local_port: *local_port,
+                remote_port: *remote_port,
+                protocol: *protocol,
+                static_ip: *static_ip,
+                gateway: *gateway,
+                subnet_mask: *subnet_mask,
+            },
+            Node::Ipv6 { local, remote, local_port, remote_port, protocol, origin, prefix_length, gateway } => Node::Ipv6 {
+                local: *local,
+                remote: *remote,
+                local_port: *local_port,
+                remote_port: *remote_port,
+                protocol: *protocol,
+                origin: *origin,
+                prefix_length: *prefix_length,
+                gateway: *gateway,
+            },
+            Node::Uri(uri) => Node::Uri(uri.clone()),
+            Node::HardDrive { partition_number, start, size, signature } => Node::HardDrive {
+                partition_number: *partition_number,
+                start: *start,
+                size: *size,
+                signature: signature.clone(),
+            },
+            Node::CdRom { boot_entry, start, size } => Node::CdRom { boot_entry: *boot_entry, start: *start, size: *size },
+            Node::FilePath(path) => Node::FilePath(path.clone()),
+            Node::Vendor { kind, guid, data } => Node::Vendor { kind: *kind, guid: clone_guid(guid), data: data.clone() },
+            Node::EndInstance => Node::EndInstance,
+            Node::End => Node::End,
+            Node::Unknown { node_type, sub_type, data } => Node::Unknown { node_type: *node_type, sub_type: *sub_type, data: data.clone() },
+        }
+    }
+}
+
+/// Iterator over the nodes of a device path in its binary form.
+///
+/// Yields every node including the final `Node::End`. Stops early if a node header is malformed.
+pub struct Nodes<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Nodes<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        let len = node_len(self.bytes)?;
+        let (node, rest) = self.bytes.split_at(len);
+        self.bytes = if node[0] == END_DEVICE_PATH_TYPE && node[1] == END_ENTIRE_DEVICE_PATH_SUBTYPE { &[] } else { rest };
+        Some(Node::from_bytes(node))
+    }
+}
+
+/// Length of the node at the start of `bytes` if its header is sane
+pub (crate) fn node_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < NODE_HEADER_SIZE {
+        return None;
+    }
+
+    let len = le_u16(&bytes[2..]) as usize;
+    if len < NODE_HEADER_SIZE || len > bytes.len() {
+        return None;
+    }
+
+    Some(len)
+}
+
+fn ipv4(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+fn ipv6(bytes: &[u8]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&bytes[..16]);
+    Ipv6Addr::from(octets)
+}
+
+fn guid(bytes: &[u8]) -> EFI_GUID {
+    let mut raw = [0u8; 16];
+    raw.copy_from_slice(&bytes[..16]);
+    guid_from_bytes(&raw)
+}
+
+fn le_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_type: u8, sub_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![node_type, sub_type];
+        bytes.extend_from_slice(&((data.len() + NODE_HEADER_SIZE) as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn end() -> Vec<u8> {
+        node(END_DEVICE_PATH_TYPE, END_ENTIRE_DEVICE_PATH_SUBTYPE, &[])
+    }
+
+    #[test]
+    fn decodes_a_network_path() {
+        let mut mac = [0u8; 33];
+        mac[..6].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        mac[32] = 1;
+
+        let mut ip = vec![10, 0, 2, 15, 10, 0, 2, 2];
+        ip.extend_from_slice(&68u16.to_le_bytes());
+        ip.extend_from_slice(&67u16.to_le_bytes());
+        ip.extend_from_slice(&17u16.to_le_bytes());
+        ip.push(0);
+        ip.extend_from_slice(&[10, 0, 2, 2, 255, 255, 255, 0]);
+
+        let path = [
+            node(ACPI_DEVICE_PATH, ACPI_DP, &[0xD0, 0x41, 0x03, 0x0A, 0, 0, 0, 0]),
+            node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 3]),
+            node(MESSAGING_DEVICE_PATH, MSG_MAC_ADDR_DP, &mac),
+            node(MESSAGING_DEVICE_PATH, MSG_IPV4_DP, &ip),
+            node(MESSAGING_DEVICE_PATH, MSG_URI_DP, b"http://10.0.2.2/boot.efi"),
+            end(),
+        ].concat();
+
+        let nodes = Nodes::new(&path).collect::<Vec<_>>();
+        assert_eq!(nodes.len(), 6);
+        assert_eq!(nodes[0], Node::Acpi { hid: 0x0A03_41D0, uid: 0 });
+        assert_eq!(nodes[1], Node::Pci { function: 0, device: 3 });
+        match nodes[2] {
+            Node::Mac { address, if_type } => {
+                assert_eq!(&address[..6], &[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+                assert_eq!(if_type, 1);
+            },
+            ref n => panic!("unexpected node {:?}", n),
+        }
+        assert_eq!(nodes[3], Node::Ipv4 {
+            local: Ipv4Addr::new(10, 0, 2, 15),
+            remote: Ipv4Addr::new(10, 0, 2, 2),
+            local_port: 68,
+            remote_port: 67,
+            protocol: 17,
+            static_ip: false,
+            gateway: Ipv4Addr::new(10, 0, 2, 2),
+            subnet_mask: Ipv4Addr::new(255, 255, 255, 0),
+        });
+        assert_eq!(nodes[4], Node::Uri("http://10.0.2.2/boot.efi".into()));
+        assert_eq!(nodes[5], Node::End);
+    }
+
+    #[test]
+    fn decodes_a_disk_path() {
+        let mut hd = Vec::new();
+        hd.extend_from_slice(&1u32.to_le_bytes());
+        hd.extend_from_slice(&2048u64.to_le_bytes());
+        hd.extend_from_slice(&409_600u64.to_le_bytes());
+        hd.extend_from_slice(&[0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+        hd.extend_from_slice(&[2, 2]);
+
+        let file = "\\EFI\\BOOT\\BOOTX64.EFI\0".encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect::<Vec<_>>();
+
+        let path = [
+            node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 0x1F]),
+            node(MESSAGING_DEVICE_PATH, MSG_SATA_DP, &[0, 0, 0xFF, 0xFF, 0, 0]),
+            node(MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP, &hd),
+            node(MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP, &file),
+            end(),
+        ].concat();
+
+        let nodes = Nodes::new(&path).collect::<Vec<_>>();
+        assert_eq!(nodes[1], Node::Sata { hba_port: 0, port_multiplier_port: 0xFFFF, lun: 0 });
+        assert_eq!(nodes[2], Node::HardDrive {
+            partition_number: 1,
+            start: 2048,
+            size: 409_600,
+            signature: PartitionSignature::Gpt(EFI_GUID(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B])),
+        });
+        assert_eq!(nodes[3], Node::FilePath("\\EFI\\BOOT\\BOOTX64.EFI".into()));
+    }
+
+    #[test]
+    fn unknown_and_truncated_nodes_are_kept_raw() {
+        let path = [
+            node(HARDWARE_DEVICE_PATH, 0x42, &[1, 2, 3]),
+            node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[7]), // One byte short
+            end(),
+        ].concat();
+
+        let nodes = Nodes::new(&path).collect::<Vec<_>>();
+        assert_eq!(nodes[0], Node::Unknown { node_type: HARDWARE_DEVICE_PATH, sub_type: 0x42, data: vec![1, 2, 3] });
+        assert_eq!(nodes[1], Node::Unknown { node_type: HARDWARE_DEVICE_PATH, sub_type: HW_PCI_DP, data: vec![7] });
+    }
+
+    #[test]
+    fn short_ipv6_nodes_and_headers_are_kept_raw() {
+        let ipv6 = node(MESSAGING_DEVICE_PATH, MSG_IPV6_DP, &[0; 38]);
+        assert_eq!(Node::from_bytes(&ipv6), Node::Unknown { node_type: MESSAGING_DEVICE_PATH, sub_type: MSG_IPV6_DP, data: vec![0; 38] });
+
+        assert_eq!(Node::from_bytes(&[]), Node::Unknown { node_type: 0, sub_type: 0, data: Vec::new() });
+        assert_eq!(Node::from_bytes(&[MESSAGING_DEVICE_PATH, MSG_IPV6_DP, 4]),
+            Node::Unknown { node_type: MESSAGING_DEVICE_PATH, sub_type: MSG_IPV6_DP, data: Vec::new() });
+    }
+
+    #[test]
+    fn iteration_stops_at_end_and_on_malformed_headers() {
+        let mut path = [node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 1]), end(), node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 2])].concat();
+        assert_eq!(Nodes::new(&path).count(), 2);
+
+        path[2] = 2; // Length smaller than the header
+        assert_eq!(Nodes::new(&path).count(), 0);
+    }
+}
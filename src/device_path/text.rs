@@ -0,0 +1,527 @@
+//! Conversion between device paths and their text form.
+//!
+//! Follows the syntax in the "Device Path Text Representation" chapter of the UEFI spec,
+//! e.g. `PciRoot(0x0)/Pci(0x1,0x0)/MAC(525400123456,0x1)/IPv4(10.0.2.2,UDP,DHCP,10.0.2.15,10.0.2.2,255.255.255.0)`.
+//! Unlike `EFI_DEVICE_PATH_TO_TEXT_PROTOCOL` this is plain Rust, so it works on firmware
+//! that doesn't carry that protocol and on the host.
+//!
+//! A few fields have no text form in the spec (e.g. the ports of IPv4 and IPv6 nodes).
+//! These come back as zero after a round trip.
+
+use super::node::{Node, Nodes, PartitionSignature, VendorKind};
+use crate::{Guid, Result, EfiError, EfiErrorKind, net::{Ipv4Addr, Ipv6Addr}};
+use ffi::EFI_GUID;
+use core::{fmt, str::FromStr, convert::TryFrom};
+use alloc::{vec::Vec, string::{String, ToString}};
+
+const PNP_EISA_ID: u32 = 0x41D0; // "PNP" in compressed EISA form
+
+const TCP: u16 = 6;
+const UDP: u16 = 17;
+
+/// Formats a binary device path as text. Instances are separated by `,`.
+pub fn path_to_text(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    let mut at_start = true;
+    for node in Nodes::new(bytes) {
+        match node {
+            Node::End => break,
+            Node::EndInstance => {
+                text.push(',');
+                at_start = true;
+            },
+            node => {
+                if !at_start {
+                    text.push('/');
+                }
+                text.push_str(&node.to_string());
+                at_start = false;
+            },
+        }
+    }
+
+    text
+}
+
+/// Parses the text form of a device path into its binary form, final end node included
+pub fn text_to_path(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for (i, instance) in split_top_level(text, ',').into_iter().enumerate() {
+        if i > 0 {
+            bytes.extend_from_slice(&Node::EndInstance.to_bytes());
+        }
+
+        for node in split_top_level(instance, '/').into_iter().filter(|n| !n.is_empty()) {
+            let encoded = text_to_node(node)?.to_bytes();
+            if encoded.len() > u16::MAX as usize {
+                return Err(invalid()); // Too long to fit in the node's length field
+            }
+            bytes.extend_from_slice(&encoded);
+        }
+    }
+
+    bytes.extend_from_slice(&Node::End.to_bytes());
+    Ok(bytes)
+}
+
+/// Parses the text form of a single node.
+///
+/// Text that isn't of the form `Name(...)` with a known name is taken to be a file path,
+/// which is how the firmware's parser treats it too.
+pub fn text_to_node(text: &str) -> Result<Node> {
+    let (name, args) = match split_call(text) {
+        Some(call) => call,
+        None => return Ok(Node::FilePath(text.into())),
+    };
+
+    let node = match name {
+        "Pci" => {
+            let a = Args::new(args, 2)?;
+            Node::Pci { device: a.int(0)?, function: a.int(1)? }
+        },
+        "PciRoot" => acpi(0x0A03, args)?,
+        "PcieRoot" => acpi(0x0A08, args)?,
+        "Floppy" => acpi(0x0604, args)?,
+        "Keyboard" => acpi(0x0301, args)?,
+        "Serial" => acpi(0x0501, args)?,
+        "ParallelPort" => acpi(0x0401, args)?,
+        "Acpi" => {
+            let a = Args::new(args, 2)?;
+            Node::Acpi { hid: parse_hid(a.get(0))?, uid: a.int(1)? }
+        },
+        "USB" => {
+            let a = Args::new(args, 2)?;
+            Node::Usb { parent_port: a.int(0)?, interface: a.int(1)? }
+        },
+        "Sata" => {
+            let a = Args::new(args, 3)?;
+            Node::Sata { hba_port: a.int(0)?, port_multiplier_port: a.int(1)?, lun: a.int(2)? }
+        },
+        "NVMe" => {
+            let a = Args::new(args, 2)?;
+            let eui = parse_hex(&a.get(1).replace('-', ""))?;
+            if eui.len() != 8 {
+                return Err(invalid());
+            }
+
+            let mut raw = [0u8; 8];
+            raw.copy_from_slice(&eui);
+            Node::Nvme { namespace_id: a.int(0)?, eui64: u64::from_be_bytes(raw) }
+        },
+        "MAC" => {
+            let a = Args::new(args, 2)?;
+            let raw = parse_hex(a.get(0))?;
+            if raw.len() > 32 {
+                return Err(invalid());
+            }
+
+            let mut address = [0u8; 32];
+            address[..raw.len()].copy_from_slice(&raw);
+            Node::Mac { address, if_type: a.int(1)? }
+        },
+        "IPv4" => {
+            let a = Args::new(args, 6)?;
+            Node::Ipv4 {
+                remote: ip(a.get(0))?,
+                protocol: protocol(a.get(1))?,
+                static_ip: match a.get(2) {
+                    "Static" => true,
+                    "DHCP" | "" => false,
+                    _ => return Err(invalid()),
+                },
+                local: ip(a.get(3))?,
+                gateway: ip(a.get(4))?,
+                subnet_mask: ip(a.get(5))?,
+                local_port: 0,
+                remote_port: 0,
+            }
+        },
+        "IPv6" => {
+            let a = Args::new(args, 6)?;
+            Node::Ipv6 {
+                remote: ip(a.get(0))?,
+                protocol: protocol(a.get(1))?,
+                origin: match a.get(2) {
+                    "Static" | "" => 0,
+                    "StatelessAutoConfigure" => 1,
+                    "StatefulAutoConfigure" => 2,
+                    _ => return Err(invalid()),
+                },
+                local: ip(a.get(3))?,
+                gateway: ip(a.get(4))?,
+                prefix_length: a.int(5)?,
+                local_port: 0,
+                remote_port: 0,
+            }
+        },
+        "Uri" => Node::Uri(args.into()), // URIs may contain commas so no splitting here
+        "HD" => {
+            let a = Args::new(args, 5)?;
+            let signature = match a.get(1) {
+                "GPT" => PartitionSignature::Gpt(parse_guid(a.get(2))?),
+                "MBR" => PartitionSignature::Mbr(a.int(2)?),
+                other if parse_int(other)? == 0 => PartitionSignature::None,
+                _ => return Err(invalid()),
+            };
+            Node::HardDrive { partition_number: a.int(0)?, signature, start: a.int(3)?, size: a.int(4)? }
+        },
+        "CDROM" => {
+            let a = Args::new(args, 3)?;
+            Node::CdRom { boot_entry: a.int(0)?, start: a.int(1)?, size: a.int(2)? }
+        },
+        "VenHw" => vendor(VendorKind::Hardware, args)?,
+        "VenMsg" => vendor(VendorKind::Messaging, args)?,
+        "VenMedia" => vendor(VendorKind::Media, args)?,
+        "Path" => {
+            let a = Args::new(args, 3)?;
+            Node::Unknown { node_type: a.int(0)?, sub_type: a.int(1)?, data: parse_hex(a.get(2))? }
+        },
+        _ => Node::FilePath(text.into()),
+    };
+
+    Ok(node)
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Pci { function, device } => write!(f, "Pci(0x{:X},0x{:X})", device, function),
+            Node::Acpi { hid, uid } if hid & 0xFFFF == PNP_EISA_ID => match hid >> 16 {
+                0x0A03 => write!(f, "PciRoot(0x{:X})", uid),
+                0x0A08 => write!(f, "PcieRoot(0x{:X})", uid),
+                0x0604 => write!(f, "Floppy(0x{:X})", uid),
+                0x0301 => write!(f, "Keyboard(0x{:X})", uid),
+                0x0501 => write!(f, "Serial(0x{:X})", uid),
+                0x0401 => write!(f, "ParallelPort(0x{:X})", uid),
+                id => write!(f, "Acpi(PNP{:04X},0x{:X})", id, uid),
+            },
+            Node::Acpi { hid, uid } => write!(f, "Acpi(0x{:08X},0x{:X})", hid, uid),
+            Node::Usb { parent_port, interface } => write!(f, "USB(0x{:X},0x{:X})", parent_port, interface),
+            Node::Sata { hba_port, port_multiplier_port, lun } => write!(f, "Sata(0x{:X},0x{:X},0x{:X})", hba_port, port_multiplier_port, lun),
+            Node::Nvme { namespace_id, eui64 } => {
+                write!(f, "NVMe(0x{:X},", namespace_id)?;
+                for (i, b) in eui64.to_be_bytes().iter().enumerate() {
+                    write!(f, "{}{:02X}", if i > 0 { "-" } else { "" }, b)?;
+                }
+                write!(f, ")")
+            },
+            Node::Mac { address, if_type } => {
+                // Ethernet and IEEE 802 addresses are 6 bytes. Other link types may use all 32.
+                let len = if *if_type <= 1 { 6 } else { address.len() };
+                write!(f, "MAC(")?;
+                write_hex(f, &address[..len])?;
+                write!(f, ",0x{:X})", if_type)
+            },
+            Node::Ipv4 { local, remote, protocol, static_ip, gateway, subnet_mask, .. } => {
+                write!(f, "IPv4({},", remote)?;
+                write_protocol(f, *protocol)?;
+                write!(f, ",{},{},{},{})", if *static_ip { "Static" } else { "DHCP" }, local, gateway, subnet_mask)
+            },
+            Node::Ipv6 { local, remote, protocol, origin, prefix_length, gateway, .. } => {
+                write!(f, "IPv6({},", remote)?;
+                write_protocol(f, *protocol)?;
+                let origin = match origin {
+                    0 => "Static",
+                    1 => "StatelessAutoConfigure",
+                    _ => "StatefulAutoConfigure",
+                };
+                write!(f, ",{},{},{},0x{:X})", origin, local, gateway, prefix_length)
+            },
+            Node::Uri(uri) => write!(f, "Uri({})", uri),
+            Node::HardDrive { partition_number, start, size, signature } => {
+                write!(f, "HD({},", partition_number)?;
+                match signature {
+                    PartitionSignature::Gpt(guid) => write!(f, "GPT,{},", GuidText(guid))?,
+                    PartitionSignature::Mbr(sig) => write!(f, "MBR,0x{:08X},", sig)?,
+                    PartitionSignature::None => write!(f, "0,0,")?,
+                }
+                write!(f, "0x{:X},0x{:X})", start, size)
+            },
+            Node::CdRom { boot_entry, start, size } => write!(f, "CDROM(0x{:X},0x{:X},0x{:X})", boot_entry, start, size),
+            Node::FilePath(path) => write!(f, "{}", path),
+            Node::Vendor { kind, guid, data } => {
+                let name = match kind {
+                    VendorKind::Hardware => "VenHw",
+                    VendorKind::Messaging => "VenMsg",
+                    VendorKind::Media => "VenMedia",
+                };
+                write!(f, "{}({}", name, GuidText(guid))?;
+                if !data.is_empty() {
+                    write!(f, ",")?;
+                    write_hex(f, data)?;
+                }
+                write!(f, ")")
+            },
+            Node::EndInstance | Node::End => Ok(()), // These are separators and have no text of their own
+            Node::Unknown { node_type, sub_type, data } => {
+                write!(f, "Path({},{},", node_type, sub_type)?;
+                write_hex(f, data)?;
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+impl FromStr for Node {
+    type Err = EfiError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        text_to_node(s)
+    }
+}
+
+struct GuidText<'a>(&'a Guid);
+
+impl<'a> fmt::Display for GuidText<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-", g.0, g.1, g.2, g.3[0], g.3[1])?;
+        write_hex(f, &g.3[2..])
+    }
+}
+
+// Positional arguments of a node. Trailing ones may be left out and read as empty.
+struct Args<'a> {
+    args: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    fn new(text: &'a str, max: usize) -> Result<Self> {
+        let args = text.split(',').map(str::trim).collect::<Vec<_>>();
+        if args.len() > max {
+            return Err(invalid());
+        }
+
+        Ok(Self { args })
+    }
+
+    fn get(&self, i: usize) -> &'a str {
+        self.args.get(i).cloned().unwrap_or("")
+    }
+
+    fn int<T: TryFrom<u64>>(&self, i: usize) -> Result<T> {
+        T::try_from(parse_int(self.get(i))?).map_err(|_| invalid())
+    }
+}
+
+fn acpi(eisa_id: u32, args: &str) -> Result<Node> {
+    let a = Args::new(args, 1)?;
+    Ok(Node::Acpi { hid: (eisa_id << 16) | PNP_EISA_ID, uid: a.int(0)? })
+}
+
+fn vendor(kind: VendorKind, args: &str) -> Result<Node> {
+    let a = Args::new(args, 2)?;
+    Ok(Node::Vendor { kind, guid: parse_guid(a.get(0))?, data: parse_hex(a.get(1))? })
+}
+
+// Either a compressed EISA id like "PNP0A03" or a plain number
+fn parse_hid(text: &str) -> Result<u32> {
+    let bytes = text.as_bytes();
+    if bytes.len() == 7 && bytes[..3].iter().all(|b| b.is_ascii_uppercase()) {
+        let vendor = bytes[..3].iter().fold(0, |acc, b| (acc << 5) | u32::from(b - b'@'));
+        let product = u32::from_str_radix(&text[3..], 16).map_err(|_| invalid())?;
+        return Ok((product << 16) | vendor);
+    }
+
+    u32::try_from(parse_int(text)?).map_err(|_| invalid())
+}
+
+fn protocol(text: &str) -> Result<u16> {
+    match text {
+        "TCP" => Ok(TCP),
+        "UDP" => Ok(UDP),
+        other => u16::try_from(parse_int(other)?).map_err(|_| invalid()),
+    }
+}
+
+// Missing addresses are all zeros, like the firmware's parser does it
+fn ip<T: FromStr + Unspecified>(text: &str) -> Result<T> {
+    if text.is_empty() {
+        return Ok(T::unspecified());
+    }
+
+    text.parse().map_err(|_| invalid())
+}
+
+trait Unspecified {
+    fn unspecified() -> Self;
+}
+
+impl Unspecified for Ipv4Addr {
+    fn unspecified() -> Self {
+        Ipv4Addr::unspecified()
+    }
+}
+
+impl Unspecified for Ipv6Addr {
+    fn unspecified() -> Self {
+        Ipv6Addr::unspecified()
+    }
+}
+
+// Hex with a 0x prefix, decimal otherwise. Empty means zero.
+fn parse_int(text: &str) -> Result<u64> {
+    let result = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if text.is_empty() {
+        Ok(0)
+    } else {
+        text.parse()
+    };
+
+    result.map_err(|_| invalid())
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(invalid());
+    }
+
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+fn parse_guid(text: &str) -> Result<Guid> {
+    let parts = text.split('-').collect::<Vec<_>>();
+    let lens = [8, 4, 4, 4, 12];
+    if parts.len() != lens.len() || parts.iter().zip(lens.iter()).any(|(p, len)| p.len() != *len) {
+        return Err(invalid());
+    }
+
+    let data1 = u32::from_str_radix(parts[0], 16).map_err(|_| invalid())?;
+    let data2 = u16::from_str_radix(parts[1], 16).map_err(|_| invalid())?;
+    let data3 = u16::from_str_radix(parts[2], 16).map_err(|_| invalid())?;
+    let mut data4 = [0u8; 8];
+    data4.copy_from_slice(&parse_hex(&[parts[3], parts[4]].concat())?);
+    Ok(EFI_GUID(data1, data2, data3, data4))
+}
+
+// Splits "Name(args)" into its parts
+fn split_call(text: &str) -> Option<(&str, &str)> {
+    let open = text.find('(')?;
+    let name = &text[..open];
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric()) || !text.ends_with(')') {
+        return None;
+    }
+
+    Some((name, &text[open + 1..text.len() - 1]))
+}
+
+// Splits on `sep` except inside parentheses, so that URIs and argument lists stay whole
+fn split_top_level(text: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            c if c == sep && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
+
+fn write_hex(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{:02X}", b)?;
+    }
+    Ok(())
+}
+
+fn write_protocol(f: &mut fmt::Formatter, protocol: u16) -> fmt::Result {
+    match protocol {
+        TCP => write!(f, "TCP"),
+        UDP => write!(f, "UDP"),
+        other => write!(f, "0x{:X}", other),
+    }
+}
+
+fn invalid() -> EfiError {
+    EfiErrorKind::InvalidParameter.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(text: &str) {
+        let bytes = text_to_path(text).unwrap();
+        assert_eq!(path_to_text(&bytes), text);
+    }
+
+    #[test]
+    fn network_path_round_trips() {
+        round_trip("PciRoot(0x0)/Pci(0x3,0x0)/MAC(525400123456,0x1)/IPv4(10.0.2.2,UDP,DHCP,10.0.2.15,10.0.2.2,255.255.255.0)/Uri(http://10.0.2.2/a,b/boot.efi)");
+        round_trip("PciRoot(0x0)/Pci(0x3,0x0)/MAC(525400123456,0x1)/IPv6(2001:db8::1,TCP,StatelessAutoConfigure,2001:db8::42,fe80::1,0x40)");
+    }
+
+    #[test]
+    fn disk_paths_round_trip() {
+        round_trip("PciRoot(0x0)/Pci(0x1F,0x2)/Sata(0x0,0xFFFF,0x0)/HD(1,GPT,C12A7328-F81F-11D2-BA4B-00A0C93EC93B,0x800,0x64000)/\\EFI\\BOOT\\BOOTX64.EFI");
+        round_trip("PcieRoot(0x1)/Pci(0x0,0x0)/NVMe(0x1,00-25-38-5A-91-B0-12-34)/HD(2,MBR,0xDEADBEEF,0x1000,0x2000)");
+        round_trip("PciRoot(0x0)/Pci(0x1,0x1)/USB(0x2,0x0)/CDROM(0x1,0x3C,0x2D0)");
+        round_trip("VenHw(93E34C7E-B50E-11DF-9223-2443DFD72085,0102)/VenMedia(1428F772-B64A-441E-B8C3-9EBDD7F893C7)/Path(4,42,ABCD)/Acpi(PNP0C09,0x0)/Acpi(0x12345678,0x2)");
+    }
+
+    #[test]
+    fn multi_instance_paths_round_trip() {
+        round_trip("PciRoot(0x0)/Pci(0x2,0x0),PciRoot(0x0)/Pci(0x1F,0x0)/Serial(0x0)");
+
+        let bytes = text_to_path("Pci(0x1,0x0),Pci(0x2,0x0)").unwrap();
+        let nodes = Nodes::new(&bytes).collect::<Vec<_>>();
+        assert_eq!(nodes, vec![
+            Node::Pci { device: 1, function: 0 },
+            Node::EndInstance,
+            Node::Pci { device: 2, function: 0 },
+            Node::End,
+        ]);
+    }
+
+    #[test]
+    fn parses_abbreviated_and_decimal_forms() {
+        assert_eq!(text_to_node("Pci(31,2)").unwrap(), Node::Pci { device: 31, function: 2 });
+        assert_eq!(text_to_node("PciRoot(0x0)").unwrap(), Node::Acpi { hid: 0x0A03_41D0, uid: 0 });
+        assert_eq!(text_to_node("Acpi(PNP0A03,0x0)").unwrap(), Node::Acpi { hid: 0x0A03_41D0, uid: 0 });
+        assert_eq!(text_to_node("IPv4(192.168.1.1)").unwrap(), Node::Ipv4 {
+            local: Ipv4Addr::unspecified(),
+            remote: Ipv4Addr::new(192, 168, 1, 1),
+            local_port: 0,
+            remote_port: 0,
+            protocol: 0,
+            static_ip: false,
+            gateway: Ipv4Addr::unspecified(),
+            subnet_mask: Ipv4Addr::unspecified(),
+        });
+        assert_eq!(text_to_node("Unknown(1)").unwrap(), Node::FilePath("Unknown(1)".into()));
+    }
+
+    #[test]
+    fn rejects_malformed_nodes() {
+        assert!(text_to_node("Pci(0x1,0x0,0x0)").is_err());
+        assert!(text_to_node("Pci(0x100,0x0)").is_err());
+        assert!(text_to_node("HD(1,GPT,not-a-guid,0x0,0x0)").is_err());
+        assert!(text_to_node("MAC(5254001,0x1)").is_err());
+        assert!(text_to_node("IPv4(10.0.2)").is_err());
+    }
+
+    #[test]
+    fn rejects_nodes_too_long_for_their_length_field() {
+        let path = "a".repeat(u16::MAX as usize / 2);
+        assert!(text_to_path(&path).is_err());
+        assert!(text_to_path(&path[..1000]).is_ok());
+    }
+
+    #[test]
+    fn text_matches_binary_encoding() {
+        let bytes = text_to_path("Pci(0x1F,0x2)").unwrap();
+        assert_eq!(bytes, [1, 1, 6, 0, 0x2, 0x1F, 0x7F, 0xFF, 4, 0]);
+    }
+}
@@ -0,0 +1,749 @@
+//! GUID Partition Table parsing and editing.
+//!
+//! Works over anything that implements `io::Read + io::Seek` (plus `io::Write` for editing),
+//! which means a `BlockIo` device on the firmware or a disk image file on the host.
+
+use crate::{
+    Result,
+    EfiErrorKind,
+    Guid,
+    io::{Read, Write, Seek, SeekFrom},
+    utils::{crc32, guid_from_bytes, guid_to_bytes, clone_guid},
+};
+use super::{read_at, write_at, mbr::Mbr};
+use ffi::EFI_GUID;
+use core::cmp;
+use alloc::{vec::Vec, string::String};
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+const DEFAULT_NUM_ENTRIES: u32 = 128;
+const DEFAULT_ENTRY_SIZE: u32 = 128;
+const NAME_LEN: usize = 36; // In UTF-16 code units
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024; // New partitions start on 1 MiB boundaries like every other partitioning tool does
+
+pub const UNUSED_PARTITION_GUID: Guid = EFI_GUID(0, 0, 0, [0; 8]);
+pub const EFI_SYSTEM_PARTITION_GUID: Guid = EFI_GUID(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+pub const BASIC_DATA_PARTITION_GUID: Guid = EFI_GUID(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+pub const MICROSOFT_RESERVED_PARTITION_GUID: Guid = EFI_GUID(0xE3C9E316, 0x0B5C, 0x4DB8, [0x81, 0x7D, 0xF9, 0x2D, 0xF0, 0x02, 0x15, 0xAE]);
+pub const LINUX_FILESYSTEM_PARTITION_GUID: Guid = EFI_GUID(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+pub const LINUX_SWAP_PARTITION_GUID: Guid = EFI_GUID(0x0657FD6D, 0xA4AB, 0x43C4, [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F]);
+
+/// Partition attribute bits
+pub const ATTR_REQUIRED_PARTITION: u64 = 1 << 0;
+pub const ATTR_NO_BLOCK_IO_PROTOCOL: u64 = 1 << 1;
+pub const ATTR_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+/// A GPT header as it lives on disk (either the primary or the backup)
+#[derive(Debug)]
+pub struct GptHeader {
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc32: u32,
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub partition_entry_lba: u64,
+    pub num_partition_entries: u32,
+    pub size_of_partition_entry: u32,
+    pub partition_entry_array_crc32: u32,
+}
+
+impl GptHeader {
+    /// Parses and validates the signature, size and CRC of a header
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < GPT_HEADER_SIZE as usize || &buf[..8] != GPT_SIGNATURE {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let header_size = le_u32(&buf[12..]);
+        if header_size < GPT_HEADER_SIZE || header_size as usize > buf.len() {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let header_crc32 = le_u32(&buf[16..]);
+        let mut copy = buf[..header_size as usize].to_vec();
+        copy[16..20].copy_from_slice(&[0; 4]); // CRC is computed with the CRC field itself zeroed
+        if crc32(&copy) != header_crc32 {
+            return Err(EfiErrorKind::CrcError.into());
+        }
+
+        let header = Self {
+            revision: le_u32(&buf[8..]),
+            header_size,
+            header_crc32,
+            my_lba: le_u64(&buf[24..]),
+            alternate_lba: le_u64(&buf[32..]),
+            first_usable_lba: le_u64(&buf[40..]),
+            last_usable_lba: le_u64(&buf[48..]),
+            disk_guid: guid_at(&buf[56..]),
+            partition_entry_lba: le_u64(&buf[72..]),
+            num_partition_entries: le_u32(&buf[80..]),
+            size_of_partition_entry: le_u32(&buf[84..]),
+            partition_entry_array_crc32: le_u32(&buf[88..]),
+        };
+
+        // Entry size must be 128 * 2^n as per the spec
+        if header.size_of_partition_entry < DEFAULT_ENTRY_SIZE || !header.size_of_partition_entry.is_power_of_two() {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        Ok(header)
+    }
+
+    /// Serializes the header, filling in `header_crc32`
+    pub fn to_bytes(&mut self) -> Vec<u8> {
+        let mut buf = vec![0u8; GPT_HEADER_SIZE as usize];
+        buf[..8].copy_from_slice(GPT_SIGNATURE);
+        buf[8..12].copy_from_slice(&self.revision.to_le_bytes());
+        buf[12..16].copy_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.my_lba.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.alternate_lba.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        buf[56..72].copy_from_slice(&guid_to_bytes(&self.disk_guid));
+        buf[72..80].copy_from_slice(&self.partition_entry_lba.to_le_bytes());
+        buf[80..84].copy_from_slice(&self.num_partition_entries.to_le_bytes());
+        buf[84..88].copy_from_slice(&self.size_of_partition_entry.to_le_bytes());
+        buf[88..92].copy_from_slice(&self.partition_entry_array_crc32.to_le_bytes());
+
+        self.header_size = GPT_HEADER_SIZE;
+        self.header_crc32 = crc32(&buf);
+        buf[16..20].copy_from_slice(&self.header_crc32.to_le_bytes());
+        buf
+    }
+}
+
+#[derive(Debug)]
+pub struct GptPartition {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub starting_lba: u64,
+    pub ending_lba: u64, // Inclusive
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    pub fn new<S: Into<String>>(type_guid: Guid, unique_guid: Guid, starting_lba: u64, ending_lba: u64, name: S) -> Self {
+        Self { type_guid, unique_guid, starting_lba, ending_lba, attributes: 0, name: name.into() }
+    }
+
+    pub fn size_in_lba(&self) -> u64 {
+        self.ending_lba - self.starting_lba + 1
+    }
+
+    fn overlaps(&self, starting_lba: u64, ending_lba: u64) -> bool {
+        self.starting_lba <= ending_lba && starting_lba <= self.ending_lba
+    }
+
+    // Returns None for unused entries
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let type_guid = guid_at(buf);
+        if type_guid == UNUSED_PARTITION_GUID {
+            return None;
+        }
+
+        let name_utf16 = buf[56..56 + NAME_LEN * 2]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect::<Vec<_>>();
+
+        Some(Self {
+            type_guid,
+            unique_guid: guid_at(&buf[16..]),
+            starting_lba: le_u64(&buf[32..]),
+            ending_lba: le_u64(&buf[40..]),
+            attributes: le_u64(&buf[48..]),
+            name: String::from_utf16_lossy(&name_utf16),
+        })
+    }
+
+    fn to_bytes(&self, buf: &mut [u8]) {
+        buf[..16].copy_from_slice(&guid_to_bytes(&self.type_guid));
+        buf[16..32].copy_from_slice(&guid_to_bytes(&self.unique_guid));
+        buf[32..40].copy_from_slice(&self.starting_lba.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.ending_lba.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        // Names longer than 36 UTF-16 units are silently truncated
+        for (i, c) in self.name.encode_utf16().take(NAME_LEN).enumerate() {
+            buf[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+}
+
+impl Clone for GptPartition {
+    fn clone(&self) -> Self {
+        Self {
+            type_guid: clone_guid(&self.type_guid),
+            unique_guid: clone_guid(&self.unique_guid),
+            starting_lba: self.starting_lba,
+            ending_lba: self.ending_lba,
+            attributes: self.attributes,
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// The partition layout of a GPT disk.
+///
+/// Read it from a disk with `Gpt::read` or create a fresh one with `Gpt::new`,
+/// edit it in memory and then persist it with `Gpt::write`, which always writes
+/// the protective MBR and both the primary and the backup structures.
+pub struct Gpt {
+    block_size: u64,
+    total_blocks: u64,
+    disk_guid: Guid,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    entry_size: u32,
+    entries: Vec<Option<GptPartition>>, // One slot per entry in the table so that partition indices remain stable
+    primary_valid: bool,
+    backup_valid: bool,
+}
+
+impl Gpt {
+    /// Creates an empty partition table for a disk with `total_blocks` blocks of size `block_size`
+    pub fn new(total_blocks: u64, block_size: u32, disk_guid: Guid) -> Result<Self> {
+        let block_size = block_size as u64;
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let entry_blocks = entry_array_blocks(DEFAULT_NUM_ENTRIES, DEFAULT_ENTRY_SIZE, block_size);
+        let first_usable_lba = 2 + entry_blocks; // MBR, header and then the entries
+        let overhead = first_usable_lba + entry_blocks + 1; // Plus backup entries and backup header at the end
+        if total_blocks <= overhead {
+            return Err(EfiErrorKind::VolumeFull.into());
+        }
+
+        Ok(Self {
+            block_size,
+            total_blocks,
+            disk_guid,
+            first_usable_lba,
+            last_usable_lba: total_blocks - entry_blocks - 2,
+            entry_size: DEFAULT_ENTRY_SIZE,
+            entries: (0..DEFAULT_NUM_ENTRIES).map(|_| None).collect(),
+            primary_valid: false,
+            backup_valid: false,
+        })
+    }
+
+    /// Reads the partition table from `disk`.
+    ///
+    /// The primary header and entries are tried first. If they're corrupt the backup ones are used.
+    /// Use `primary_valid()` and `backup_valid()` to find out whether either copy needs repair,
+    /// which a subsequent `write()` will do.
+    pub fn read<D: Read + Seek>(disk: &mut D, block_size: u32) -> Result<Self> {
+        let block_size = block_size as u64;
+        if block_size < 512 || !block_size.is_power_of_two() {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let total_blocks = disk.seek(SeekFrom::End(0))? / block_size;
+        if total_blocks < 3 {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let primary = read_header_and_entries(disk, 1, block_size);
+        let backup_lba = match primary {
+            Ok((ref header, _)) if header.alternate_lba < total_blocks => header.alternate_lba,
+            _ => total_blocks - 1,
+        };
+        let backup = read_header_and_entries(disk, backup_lba, block_size);
+
+        let primary_valid = primary.is_ok();
+        let backup_valid = backup.is_ok();
+        let (header, entries) = match (primary, backup) {
+            (Ok(p), _) => p,
+            (Err(_), Ok(b)) => b,
+            (Err(e), Err(_)) => return Err(e),
+        };
+
+        // Don't trust the header to keep partitions clear of the entry arrays and headers. `write`
+        // always puts the primary entries at LBA 2 even if they were read from somewhere else.
+        let entry_blocks = entry_array_blocks(entries.len() as u32, header.size_of_partition_entry, block_size);
+        let first_lba_after_primary = 2 + entry_blocks;
+        let last_lba_before_backup = total_blocks.saturating_sub(entry_blocks + 2);
+
+        Ok(Self {
+            block_size,
+            total_blocks,
+            disk_guid: header.disk_guid,
+            first_usable_lba: cmp::max(header.first_usable_lba, first_lba_after_primary),
+            last_usable_lba: cmp::min(header.last_usable_lba, last_lba_before_backup),
+            entry_size: header.size_of_partition_entry,
+            entries,
+            primary_valid,
+            backup_valid,
+        })
+    }
+
+    /// Writes the protective MBR, the primary and the backup headers and entry arrays to `disk`
+    pub fn write<D: Write + Seek>(&mut self, disk: &mut D) -> Result<()> {
+        let entry_bytes = self.entries_to_bytes();
+        let entry_array_crc32 = crc32(&entry_bytes);
+        let entry_blocks = entry_array_blocks(self.entries.len() as u32, self.entry_size, self.block_size);
+        let backup_header_lba = self.total_blocks - 1;
+        let backup_entries_lba = backup_header_lba.checked_sub(entry_blocks)
+            .filter(|&lba| lba > self.last_usable_lba)
+            .ok_or_else(|| crate::EfiError::from(EfiErrorKind::VolumeCorrupted))?;
+
+        let mut primary = GptHeader {
+            revision: GPT_REVISION,
+            header_size: GPT_HEADER_SIZE,
+            header_crc32: 0,
+            my_lba: 1,
+            alternate_lba: backup_header_lba,
+            first_usable_lba: self.first_usable_lba,
+            last_usable_lba: self.last_usable_lba,
+            disk_guid: clone_guid(&self.disk_guid),
+            partition_entry_lba: 2,
+            num_partition_entries: self.entries.len() as u32,
+            size_of_partition_entry: self.entry_size,
+            partition_entry_array_crc32: entry_array_crc32,
+        };
+
+        let mut backup = GptHeader {
+            my_lba: backup_header_lba,
+            alternate_lba: 1,
+            partition_entry_lba: backup_entries_lba,
+            disk_guid: clone_guid(&self.disk_guid),
+            ..primary
+        };
+
+        Mbr::protective(self.total_blocks).write(disk)?;
+
+        // Entries are written before their headers so that a header never vouches for entries that aren't there yet
+        write_at(disk, 2 * self.block_size, &entry_bytes)?;
+        write_at(disk, self.block_size, &self.pad_to_block(primary.to_bytes()))?;
+        write_at(disk, backup_entries_lba * self.block_size, &entry_bytes)?;
+        write_at(disk, backup_header_lba * self.block_size, &self.pad_to_block(backup.to_bytes()))?;
+        disk.flush()?;
+
+        self.primary_valid = true;
+        self.backup_valid = true;
+        Ok(())
+    }
+
+    pub fn disk_guid(&self) -> &Guid {
+        &self.disk_guid
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size as u32
+    }
+
+    pub fn total_blocks(&self) -> u64 {
+        self.total_blocks
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        self.first_usable_lba
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        self.last_usable_lba
+    }
+
+    /// False if the primary header or entries were corrupt when the table was read
+    pub fn primary_valid(&self) -> bool {
+        self.primary_valid
+    }
+
+    /// False if the backup header or entries were corrupt when the table was read
+    pub fn backup_valid(&self) -> bool {
+        self.backup_valid
+    }
+
+    /// Used partitions along with their index in the entry array
+    pub fn partitions(&self) -> impl Iterator<Item=(usize, &GptPartition)> {
+        self.entries.iter().enumerate().filter_map(|(i, e)| e.as_ref().map(|p| (i, p)))
+    }
+
+    pub fn partition(&self, index: usize) -> Option<&GptPartition> {
+        self.entries.get(index).and_then(|e| e.as_ref())
+    }
+
+    /// Adds the given partition in the first free entry and returns the index of that entry.
+    /// Fails if the partition lies outside the usable area or overlaps an existing one.
+    pub fn add_partition(&mut self, partition: GptPartition) -> Result<usize> {
+        if partition.type_guid == UNUSED_PARTITION_GUID {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+        self.check_range(partition.starting_lba, partition.ending_lba, None)?;
+
+        let index = self.entries.iter().position(|e| e.is_none())
+            .ok_or_else(|| crate::EfiError::from(EfiErrorKind::OutOfResources))?;
+        self.entries[index] = Some(partition);
+        Ok(index)
+    }
+
+    /// Creates a partition of `size_in_lba` blocks in the first free gap large enough for it
+    pub fn create_partition<S: Into<String>>(&mut self, type_guid: Guid, unique_guid: Guid, size_in_lba: u64, name: S) -> Result<usize> {
+        if size_in_lba == 0 {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let alignment = cmp::max(PARTITION_ALIGNMENT / self.block_size, 1);
+        let mut used = self.partitions().map(|(_, p)| (p.starting_lba, p.ending_lba)).collect::<Vec<_>>();
+        used.sort();
+
+        let too_big = || crate::EfiError::from(EfiErrorKind::InvalidParameter);
+        let corrupted = || crate::EfiError::from(EfiErrorKind::VolumeCorrupted);
+        let mut candidate = align_up(self.first_usable_lba, alignment).ok_or_else(corrupted)?;
+        for (start, end) in used {
+            if candidate.checked_add(size_in_lba).ok_or_else(too_big)? <= start {
+                break;
+            }
+            if end >= candidate {
+                candidate = end.checked_add(1).and_then(|lba| align_up(lba, alignment)).ok_or_else(corrupted)?;
+            }
+        }
+
+        let ending_lba = candidate.checked_add(size_in_lba - 1).ok_or_else(too_big)?;
+        if ending_lba > self.last_usable_lba {
+            return Err(EfiErrorKind::VolumeFull.into());
+        }
+
+        self.add_partition(GptPartition::new(type_guid, unique_guid, candidate, ending_lba, name))
+    }
+
+    /// Removes the partition at `index` and returns it
+    pub fn delete_partition(&mut self, index: usize) -> Result<GptPartition> {
+        self.entries.get_mut(index)
+            .and_then(|e| e.take())
+            .ok_or_else(|| EfiErrorKind::NotFound.into())
+    }
+
+    /// Grows or shrinks the partition at `index` by moving its end. The start stays where it is.
+    pub fn resize_partition(&mut self, index: usize, new_size_in_lba: u64) -> Result<()> {
+        if new_size_in_lba == 0 {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let starting_lba = self.partition(index)
+            .ok_or_else(|| crate::EfiError::from(EfiErrorKind::NotFound))?
+            .starting_lba;
+        let ending_lba = starting_lba.checked_add(new_size_in_lba - 1)
+            .ok_or_else(|| crate::EfiError::from(EfiErrorKind::InvalidParameter))?;
+        self.check_range(starting_lba, ending_lba, Some(index))?;
+
+        if let Some(Some(ref mut partition)) = self.entries.get_mut(index) {
+            partition.ending_lba = ending_lba;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, starting_lba: u64, ending_lba: u64, ignore_index: Option<usize>) -> Result<()> {
+        if starting_lba > ending_lba || starting_lba < self.first_usable_lba || ending_lba > self.last_usable_lba {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let overlaps = self.partitions()
+            .any(|(i, p)| Some(i) != ignore_index && p.overlaps(starting_lba, ending_lba));
+        if overlaps {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        Ok(())
+    }
+
+    fn entries_to_bytes(&self) -> Vec<u8> {
+        let entry_size = self.entry_size as usize;
+        let mut buf = vec![0u8; self.entries.len() * entry_size];
+        for (i, entry) in self.entries.iter().enumerate() {
+            if let Some(partition) = entry {
+                partition.to_bytes(&mut buf[i * entry_size..(i + 1) * entry_size]);
+            }
+        }
+        buf
+    }
+
+    fn pad_to_block(&self, mut buf: Vec<u8>) -> Vec<u8> {
+        buf.resize(self.block_size as usize, 0);
+        buf
+    }
+}
+
+fn read_header_and_entries<D: Read + Seek>(disk: &mut D, lba: u64, block_size: u64) -> Result<(GptHeader, Vec<Option<GptPartition>>)> {
+    let mut block = vec![0u8; block_size as usize];
+    read_at(disk, lba * block_size, &mut block)?;
+
+    let header = GptHeader::from_bytes(&block)?;
+    if header.my_lba != lba {
+        return Err(EfiErrorKind::VolumeCorrupted.into());
+    }
+
+    // Guard against absurd sizes in a header that happens to have a valid CRC
+    let array_size = header.num_partition_entries as u64 * header.size_of_partition_entry as u64;
+    if array_size > 16 * 1024 * 1024 {
+        return Err(EfiErrorKind::VolumeCorrupted.into());
+    }
+
+    let mut entry_bytes = vec![0u8; array_size as usize];
+    let entries_offset = header.partition_entry_lba.checked_mul(block_size)
+        .ok_or_else(|| crate::EfiError::from(EfiErrorKind::VolumeCorrupted))?;
+    read_at(disk, entries_offset, &mut entry_bytes)?;
+    if crc32(&entry_bytes) != header.partition_entry_array_crc32 {
+        return Err(EfiErrorKind::CrcError.into());
+    }
+
+    let entries = entry_bytes
+        .chunks(header.size_of_partition_entry as usize)
+        .map(GptPartition::from_bytes)
+        .collect::<Vec<_>>();
+
+    if entries.iter().flatten().any(|p| p.starting_lba > p.ending_lba) {
+        return Err(EfiErrorKind::VolumeCorrupted.into());
+    }
+
+    Ok((header, entries))
+}
+
+fn entry_array_blocks(num_entries: u32, entry_size: u32, block_size: u64) -> u64 {
+    let bytes = num_entries as u64 * entry_size as u64;
+    bytes.div_ceil(block_size)
+}
+
+fn align_up(value: u64, alignment: u64) -> Option<u64> {
+    value.div_ceil(alignment).checked_mul(alignment)
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn guid_at(buf: &[u8]) -> Guid {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&buf[..16]);
+    guid_from_bytes(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Cursor;
+
+    const DISK_BLOCKS: u64 = 16 * 1024; // 8 MiB with 512 byte blocks
+
+    fn guid(n: u8) -> Guid {
+        EFI_GUID(0x1234_5678, 0x9ABC, 0xDEF0, [n; 8])
+    }
+
+    fn blank_disk() -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0u8; (DISK_BLOCKS * 512) as usize])
+    }
+
+    fn disk_with_two_partitions() -> Cursor<Vec<u8>> {
+        let mut disk = blank_disk();
+        let mut gpt = Gpt::new(DISK_BLOCKS, 512, guid(0)).unwrap();
+        gpt.create_partition(EFI_SYSTEM_PARTITION_GUID, guid(1), 2048, "EFI system partition").unwrap();
+        gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(2), 4096, "data").unwrap();
+        gpt.write(&mut disk).unwrap();
+        disk
+    }
+
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn guid_bytes_are_mixed_endian() {
+        let bytes = guid_to_bytes(&EFI_SYSTEM_PARTITION_GUID);
+        assert_eq!(&bytes[..8], &[0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11]);
+        assert_eq!(guid_from_bytes(&bytes), EFI_SYSTEM_PARTITION_GUID);
+    }
+
+    #[test]
+    fn write_then_read_round_trips() {
+        let mut disk = disk_with_two_partitions();
+
+        assert!(Mbr::read(&mut disk).unwrap().is_protective());
+
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(gpt.primary_valid());
+        assert!(gpt.backup_valid());
+        assert_eq!(gpt.disk_guid(), &guid(0));
+        assert_eq!(gpt.first_usable_lba(), 34);
+        assert_eq!(gpt.last_usable_lba(), DISK_BLOCKS - 34);
+
+        let partitions = gpt.partitions().collect::<Vec<_>>();
+        assert_eq!(partitions.len(), 2);
+
+        let (index, esp) = partitions[0];
+        assert_eq!(index, 0);
+        assert_eq!(esp.type_guid, EFI_SYSTEM_PARTITION_GUID);
+        assert_eq!(esp.unique_guid, guid(1));
+        assert_eq!(esp.starting_lba, 2048);
+        assert_eq!(esp.ending_lba, 4095);
+        assert_eq!(esp.name, "EFI system partition");
+
+        let (_, data) = partitions[1];
+        assert_eq!(data.starting_lba, 4096);
+        assert_eq!(data.size_in_lba(), 4096);
+        assert_eq!(data.name, "data");
+    }
+
+    #[test]
+    fn falls_back_to_backup_when_primary_is_corrupt() {
+        let mut disk = disk_with_two_partitions();
+        disk.get_mut()[512 + 24] ^= 0xFF; // Corrupt a field covered by the primary header's CRC
+
+        let mut gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(!gpt.primary_valid());
+        assert!(gpt.backup_valid());
+        assert_eq!(gpt.partitions().count(), 2);
+
+        // Writing the table back regenerates the primary
+        gpt.write(&mut disk).unwrap();
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(gpt.primary_valid());
+    }
+
+    #[test]
+    fn falls_back_to_backup_when_primary_entries_are_corrupt() {
+        let mut disk = disk_with_two_partitions();
+        disk.get_mut()[2 * 512 + 40] ^= 0xFF;
+
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(!gpt.primary_valid());
+        assert_eq!(gpt.partition(0).unwrap().ending_lba, 4095);
+    }
+
+    #[test]
+    fn fails_when_both_copies_are_corrupt() {
+        let mut disk = disk_with_two_partitions();
+        let len = disk.get_ref().len();
+        disk.get_mut()[512 + 24] ^= 0xFF;
+        disk.get_mut()[len - 512 + 24] ^= 0xFF;
+
+        assert_eq!(Gpt::read(&mut disk, 512).err().unwrap().kind(), EfiErrorKind::CrcError);
+    }
+
+    #[test]
+    fn blank_disk_is_not_gpt() {
+        let mut disk = blank_disk();
+        assert_eq!(Gpt::read(&mut disk, 512).err().unwrap().kind(), EfiErrorKind::VolumeCorrupted);
+    }
+
+    #[test]
+    fn overlapping_partitions_are_rejected() {
+        let mut gpt = Gpt::new(DISK_BLOCKS, 512, guid(0)).unwrap();
+        gpt.add_partition(GptPartition::new(BASIC_DATA_PARTITION_GUID, guid(1), 2048, 4095, "a")).unwrap();
+
+        let overlapping = GptPartition::new(BASIC_DATA_PARTITION_GUID, guid(2), 4095, 5000, "b");
+        assert_eq!(gpt.add_partition(overlapping).err().unwrap().kind(), EfiErrorKind::InvalidParameter);
+
+        let outside = GptPartition::new(BASIC_DATA_PARTITION_GUID, guid(2), 10, 20, "c");
+        assert_eq!(gpt.add_partition(outside).err().unwrap().kind(), EfiErrorKind::InvalidParameter);
+    }
+
+    #[test]
+    fn create_partition_fills_gaps_and_reports_full() {
+        let mut gpt = Gpt::new(DISK_BLOCKS, 512, guid(0)).unwrap();
+        let first = gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(1), 2048, "a").unwrap();
+        gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(2), 2048, "b").unwrap();
+        gpt.delete_partition(first).unwrap();
+
+        // Fits in the hole left behind by the first partition
+        let index = gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(3), 1024, "c").unwrap();
+        assert_eq!(gpt.partition(index).unwrap().starting_lba, 2048);
+
+        let too_big = gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(4), DISK_BLOCKS, "d");
+        assert_eq!(too_big.err().unwrap().kind(), EfiErrorKind::VolumeFull);
+    }
+
+    #[test]
+    fn resize_respects_neighbours() {
+        let mut gpt = Gpt::new(DISK_BLOCKS, 512, guid(0)).unwrap();
+        let a = gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(1), 2048, "a").unwrap();
+        gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(2), 2048, "b").unwrap();
+
+        gpt.resize_partition(a, 1024).unwrap();
+        assert_eq!(gpt.partition(a).unwrap().ending_lba, 3071);
+        assert!(gpt.resize_partition(a, 4096).is_err());
+        assert_eq!(gpt.delete_partition(7).err().unwrap().kind(), EfiErrorKind::NotFound);
+    }
+
+    #[test]
+    fn huge_sizes_are_rejected_instead_of_overflowing() {
+        let mut gpt = Gpt::new(DISK_BLOCKS, 512, guid(0)).unwrap();
+        let a = gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(1), 2048, "a").unwrap();
+
+        let too_big = gpt.create_partition(BASIC_DATA_PARTITION_GUID, guid(2), u64::MAX, "b");
+        assert_eq!(too_big.err().unwrap().kind(), EfiErrorKind::InvalidParameter);
+        assert_eq!(gpt.resize_partition(a, u64::MAX).err().unwrap().kind(), EfiErrorKind::InvalidParameter);
+    }
+
+    #[test]
+    fn header_values_are_not_trusted() {
+        let mut disk = disk_with_two_partitions();
+        let mut primary = GptHeader::from_bytes(&disk.get_ref()[512..1024]).unwrap();
+
+        // A last usable LBA overlapping the backup entries is clamped to just before them
+        primary.last_usable_lba = DISK_BLOCKS - 2;
+        disk.get_mut()[512..512 + GPT_HEADER_SIZE as usize].copy_from_slice(&primary.to_bytes());
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(gpt.primary_valid());
+        assert_eq!(gpt.last_usable_lba(), DISK_BLOCKS - 34);
+
+        // Same for a first usable LBA overlapping the primary entries, which write() puts at LBA 2
+        primary.first_usable_lba = 2;
+        disk.get_mut()[512..512 + GPT_HEADER_SIZE as usize].copy_from_slice(&primary.to_bytes());
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(gpt.primary_valid());
+        assert_eq!(gpt.first_usable_lba(), 34);
+
+        // An entry array offset that overflows makes the primary invalid rather than panicking
+        primary.partition_entry_lba = u64::MAX;
+        disk.get_mut()[512..512 + GPT_HEADER_SIZE as usize].copy_from_slice(&primary.to_bytes());
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(!gpt.primary_valid());
+        assert!(gpt.backup_valid());
+    }
+
+    #[test]
+    fn entries_ending_before_they_start_are_rejected() {
+        let mut disk = disk_with_two_partitions();
+        let mut primary = GptHeader::from_bytes(&disk.get_ref()[512..1024]).unwrap();
+
+        // Swap the first entry's starting and ending LBA and fix up the CRCs so only the values are wrong
+        let entries = &mut disk.get_mut()[1024..1024 + (DEFAULT_NUM_ENTRIES * DEFAULT_ENTRY_SIZE) as usize];
+        let (starting_lba, ending_lba) = (le_u64(&entries[32..]), le_u64(&entries[40..]));
+        entries[32..40].copy_from_slice(&ending_lba.to_le_bytes());
+        entries[40..48].copy_from_slice(&starting_lba.to_le_bytes());
+        primary.partition_entry_array_crc32 = crc32(entries);
+        disk.get_mut()[512..512 + GPT_HEADER_SIZE as usize].copy_from_slice(&primary.to_bytes());
+
+        let gpt = Gpt::read(&mut disk, 512).unwrap();
+        assert!(!gpt.primary_valid());
+        assert!(gpt.backup_valid());
+        assert_eq!(gpt.partition(0).unwrap().size_in_lba(), 2048);
+    }
+
+    #[test]
+    fn supports_4k_blocks() {
+        let total_blocks = 2048;
+        let mut disk = Cursor::new(vec![0u8; total_blocks * 4096]);
+        let mut gpt = Gpt::new(total_blocks as u64, 4096, guid(0)).unwrap();
+        assert_eq!(gpt.first_usable_lba(), 6);
+        gpt.create_partition(LINUX_FILESYSTEM_PARTITION_GUID, guid(1), 256, "root").unwrap();
+        gpt.write(&mut disk).unwrap();
+
+        let gpt = Gpt::read(&mut disk, 4096).unwrap();
+        assert_eq!(gpt.partition(0).unwrap().starting_lba, 256);
+    }
+}
@@ -0,0 +1,185 @@
+//! Master Boot Record parsing and writing.
+//!
+//! On GPT disks the MBR is a "protective" one with a single partition of
+//! type 0xEE covering the whole disk. See `gpt` for the real partition table.
+
+use crate::{Result, EfiErrorKind, io::{Read, Write, Seek}};
+use super::{read_at, write_at};
+use core::cmp;
+
+pub const MBR_SIZE: usize = 512;
+const BOOT_CODE_SIZE: usize = 440;
+const DISK_SIGNATURE_OFFSET: usize = 440;
+const PARTITION_TABLE_OFFSET: usize = 446;
+const PARTITION_RECORD_SIZE: usize = 16;
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// OS type used by the single partition in a protective MBR
+pub const GPT_PROTECTIVE_OS_TYPE: u8 = 0xEE;
+
+/// OS type of an EFI system partition when described by a plain MBR
+pub const EFI_SYSTEM_PARTITION_OS_TYPE: u8 = 0xEF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MbrPartition {
+    pub bootable: bool,
+    pub starting_chs: [u8; 3],
+    pub os_type: u8,
+    pub ending_chs: [u8; 3],
+    pub starting_lba: u32,
+    pub size_in_lba: u32,
+}
+
+impl MbrPartition {
+    /// A record with os type zero is unused
+    pub fn is_used(&self) -> bool {
+        self.os_type != 0
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut starting_chs = [0u8; 3];
+        let mut ending_chs = [0u8; 3];
+        starting_chs.copy_from_slice(&bytes[1..4]);
+        ending_chs.copy_from_slice(&bytes[5..8]);
+
+        Self {
+            bootable: bytes[0] == 0x80,
+            starting_chs,
+            os_type: bytes[4],
+            ending_chs,
+            starting_lba: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            size_in_lba: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+        }
+    }
+
+    fn to_bytes(self, bytes: &mut [u8]) {
+        bytes[0] = if self.bootable { 0x80 } else { 0 };
+        bytes[1..4].copy_from_slice(&self.starting_chs);
+        bytes[4] = self.os_type;
+        bytes[5..8].copy_from_slice(&self.ending_chs);
+        bytes[8..12].copy_from_slice(&self.starting_lba.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.size_in_lba.to_le_bytes());
+    }
+}
+
+pub struct Mbr {
+    boot_code: [u8; BOOT_CODE_SIZE], // Preserved as is so that writing back an MBR we read doesn't clobber the boot loader
+    pub disk_signature: u32,
+    pub partitions: [MbrPartition; 4],
+}
+
+impl Mbr {
+    /// An empty MBR with no boot code and no partitions
+    pub fn new(disk_signature: u32) -> Self {
+        Self { boot_code: [0; BOOT_CODE_SIZE], disk_signature, partitions: [MbrPartition::default(); 4] }
+    }
+
+    /// A protective MBR for a GPT disk with `total_blocks` logical blocks
+    pub fn protective(total_blocks: u64) -> Self {
+        let mut mbr = Self::new(0);
+        mbr.partitions[0] = MbrPartition {
+            bootable: false,
+            starting_chs: [0x00, 0x02, 0x00],
+            os_type: GPT_PROTECTIVE_OS_TYPE,
+            ending_chs: [0xFF, 0xFF, 0xFF],
+            starting_lba: 1,
+            size_in_lba: cmp::min(total_blocks.saturating_sub(1), u32::MAX as u64) as u32, // Disks too big for 32 bits get 0xFFFFFFFF as per the spec
+        };
+        mbr
+    }
+
+    pub fn read<D: Read + Seek>(disk: &mut D) -> Result<Self> {
+        let mut buf = [0u8; MBR_SIZE];
+        read_at(disk, 0, &mut buf)?;
+        Self::from_bytes(&buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < MBR_SIZE || buf[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != BOOT_SIGNATURE {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let mut boot_code = [0u8; BOOT_CODE_SIZE];
+        boot_code.copy_from_slice(&buf[..BOOT_CODE_SIZE]);
+
+        let mut partitions = [MbrPartition::default(); 4];
+        for (i, partition) in partitions.iter_mut().enumerate() {
+            let start = PARTITION_TABLE_OFFSET + i * PARTITION_RECORD_SIZE;
+            *partition = MbrPartition::from_bytes(&buf[start..start + PARTITION_RECORD_SIZE]);
+        }
+
+        let sig = &buf[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4];
+        Ok(Self { boot_code, disk_signature: u32::from_le_bytes([sig[0], sig[1], sig[2], sig[3]]), partitions })
+    }
+
+    pub fn write<D: Write + Seek>(&self, disk: &mut D) -> Result<()> {
+        write_at(disk, 0, &self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> [u8; MBR_SIZE] {
+        let mut buf = [0u8; MBR_SIZE];
+        buf[..BOOT_CODE_SIZE].copy_from_slice(&self.boot_code);
+        buf[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4].copy_from_slice(&self.disk_signature.to_le_bytes());
+        for (i, partition) in self.partitions.iter().enumerate() {
+            let start = PARTITION_TABLE_OFFSET + i * PARTITION_RECORD_SIZE;
+            partition.to_bytes(&mut buf[start..start + PARTITION_RECORD_SIZE]);
+        }
+        buf[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2].copy_from_slice(&BOOT_SIGNATURE);
+        buf
+    }
+
+    /// True if this is the protective MBR of a GPT disk
+    pub fn is_protective(&self) -> bool {
+        self.partitions.iter().any(|p| p.os_type == GPT_PROTECTIVE_OS_TYPE)
+    }
+
+    /// Partition records that are in use
+    pub fn used_partitions(&self) -> impl Iterator<Item=&MbrPartition> {
+        self.partitions.iter().filter(|p| p.is_used())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Cursor;
+
+    #[test]
+    fn protective_mbr_round_trip() {
+        let mut disk = Cursor::new(vec![0u8; 4096]);
+        Mbr::protective(8).write(&mut disk).unwrap();
+
+        let bytes = disk.get_ref();
+        assert_eq!(&bytes[510..512], &[0x55, 0xAA]);
+        assert_eq!(bytes[446 + 4], 0xEE);
+
+        let mbr = Mbr::read(&mut disk).unwrap();
+        assert!(mbr.is_protective());
+        assert_eq!(mbr.used_partitions().count(), 1);
+        assert_eq!(mbr.partitions[0].starting_lba, 1);
+        assert_eq!(mbr.partitions[0].size_in_lba, 7);
+    }
+
+    #[test]
+    fn protective_mbr_caps_size_for_huge_disks() {
+        let mbr = Mbr::protective(1 << 40);
+        assert_eq!(mbr.partitions[0].size_in_lba, 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn boot_code_is_preserved() {
+        let mut buf = Mbr::new(0xDEAD_BEEF).to_bytes();
+        buf[0] = 0xEB;
+        buf[1] = 0x63;
+        let mbr = Mbr::from_bytes(&buf).unwrap();
+        assert_eq!(mbr.disk_signature, 0xDEAD_BEEF);
+        assert_eq!(&mbr.to_bytes()[..], &buf[..]);
+    }
+
+    #[test]
+    fn missing_boot_signature_is_rejected() {
+        let buf = [0u8; 512];
+        assert_eq!(Mbr::from_bytes(&buf).err().unwrap().kind(), EfiErrorKind::VolumeCorrupted);
+    }
+}
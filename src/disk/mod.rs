@@ -0,0 +1,287 @@
+pub mod mbr;
+pub mod gpt;
+pub mod ramdisk;
+
+use crate::{
+    Result,
+    EfiErrorKind,
+    system_table,
+    image_handle,
+    io::{self, Read, Write, Seek, SeekFrom},
+    boot_services::locate_handles,
+};
+use ffi::{
+    EFI_GUID,
+    EFI_HANDLE,
+    EFI_STATUS,
+    BOOLEAN,
+    UINT32,
+    UINT64,
+    UINTN,
+    VOID,
+    boot_services::EFI_OPEN_PROTOCOL_BY_HANDLE_PROTOCOL,
+};
+use core::{ptr, mem, cmp};
+use alloc::vec::Vec;
+
+pub use self::mbr::{Mbr, MbrPartition};
+pub use self::gpt::{Gpt, GptHeader, GptPartition};
+pub use self::ramdisk::{RamDisk, RamDiskType};
+
+// efi_ffi doesn't define the block I/O protocol yet. So we carry our own definitions here.
+#[allow(non_snake_case)]
+pub (crate) mod raw {
+    use super::*;
+
+    pub const EFI_BLOCK_IO_PROTOCOL_GUID: EFI_GUID = EFI_GUID(0x964E5B21, 0x6459, 0x11D2, [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B]);
+
+    #[repr(C)]
+    pub struct EFI_BLOCK_IO_MEDIA {
+        pub MediaId: UINT32,
+        pub RemovableMedia: BOOLEAN,
+        pub MediaPresent: BOOLEAN,
+        pub LogicalPartition: BOOLEAN,
+        pub ReadOnly: BOOLEAN,
+        pub WriteCaching: BOOLEAN,
+        pub BlockSize: UINT32,
+        pub IoAlign: UINT32,
+        pub LastBlock: UINT64,
+    }
+
+    #[repr(C)]
+    pub struct EFI_BLOCK_IO_PROTOCOL {
+        pub Revision: UINT64,
+        pub Media: *const EFI_BLOCK_IO_MEDIA,
+        pub Reset: extern "win64" fn(This: *const EFI_BLOCK_IO_PROTOCOL, ExtendedVerification: BOOLEAN) -> EFI_STATUS,
+        pub ReadBlocks: extern "win64" fn(This: *const EFI_BLOCK_IO_PROTOCOL, MediaId: UINT32, Lba: UINT64, BufferSize: UINTN, Buffer: *mut VOID) -> EFI_STATUS,
+        pub WriteBlocks: extern "win64" fn(This: *const EFI_BLOCK_IO_PROTOCOL, MediaId: UINT32, Lba: UINT64, BufferSize: UINTN, Buffer: *const VOID) -> EFI_STATUS,
+        pub FlushBlocks: extern "win64" fn(This: *const EFI_BLOCK_IO_PROTOCOL) -> EFI_STATUS,
+    }
+}
+
+use self::raw::{EFI_BLOCK_IO_PROTOCOL, EFI_BLOCK_IO_PROTOCOL_GUID};
+
+/// A block device such as a whole disk or a partition on it.
+///
+/// Implements `io::Read`, `io::Write` and `io::Seek` in terms of byte offsets,
+/// so it can be handed to the partition table parsers in this module
+/// (and anything else that works over those traits).
+pub struct BlockIo {
+    handle: EFI_HANDLE,
+    protocol: *const EFI_BLOCK_IO_PROTOCOL,
+    pos: u64,
+}
+
+impl BlockIo {
+    // TODO: this should return an iterator instead to avoid allocations
+    pub fn get_all() -> Result<Vec<BlockIo>> {
+        let handles = locate_handles(&EFI_BLOCK_IO_PROTOCOL_GUID)?;
+        let devices = handles.iter().filter_map(|h| Self::open_on(*h).ok()).collect();
+        Ok(devices)
+    }
+
+    pub fn open_on(handle: EFI_HANDLE) -> Result<Self> {
+        let bs = (*system_table()).BootServices;
+        let protocol: *const EFI_BLOCK_IO_PROTOCOL = ptr::null();
+        unsafe {
+            ret_on_err!(((*bs).OpenProtocol)(handle, &EFI_BLOCK_IO_PROTOCOL_GUID, mem::transmute(&protocol), image_handle(), ptr::null(), EFI_OPEN_PROTOCOL_BY_HANDLE_PROTOCOL));
+        }
+
+        if protocol.is_null() {
+            return Err(EfiErrorKind::NotFound.into());
+        }
+
+        Ok(Self { handle, protocol, pos: 0 })
+    }
+
+    pub fn handle(&self) -> EFI_HANDLE {
+        self.handle
+    }
+
+    fn media(&self) -> &raw::EFI_BLOCK_IO_MEDIA {
+        unsafe { &*(*self.protocol).Media } // Media is re-read on every call because firmware updates it in place when media changes
+    }
+
+    pub fn media_id(&self) -> u32 {
+        self.media().MediaId
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.media().BlockSize
+    }
+
+    pub fn last_block(&self) -> u64 {
+        self.media().LastBlock
+    }
+
+    /// Size of the device in bytes
+    pub fn size(&self) -> u64 {
+        (self.last_block() + 1) * self.block_size() as u64
+    }
+
+    pub fn is_removable(&self) -> bool {
+        crate::from_boolean(self.media().RemovableMedia)
+    }
+
+    pub fn is_media_present(&self) -> bool {
+        crate::from_boolean(self.media().MediaPresent)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        crate::from_boolean(self.media().ReadOnly)
+    }
+
+    /// True if this device is a partition on some disk rather than the whole disk
+    pub fn is_logical_partition(&self) -> bool {
+        crate::from_boolean(self.media().LogicalPartition)
+    }
+
+    /// Reads whole blocks starting at `lba`. The length of `buf` must be a multiple of the block size.
+    // TODO: We ignore IoAlign. Our allocator only guarantees 8 byte alignment so devices demanding more will fail here.
+    pub fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        unsafe {
+            ret_on_err!(((*self.protocol).ReadBlocks)(self.protocol, self.media_id(), lba, buf.len(), buf.as_mut_ptr() as *mut VOID));
+        }
+
+        Ok(())
+    }
+
+    /// Writes whole blocks starting at `lba`. The length of `buf` must be a multiple of the block size.
+    pub fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        unsafe {
+            ret_on_err!(((*self.protocol).WriteBlocks)(self.protocol, self.media_id(), lba, buf.len(), buf.as_ptr() as *const VOID));
+        }
+
+        Ok(())
+    }
+
+    pub fn flush_blocks(&mut self) -> Result<()> {
+        unsafe {
+            ret_on_err!(((*self.protocol).FlushBlocks)(self.protocol));
+        }
+
+        Ok(())
+    }
+}
+
+fn to_io_err(_: crate::EfiError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Block I/O operation failed") // TODO: Don't swallow EFI status like this
+}
+
+impl Read for BlockIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_size = self.block_size() as u64;
+        let lba = self.pos / block_size;
+        let offset = (self.pos % block_size) as usize;
+        let remaining = cmp::min(buf.len() as u64, size - self.pos) as usize;
+
+        let bytes_read = if offset == 0 && remaining >= block_size as usize {
+            // Aligned. Read as many whole blocks as we can straight into the caller's buffer
+            let len = remaining - (remaining % block_size as usize);
+            self.read_blocks(lba, &mut buf[..len]).map_err(to_io_err)?;
+            len
+        } else {
+            // Unaligned. Go through a bounce buffer for one block
+            let mut block = vec![0u8; block_size as usize];
+            self.read_blocks(lba, &mut block).map_err(to_io_err)?;
+            let len = cmp::min(remaining, block.len() - offset);
+            buf[..len].copy_from_slice(&block[offset..offset + len]);
+            len
+        };
+
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl Write for BlockIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.size();
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos >= size {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        let block_size = self.block_size() as u64;
+        let lba = self.pos / block_size;
+        let offset = (self.pos % block_size) as usize;
+        let remaining = cmp::min(buf.len() as u64, size - self.pos) as usize;
+
+        let bytes_written = if offset == 0 && remaining >= block_size as usize {
+            let len = remaining - (remaining % block_size as usize);
+            self.write_blocks(lba, &buf[..len]).map_err(to_io_err)?;
+            len
+        } else {
+            // Partial block. Read-modify-write
+            let mut block = vec![0u8; block_size as usize];
+            self.read_blocks(lba, &mut block).map_err(to_io_err)?;
+            let len = cmp::min(remaining, block.len() - offset);
+            block[offset..offset + len].copy_from_slice(&buf[..len]);
+            self.write_blocks(lba, &block).map_err(to_io_err)?;
+            len
+        };
+
+        self.pos += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_blocks().map_err(to_io_err)
+    }
+}
+
+impl Seek for BlockIo {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            },
+            SeekFrom::End(n) => (self.size(), n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+// Helpers shared by the partition table parsers and the file systems
+
+pub (crate) fn read_at<D: Read + Seek>(disk: &mut D, offset: u64, buf: &mut [u8]) -> Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(buf)?;
+    Ok(())
+}
+
+pub (crate) fn write_at<D: Write + Seek>(disk: &mut D, offset: u64, buf: &[u8]) -> Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.write_all(buf)?;
+    Ok(())
+}
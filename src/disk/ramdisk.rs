@@ -0,0 +1,145 @@
+//! RAM disks registered with the firmware through `EFI_RAM_DISK_PROTOCOL`.
+//!
+//! Once registered the firmware creates a block device for the buffer and connects its own
+//! drivers (partition, FAT, ISO9660 etc.) to it. The returned device path can then be used
+//! with `image::load_image_from_path` like that of any other device.
+
+use crate::{
+    Result,
+    EfiErrorKind,
+    system_table,
+    device_path::DevicePath,
+};
+use ffi::{
+    EFI_GUID,
+    EFI_STATUS,
+    UINT64,
+    device_path::EFI_DEVICE_PATH_PROTOCOL,
+};
+use core::{ptr, mem};
+use alloc::vec::Vec;
+
+// efi_ffi doesn't define the RAM disk protocol yet. So we carry our own definitions here.
+#[allow(non_snake_case)]
+pub (crate) mod raw {
+    use super::*;
+
+    pub const EFI_RAM_DISK_PROTOCOL_GUID: EFI_GUID = EFI_GUID(0xAB38A0DF, 0x6873, 0x44A9, [0x87, 0xE6, 0xD4, 0xEB, 0x56, 0x14, 0x84, 0x49]);
+
+    pub const EFI_VIRTUAL_DISK_GUID: EFI_GUID = EFI_GUID(0x77AB535A, 0x45FC, 0x624B, [0x55, 0x60, 0xF7, 0xB2, 0x81, 0xD1, 0xF9, 0x6E]);
+    pub const EFI_VIRTUAL_CD_GUID: EFI_GUID = EFI_GUID(0x3D5ABD30, 0x4175, 0x87CE, [0x6D, 0x64, 0xD2, 0xAD, 0xE5, 0x23, 0xC4, 0xBB]);
+    pub const EFI_PERSISTENT_VIRTUAL_DISK_GUID: EFI_GUID = EFI_GUID(0x5CEA02C9, 0x4D07, 0x69D3, [0x26, 0x9F, 0x44, 0x96, 0xFB, 0xE0, 0x96, 0xF9]);
+    pub const EFI_PERSISTENT_VIRTUAL_CD_GUID: EFI_GUID = EFI_GUID(0x08018188, 0x42CD, 0xBB48, [0x10, 0x0F, 0x53, 0x87, 0xD5, 0x3D, 0xED, 0x3D]);
+
+    #[repr(C)]
+    pub struct EFI_RAM_DISK_PROTOCOL {
+        pub Register: extern "win64" fn(RamDiskBase: UINT64, RamDiskSize: UINT64, RamDiskType: *const EFI_GUID, ParentDevicePath: *const EFI_DEVICE_PATH_PROTOCOL, DevicePath: *mut *const EFI_DEVICE_PATH_PROTOCOL) -> EFI_STATUS,
+        pub Unregister: extern "win64" fn(DevicePath: *const EFI_DEVICE_PATH_PROTOCOL) -> EFI_STATUS,
+    }
+}
+
+use self::raw::{EFI_RAM_DISK_PROTOCOL, EFI_RAM_DISK_PROTOCOL_GUID};
+
+/// How the firmware should present a RAM disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamDiskType {
+    /// A hard disk image (raw, MBR or GPT)
+    VirtualDisk,
+    /// A CD/DVD image such as an ISO
+    VirtualCd,
+    /// Like `VirtualDisk` but also described to the OS through the ACPI NFIT table
+    PersistentVirtualDisk,
+    /// Like `VirtualCd` but also described to the OS through the ACPI NFIT table
+    PersistentVirtualCd,
+}
+
+impl RamDiskType {
+    fn guid(self) -> &'static EFI_GUID {
+        match self {
+            RamDiskType::VirtualDisk => &raw::EFI_VIRTUAL_DISK_GUID,
+            RamDiskType::VirtualCd => &raw::EFI_VIRTUAL_CD_GUID,
+            RamDiskType::PersistentVirtualDisk => &raw::EFI_PERSISTENT_VIRTUAL_DISK_GUID,
+            RamDiskType::PersistentVirtualCd => &raw::EFI_PERSISTENT_VIRTUAL_CD_GUID,
+        }
+    }
+}
+
+/// A buffer registered with the firmware as a RAM disk.
+///
+/// Owns the buffer for as long as it is registered. Dropping it unregisters the disk.
+pub struct RamDisk {
+    buffer: Vec<u8>,
+    disk_type: RamDiskType,
+    device_path: DevicePath,
+    protocol: *const EFI_RAM_DISK_PROTOCOL,
+}
+
+impl RamDisk {
+    /// Registers `buffer` as a RAM disk of the given type
+    pub fn register(buffer: Vec<u8>, disk_type: RamDiskType) -> Result<Self> {
+        if buffer.is_empty() {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let protocol = ram_disk_protocol()?;
+        let mut path: *const EFI_DEVICE_PATH_PROTOCOL = ptr::null();
+        unsafe {
+            ret_on_err!(((*protocol).Register)(buffer.as_ptr() as UINT64, buffer.len() as UINT64, disk_type.guid(), ptr::null(), &mut path));
+        }
+
+        let device_path = DevicePath::from_ptr(path)?;
+        Ok(Self { buffer, disk_type, device_path, protocol })
+    }
+
+    /// The device path of the block device the firmware created for this disk
+    pub fn device_path(&self) -> &DevicePath {
+        &self.device_path
+    }
+
+    pub fn disk_type(&self) -> RamDiskType {
+        self.disk_type
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Unregisters the disk and hands the buffer back
+    pub fn unregister(mut self) -> Result<Vec<u8>> {
+        self.unregister_inner()?;
+        let buffer = mem::take(&mut self.buffer);
+        mem::forget(self); // Already unregistered. Nothing left for drop to do.
+        Ok(buffer)
+    }
+
+    fn unregister_inner(&mut self) -> Result<()> {
+        unsafe {
+            ret_on_err!(((*self.protocol).Unregister)(self.device_path.as_ptr()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        if self.unregister_inner().is_err() {
+            // The firmware may still be using the buffer so we must not free it
+            mem::forget(mem::take(&mut self.buffer));
+        }
+    }
+}
+
+fn ram_disk_protocol() -> Result<*const EFI_RAM_DISK_PROTOCOL> {
+    let bs = (*system_table()).BootServices;
+
+    let protocol: *const EFI_RAM_DISK_PROTOCOL = ptr::null();
+    unsafe {
+        ret_on_err!(((*bs).LocateProtocol)(&EFI_RAM_DISK_PROTOCOL_GUID, ptr::null(), mem::transmute(&protocol)));
+    }
+
+    if protocol.is_null() {
+        return Err(EfiErrorKind::Unsupported.into()); // Firmware without RamDiskDxe
+    }
+
+    Ok(protocol)
+}
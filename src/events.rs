@@ -1,5 +1,10 @@
 use ffi::{
+    EFI_GUID,
+    EFI_HANDLE,
+    UINT32,
     UINT64,
+    VOID,
+    EFI_STATUS,
     EFI_SUCCESS,
     EFI_NOT_READY,
     EFI_EVENT,
@@ -12,11 +17,13 @@ use ffi::{
         TPL_NOTIFY,
         // TPL_HIGH_LEVEL,
         EFI_TIMER_DELAY,
+        EFI_EVENT_NOTIFY,
     },
 };
 
-use core::{ptr, time::Duration};
-use crate::{system_table, Result};
+use core::{ptr, mem, time::Duration};
+use alloc::{boxed::Box, vec::Vec};
+use crate::{system_table, Guid, Result, EfiErrorKind, boot_services::locate_next_notified_handle};
 
 pub trait Signal {
     fn signal(&mut self) -> Result<()>;
@@ -37,6 +44,7 @@ pub enum NotifyType {
     Signal = EVT_NOTIFY_SIGNAL,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(usize)]
 pub enum EventTpl {
     Callback = TPL_CALLBACK,
@@ -57,145 +65,312 @@ pub enum EventTpl {
 //      on an event if EVT_TIMER attribute is not present.
 
 
-// TODO: Disabled until we figured out a better design. Enable them back
-// extern "win64" fn common_notify_func<F: FnMut()>(_event: EFI_EVENT, context: *const VOID) -> EFI_STATUS {
-//     if !context.is_null() {
-//         let closure: *mut F = unsafe { mem::transmute(context) }; // Safe to make this transmute because we know this is the pointer to the closure
-//         unsafe { (*closure)(); }
-//     }
-//     EFI_SUCCESS
-// }
-
-// struct Event<F: FnMut()>
-// {
-//     inner: EFI_EVENT,
-//     _notify_func: Option<Box<F>>, // On heap 'cause we pass its underlying raw ptr to notify func as context. Hence needs to be stable ptr.
-// }
-
-// impl<F: FnMut()> Event<F> {
-//     fn create<N: Into<Option<F>>>(notify_flags: UINT32, tpl: EventTpl, notify_func: N) -> Result<Self> {
-//         let bs = system_table().BootServices;
-//         let notify_func: Option<F> = notify_func.into();
-
-//         let raw_func_ptr =  if let Some(notify_func) = notify_func {
-//             let boxed_notify_func = Box::new(notify_func);
-//             Box::into_raw(boxed_notify_func)
-//         } else {
-//             ptr::null_mut()
-//         };
-
-//         let mut event: EFI_EVENT = ptr::null();
-//         unsafe {
-//             ret_on_err!(((*bs).CreateEvent)(notify_flags, tpl as EFI_TPL, common_notify_func::<F>, raw_func_ptr as *const VOID, &mut event));
-//         }
-
-//         let notify_func_opt = if !raw_func_ptr.is_null() {
-//             Some(unsafe { Box::from_raw(raw_func_ptr) })
-//         } else {
-//             None
-//         };
-
-//         Ok(Self { inner: event, _notify_func: notify_func_opt })
-//     }
-
-//     fn wait(&self) -> Result<()> {
-//         let bs = system_table().BootServices;
-//         unsafe {
-//             let mut signaled_index = 0;
-//             ret_on_err!(((*bs).WaitForEvent)(1, &self.inner, &mut signaled_index));
-//         }
-
-//         Ok(())
-//     }
-
-//     fn signal(&mut self) -> Result<()> {
-//         let bs = system_table().BootServices;
-//         unsafe { ret_on_err!(((*bs).SignalEvent)(self.inner)); }
-//         Ok(())
-//     }
-
-//     fn is_signaled(&self) ->  Result<bool> {
-//         let bs = system_table().BootServices;
-//         let status = unsafe { ((*bs).CheckEvent)(self.inner) };
-//         match status {
-//             EFI_SUCCESS => Ok(true),
-//             EFI_NOT_READY=> Ok(false),
-//             s => Err(s.into())
-//         }
-//     }
-
-//     #[inline]
-//     fn as_raw(&self) -> EFI_EVENT {
-//         self.inner
-//     }
-// }
-
-// impl<F: FnMut()> Drop for Event<F> {
-//     fn drop(&mut self) {
-//         let bs = system_table().BootServices;
-//         unsafe {
-//             ((*bs).CloseEvent)(self.inner); // Can't do a fucking thing if it returns failure
-//         }
-//     }
-// }
-
-// pub struct NotifySignalEvent<F: FnMut()>(Event<F>);
-
-// impl<F: FnMut()> NotifySignalEvent<F> {
-//     pub fn create<N: Into<Option<F>>>(tpl: EventTpl, notify_func: N) -> Result<Self> {
-//         let inner = Event::create(NotifyType::Signal as UINT32, tpl, notify_func)?;
-//         Ok(NotifySignalEvent(inner))
-//     }
-// }
-
-// impl<F: FnMut()> Signal for NotifySignalEvent<F> {
-//     #[inline]
-//     fn signal(&mut self) -> Result<()> {
-//         self.0.signal()
-//     }
-// }
-
-// impl<F: FnMut()> AsRawEvt for NotifySignalEvent<F> {
-//     #[inline]
-//     unsafe fn as_raw(&self) -> EFI_EVENT {
-//         self.0.as_raw()
-//     }
-// }
-
-// pub struct NotifyWaitEvent<F: FnMut()>(Event<F>);
-
-// impl<F: FnMut()> NotifyWaitEvent<F> {
-//     pub fn create<N: Into<Option<F>>>(tpl: EventTpl, notify_func: N) -> Result<Self> {
-//         let inner = Event::create(NotifyType::Wait as UINT32, tpl, notify_func)?;
-//         Ok(NotifyWaitEvent(inner))
-//     }
-// }
-
-// impl<F: FnMut()> Signal for NotifyWaitEvent<F> {
-//     #[inline]
-//     fn signal(&mut self) -> Result<()> {
-//         self.0.signal()
-//     }
-// }
-
-// impl<F: FnMut()> Wait for NotifyWaitEvent<F> {
-//     #[inline]
-//     fn wait(&self) -> Result<()> {
-//         self.0.wait()
-//     }
-
-//     #[inline]
-//     fn is_signaled(&self) ->  Result<bool> {
-//         self.0.is_signaled()
-//     }
-// }
-
-// impl<F: FnMut()> AsRawEvt for NotifyWaitEvent<F> {
-//     #[inline]
-//     unsafe fn as_raw(&self) -> EFI_EVENT {
-//         self.0.as_raw()
-//     }
-// }
+// efi_ffi doesn't define the signature of CreateEventEx yet
+type EfiCreateEventEx = extern "win64" fn(
+    event_type: UINT32,
+    notify_tpl: EFI_TPL,
+    notify_function: Option<EFI_EVENT_NOTIFY>,
+    notify_context: *const VOID,
+    event_group: *const EFI_GUID,
+    event: *mut EFI_EVENT
+) -> EFI_STATUS;
+
+/// Signaled when `ExitBootServices` is called. Notify functions must not allocate memory or use
+/// timers and events since the memory map is about to be handed over to the OS.
+pub const EVENT_GROUP_EXIT_BOOT_SERVICES: Guid = EFI_GUID(0x27ABF055, 0xB1B8, 0x4C26, [0x80, 0x48, 0x74, 0x8F, 0x37, 0xBA, 0xA2, 0xDF]);
+/// Signaled when the OS calls `SetVirtualAddressMap`
+pub const EVENT_GROUP_VIRTUAL_ADDRESS_CHANGE: Guid = EFI_GUID(0x13FA7698, 0xC831, 0x49C7, [0x87, 0xEA, 0x8F, 0x43, 0xFC, 0xC2, 0x51, 0x96]);
+/// Signaled whenever the memory map changes
+pub const EVENT_GROUP_MEMORY_MAP_CHANGE: Guid = EFI_GUID(0x78BEE926, 0x692F, 0x48FD, [0x9E, 0xDB, 0x01, 0x42, 0x2E, 0xF0, 0xD7, 0xAB]);
+/// Signaled by the boot manager right before it boots a boot option
+pub const EVENT_GROUP_READY_TO_BOOT: Guid = EFI_GUID(0x7CE88FB3, 0x4BD7, 0x4679, [0x87, 0xA8, 0xA8, 0xD8, 0xDE, 0xE5, 0x0D, 0x2B]);
+
+extern "win64" fn empty_notify_func(_event: EFI_EVENT, _context: *const VOID) -> EFI_STATUS {
+    EFI_SUCCESS
+}
+
+extern "win64" fn common_notify_func<F: FnMut()>(_event: EFI_EVENT, context: *const VOID) -> EFI_STATUS {
+    if !context.is_null() {
+        let closure = context as *mut F; // Safe to make this cast because we know this is the pointer to the boxed closure
+        unsafe { (*closure)(); }
+    }
+    EFI_SUCCESS
+}
+
+/// An event with a closure as its notify function.
+///
+/// The closure is boxed so that the pointer handed to the firmware as the notify context stays put.
+/// The firmware calls it at the event's TPL, so it must not block or raise the TPL any further.
+/// Since it interrupts whatever code was running at a lower TPL it has to be `Send`, the same as
+/// if it ran on another thread.
+struct Event<F: FnMut() + Send + 'static> {
+    inner: EFI_EVENT,
+    _notify_func: Box<F>,
+}
+
+impl<F: FnMut() + Send + 'static> Event<F> {
+    fn create(event_type: UINT32, tpl: EventTpl, notify_func: F) -> Result<Self> {
+        Self::create_in_group(event_type, tpl, notify_func, None)
+    }
+
+    fn create_in_group(event_type: UINT32, tpl: EventTpl, notify_func: F, group: Option<&Guid>) -> Result<Self> {
+        let bs = system_table().BootServices;
+        let notify_func = Box::into_raw(Box::new(notify_func));
+
+        let mut event: EFI_EVENT = ptr::null();
+        let status = unsafe {
+            match group {
+                Some(group) => {
+                    let create_event_ex: EfiCreateEventEx = mem::transmute((*bs).CreateEventEx);
+                    create_event_ex(event_type, tpl as EFI_TPL, Some(common_notify_func::<F>), notify_func as *const VOID, group, &mut event)
+                },
+                None => ((*bs).CreateEvent)(event_type, tpl as EFI_TPL, Some(common_notify_func::<F>), notify_func as *const VOID, &mut event),
+            }
+        };
+        let notify_func = unsafe { Box::from_raw(notify_func) }; // Take ownership back whether or not the event got created
+        ret_on_err!(status);
+
+        Ok(Self { inner: event, _notify_func: notify_func })
+    }
+
+    fn wait(&self) -> Result<()> {
+        wait(self.inner)
+    }
+
+    fn signal(&mut self) -> Result<()> {
+        let bs = system_table().BootServices;
+        unsafe { ret_on_err!(((*bs).SignalEvent)(self.inner)); }
+        Ok(())
+    }
+
+    fn is_signaled(&self) ->  Result<bool> {
+        is_signaled(self.inner)
+    }
+
+    #[inline]
+    fn as_raw(&self) -> EFI_EVENT {
+        self.inner
+    }
+}
+
+impl<F: FnMut() + Send + 'static> Drop for Event<F> {
+    fn drop(&mut self) {
+        // Closing the event first guarantees the firmware won't call the closure after it is freed below
+        let bs = system_table().BootServices;
+        unsafe {
+            ((*bs).CloseEvent)(self.inner); // Can't do a fucking thing if it returns failure
+        }
+    }
+}
+
+/// An event whose closure runs once each time the event is signaled
+pub struct NotifySignalEvent<F: FnMut() + Send + 'static>(Event<F>);
+
+impl<F: FnMut() + Send + 'static> NotifySignalEvent<F> {
+    pub fn create(tpl: EventTpl, notify_func: F) -> Result<Self> {
+        let inner = Event::create(NotifyType::Signal as UINT32, tpl, notify_func)?;
+        Ok(NotifySignalEvent(inner))
+    }
+}
+
+impl<F: FnMut() + Send + 'static> Signal for NotifySignalEvent<F> {
+    #[inline]
+    fn signal(&mut self) -> Result<()> {
+        self.0.signal()
+    }
+}
+
+impl<F: FnMut() + Send + 'static> AsRawEvt for NotifySignalEvent<F> {
+    #[inline]
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0.as_raw()
+    }
+}
+
+/// An event whose closure runs every time the event is waited on or checked until it gets signaled
+pub struct NotifyWaitEvent<F: FnMut() + Send + 'static>(Event<F>);
+
+impl<F: FnMut() + Send + 'static> NotifyWaitEvent<F> {
+    pub fn create(tpl: EventTpl, notify_func: F) -> Result<Self> {
+        let inner = Event::create(NotifyType::Wait as UINT32, tpl, notify_func)?;
+        Ok(NotifyWaitEvent(inner))
+    }
+}
+
+impl<F: FnMut() + Send + 'static> Signal for NotifyWaitEvent<F> {
+    #[inline]
+    fn signal(&mut self) -> Result<()> {
+        self.0.signal()
+    }
+}
+
+impl<F: FnMut() + Send + 'static> Wait for NotifyWaitEvent<F> {
+    #[inline]
+    fn wait(&self) -> Result<()> {
+        self.0.wait()
+    }
+
+    #[inline]
+    fn is_signaled(&self) ->  Result<bool> {
+        self.0.is_signaled()
+    }
+}
+
+impl<F: FnMut() + Send + 'static> AsRawEvt for NotifyWaitEvent<F> {
+    #[inline]
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0.as_raw()
+    }
+}
+
+/// A member of an event group. Its closure runs whenever any member of the group is signaled,
+/// including by the firmware for the well-known `EVENT_GROUP_*` groups.
+///
+/// ```ignore
+/// let _cleanup = GroupEvent::create(&EVENT_GROUP_READY_TO_BOOT, EventTpl::Callback, || flush_logs())?;
+/// ```
+pub struct GroupEvent<F: FnMut() + Send + 'static>(Event<F>);
+
+impl<F: FnMut() + Send + 'static> GroupEvent<F> {
+    pub fn create(group: &Guid, tpl: EventTpl, notify_func: F) -> Result<Self> {
+        let inner = Event::create_in_group(NotifyType::Signal as UINT32, tpl, notify_func, Some(group))?;
+        Ok(GroupEvent(inner))
+    }
+}
+
+impl<F: FnMut() + Send + 'static> Signal for GroupEvent<F> {
+    /// Signals every event in the group
+    #[inline]
+    fn signal(&mut self) -> Result<()> {
+        self.0.signal()
+    }
+}
+
+impl<F: FnMut() + Send + 'static> AsRawEvt for GroupEvent<F> {
+    #[inline]
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0.as_raw()
+    }
+}
+
+/// Signals all events in `group` without having to be a member.
+///
+/// Meant for custom groups. Don't signal the well-known `EVENT_GROUP_*` groups,
+/// that's the firmware's job.
+pub fn signal_group(group: &Guid) -> Result<()> {
+    let bs = system_table().BootServices;
+    let mut event: EFI_EVENT = ptr::null();
+    unsafe {
+        // The spec's way of signaling a group is to signal a member so we join it briefly
+        let create_event_ex: EfiCreateEventEx = mem::transmute((*bs).CreateEventEx);
+        ret_on_err!(create_event_ex(EVT_NOTIFY_SIGNAL, TPL_CALLBACK, Some(empty_notify_func), ptr::null(), group, &mut event));
+        let status = ((*bs).SignalEvent)(event);
+        ((*bs).CloseEvent)(event);
+        ret_on_err!(status);
+    }
+
+    Ok(())
+}
+
+// efi_ffi doesn't define the signature of RegisterProtocolNotify yet
+type EfiRegisterProtocolNotify = extern "win64" fn(protocol: *const EFI_GUID, event: EFI_EVENT, registration: *mut *const VOID) -> EFI_STATUS;
+
+/// Hands out handles on which a protocol gets installed from now on, e.g. NICs or removable media
+/// that show up after the application started. Handles that already had the protocol are not
+/// included, so look those up separately.
+///
+/// Iterating blocks until the next handle appears. For a non-blocking check use `try_next`,
+/// or wait on the event alongside others with `wait_any`.
+///
+/// ```ignore
+/// for handle in ProtocolNotify::register(&EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID)? {
+///     mount(handle?)?;
+/// }
+/// ```
+pub struct ProtocolNotify {
+    event: EFI_EVENT,
+    registration: *const VOID,
+}
+
+impl ProtocolNotify {
+    pub fn register(protocol: &Guid) -> Result<Self> {
+        let bs = system_table().BootServices;
+        let mut event: EFI_EVENT = ptr::null();
+        unsafe {
+            ret_on_err!(((*bs).CreateEvent)(0, TPL_CALLBACK, None, ptr::null(), &mut event));
+        }
+
+        let mut notify = ProtocolNotify { event, registration: ptr::null() }; // Closes the event on drop if registration fails
+        unsafe {
+            let register: EfiRegisterProtocolNotify = mem::transmute((*bs).RegisterProtocolNotify);
+            ret_on_err!(register(protocol, notify.event, &mut notify.registration));
+        }
+
+        Ok(notify)
+    }
+
+    /// The next new handle or `None` if there isn't one at the moment
+    pub fn try_next(&mut self) -> Result<Option<EFI_HANDLE>> {
+        locate_next_notified_handle(self.registration)
+    }
+
+    /// Blocks till there's a new handle
+    pub fn next_handle(&mut self) -> Result<EFI_HANDLE> {
+        loop {
+            if let Some(handle) = self.try_next()? {
+                return Ok(handle);
+            }
+            self.wait()?;
+        }
+    }
+
+    /// Same as `next_handle` but lets other tasks run in the meantime
+    #[cfg(feature = "task")]
+    pub async fn next_handle_async(&mut self) -> Result<EFI_HANDLE> {
+        loop {
+            if let Some(handle) = self.try_next()? {
+                return Ok(handle);
+            }
+            crate::task::wait(self).await?;
+        }
+    }
+}
+
+impl Iterator for ProtocolNotify {
+    type Item = Result<EFI_HANDLE>;
+
+    /// Never returns `None`. Iteration only ends when the caller stops or on an error.
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_handle())
+    }
+}
+
+impl Wait for ProtocolNotify {
+    fn wait(&self) -> Result<()> {
+        wait(self.event)
+    }
+
+    fn is_signaled(&self) ->  Result<bool> {
+        is_signaled(self.event)
+    }
+}
+
+impl AsRawEvt for ProtocolNotify {
+    #[inline]
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.event
+    }
+}
+
+impl Drop for ProtocolNotify {
+    fn drop(&mut self) {
+        // Closing the event also cancels the registration
+        let bs = system_table().BootServices;
+        unsafe {
+            ((*bs).CloseEvent)(self.event); // Can't do a fucking thing if it returns failure
+        }
+    }
+}
 
 pub enum TimerSchedule {
     Relative,
@@ -235,21 +410,11 @@ impl Timer {
     }
 
     pub fn set(&mut self, interval: Duration, schedule: TimerSchedule) -> Result<()> {
-        let bs = system_table().BootServices;
-        unsafe {
-            ret_on_err!(((*bs).SetTimer)(self.0, schedule.as_raw(), as_100ns_units(&interval)));
-        }
-
-        Ok(())
+        set_timer(self.0, interval, schedule)
     }
 
     pub fn cancel(&mut self) -> Result<()> {
-        let bs = system_table().BootServices;
-        unsafe {
-            ret_on_err!(((*bs).SetTimer)(self.0, EFI_TIMER_DELAY::TimerCancel, 0));
-        }
-        
-        Ok(())
+        cancel_timer(self.0)
     }
 
 }
@@ -265,23 +430,11 @@ impl Drop for Timer {
 
 impl Wait for Timer {
     fn wait(&self) -> Result<()> {
-        let bs = system_table().BootServices;
-        unsafe {
-            let mut signaled_index = 0;
-            ret_on_err!(((*bs).WaitForEvent)(1, &self.0, &mut signaled_index));
-        }
-
-        Ok(())
+        wait(self.0)
     }
 
     fn is_signaled(&self) ->  Result<bool> {
-        let bs = system_table().BootServices;
-        let status = unsafe { ((*bs).CheckEvent)(self.0) };
-        match status {
-            EFI_SUCCESS => Ok(true),
-            EFI_NOT_READY=> Ok(false),
-            s => Err(s.into())
-        }
+        is_signaled(self.0)
     }
 }
 
@@ -293,70 +446,141 @@ impl AsRawEvt for Timer {
 }
 
 
-// TODO: Disabled until we figured out a better design. Enable them back
-// pub struct NotifySignalTimer<F: FnMut()>(Timer<F>);
+/// A timer whose closure runs each time it fires. With `TimerSchedule::Periodic` that's on every tick.
+pub struct NotifySignalTimer<F: FnMut() + Send + 'static>(Event<F>);
 
-// impl<F: FnMut()> NotifySignalTimer<F> {
-//     pub fn create<N: Into<Option<F>>>(tpl: EventTpl, notify_func: N) -> Result<Self> {
-//         let inner = Timer::create(NotifyType::Signal, tpl, notify_func)?;
-//         Ok(NotifySignalTimer(inner))
-//     }
+impl<F: FnMut() + Send + 'static> NotifySignalTimer<F> {
+    /// Creates the timer inactive. Call `set` to start it.
+    pub fn create(tpl: EventTpl, notify_func: F) -> Result<Self> {
+        let inner = Event::create(EVT_TIMER | NotifyType::Signal as UINT32, tpl, notify_func)?;
+        Ok(NotifySignalTimer(inner))
+    }
 
-//     #[inline]
-//     pub fn set(&mut self, interval: Duration, schedule: TimerSchedule) -> Result<()> {
-//         self.0.set(interval, schedule)
-//     }
+    #[inline]
+    pub fn set(&mut self, interval: Duration, schedule: TimerSchedule) -> Result<()> {
+        set_timer(self.0.as_raw(), interval, schedule)
+    }
 
-//     #[inline]
-//     pub fn cancel(&mut self) -> Result<()> {
-//         self.0.cancel()
-//     }
-// }
+    #[inline]
+    pub fn cancel(&mut self) -> Result<()> {
+        cancel_timer(self.0.as_raw())
+    }
+}
 
-// impl<F: FnMut()> AsRawEvt for NotifySignalTimer<F> {
-//     #[inline]
-//     unsafe fn as_raw(&self) -> EFI_EVENT {
-//         self.0.as_raw()
-//     }
-// }
+impl<F: FnMut() + Send + 'static> AsRawEvt for NotifySignalTimer<F> {
+    #[inline]
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0.as_raw()
+    }
+}
 
-// pub struct NotifyWaitTimer<F: FnMut()>(Timer<F>);
+/// A timer whose closure runs whenever it is waited on or checked before it has fired
+pub struct NotifyWaitTimer<F: FnMut() + Send + 'static>(Event<F>);
 
-// impl<F: FnMut()> NotifyWaitTimer<F> {
-//     pub fn create<N: Into<Option<F>>>(tpl: EventTpl, notify_func: N) -> Result<Self> {
-//         let inner = Timer::create(NotifyType::Wait, tpl, notify_func)?;
-//         Ok(NotifyWaitTimer(inner))
-//     }
+impl<F: FnMut() + Send + 'static> NotifyWaitTimer<F> {
+    /// Creates the timer inactive. Call `set` to start it.
+    pub fn create(tpl: EventTpl, notify_func: F) -> Result<Self> {
+        let inner = Event::create(EVT_TIMER | NotifyType::Wait as UINT32, tpl, notify_func)?;
+        Ok(NotifyWaitTimer(inner))
+    }
 
-//     #[inline]
-//     pub fn set(&mut self, interval: Duration, schedule: TimerSchedule) -> Result<()> {
-//         self.0.set(interval, schedule)
-//     }
+    #[inline]
+    pub fn set(&mut self, interval: Duration, schedule: TimerSchedule) -> Result<()> {
+        set_timer(self.0.as_raw(), interval, schedule)
+    }
 
-//     #[inline]
-//     pub fn cancel(&mut self) -> Result<()> {
-//         self.0.cancel()
-//     }
-// }
+    #[inline]
+    pub fn cancel(&mut self) -> Result<()> {
+        cancel_timer(self.0.as_raw())
+    }
+}
 
-// impl<F: FnMut()> Wait for NotifyWaitTimer<F> {
-//     #[inline]
-//     fn wait(&self) -> Result<()> {
-//         self.0.wait()
-//     }
+impl<F: FnMut() + Send + 'static> Wait for NotifyWaitTimer<F> {
+    #[inline]
+    fn wait(&self) -> Result<()> {
+        self.0.wait()
+    }
     
-//     #[inline]
-//     fn is_signaled(&self) ->  Result<bool> {
-//         self.0.is_signaled()
-//     }
-// }
-
-// impl<F: FnMut()> AsRawEvt for NotifyWaitTimer<F> {
-//     #[inline]
-//     unsafe fn as_raw(&self) -> EFI_EVENT {
-//         self.0.as_raw()
-//     }
-// }
+    #[inline]
+    fn is_signaled(&self) ->  Result<bool> {
+        self.0.is_signaled()
+    }
+}
+
+impl<F: FnMut() + Send + 'static> AsRawEvt for NotifyWaitTimer<F> {
+    #[inline]
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0.as_raw()
+    }
+}
+
+/// Blocks until one of `events` is signaled or `timeout` expires, whichever comes first.
+///
+/// Returns the index into `events` of the event that was signaled or `None` on timeout.
+/// Like any wait this has to be called at `TPL_APPLICATION` and the events must not be `NotifyType::Signal` events.
+pub fn wait_any(events: &[&dyn AsRawEvt], timeout: Option<Duration>) -> Result<Option<usize>> {
+    if events.is_empty() && timeout.is_none() {
+        return Err(EfiErrorKind::InvalidParameter.into()); // Would wait forever
+    }
+
+    let mut raw_events = events.iter().map(|e| unsafe { e.as_raw() }).collect::<Vec<_>>();
+
+    // The timer is last so that indices of the caller's events are unaffected
+    let timer = timeout.map(|t| Timer::create(t, TimerSchedule::Relative, TimerState::Active, EventTpl::Callback)).transpose()?;
+    if let Some(ref timer) = timer {
+        raw_events.push(unsafe { timer.as_raw() });
+    }
+
+    let bs = system_table().BootServices;
+    let mut signaled_index = 0;
+    unsafe {
+        ret_on_err!(((*bs).WaitForEvent)(raw_events.len(), raw_events.as_ptr(), &mut signaled_index));
+    }
+
+    if signaled_index < events.len() {
+        Ok(Some(signaled_index))
+    } else {
+        Ok(None)
+    }
+}
+
+fn wait(event: EFI_EVENT) -> Result<()> {
+    let bs = system_table().BootServices;
+    unsafe {
+        let mut signaled_index = 0;
+        ret_on_err!(((*bs).WaitForEvent)(1, &event, &mut signaled_index));
+    }
+
+    Ok(())
+}
+
+fn is_signaled(event: EFI_EVENT) -> Result<bool> {
+    let bs = system_table().BootServices;
+    let status = unsafe { ((*bs).CheckEvent)(event) };
+    match status {
+        EFI_SUCCESS => Ok(true),
+        EFI_NOT_READY=> Ok(false),
+        s => Err(s.into())
+    }
+}
+
+fn set_timer(event: EFI_EVENT, interval: Duration, schedule: TimerSchedule) -> Result<()> {
+    let bs = system_table().BootServices;
+    unsafe {
+        ret_on_err!(((*bs).SetTimer)(event, schedule.as_raw(), as_100ns_units(&interval)));
+    }
+
+    Ok(())
+}
+
+fn cancel_timer(event: EFI_EVENT) -> Result<()> {
+    let bs = system_table().BootServices;
+    unsafe {
+        ret_on_err!(((*bs).SetTimer)(event, EFI_TIMER_DELAY::TimerCancel, 0));
+    }
+
+    Ok(())
+}
 
 fn as_100ns_units(dur: &Duration) -> UINT64 {
     const T_100NS_UNITS_IN_A_SEC: UINT64 = 10_000_000;
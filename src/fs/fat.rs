@@ -0,0 +1,1717 @@
+//! FAT12, FAT16 and FAT32 file systems with long file name support.
+//!
+//! Mount an existing volume (for instance one made by `mkfs.fat`) with `FileSystem::mount`
+//! or create a fresh one with `FileSystem::format`. Reading only needs the volume to
+//! implement `io::Read + io::Seek`. Modifying it needs `io::Write` as well.
+
+use crate::{
+    Result,
+    EfiError,
+    EfiErrorKind,
+    io::{self, Read, Write, Seek, SeekFrom},
+    disk::{read_at, write_at},
+};
+use core::cmp;
+use alloc::{vec::Vec, string::String};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+
+const DIR_ENTRY_SIZE: u64 = 32;
+const DELETED_ENTRY: u8 = 0xE5;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255; // In UTF-16 code units
+const MAX_SHORT_NAME_TAIL: u32 = 999; // Gives up on "~n" tails past this many names sharing a prefix
+
+// Case flags in the NTRes byte set by Windows and Linux for short names that are all lower case
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+const FAT32_MAX_CLUSTERS: u32 = 0x0FFF_FFF4;
+
+const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+const FS_INFO_STRUC_SIG: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIG: u32 = 0xAA55_0000;
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn eoc(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    // Any value from 0x?FF8 upwards marks the end of a chain
+    fn is_eoc(self, value: u32) -> bool {
+        value >= self.eoc() - 7
+    }
+
+    fn from_cluster_count(count: u32) -> Self {
+        if count <= FAT12_MAX_CLUSTERS {
+            FatType::Fat12
+        } else if count <= FAT16_MAX_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    fn label(self) -> &'static [u8; 8] {
+        match self {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        }
+    }
+}
+
+/// A date and time as stored in directory entries (two second resolution, no time zone)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        // The FAT epoch
+        Self { year: 1980, month: 1, day: 1, hour: 0, minute: 0, second: 0 }
+    }
+}
+
+impl Timestamp {
+    fn from_dos(date: u16, time: u16) -> Self {
+        Self {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+        }
+    }
+
+    fn to_dos(self) -> (u16, u16) {
+        let date = ((self.year.saturating_sub(1980) & 0x7F) << 9) | ((self.month as u16 & 0x0F) << 5) | (self.day as u16 & 0x1F);
+        let time = ((self.hour as u16 & 0x1F) << 11) | ((self.minute as u16 & 0x3F) << 5) | ((self.second as u16 / 2) & 0x1F);
+        (date, time)
+    }
+}
+
+/// Options for `FileSystem::format`
+pub struct FormatOptions {
+    /// The FAT type to use. When `None` it is picked based on the size of the volume.
+    pub fat_type: Option<FatType>,
+    /// Up to 11 characters. When `None` the volume is labeled "NO NAME".
+    pub volume_label: Option<String>,
+    pub volume_id: u32,
+    pub bytes_per_sector: u16,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { fat_type: None, volume_label: None, volume_id: 0, bytes_per_sector: 512 }
+    }
+}
+
+// Geometry of the volume derived from the BIOS parameter block
+struct Layout {
+    fat_type: FatType,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    num_fats: u64,
+    fat_offset: u64,
+    fat_size: u64, // In bytes
+    root_dir_offset: u64, // FAT12/16 only
+    root_dir_entries: u64, // FAT12/16 only
+    root_cluster: u32, // FAT32 only
+    fs_info_sector: u64, // FAT32 only
+    data_offset: u64,
+    cluster_count: u32,
+    volume_label: String,
+}
+
+impl Layout {
+    fn from_boot_sector(buf: &[u8]) -> Result<Self> {
+        if buf[510] != 0x55 || buf[511] != 0xAA {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let bytes_per_sector = le_u16(&buf[11..]) as u64;
+        let sectors_per_cluster = buf[13] as u64;
+        let reserved_sectors = le_u16(&buf[14..]) as u64;
+        let num_fats = buf[16] as u64;
+        let root_dir_entries = le_u16(&buf[17..]) as u64;
+        let total_sectors = match le_u16(&buf[19..]) {
+            0 => le_u32(&buf[32..]) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match le_u16(&buf[22..]) {
+            0 => le_u32(&buf[36..]) as u64,
+            n => n as u64,
+        };
+
+        let valid = [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && num_fats > 0
+            && fat_sectors > 0;
+        if !valid {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let root_dir_sectors = (root_dir_entries * DIR_ENTRY_SIZE).div_ceil(bytes_per_sector);
+        let data_start = reserved_sectors + num_fats * fat_sectors + root_dir_sectors;
+        if data_start >= total_sectors {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let cluster_count = ((total_sectors - data_start) / sectors_per_cluster) as u32;
+        let fat_type = FatType::from_cluster_count(cluster_count);
+        let (root_cluster, fs_info_sector, label_offset) = match fat_type {
+            FatType::Fat32 => (le_u32(&buf[44..]), le_u16(&buf[48..]) as u64, 71),
+            _ => (0, 0, 43),
+        };
+
+        if fat_type == FatType::Fat32 && (root_dir_entries != 0 || root_cluster < 2 || root_cluster > cluster_count + 1) {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        // The label is only meaningful if the extended boot signature is there
+        let ext_sig_offset = label_offset - 5;
+        let volume_label = if buf[ext_sig_offset] == 0x29 {
+            String::from_utf8_lossy(&buf[label_offset..label_offset + 11]).trim_end().into()
+        } else {
+            String::new()
+        };
+
+        Ok(Self {
+            fat_type,
+            bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            num_fats,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            root_dir_offset: (reserved_sectors + num_fats * fat_sectors) * bytes_per_sector,
+            root_dir_entries,
+            root_cluster,
+            fs_info_sector,
+            data_offset: data_start * bytes_per_sector,
+            cluster_count,
+            volume_label,
+        })
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster <= self.cluster_count + 1
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DirLoc {
+    FixedRoot, // The root directory of FAT12/16 volumes lives in its own region rather than in clusters
+    Chain(u32),
+}
+
+/// An entry in a directory
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    name: String,
+    short_name: String,
+    attributes: u8,
+    size: u32,
+    first_cluster: u32,
+    created: Timestamp,
+    modified: Timestamp,
+    slots: Vec<u64>, // Disk offsets of the long name entries followed by the short entry
+}
+
+impl DirEntry {
+    /// The long name if there is one, the short name otherwise
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The 8.3 name
+    pub fn short_name(&self) -> &str {
+        &self.short_name
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.attributes
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir()
+    }
+
+    /// Size in bytes. Always zero for directories.
+    pub fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    pub fn created(&self) -> Timestamp {
+        self.created
+    }
+
+    pub fn modified(&self) -> Timestamp {
+        self.modified
+    }
+
+    fn short_slot(&self) -> u64 {
+        self.slots[self.slots.len() - 1]
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// A mounted FAT volume
+pub struct FileSystem<D> {
+    disk: D,
+    layout: Layout,
+    fat_cache: (u64, Vec<u8>), // Sector number within the FAT and its contents. Avoids a disk read for every FAT entry.
+    next_free: u32,
+    fs_info_invalidated: bool,
+    now: Timestamp,
+}
+
+impl<D: Read + Seek> FileSystem<D> {
+    /// Mounts the FAT volume that starts at offset zero of `disk`
+    pub fn mount(mut disk: D) -> Result<Self> {
+        let mut boot_sector = [0u8; 512];
+        read_at(&mut disk, 0, &mut boot_sector)?;
+        let layout = Layout::from_boot_sector(&boot_sector)?;
+        let sector_size = layout.bytes_per_sector as usize;
+
+        Ok(Self {
+            disk,
+            layout,
+            fat_cache: (u64::MAX, vec![0u8; sector_size]),
+            next_free: 2,
+            fs_info_invalidated: false,
+            now: Timestamp::default(),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    /// The label from the boot sector
+    pub fn volume_label(&self) -> &str {
+        &self.layout.volume_label
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.layout.cluster_size
+    }
+
+    /// Gives back the underlying disk
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Lists the entries of the directory at `path` excluding "." and ".."
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+        let dir = self.resolve_dir(&split_path(path)?)?;
+        self.list_dir(dir)
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<DirEntry> {
+        let components = split_path(path)?;
+        let (name, parent) = components.split_last()
+            .ok_or_else(|| EfiError::from(EfiErrorKind::InvalidParameter))?; // The root has no directory entry
+        let dir = self.resolve_dir(parent)?;
+        self.find(dir, name)?.ok_or_else(|| EfiErrorKind::NotFound.into())
+    }
+
+    /// Opens an existing file. Reading needs only `io::Read + io::Seek` from the volume.
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>> {
+        let entry = self.metadata(path)?;
+        if entry.is_dir() {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        Ok(File::new(self, &entry))
+    }
+
+    fn resolve_dir(&mut self, components: &[&str]) -> Result<DirLoc> {
+        let mut dir = self.root_dir();
+        for component in components {
+            let entry = self.find(dir, component)?.ok_or_else(|| EfiError::from(EfiErrorKind::NotFound))?;
+            if !entry.is_dir() {
+                return Err(EfiErrorKind::InvalidParameter.into());
+            }
+            dir = self.dir_of(&entry);
+        }
+        Ok(dir)
+    }
+
+    fn root_dir(&self) -> DirLoc {
+        match self.layout.fat_type {
+            FatType::Fat32 => DirLoc::Chain(self.layout.root_cluster),
+            _ => DirLoc::FixedRoot,
+        }
+    }
+
+    fn dir_of(&self, entry: &DirEntry) -> DirLoc {
+        match entry.first_cluster {
+            0 => self.root_dir(), // ".." entries pointing at the root use cluster 0
+            c => DirLoc::Chain(c),
+        }
+    }
+
+    fn find(&mut self, dir: DirLoc, name: &str) -> Result<Option<DirEntry>> {
+        Ok(self.list_dir(dir)?.into_iter().find(|e| e.matches(name)))
+    }
+
+    fn list_dir(&mut self, dir: DirLoc) -> Result<Vec<DirEntry>> {
+        let raw = self.read_dir_raw(dir)?;
+        let mut entries = Vec::new();
+        let mut lfn = LongNameParser::default();
+
+        for (offset, bytes) in raw {
+            match bytes[0] {
+                0 => break, // No more entries after this one
+                DELETED_ENTRY => {
+                    lfn.reset();
+                    continue;
+                },
+                _ => {},
+            }
+
+            let attributes = bytes[11];
+            if attributes & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+                lfn.push(offset, &bytes);
+                continue;
+            }
+
+            let short = short_name_of(&bytes);
+            let (long_name, mut slots) = lfn.finish(&bytes);
+            if attributes & ATTR_VOLUME_ID != 0 || bytes[0] == b'.' {
+                continue;
+            }
+
+            slots.push(offset);
+            let first_cluster = match self.layout.fat_type {
+                FatType::Fat32 => (le_u16(&bytes[20..]) as u32) << 16 | le_u16(&bytes[26..]) as u32,
+                _ => le_u16(&bytes[26..]) as u32,
+            };
+
+            entries.push(DirEntry {
+                name: long_name.unwrap_or_else(|| short.clone()),
+                short_name: short,
+                attributes,
+                size: le_u32(&bytes[28..]),
+                first_cluster,
+                created: Timestamp::from_dos(le_u16(&bytes[16..]), le_u16(&bytes[14..])),
+                modified: Timestamp::from_dos(le_u16(&bytes[24..]), le_u16(&bytes[22..])),
+                slots,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    // Every 32 byte slot of a directory along with its offset on disk
+    fn read_dir_raw(&mut self, dir: DirLoc) -> Result<Vec<(u64, [u8; 32])>> {
+        let regions = match dir {
+            DirLoc::FixedRoot => vec![(self.layout.root_dir_offset, self.layout.root_dir_entries * DIR_ENTRY_SIZE)],
+            DirLoc::Chain(first) => self.chain(first)?
+                .into_iter()
+                .map(|c| (self.layout.cluster_offset(c), self.layout.cluster_size))
+                .collect(),
+        };
+
+        let mut slots = Vec::new();
+        for (offset, len) in regions {
+            let mut buf = vec![0u8; len as usize];
+            read_at(&mut self.disk, offset, &mut buf)?;
+            for (i, chunk) in buf.chunks(DIR_ENTRY_SIZE as usize).enumerate() {
+                let mut slot = [0u8; 32];
+                slot.copy_from_slice(chunk);
+                slots.push((offset + i as u64 * DIR_ENTRY_SIZE, slot));
+            }
+        }
+
+        Ok(slots)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32> {
+        let n = cluster as u64;
+        match self.layout.fat_type {
+            FatType::Fat12 => {
+                let offset = n + n / 2;
+                let value = self.fat_byte(offset)? as u32 | (self.fat_byte(offset + 1)? as u32) << 8;
+                Ok(if n & 1 == 1 { value >> 4 } else { value & 0x0FFF })
+            },
+            FatType::Fat16 => {
+                Ok(self.fat_byte(n * 2)? as u32 | (self.fat_byte(n * 2 + 1)? as u32) << 8)
+            },
+            FatType::Fat32 => {
+                let mut value = 0;
+                for i in 0..4 {
+                    value |= (self.fat_byte(n * 4 + i)? as u32) << (8 * i);
+                }
+                Ok(value & 0x0FFF_FFFF)
+            },
+        }
+    }
+
+    fn fat_byte(&mut self, offset: u64) -> Result<u8> {
+        let sector_size = self.layout.bytes_per_sector;
+        let sector = offset / sector_size;
+        if self.fat_cache.0 != sector {
+            self.fat_cache.0 = u64::MAX; // Stays invalid if the read below fails
+            read_at(&mut self.disk, self.layout.fat_offset + sector * sector_size, &mut self.fat_cache.1)?;
+            self.fat_cache.0 = sector;
+        }
+        Ok(self.fat_cache.1[(offset % sector_size) as usize])
+    }
+
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+        if self.layout.fat_type.is_eoc(next) {
+            Ok(None)
+        } else if self.layout.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(EfiErrorKind::VolumeCorrupted.into()) // Free or bad clusters have no business being in a chain
+        }
+    }
+
+    fn chain(&mut self, first: u32) -> Result<Vec<u32>> {
+        if !self.layout.is_valid_cluster(first) {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let mut chain = vec![first];
+        let mut cluster = first;
+        while let Some(next) = self.next_cluster(cluster)? {
+            if chain.len() > self.layout.cluster_count as usize {
+                return Err(EfiErrorKind::VolumeCorrupted.into()); // Chain has a loop
+            }
+            chain.push(next);
+            cluster = next;
+        }
+        Ok(chain)
+    }
+}
+
+impl<D: Read + Write + Seek> FileSystem<D> {
+    /// Creates a new, empty FAT volume covering the whole of `disk` and mounts it
+    pub fn format(mut disk: D, options: &FormatOptions) -> Result<Self> {
+        let bytes_per_sector = options.bytes_per_sector as u64;
+        if ![512, 1024, 2048, 4096].contains(&bytes_per_sector) {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let label = match options.volume_label {
+            Some(ref label) => Some(volume_label_bytes(label)?),
+            None => None,
+        };
+
+        let size = disk.seek(SeekFrom::End(0))?;
+        let total_sectors = cmp::min(size / bytes_per_sector, u32::MAX as u64);
+        let fat_type = options.fat_type.unwrap_or(match size {
+            s if s < 16 * 1024 * 1024 => FatType::Fat12,
+            s if s < 512 * 1024 * 1024 => FatType::Fat16,
+            _ => FatType::Fat32,
+        });
+
+        let geometry = Geometry::compute(fat_type, total_sectors, bytes_per_sector)
+            .ok_or_else(|| EfiError::from(EfiErrorKind::VolumeFull))?; // Volume is too small (or too big) for the type
+
+        let boot_sector = geometry.boot_sector(options.volume_id, label.as_ref().unwrap_or(b"NO NAME    "));
+
+        // Wipe everything up to the end of the root directory so stale data doesn't turn into garbage entries
+        let root_dir_end = match fat_type {
+            FatType::Fat32 => geometry.data_offset() + geometry.cluster_size(),
+            _ => geometry.data_offset(),
+        };
+        zero_range(&mut disk, 0, root_dir_end)?;
+
+        write_at(&mut disk, 0, &boot_sector)?;
+        let mut fat_start = vec![0u8; 12];
+        match fat_type {
+            FatType::Fat12 => fat_start[..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]),
+            FatType::Fat16 => fat_start[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]),
+            FatType::Fat32 => {
+                // Clusters 0 and 1 are reserved and cluster 2 holds the root directory
+                fat_start.copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F]);
+
+                let mut fs_info = vec![0u8; bytes_per_sector as usize];
+                fs_info[0..4].copy_from_slice(&FS_INFO_LEAD_SIG.to_le_bytes());
+                fs_info[484..488].copy_from_slice(&FS_INFO_STRUC_SIG.to_le_bytes());
+                fs_info[488..492].copy_from_slice(&(geometry.cluster_count - 1).to_le_bytes());
+                fs_info[492..496].copy_from_slice(&3u32.to_le_bytes());
+                fs_info[508..512].copy_from_slice(&FS_INFO_TRAIL_SIG.to_le_bytes());
+                write_at(&mut disk, bytes_per_sector, &fs_info)?;
+
+                // Backup boot sector and FSInfo
+                write_at(&mut disk, 6 * bytes_per_sector, &boot_sector)?;
+                write_at(&mut disk, 7 * bytes_per_sector, &fs_info)?;
+            },
+        }
+        for i in 0..2 {
+            write_at(&mut disk, geometry.fat_offset() + i * geometry.fat_sectors * bytes_per_sector, &fat_start)?;
+        }
+
+        let root_dir_offset = match fat_type {
+            FatType::Fat32 => geometry.data_offset(),
+            _ => geometry.data_offset() - geometry.root_dir_sectors() * bytes_per_sector,
+        };
+        if let Some(label) = label {
+            let mut entry = [0u8; 32];
+            entry[..11].copy_from_slice(&label);
+            entry[11] = ATTR_VOLUME_ID;
+            write_at(&mut disk, root_dir_offset, &entry)?;
+        }
+
+        disk.flush()?;
+        Self::mount(disk)
+    }
+
+    /// Sets the time stamped on entries created or modified from here on.
+    /// The default is 1980-01-01 00:00:00 since this module has no clock of its own.
+    pub fn set_time(&mut self, now: Timestamp) {
+        self.now = now;
+    }
+
+    /// Creates a file, or truncates it if it already exists, and opens it for writing
+    pub fn create(&mut self, path: &str) -> Result<File<'_, D>> {
+        let components = split_path(path)?;
+        let (name, parent) = components.split_last()
+            .ok_or_else(|| EfiError::from(EfiErrorKind::InvalidParameter))?;
+        let dir = self.resolve_dir(parent)?;
+
+        let entry = match self.find(dir, name)? {
+            Some(ref entry) if entry.is_dir() => return Err(EfiErrorKind::AccessDenied.into()),
+            Some(entry) => {
+                if entry.first_cluster != 0 {
+                    self.free_chain(entry.first_cluster)?;
+                }
+                self.update_entry(entry.short_slot(), 0, 0)?;
+                DirEntry { first_cluster: 0, size: 0, ..entry }
+            },
+            None => self.add_entry(dir, name, ATTR_ARCHIVE, 0)?,
+        };
+
+        Ok(File::new(self, &entry))
+    }
+
+    /// Creates an empty directory. The parent must already exist.
+    pub fn create_dir(&mut self, path: &str) -> Result<()> {
+        let components = split_path(path)?;
+        let (name, parent) = components.split_last()
+            .ok_or_else(|| EfiError::from(EfiErrorKind::InvalidParameter))?;
+        let dir = self.resolve_dir(parent)?;
+        if self.find(dir, name)?.is_some() {
+            return Err(EfiErrorKind::AccessDenied.into());
+        }
+
+        let cluster = self.alloc_cluster(None)?;
+        self.zero_cluster(cluster)?;
+
+        let parent_cluster = match dir {
+            DirLoc::Chain(c) if c != self.layout.root_cluster => c,
+            _ => 0,
+        };
+        let offset = self.layout.cluster_offset(cluster);
+        let dot = self.short_entry(b".          ", ATTR_DIRECTORY, cluster, 0);
+        let dot_dot = self.short_entry(b"..         ", ATTR_DIRECTORY, parent_cluster, 0);
+        write_at(&mut self.disk, offset, &dot)?;
+        write_at(&mut self.disk, offset + DIR_ENTRY_SIZE, &dot_dot)?;
+
+        if let Err(e) = self.add_entry(dir, name, ATTR_DIRECTORY, cluster) {
+            self.free_chain(cluster)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes a file or an empty directory
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        let entry = self.metadata(path)?;
+        if entry.is_dir() && !self.list_dir(self.dir_of(&entry))?.is_empty() {
+            return Err(EfiErrorKind::AccessDenied.into());
+        }
+
+        // Mark the entries deleted first so a failure halfway through leaks clusters rather than leaving an entry pointing at free ones
+        for slot in &entry.slots {
+            write_at(&mut self.disk, *slot, &[DELETED_ENTRY])?;
+        }
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster)?;
+        }
+        self.disk.flush()?;
+        Ok(())
+    }
+
+    fn add_entry(&mut self, dir: DirLoc, name: &str, attributes: u8, first_cluster: u32) -> Result<DirEntry> {
+        validate_name(name)?;
+
+        let raw = self.read_dir_raw(dir)?;
+        let existing = raw.iter()
+            .filter(|(_, b)| b[0] != 0 && b[0] != DELETED_ENTRY && b[11] & ATTR_LONG_NAME_MASK != ATTR_LONG_NAME)
+            .map(|(_, b)| {
+                let mut short = [0u8; 11];
+                short.copy_from_slice(&b[..11]);
+                short
+            })
+            .collect::<Vec<_>>();
+
+        let (short, needs_long_name) = generate_short_name(name, &existing)?;
+        let utf16 = name.encode_utf16().collect::<Vec<_>>();
+        let long_entries = if needs_long_name { utf16.len().div_ceil(LFN_CHARS_PER_ENTRY) } else { 0 };
+        let slots = self.find_free_slots(dir, raw, long_entries + 1)?;
+
+        let checksum = lfn_checksum(&short);
+        for (i, slot) in slots[..long_entries].iter().enumerate() {
+            let ordinal = long_entries - i; // Long entries are stored last part first
+            let entry = long_name_entry(&utf16, ordinal, ordinal == long_entries, checksum);
+            write_at(&mut self.disk, *slot, &entry)?;
+        }
+
+        let short_slot = slots[long_entries];
+        let entry = self.short_entry(&short, attributes, first_cluster, 0);
+        write_at(&mut self.disk, short_slot, &entry)?;
+        self.disk.flush()?;
+
+        Ok(DirEntry {
+            name: name.into(),
+            short_name: short_name_of(&entry),
+            attributes,
+            size: 0,
+            first_cluster,
+            created: self.now,
+            modified: self.now,
+            slots,
+        })
+    }
+
+    // Finds `count` consecutive free slots, growing the directory if needed
+    fn find_free_slots(&mut self, dir: DirLoc, mut raw: Vec<(u64, [u8; 32])>, count: usize) -> Result<Vec<u64>> {
+        loop {
+            let mut run = Vec::new();
+            for (offset, bytes) in &raw {
+                if bytes[0] == 0 || bytes[0] == DELETED_ENTRY {
+                    run.push(*offset);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+
+            let last = match dir {
+                DirLoc::FixedRoot => return Err(EfiErrorKind::VolumeFull.into()),
+                DirLoc::Chain(first) => *self.chain(first)?.last().unwrap(),
+            };
+            let cluster = self.alloc_cluster(Some(last))?;
+            self.zero_cluster(cluster)?;
+
+            let offset = self.layout.cluster_offset(cluster);
+            raw.extend((0..self.layout.cluster_size / DIR_ENTRY_SIZE).map(|i| (offset + i * DIR_ENTRY_SIZE, [0u8; 32])));
+        }
+    }
+
+    fn short_entry(&self, short: &[u8; 11], attributes: u8, first_cluster: u32, size: u32) -> [u8; 32] {
+        let (date, time) = self.now.to_dos();
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(short);
+        entry[11] = attributes;
+        entry[14..16].copy_from_slice(&time.to_le_bytes());
+        entry[16..18].copy_from_slice(&date.to_le_bytes());
+        entry[18..20].copy_from_slice(&date.to_le_bytes());
+        entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    // Rewrites the cluster, size and modification time of the short entry at `slot`
+    fn update_entry(&mut self, slot: u64, first_cluster: u32, size: u32) -> Result<()> {
+        let (date, time) = self.now.to_dos();
+        let mut buf = [0u8; 14];
+        buf[0..2].copy_from_slice(&date.to_le_bytes()); // Last access date
+        buf[2..4].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        buf[4..6].copy_from_slice(&time.to_le_bytes());
+        buf[6..8].copy_from_slice(&date.to_le_bytes());
+        buf[8..10].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        buf[10..14].copy_from_slice(&size.to_le_bytes());
+        write_at(&mut self.disk, slot + 18, &buf)
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<()> {
+        let n = cluster as u64;
+        let (offset, bytes, len) = match self.layout.fat_type {
+            FatType::Fat12 => {
+                let offset = n + n / 2;
+                let old = self.fat_byte(offset)? as u16 | (self.fat_byte(offset + 1)? as u16) << 8;
+                let new = if n & 1 == 1 {
+                    (old & 0x000F) | ((value as u16) << 4)
+                } else {
+                    (old & 0xF000) | (value as u16 & 0x0FFF)
+                };
+                (offset, (new as u32).to_le_bytes(), 2)
+            },
+            FatType::Fat16 => (n * 2, value.to_le_bytes(), 2),
+            FatType::Fat32 => {
+                // The top four bits are reserved and must be preserved
+                let old = self.fat_byte(n * 4 + 3)? as u32;
+                (n * 4, ((value & 0x0FFF_FFFF) | (old & 0xF0) << 24).to_le_bytes(), 4)
+            },
+        };
+
+        for i in 0..self.layout.num_fats {
+            write_at(&mut self.disk, self.layout.fat_offset + i * self.layout.fat_size + offset, &bytes[..len])?;
+        }
+
+        // Keep the cache coherent
+        let sector_size = self.layout.bytes_per_sector;
+        for (i, byte) in bytes[..len].iter().enumerate() {
+            let byte_offset = offset + i as u64;
+            if byte_offset / sector_size == self.fat_cache.0 {
+                self.fat_cache.1[(byte_offset % sector_size) as usize] = *byte;
+            }
+        }
+
+        self.invalidate_fs_info()
+    }
+
+    // Allocates a cluster, marks it as the end of a chain and links it after `prev` if given
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32> {
+        let first = self.next_free;
+        let last = self.layout.cluster_count + 1;
+        let mut cluster = None;
+        for c in (first..=last).chain(2..first) {
+            if self.fat_entry(c)? == 0 {
+                cluster = Some(c);
+                break;
+            }
+        }
+
+        let cluster = cluster.ok_or_else(|| EfiError::from(EfiErrorKind::VolumeFull))?;
+        self.set_fat_entry(cluster, self.layout.fat_type.eoc())?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        self.next_free = if cluster == last { 2 } else { cluster + 1 };
+        Ok(cluster)
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<()> {
+        zero_range(&mut self.disk, self.layout.cluster_offset(cluster), self.layout.cluster_size)
+    }
+
+    // The free cluster count in FSInfo is only a hint. Rather than keeping it accurate
+    // we mark it unknown on the first modification, which the spec allows.
+    fn invalidate_fs_info(&mut self) -> Result<()> {
+        if self.fs_info_invalidated || self.layout.fat_type != FatType::Fat32 {
+            return Ok(());
+        }
+        self.fs_info_invalidated = true;
+
+        let sector = self.layout.fs_info_sector;
+        if sector == 0 || sector == 0xFFFF {
+            return Ok(());
+        }
+
+        let offset = sector * self.layout.bytes_per_sector;
+        let mut lead_sig = [0u8; 4];
+        read_at(&mut self.disk, offset, &mut lead_sig)?;
+        if u32::from_le_bytes(lead_sig) == FS_INFO_LEAD_SIG {
+            write_at(&mut self.disk, offset + 488, &FS_INFO_UNKNOWN.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// An open file. Implements `io::Read` and `io::Seek`, plus `io::Write` if the volume is writable.
+pub struct File<'a, D> {
+    fs: &'a mut FileSystem<D>,
+    entry_slot: u64,
+    first_cluster: u32,
+    size: u32,
+    pos: u64,
+    cursor: Option<(u64, u32)>, // Index within the chain and the cluster number last visited. Makes sequential access O(1).
+}
+
+impl<'a, D: Read + Seek> File<'a, D> {
+    fn new(fs: &'a mut FileSystem<D>, entry: &DirEntry) -> Self {
+        Self { fs, entry_slot: entry.short_slot(), first_cluster: entry.first_cluster, size: entry.size, pos: 0, cursor: None }
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    // Follows the chain to the cluster at `index`. Returns None if the chain is shorter than that.
+    fn cluster_at(&mut self, index: u64) -> Result<Option<u32>> {
+        if self.first_cluster == 0 {
+            return Ok(None);
+        }
+
+        let (mut i, mut cluster) = match self.cursor {
+            Some((i, c)) if i <= index => (i, c),
+            _ => (0, self.first_cluster),
+        };
+
+        while i < index {
+            cluster = match self.fs.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            i += 1;
+        }
+
+        self.cursor = Some((i, cluster));
+        Ok(Some(cluster))
+    }
+}
+
+impl<'a, D: Read + Write + Seek> File<'a, D> {
+    /// Truncates or extends the file. Extended bytes are zero.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        if len > u32::MAX as u64 {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        let size = self.size as u64;
+        if len > size {
+            let pos = self.pos;
+            self.pos = size;
+            let result = self.write_zeros(len - size);
+            self.pos = pos;
+            return result;
+        }
+
+        let cluster_size = self.fs.layout.cluster_size;
+        let keep = len.div_ceil(cluster_size);
+        if keep == 0 {
+            if self.first_cluster != 0 {
+                self.fs.free_chain(self.first_cluster)?;
+            }
+            self.first_cluster = 0;
+        } else if let Some(last) = self.cluster_at(keep - 1)? {
+            if let Some(next) = self.fs.next_cluster(last)? {
+                self.fs.set_fat_entry(last, self.fs.layout.fat_type.eoc())?;
+                self.fs.free_chain(next)?;
+            }
+        }
+
+        self.cursor = None;
+        self.size = len as u32;
+        self.fs.update_entry(self.entry_slot, self.first_cluster, self.size)
+    }
+
+    // Like `cluster_at` but extends the chain when it's too short
+    fn cluster_for_write(&mut self, index: u64) -> Result<u32> {
+        if self.first_cluster == 0 {
+            self.first_cluster = self.fs.alloc_cluster(None)?;
+            self.cursor = None;
+        }
+
+        let (mut i, mut cluster) = match self.cursor {
+            Some((i, c)) if i <= index => (i, c),
+            _ => (0, self.first_cluster),
+        };
+
+        while i < index {
+            cluster = match self.fs.next_cluster(cluster)? {
+                Some(next) => next,
+                None => self.fs.alloc_cluster(Some(cluster))?,
+            };
+            i += 1;
+        }
+
+        self.cursor = Some((i, cluster));
+        Ok(cluster)
+    }
+
+    fn write_zeros(&mut self, mut count: u64) -> Result<()> {
+        let zeros = vec![0u8; cmp::min(count, self.fs.layout.cluster_size) as usize];
+        while count > 0 {
+            let len = cmp::min(count, zeros.len() as u64) as usize;
+            self.write_all(&zeros[..len])?;
+            count -= len as u64;
+        }
+        Ok(())
+    }
+
+    fn write_inner(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.pos > self.size as u64 {
+            // Fill the hole left by seeking past the end
+            let hole = self.pos - self.size as u64;
+            self.pos = self.size as u64;
+            self.write_zeros(hole)?;
+        }
+
+        let max_len = (u32::MAX as u64).saturating_sub(self.pos); // FAT files can't be 4 GiB or bigger
+        if max_len == 0 {
+            return Err(EfiErrorKind::VolumeFull.into());
+        }
+
+        let cluster_size = self.fs.layout.cluster_size;
+        let offset = self.pos % cluster_size;
+        let len = cmp::min(cmp::min(buf.len() as u64, cluster_size - offset), max_len) as usize;
+
+        let first_cluster = self.first_cluster;
+        let cluster = self.cluster_for_write(self.pos / cluster_size)?;
+        write_at(&mut self.fs.disk, self.fs.layout.cluster_offset(cluster) + offset, &buf[..len])?;
+
+        self.pos += len as u64;
+        let size = cmp::max(self.size, self.pos as u32);
+        if size != self.size || first_cluster != self.first_cluster {
+            self.size = size;
+            self.fs.update_entry(self.entry_slot, self.first_cluster, self.size)?;
+        }
+
+        Ok(len)
+    }
+}
+
+fn to_io_err(e: EfiError) -> io::Error {
+    let kind = match e.kind() {
+        EfiErrorKind::VolumeFull => io::ErrorKind::WriteZero,
+        EfiErrorKind::VolumeCorrupted => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, "FAT operation failed")
+}
+
+impl<'a, D: Read + Seek> Read for File<'a, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.size as u64;
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let cluster_size = self.fs.layout.cluster_size;
+        let offset = self.pos % cluster_size;
+        let len = cmp::min(cmp::min(buf.len() as u64, cluster_size - offset), size - self.pos) as usize;
+
+        let cluster = self.cluster_at(self.pos / cluster_size)
+            .map_err(to_io_err)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "cluster chain is shorter than the file size"))?;
+        read_at(&mut self.fs.disk, self.fs.layout.cluster_offset(cluster) + offset, &mut buf[..len]).map_err(to_io_err)?;
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a, D: Read + Write + Seek> Write for File<'a, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_inner(buf).map_err(to_io_err)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fs.disk.flush()
+    }
+}
+
+impl<'a, D> Seek for File<'a, D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            },
+            SeekFrom::End(n) => (self.size as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+// Sizes of the various regions for a volume about to be formatted
+struct Geometry {
+    fat_type: FatType,
+    bytes_per_sector: u64,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    root_dir_entries: u64,
+    total_sectors: u64,
+    fat_sectors: u64,
+    cluster_count: u32,
+}
+
+impl Geometry {
+    fn compute(fat_type: FatType, total_sectors: u64, bytes_per_sector: u64) -> Option<Self> {
+        // Try the customary cluster size first and fall back to whatever yields a valid cluster count
+        let volume_size = total_sectors * bytes_per_sector;
+        let preferred_cluster_size = match fat_type {
+            FatType::Fat32 if volume_size <= 260 * 1024 * 1024 => 512,
+            FatType::Fat32 if volume_size <= 8 << 30 => 4096,
+            FatType::Fat32 if volume_size <= 16 << 30 => 8192,
+            FatType::Fat32 if volume_size <= 32 << 30 => 16384,
+            FatType::Fat32 => 32768,
+            _ => 0,
+        };
+        let preferred = cmp::max(preferred_cluster_size / bytes_per_sector, 1);
+
+        core::iter::once(preferred)
+            .chain((0..8).map(|i| 1 << i))
+            .filter(|spc| spc * bytes_per_sector <= 32768)
+            .filter_map(|spc| Self::with_cluster_size(fat_type, total_sectors, bytes_per_sector, spc))
+            .next()
+    }
+
+    fn with_cluster_size(fat_type: FatType, total_sectors: u64, bytes_per_sector: u64, sectors_per_cluster: u64) -> Option<Self> {
+        let (reserved_sectors, root_dir_entries) = match fat_type {
+            FatType::Fat32 => (32, 0),
+            _ => (1, 512),
+        };
+        let root_dir_sectors = (root_dir_entries * DIR_ENTRY_SIZE).div_ceil(bytes_per_sector);
+
+        // The FAT size depends on the cluster count which depends on the FAT size. Iterate until it settles.
+        let mut fat_sectors = 1;
+        let cluster_count = loop {
+            let overhead = reserved_sectors + 2 * fat_sectors + root_dir_sectors;
+            if overhead >= total_sectors {
+                return None;
+            }
+
+            let cluster_count = (total_sectors - overhead) / sectors_per_cluster;
+            let fat_bytes = match fat_type {
+                FatType::Fat12 => ((cluster_count + 2) * 3).div_ceil(2),
+                FatType::Fat16 => (cluster_count + 2) * 2,
+                FatType::Fat32 => (cluster_count + 2) * 4,
+            };
+            let needed = fat_bytes.div_ceil(bytes_per_sector);
+            if needed <= fat_sectors {
+                break cluster_count;
+            }
+            fat_sectors = needed;
+        };
+
+        let (min, max) = match fat_type {
+            FatType::Fat12 => (1, FAT12_MAX_CLUSTERS as u64),
+            FatType::Fat16 => (FAT12_MAX_CLUSTERS as u64 + 1, FAT16_MAX_CLUSTERS as u64),
+            FatType::Fat32 => (FAT16_MAX_CLUSTERS as u64 + 1, FAT32_MAX_CLUSTERS as u64),
+        };
+        if cluster_count < min || cluster_count > max {
+            return None;
+        }
+
+        Some(Self {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            root_dir_entries,
+            total_sectors,
+            fat_sectors,
+            cluster_count: cluster_count as u32,
+        })
+    }
+
+    fn root_dir_sectors(&self) -> u64 {
+        (self.root_dir_entries * DIR_ENTRY_SIZE).div_ceil(self.bytes_per_sector)
+    }
+
+    fn fat_offset(&self) -> u64 {
+        self.reserved_sectors * self.bytes_per_sector
+    }
+
+    fn data_offset(&self) -> u64 {
+        (self.reserved_sectors + 2 * self.fat_sectors + self.root_dir_sectors()) * self.bytes_per_sector
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * self.bytes_per_sector
+    }
+
+    fn boot_sector(&self, volume_id: u32, label: &[u8; 11]) -> Vec<u8> {
+        let mut buf = vec![0u8; self.bytes_per_sector as usize];
+        buf[3..11].copy_from_slice(b"MSWIN4.1"); // The OEM name most likely to keep old drivers happy
+        buf[11..13].copy_from_slice(&(self.bytes_per_sector as u16).to_le_bytes());
+        buf[13] = self.sectors_per_cluster as u8;
+        buf[14..16].copy_from_slice(&(self.reserved_sectors as u16).to_le_bytes());
+        buf[16] = 2; // Number of FATs
+        buf[17..19].copy_from_slice(&(self.root_dir_entries as u16).to_le_bytes());
+        if self.total_sectors < 0x10000 && self.fat_type != FatType::Fat32 {
+            buf[19..21].copy_from_slice(&(self.total_sectors as u16).to_le_bytes());
+        } else {
+            buf[32..36].copy_from_slice(&(self.total_sectors as u32).to_le_bytes());
+        }
+        buf[21] = 0xF8; // Media type: fixed disk
+        buf[24..26].copy_from_slice(&32u16.to_le_bytes()); // Sectors per track. Meaningless but expected.
+        buf[26..28].copy_from_slice(&64u16.to_le_bytes()); // Number of heads. Ditto.
+
+        let ext_offset = match self.fat_type {
+            FatType::Fat32 => {
+                buf[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+                buf[36..40].copy_from_slice(&(self.fat_sectors as u32).to_le_bytes());
+                buf[44..48].copy_from_slice(&2u32.to_le_bytes()); // Root cluster
+                buf[48..50].copy_from_slice(&1u16.to_le_bytes()); // FSInfo sector
+                buf[50..52].copy_from_slice(&6u16.to_le_bytes()); // Backup boot sector
+                64
+            },
+            _ => {
+                buf[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+                buf[22..24].copy_from_slice(&(self.fat_sectors as u16).to_le_bytes());
+                36
+            },
+        };
+
+        buf[ext_offset] = 0x80; // Drive number
+        buf[ext_offset + 2] = 0x29; // Extended boot signature
+        buf[ext_offset + 3..ext_offset + 7].copy_from_slice(&volume_id.to_le_bytes());
+        buf[ext_offset + 7..ext_offset + 18].copy_from_slice(label);
+        buf[ext_offset + 18..ext_offset + 26].copy_from_slice(self.fat_type.label());
+        buf[510] = 0x55;
+        buf[511] = 0xAA;
+        buf
+    }
+}
+
+// Accumulates long name entries until the short entry they belong to shows up
+#[derive(Default)]
+struct LongNameParser {
+    parts: Vec<[u16; LFN_CHARS_PER_ENTRY]>,
+    slots: Vec<u64>,
+    expected: u8, // Ordinal of the next entry we expect
+    checksum: u8,
+}
+
+impl LongNameParser {
+    fn reset(&mut self) {
+        self.parts.clear();
+        self.slots.clear();
+        self.expected = 0;
+    }
+
+    fn push(&mut self, offset: u64, bytes: &[u8; 32]) {
+        let ordinal = bytes[0] & 0x1F;
+        if bytes[0] & LAST_LONG_ENTRY != 0 {
+            // The last part comes first and starts a new sequence
+            self.reset();
+            self.parts = vec![[0u16; LFN_CHARS_PER_ENTRY]; ordinal as usize];
+            self.checksum = bytes[13];
+        } else if ordinal == 0 || ordinal != self.expected || bytes[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        if ordinal == 0 {
+            self.reset();
+            return;
+        }
+
+        let part = &mut self.parts[ordinal as usize - 1];
+        for (c, offset) in part.iter_mut().zip(LFN_CHAR_OFFSETS.iter()) {
+            *c = le_u16(&bytes[*offset..]);
+        }
+        self.slots.push(offset);
+        self.expected = ordinal - 1;
+    }
+
+    // Returns the long name, if a complete one matching the short entry was collected, and the slots it occupied
+    fn finish(&mut self, short_entry: &[u8; 32]) -> (Option<String>, Vec<u64>) {
+        let mut short = [0u8; 11];
+        short.copy_from_slice(&short_entry[..11]);
+
+        let result = if !self.parts.is_empty() && self.expected == 0 && self.checksum == lfn_checksum(&short) {
+            let utf16 = self.parts.iter()
+                .flat_map(|p| p.iter().cloned())
+                .take_while(|c| *c != 0)
+                .collect::<Vec<_>>();
+            (Some(String::from_utf16_lossy(&utf16)), core::mem::take(&mut self.slots))
+        } else {
+            (None, Vec::new())
+        };
+
+        self.reset();
+        result
+    }
+}
+
+fn long_name_entry(utf16: &[u16], ordinal: usize, is_last: bool, checksum: u8) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[0] = ordinal as u8 | if is_last { LAST_LONG_ENTRY } else { 0 };
+    entry[11] = ATTR_LONG_NAME;
+    entry[13] = checksum;
+
+    let start = (ordinal - 1) * LFN_CHARS_PER_ENTRY;
+    for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+        // The name is terminated by a NUL if there's room and then padded with 0xFFFF
+        let c = match utf16.get(start + i) {
+            Some(c) => *c,
+            None if start + i == utf16.len() => 0,
+            None => 0xFFFF,
+        };
+        entry[*offset..*offset + 2].copy_from_slice(&c.to_le_bytes());
+    }
+    entry
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*b))
+}
+
+// Human readable form of the 8.3 name in a short entry
+fn short_name_of(entry: &[u8]) -> String {
+    let ntres = entry[12];
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        let mut s = String::new();
+        for (i, b) in bytes.iter().enumerate() {
+            let b = if i == 0 && *b == 0x05 { DELETED_ENTRY } else { *b }; // 0x05 stands in for a real 0xE5
+            let c = if lower { b.to_ascii_lowercase() } else { b };
+            s.push(c as char); // Bytes above 0x7F are in some OEM code page we can't know. Latin-1 is as good a guess as any.
+        }
+        s.trim_end_matches(' ').into()
+    };
+
+    let base = convert(&entry[..8], ntres & NTRES_LOWER_BASE != 0);
+    let ext = convert(&entry[8..11], ntres & NTRES_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn is_valid_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+// Returns the 8.3 name to use for `name` and whether a long name is needed too
+fn generate_short_name(name: &str, existing: &[[u8; 11]]) -> Result<([u8; 11], bool)> {
+    let upper = name.to_ascii_uppercase();
+    let trimmed = upper.trim_start_matches('.');
+    let mut lossy = trimmed.len() != upper.len() || trimmed.contains(' ');
+
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+
+    let mut convert = |part: &str, max: usize| -> Vec<u8> {
+        let mut out = Vec::new();
+        for c in part.chars().filter(|c| *c != ' ' && *c != '.') {
+            if out.len() == max {
+                lossy = true;
+                break;
+            }
+            if is_valid_short_char(c) {
+                out.push(c as u8);
+            } else {
+                out.push(b'_');
+                lossy = true;
+            }
+        }
+        out
+    };
+
+    let mut base = convert(base, 8);
+    let ext = convert(ext, 3);
+    lossy |= base.len() + ext.len() + if ext.is_empty() { 0 } else { 1 } != trimmed.chars().count();
+    if base.is_empty() {
+        base.push(b'_');
+    }
+
+    let make = |base: &[u8]| {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base);
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        short
+    };
+
+    if !lossy {
+        let short = make(&base);
+        if !existing.contains(&short) {
+            return Ok((short, upper != name)); // A long name is still needed to preserve the case
+        }
+    }
+
+    for n in 1..=MAX_SHORT_NAME_TAIL {
+        let tail = format!("~{}", n);
+        let mut candidate = base[..cmp::min(base.len(), 8 - tail.len())].to_vec();
+        candidate.extend_from_slice(tail.as_bytes());
+        let short = make(&candidate);
+        if !existing.contains(&short) {
+            return Ok((short, true));
+        }
+    }
+
+    Err(EfiErrorKind::OutOfResources.into())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && !name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(EfiErrorKind::InvalidParameter.into())
+    }
+}
+
+fn volume_label_bytes(label: &str) -> Result<[u8; 11]> {
+    let upper = label.to_ascii_uppercase();
+    if upper.is_empty() || upper.len() > 11 || !upper.chars().all(|c| c == ' ' || is_valid_short_char(c)) {
+        return Err(EfiErrorKind::InvalidParameter.into());
+    }
+
+    let mut bytes = [b' '; 11];
+    bytes[..upper.len()].copy_from_slice(upper.as_bytes());
+    Ok(bytes)
+}
+
+// Splits a path on either kind of slash, resolving "." and ".."
+fn split_path(path: &str) -> Result<Vec<&str>> {
+    let mut components = Vec::new();
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {},
+            ".." => {
+                components.pop().ok_or_else(|| EfiError::from(EfiErrorKind::InvalidParameter))?;
+            },
+            c => components.push(c),
+        }
+    }
+    Ok(components)
+}
+
+fn zero_range<D: Write + Seek>(disk: &mut D, offset: u64, len: u64) -> Result<()> {
+    let zeros = vec![0u8; cmp::min(len, 64 * 1024) as usize];
+    disk.seek(SeekFrom::Start(offset))?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = cmp::min(remaining, zeros.len() as u64) as usize;
+        disk.write_all(&zeros[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+fn le_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Cursor;
+
+    fn format(size: usize, fat_type: Option<FatType>) -> FileSystem<Cursor<Vec<u8>>> {
+        let options = FormatOptions { fat_type, volume_label: Some("TEST".into()), volume_id: 0x1234_5678, ..FormatOptions::default() };
+        FileSystem::format(Cursor::new(vec![0u8; size]), &options).unwrap()
+    }
+
+    fn write_file<D: Read + Write + Seek>(fs: &mut FileSystem<D>, path: &str, data: &[u8]) {
+        fs.create(path).unwrap().write_all(data).unwrap();
+    }
+
+    fn read_file<D: Read + Seek>(fs: &mut FileSystem<D>, path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        fs.open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // Expands a fixture made by testdata/fat_fixtures.py: the image size, then (offset, length, data) runs of non-zero sectors
+    fn fixture(sparse: &[u8]) -> Cursor<Vec<u8>> {
+        let u64_at = |at: usize| le_u32(&sparse[at..]) as usize | (le_u32(&sparse[at + 4..]) as usize) << 32;
+        let mut image = vec![0u8; u64_at(0)];
+        let mut pos = 8;
+        while pos < sparse.len() {
+            let offset = u64_at(pos);
+            let len = le_u32(&sparse[pos + 8..]) as usize;
+            image[offset..offset + len].copy_from_slice(&sparse[pos + 12..pos + 12 + len]);
+            pos += 12 + len;
+        }
+        Cursor::new(image)
+    }
+
+    #[test]
+    fn format_picks_type_by_size() {
+        assert_eq!(format(1024 * 1024, None).fat_type(), FatType::Fat12);
+        assert_eq!(format(4 * 1024 * 1024, Some(FatType::Fat16)).fat_type(), FatType::Fat16);
+        assert_eq!(format(40 * 1024 * 1024, Some(FatType::Fat32)).fat_type(), FatType::Fat32);
+    }
+
+    #[test]
+    fn format_rejects_volume_too_small_for_type() {
+        let result = FileSystem::format(Cursor::new(vec![0u8; 1024 * 1024]), &FormatOptions { fat_type: Some(FatType::Fat32), ..FormatOptions::default() });
+        assert_eq!(result.err().unwrap().kind(), EfiErrorKind::VolumeFull);
+    }
+
+    #[test]
+    fn files_round_trip_on_every_fat_type() {
+        for (size, fat_type) in &[(1024 * 1024, FatType::Fat12), (4 * 1024 * 1024, FatType::Fat16), (40 * 1024 * 1024, FatType::Fat32)] {
+            let mut fs = format(*size, Some(*fat_type));
+            let data = pattern(10_000); // Spans several clusters
+            write_file(&mut fs, "/DATA.BIN", &data);
+            fs.create_dir("/EFI").unwrap();
+            fs.create_dir("/EFI/BOOT").unwrap();
+            write_file(&mut fs, "/EFI/BOOT/BOOTX64.EFI", b"MZ");
+
+            // Remount from the raw bytes to make sure everything made it to disk
+            let mut fs = FileSystem::mount(fs.into_inner()).unwrap();
+            assert_eq!(fs.fat_type(), *fat_type);
+            assert_eq!(fs.volume_label(), "TEST");
+            assert_eq!(read_file(&mut fs, "/DATA.BIN"), data);
+            assert_eq!(read_file(&mut fs, "\\efi\\boot\\bootx64.efi"), b"MZ");
+            assert_eq!(fs.metadata("/DATA.BIN").unwrap().size(), 10_000);
+        }
+    }
+
+    #[test]
+    fn reads_mkfs_fat_images() {
+        let fixtures: [(&[u8], FatType); 3] = [
+            (include_bytes!("testdata/fat12.img.sparse"), FatType::Fat12),
+            (include_bytes!("testdata/fat16.img.sparse"), FatType::Fat16),
+            (include_bytes!("testdata/fat32.img.sparse"), FatType::Fat32),
+        ];
+
+        for (sparse, fat_type) in &fixtures {
+            let mut fs = FileSystem::mount(fixture(sparse)).unwrap();
+            assert_eq!(fs.fat_type(), *fat_type);
+            assert_eq!(fs.volume_label(), "FIXTURE");
+
+            // The volume label entry in the root isn't listed
+            let root = fs.read_dir("/").unwrap();
+            let names = root.iter().map(|e| e.name()).collect::<Vec<_>>();
+            assert_eq!(names, ["hello.txt", "A long file name with spaces.txt", "EFI", "docs", "many"]);
+            assert!(root[2].is_dir() && root[3].is_dir() && !root[0].is_dir());
+
+            assert_eq!(read_file(&mut fs, "/hello.txt"), b"Hello, FAT!\n");
+            assert_eq!(read_file(&mut fs, "/a long file name with spaces.TXT"), b"long name\n".repeat(3));
+            assert_eq!(read_file(&mut fs, "/ALONGF~1.TXT"), b"long name\n".repeat(3));
+
+            let mut boot = b"MZ".to_vec();
+            boot.extend(pattern(6000));
+            assert_eq!(read_file(&mut fs, "/EFI/BOOT/BOOTX64.EFI"), boot);
+            assert_eq!(read_file(&mut fs, "/docs/Nested Directory/notes.md"), b"# Notes\n\nNested two levels deep.\n");
+
+            // Spans more than one cluster of directory entries
+            let many = fs.read_dir("/many").unwrap();
+            assert_eq!(many.len(), 25);
+            for (i, entry) in many.iter().enumerate() {
+                assert_eq!(entry.name(), format!("file number {:02}.txt", i));
+                assert_eq!(read_file(&mut fs, &format!("/many/{}", entry.name())), format!("file {}\n", i).into_bytes());
+            }
+
+            // Writing to a volume made by someone else keeps it readable
+            write_file(&mut fs, "/docs/Nested Directory/added.txt", b"added");
+            let mut fs = FileSystem::mount(fs.into_inner()).unwrap();
+            assert_eq!(read_file(&mut fs, "/docs/Nested Directory/added.txt"), b"added");
+            assert_eq!(read_file(&mut fs, "/docs/Nested Directory/notes.md"), b"# Notes\n\nNested two levels deep.\n");
+        }
+    }
+
+    #[test]
+    fn short_name_tails_run_out() {
+        let existing = (1..=MAX_SHORT_NAME_TAIL).map(|n| {
+            let tail = format!("~{}", n);
+            let mut short = *b"SAMEPREFTXT";
+            short[8 - tail.len()..8].copy_from_slice(tail.as_bytes());
+            short
+        }).collect::<Vec<_>>();
+
+        assert_eq!(generate_short_name("Same prefix.txt", &existing).err().unwrap().kind(), EfiErrorKind::OutOfResources);
+        assert!(generate_short_name("Same prefix.txt", &existing[1..]).is_ok());
+    }
+
+    #[test]
+    fn long_names_are_preserved() {
+        let mut fs = format(1024 * 1024, None);
+        write_file(&mut fs, "A rather long file name.conf", b"a");
+        write_file(&mut fs, "A rather long file name.config", b"b");
+        write_file(&mut fs, "readme.txt", b"c");
+        write_file(&mut fs, "Ünïcødé.txt", b"d");
+
+        let entries = fs.read_dir("/").unwrap();
+        let names = entries.iter().map(|e| e.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["A rather long file name.conf", "A rather long file name.config", "readme.txt", "Ünïcødé.txt"]);
+
+        let shorts = entries.iter().map(|e| e.short_name()).collect::<Vec<_>>();
+        assert_eq!(shorts, ["ARATHE~1.CON", "ARATHE~2.CON", "README.TXT", "_N_C_D~1.TXT"]);
+
+        // Lookups are case insensitive and work with either name
+        assert_eq!(read_file(&mut fs, "a RATHER long FILE name.CONFIG"), b"b");
+        assert_eq!(read_file(&mut fs, "ARATHE~1.CON"), b"a");
+        assert_eq!(read_file(&mut fs, "README.TXT"), b"c");
+    }
+
+    #[test]
+    fn lower_case_short_names_are_read() {
+        // Linux writes names like "readme.txt" as a bare short entry with the NTRes case flags set
+        let mut entry = [b' '; 32];
+        entry[..11].copy_from_slice(b"README  TXT");
+        entry[12] = NTRES_LOWER_BASE | NTRES_LOWER_EXT;
+        assert_eq!(short_name_of(&entry), "readme.txt");
+    }
+
+    #[test]
+    fn overwrite_seek_and_truncate() {
+        let mut fs = format(1024 * 1024, None);
+        write_file(&mut fs, "f", &pattern(5000));
+
+        {
+            let mut file = fs.open("f").unwrap();
+            file.seek(SeekFrom::Start(4096)).unwrap();
+            let mut buf = [0u8; 4];
+            file.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &pattern(5000)[4096..4100]);
+        }
+
+        {
+            let mut file = fs.create("f").unwrap();
+            assert_eq!(file.size(), 0);
+            file.write_all(b"hello").unwrap();
+            file.seek(SeekFrom::Start(3000)).unwrap(); // Leaves a hole that must read back as zeros
+            file.write_all(b"world").unwrap();
+            file.set_len(3002).unwrap();
+        }
+
+        let data = read_file(&mut fs, "f");
+        assert_eq!(data.len(), 3002);
+        assert_eq!(&data[..5], b"hello");
+        assert!(data[5..3000].iter().all(|b| *b == 0));
+        assert_eq!(&data[3000..], b"wo");
+    }
+
+    #[test]
+    fn remove_frees_clusters() {
+        let mut fs = format(1024 * 1024, None);
+        let cluster_size = fs.cluster_size() as usize;
+        let clusters = 1024 * 1024 / cluster_size;
+
+        // Fill most of the volume, delete it and fill it again. This fails if the clusters leak.
+        for _ in 0..2 {
+            write_file(&mut fs, "big", &vec![0xAA; clusters / 2 * cluster_size]);
+            fs.remove("big").unwrap();
+        }
+
+        assert_eq!(fs.metadata("big").err().unwrap().kind(), EfiErrorKind::NotFound);
+        assert!(fs.read_dir("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn directories_grow_and_must_be_empty_to_remove() {
+        let mut fs = format(40 * 1024 * 1024, Some(FatType::Fat32));
+        fs.create_dir("dir").unwrap();
+        assert_eq!(fs.create_dir("DIR").err().unwrap().kind(), EfiErrorKind::AccessDenied);
+
+        // Far more entries than fit in one 512 byte cluster
+        for i in 0..100 {
+            write_file(&mut fs, &format!("dir/file number {}", i), &[i as u8]);
+        }
+
+        let entries = fs.read_dir("dir").unwrap();
+        assert_eq!(entries.len(), 100);
+        assert_eq!(read_file(&mut fs, "dir/./../dir/file number 99"), [99]);
+
+        assert_eq!(fs.remove("dir").err().unwrap().kind(), EfiErrorKind::AccessDenied);
+        for i in 0..100 {
+            fs.remove(&format!("dir/file number {}", i)).unwrap();
+        }
+        fs.remove("dir").unwrap();
+        assert!(fs.read_dir("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn fixed_root_directory_fills_up() {
+        let mut fs = format(1024 * 1024, None);
+        for i in 0..511 { // The volume label takes up one of the 512 entries
+            fs.create(&format!("F{}", i)).unwrap();
+        }
+        assert_eq!(fs.create("ONEMORE").err().unwrap().kind(), EfiErrorKind::VolumeFull);
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        let mut fs = format(1024 * 1024, None);
+        for name in &["a:b", "what?", "trailing.", ""] {
+            assert!(fs.create(name).is_err(), "{}", name);
+        }
+        assert_eq!(fs.open("missing/file").err().unwrap().kind(), EfiErrorKind::NotFound);
+    }
+
+    #[test]
+    fn timestamps_are_recorded() {
+        let mut fs = format(1024 * 1024, None);
+        let now = Timestamp { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 42 };
+        fs.set_time(now);
+        write_file(&mut fs, "f", b"x");
+        assert_eq!(fs.metadata("f").unwrap().modified(), now);
+        assert_eq!(fs.metadata("f").unwrap().created(), now);
+    }
+}
@@ -0,0 +1,887 @@
+//! Read only ISO9660 file systems with Rock Ridge and Joliet names and El Torito boot catalogs.
+//!
+//! Names come from Rock Ridge if the volume has it, from the Joliet tree otherwise and,
+//! failing both, from the plain ISO9660 tree (with the ";1" version suffix removed).
+
+use crate::{
+    Result,
+    EfiError,
+    EfiErrorKind,
+    io::{self, Read, Seek, SeekFrom},
+    disk::read_at,
+};
+use core::cmp;
+use alloc::{vec::Vec, string::String};
+
+const SECTOR_SIZE: u64 = 2048; // Volume descriptors are always in 2048 byte sectors regardless of the logical block size
+const VOLUME_DESCRIPTOR_START: u64 = 16;
+const MAX_VOLUME_DESCRIPTORS: u64 = 64; // Guard against images with no terminator
+const STANDARD_ID: &[u8; 5] = b"CD001";
+const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+
+const VD_BOOT_RECORD: u8 = 0;
+const VD_PRIMARY: u8 = 1;
+const VD_SUPPLEMENTARY: u8 = 2;
+const VD_TERMINATOR: u8 = 255;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+const MAX_CONTINUATIONS: usize = 16; // Limit on chained Rock Ridge continuation areas so corrupt images can't loop us forever
+
+/// El Torito platform ID of entries meant for UEFI
+pub const PLATFORM_EFI: u8 = 0xEF;
+pub const PLATFORM_X86: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Names {
+    Plain,
+    RockRidge(u8), // Number of bytes to skip at the start of every system use area
+    Joliet,
+}
+
+/// An entry in a directory
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    extents: Vec<(u64, u64)>, // Byte offset and length of every extent. Files over 4 GiB have more than one.
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// An entry from the El Torito boot catalog
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootEntry {
+    pub platform_id: u8,
+    pub bootable: bool,
+    pub media_type: u8, // Emulation type. Zero means no emulation which is what EFI entries use.
+    pub load_rba: u32, // Logical block where the boot image starts
+    pub sector_count: u16, // In 512 byte virtual sectors. Often wrong for EFI images. See `FileSystem::boot_image`.
+}
+
+impl BootEntry {
+    pub fn is_efi(&self) -> bool {
+        self.platform_id == PLATFORM_EFI
+    }
+
+    fn from_bytes(platform_id: u8, buf: &[u8]) -> Self {
+        Self {
+            platform_id,
+            bootable: buf[0] == 0x88,
+            media_type: buf[1] & 0x0F,
+            load_rba: le_u32(&buf[8..]),
+            sector_count: le_u16(&buf[6..]),
+        }
+    }
+}
+
+/// A mounted ISO9660 volume
+pub struct FileSystem<R> {
+    reader: R,
+    image_size: u64, // Bounds every extent so that corrupt sizes fail instead of exhausting memory
+    block_size: u64,
+    volume_id: String,
+    root: DirEntry,
+    names: Names,
+    boot_catalog_lba: Option<u32>,
+}
+
+impl<R: Read + Seek> FileSystem<R> {
+    /// Reads the volume descriptors of the ISO9660 volume in `reader`
+    pub fn mount(mut reader: R) -> Result<Self> {
+        let mut primary = None;
+        let mut joliet = None;
+        let mut boot_catalog_lba = None;
+
+        let mut buf = vec![0u8; SECTOR_SIZE as usize];
+        for sector in VOLUME_DESCRIPTOR_START..VOLUME_DESCRIPTOR_START + MAX_VOLUME_DESCRIPTORS {
+            read_at(&mut reader, sector * SECTOR_SIZE, &mut buf)?;
+            if &buf[1..6] != STANDARD_ID {
+                return Err(EfiErrorKind::VolumeCorrupted.into());
+            }
+
+            match buf[0] {
+                VD_PRIMARY if primary.is_none() => primary = Some(buf.clone()),
+                VD_SUPPLEMENTARY if is_joliet(&buf) && joliet.is_none() => joliet = Some(buf.clone()),
+                VD_BOOT_RECORD if buf[7..7 + EL_TORITO_ID.len()] == *EL_TORITO_ID => boot_catalog_lba = Some(le_u32(&buf[71..])),
+                VD_TERMINATOR => break,
+                _ => {},
+            }
+        }
+
+        let primary = primary.ok_or_else(|| EfiError::from(EfiErrorKind::VolumeCorrupted))?;
+        let block_size = le_u16(&primary[128..]) as u64;
+        if ![512, 1024, 2048].contains(&block_size) {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let image_size = reader.seek(SeekFrom::End(0))?;
+        let mut fs = Self {
+            reader,
+            image_size,
+            block_size,
+            volume_id: String::from_utf8_lossy(&primary[40..72]).trim_end_matches([' ', '\0']).into(),
+            root: root_entry(&primary[156..190], block_size),
+            names: Names::Plain,
+            boot_catalog_lba,
+        };
+
+        if let Some(skip) = fs.rock_ridge_skip()? {
+            fs.names = Names::RockRidge(skip);
+        } else if let Some(joliet) = joliet {
+            fs.volume_id = ucs2_be(&joliet[40..72]).trim_end_matches([' ', '\0']).into();
+            fs.root = root_entry(&joliet[156..190], block_size);
+            fs.names = Names::Joliet;
+        }
+
+        Ok(fs)
+    }
+
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    pub fn has_rock_ridge(&self) -> bool {
+        matches!(self.names, Names::RockRidge(_))
+    }
+
+    pub fn has_joliet(&self) -> bool {
+        self.names == Names::Joliet
+    }
+
+    /// Gives back the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Lists the entries of the directory at `path` excluding "." and ".."
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+        let dir = self.lookup(path)?;
+        if !dir.is_dir {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+        self.list_dir(&dir)
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<DirEntry> {
+        self.lookup(path)
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<File<'_, R>> {
+        let entry = self.lookup(path)?;
+        if entry.is_dir {
+            return Err(EfiErrorKind::InvalidParameter.into());
+        }
+
+        Ok(File { fs: self, extents: entry.extents, size: entry.size, pos: 0 })
+    }
+
+    /// The entries of the El Torito boot catalog. Empty if the volume isn't bootable.
+    pub fn boot_entries(&mut self) -> Result<Vec<BootEntry>> {
+        let lba = match self.boot_catalog_lba {
+            Some(lba) => lba,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut catalog = vec![0u8; SECTOR_SIZE as usize];
+        read_at(&mut self.reader, lba as u64 * self.block_size, &mut catalog)?;
+
+        // Validation entry: header ID 1, key bytes 55 AA and all 16 bit words summing to zero
+        let validation = &catalog[..32];
+        let checksum = validation.chunks(2).fold(0u16, |sum, w| sum.wrapping_add(le_u16(w)));
+        if validation[0] != 1 || validation[30] != 0x55 || validation[31] != 0xAA || checksum != 0 {
+            return Err(EfiErrorKind::VolumeCorrupted.into());
+        }
+
+        let mut entries = vec![BootEntry::from_bytes(validation[1], &catalog[32..64])];
+
+        // Section headers (0x90, or 0x91 for the last one) each followed by their entries
+        let mut i = 64;
+        while i + 32 <= catalog.len() && (catalog[i] == 0x90 || catalog[i] == 0x91) {
+            let is_last = catalog[i] == 0x91;
+            let platform_id = catalog[i + 1];
+            let count = le_u16(&catalog[i + 2..]) as usize;
+            i += 32;
+
+            for _ in 0..count {
+                if i + 32 > catalog.len() {
+                    break;
+                }
+                entries.push(BootEntry::from_bytes(platform_id, &catalog[i..i + 32]));
+                i += 32;
+
+                // Section entry extensions only carry more selection criteria
+                while i + 32 <= catalog.len() && catalog[i] == 0x44 {
+                    i += 32;
+                }
+            }
+
+            if is_last {
+                break;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Reads the boot image of `entry`.
+    ///
+    /// Tools like xorriso frequently record a sector count of 1 (or a truncated one) for EFI boot images,
+    /// which are FAT file systems. If the image looks like FAT its size is taken from its boot sector instead.
+    pub fn boot_image(&mut self, entry: &BootEntry) -> Result<Vec<u8>> {
+        let offset = entry.load_rba as u64 * self.block_size;
+        let mut size = entry.sector_count as u64 * 512;
+
+        let mut boot_sector = [0u8; 512];
+        read_at(&mut self.reader, offset, &mut boot_sector)?;
+        if let Some(fat_size) = fat_volume_size(&boot_sector).filter(|&s| self.check_extent(offset, s).is_ok()) {
+            size = cmp::max(size, fat_size);
+        }
+        self.check_extent(offset, size)?;
+
+        let mut image = vec![0u8; size as usize];
+        read_at(&mut self.reader, offset, &mut image)?;
+        Ok(image)
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<DirEntry> {
+        let mut entry = self.root.clone();
+        let mut parents = Vec::new();
+
+        for component in path.split(['/', '\\']) {
+            match component {
+                "" | "." => continue,
+                ".." => {
+                    entry = parents.pop().ok_or_else(|| EfiError::from(EfiErrorKind::InvalidParameter))?;
+                    continue;
+                },
+                _ => {},
+            }
+
+            if !entry.is_dir {
+                return Err(EfiErrorKind::InvalidParameter.into());
+            }
+
+            let children = self.list_dir(&entry)?;
+            // Exact match first since Rock Ridge names are case sensitive
+            let child = match children.iter().position(|e| e.name == component) {
+                Some(i) => children[i].clone(),
+                None => children.into_iter()
+                    .find(|e| e.name.eq_ignore_ascii_case(component))
+                    .ok_or_else(|| EfiError::from(EfiErrorKind::NotFound))?,
+            };
+
+            parents.push(entry);
+            entry = child;
+        }
+
+        Ok(entry)
+    }
+
+    fn check_extent(&self, offset: u64, len: u64) -> Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.image_size => Ok(()),
+            _ => Err(EfiErrorKind::VolumeCorrupted.into()),
+        }
+    }
+
+    fn list_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>> {
+        let mut data = Vec::new();
+        for &(offset, len) in &dir.extents {
+            self.check_extent(offset, len)?;
+            if data.len() as u64 + len > self.image_size {
+                return Err(EfiErrorKind::VolumeCorrupted.into());
+            }
+
+            let start = data.len();
+            data.resize(start + len as usize, 0);
+            read_at(&mut self.reader, offset, &mut data[start..])?;
+
+            // Pad to a whole block so that the next extent's records start on a block boundary like they do on disk
+            let padded = (data.len() as u64).div_ceil(self.block_size) * self.block_size;
+            data.resize(padded as usize, 0);
+        }
+
+        let mut entries = Vec::new();
+        let mut pending_extents = Vec::new(); // Extents of a multi-extent file seen so far
+        let mut i = 0;
+        while i < data.len() {
+            let record_len = data[i] as usize;
+            if record_len == 0 {
+                // Records don't straddle logical blocks. The rest of this block is padding.
+                i = (i / self.block_size as usize + 1) * self.block_size as usize;
+                continue;
+            }
+            if record_len < 34 || i + record_len > data.len() {
+                return Err(EfiErrorKind::VolumeCorrupted.into());
+            }
+
+            let record = &data[i..i + record_len];
+            i += record_len;
+
+            let name_len = record[32] as usize;
+            if 33 + name_len > record.len() {
+                return Err(EfiErrorKind::VolumeCorrupted.into());
+            }
+
+            let raw_name = &record[33..33 + name_len];
+            if raw_name == [0] || raw_name == [1] {
+                continue; // "." and ".."
+            }
+
+            let flags = record[25];
+            let mut is_dir = flags & FLAG_DIRECTORY != 0;
+            let mut extent = (le_u32(&record[2..]) as u64 * self.block_size, le_u32(&record[10..]) as u64);
+            if flags & FLAG_MULTI_EXTENT != 0 {
+                pending_extents.push(extent);
+                continue;
+            }
+
+            let system_use = &record[cmp::min(33 + name_len + (1 - name_len % 2), record.len())..];
+            let name = match self.names {
+                Names::Plain => plain_name(raw_name),
+                Names::Joliet => strip_version(&ucs2_be(raw_name)).into(),
+                Names::RockRidge(skip) => {
+                    let rr = self.rock_ridge_info(system_use.get(skip as usize..).unwrap_or(&[]))?;
+                    if rr.relocated {
+                        continue; // Shows up in its original location through a CL entry instead
+                    }
+                    if let Some(child_lba) = rr.child_link {
+                        // A deep directory moved elsewhere. It appears here as an empty file pointing at the real one.
+                        is_dir = true;
+                        extent = (child_lba as u64 * self.block_size, self.dir_size_at(child_lba)?);
+                    }
+                    rr.name.unwrap_or_else(|| plain_name(raw_name))
+                },
+            };
+
+            pending_extents.push(extent);
+            let extents = core::mem::take(&mut pending_extents);
+            entries.push(DirEntry {
+                name,
+                is_dir,
+                size: extents.iter().map(|e| e.1).sum(),
+                extents,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    // Size of the directory whose extent starts at `lba`, read from its "." record
+    fn dir_size_at(&mut self, lba: u32) -> Result<u64> {
+        let mut record = [0u8; 34];
+        read_at(&mut self.reader, lba as u64 * self.block_size, &mut record)?;
+        Ok(le_u32(&record[10..]) as u64)
+    }
+
+    // Looks for the SUSP "SP" entry in the root's "." record which marks a Rock Ridge volume
+    fn rock_ridge_skip(&mut self) -> Result<Option<u8>> {
+        let (offset, _) = self.root.extents[0];
+        let mut record = [0u8; 255];
+        read_at(&mut self.reader, offset, &mut record)?;
+
+        let record_len = record[0] as usize;
+        let system_use = &record[cmp::min(34, record_len)..record_len];
+        let is_sp = system_use.len() >= 7 && &system_use[..2] == b"SP" && system_use[4] == 0xBE && system_use[5] == 0xEF;
+        Ok(if is_sp { Some(system_use[6]) } else { None })
+    }
+
+    fn rock_ridge_info(&mut self, system_use: &[u8]) -> Result<RockRidgeInfo> {
+        let mut info = RockRidgeInfo::default();
+        let mut name = Vec::new();
+        let mut area = system_use.to_vec();
+
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut i = 0;
+            while i + 4 <= area.len() {
+                let len = area[i + 2] as usize;
+                if len < 4 || i + len > area.len() {
+                    break;
+                }
+
+                let entry = &area[i..i + len];
+                match &entry[..2] {
+                    // NM entries with the CURRENT or PARENT flags are for "." and ".." which we skip
+                    b"NM" if len >= 5 && entry[4] & 0x06 == 0 => {
+                        name.extend_from_slice(&entry[5..]);
+                        info.has_name = true;
+                    },
+                    b"CE" if len >= 28 => {
+                        continuation = Some((le_u32(&entry[4..]) as u64, le_u32(&entry[12..]) as u64, le_u32(&entry[20..]) as u64));
+                    },
+                    b"CL" if len >= 12 => info.child_link = Some(le_u32(&entry[4..])),
+                    b"RE" => info.relocated = true,
+                    b"ST" => break,
+                    _ => {},
+                }
+                i += len;
+            }
+
+            match continuation {
+                Some((block, offset, len)) if len <= SECTOR_SIZE => {
+                    area = vec![0u8; len as usize];
+                    read_at(&mut self.reader, block * self.block_size + offset, &mut area)?;
+                },
+                _ => break,
+            }
+        }
+
+        if info.has_name {
+            info.name = Some(String::from_utf8_lossy(&name).into());
+        }
+        Ok(info)
+    }
+}
+
+#[derive(Default)]
+struct RockRidgeInfo {
+    name: Option<String>,
+    has_name: bool,
+    child_link: Option<u32>,
+    relocated: bool,
+}
+
+/// A file on an ISO9660 volume. Implements `io::Read` and `io::Seek`.
+pub struct File<'a, R> {
+    fs: &'a mut FileSystem<R>,
+    extents: Vec<(u64, u64)>,
+    size: u64,
+    pos: u64,
+}
+
+impl<'a, R> File<'a, R> {
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<'a, R: Read + Seek> Read for File<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Find the extent the current position falls in
+        let mut start = 0;
+        for (offset, len) in &self.extents {
+            if self.pos < start + len {
+                let within = self.pos - start;
+                let n = cmp::min(buf.len() as u64, len - within) as usize;
+                self.fs.reader.seek(SeekFrom::Start(offset + within))?;
+                let n = self.fs.reader.read(&mut buf[..n])?;
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            start += len;
+        }
+        Ok(0)
+    }
+}
+
+impl<'a, R> Seek for File<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            },
+            SeekFrom::End(n) => (self.size, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+fn root_entry(record: &[u8], block_size: u64) -> DirEntry {
+    let size = le_u32(&record[10..]) as u64;
+    DirEntry {
+        name: String::new(),
+        is_dir: true,
+        size,
+        extents: vec![(le_u32(&record[2..]) as u64 * block_size, size)],
+    }
+}
+
+// Joliet supplementary descriptors are marked by one of three UCS-2 escape sequences
+fn is_joliet(descriptor: &[u8]) -> bool {
+    let escape = &descriptor[88..91];
+    escape == b"%/@" || escape == b"%/C" || escape == b"%/E"
+}
+
+fn ucs2_be(bytes: &[u8]) -> String {
+    let utf16 = bytes.chunks(2)
+        .filter(|c| c.len() == 2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&utf16)
+}
+
+fn strip_version(name: &str) -> &str {
+    match name.rfind(';') {
+        Some(i) => &name[..i],
+        None => name,
+    }
+}
+
+// "README.TXT;1" -> "README.TXT", "KERNEL.;1" -> "KERNEL"
+fn plain_name(raw: &[u8]) -> String {
+    let name = String::from_utf8_lossy(raw);
+    strip_version(&name).trim_end_matches('.').into()
+}
+
+// Size in bytes of the FAT volume whose boot sector is `buf`, if it is one
+fn fat_volume_size(buf: &[u8]) -> Option<u64> {
+    if buf[510] != 0x55 || buf[511] != 0xAA {
+        return None;
+    }
+
+    let bytes_per_sector = le_u16(&buf[11..]) as u64;
+    if ![512, 1024, 2048, 4096].contains(&bytes_per_sector) || buf[13] == 0 || !buf[13].is_power_of_two() {
+        return None;
+    }
+
+    let total_sectors = match le_u16(&buf[19..]) {
+        0 => le_u32(&buf[32..]) as u64,
+        n => n as u64,
+    };
+    Some(total_sectors * bytes_per_sector)
+}
+
+fn le_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Cursor;
+    use crate::fs::fat;
+    use crate::io::Write;
+
+    const KERNEL: &[u8] = b"this is not really a kernel";
+
+    // Sector numbers of the structures in the test image
+    const BOOT_CATALOG: u32 = 20;
+    const ROOT: u32 = 21;
+    const BOOT_DIR: u32 = 22;
+    const JOLIET_ROOT: u32 = 23;
+    const JOLIET_BOOT_DIR: u32 = 24;
+    const KERNEL_DATA: u32 = 25;
+    const CONTINUATION: u32 = 26;
+    const EFI_IMAGE: u32 = 27;
+    const EFI_IMAGE_SIZE: usize = 128 * 1024;
+
+    fn record(name: &[u8], lba: u32, size: u32, flags: u8, system_use: &[u8]) -> Vec<u8> {
+        let pad = 1 - name.len() % 2;
+        let mut len = 33 + name.len() + pad + system_use.len();
+        len += len % 2;
+
+        let mut r = vec![0u8; len];
+        r[0] = len as u8;
+        r[2..6].copy_from_slice(&lba.to_le_bytes());
+        r[6..10].copy_from_slice(&lba.to_be_bytes());
+        r[10..14].copy_from_slice(&size.to_le_bytes());
+        r[14..18].copy_from_slice(&size.to_be_bytes());
+        r[25] = flags;
+        r[32] = name.len() as u8;
+        r[33..33 + name.len()].copy_from_slice(name);
+        r[33 + name.len() + pad..33 + name.len() + pad + system_use.len()].copy_from_slice(system_use);
+        r
+    }
+
+    fn nm(name: &str) -> Vec<u8> {
+        let mut e = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+        e.extend_from_slice(name.as_bytes());
+        e
+    }
+
+    fn ce(lba: u32, offset: u32, len: u32) -> Vec<u8> {
+        let mut e = vec![0u8; 28];
+        e[..4].copy_from_slice(&[b'C', b'E', 28, 1]);
+        e[4..8].copy_from_slice(&lba.to_le_bytes());
+        e[12..16].copy_from_slice(&offset.to_le_bytes());
+        e[20..24].copy_from_slice(&len.to_le_bytes());
+        e
+    }
+
+    fn ucs2(name: &str) -> Vec<u8> {
+        name.encode_utf16().flat_map(|c| c.to_be_bytes().to_vec()).collect()
+    }
+
+    fn descriptor(kind: u8) -> Vec<u8> {
+        let mut d = vec![0u8; 2048];
+        d[0] = kind;
+        d[1..6].copy_from_slice(STANDARD_ID);
+        d[6] = 1;
+        d
+    }
+
+    fn volume_descriptor(kind: u8, volume_id: &[u8], root_lba: u32) -> Vec<u8> {
+        let mut d = descriptor(kind);
+        d[40..40 + volume_id.len()].copy_from_slice(volume_id);
+        d[128..130].copy_from_slice(&2048u16.to_le_bytes());
+        d[156..190].copy_from_slice(&record(&[0], root_lba, 2048, FLAG_DIRECTORY, &[]));
+        d
+    }
+
+    fn put(image: &mut [u8], lba: u32, data: &[u8]) {
+        let offset = lba as usize * 2048;
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn dir(records: &[Vec<u8>]) -> Vec<u8> {
+        records.concat()
+    }
+
+    fn efi_boot_image() -> Vec<u8> {
+        let mut fs = fat::FileSystem::format(Cursor::new(vec![0u8; EFI_IMAGE_SIZE]), &fat::FormatOptions::default()).unwrap();
+        fs.create_dir("EFI").unwrap();
+        fs.create_dir("EFI/BOOT").unwrap();
+        fs.create("EFI/BOOT/BOOTX64.EFI").unwrap().write_all(b"MZ").unwrap();
+        fs.into_inner().into_inner()
+    }
+
+    fn build_image(rock_ridge: bool, joliet: bool) -> Vec<u8> {
+        let mut image = vec![0u8; EFI_IMAGE as usize * 2048 + EFI_IMAGE_SIZE];
+
+        let mut lba = 16;
+        put(&mut image, lba, &volume_descriptor(VD_PRIMARY, b"TEST_ISO", ROOT));
+        lba += 1;
+
+        let mut boot_record = descriptor(VD_BOOT_RECORD);
+        boot_record[7..7 + EL_TORITO_ID.len()].copy_from_slice(EL_TORITO_ID);
+        boot_record[71..75].copy_from_slice(&BOOT_CATALOG.to_le_bytes());
+        put(&mut image, lba, &boot_record);
+        lba += 1;
+
+        if joliet {
+            let mut svd = volume_descriptor(VD_SUPPLEMENTARY, &ucs2("Joliet Volume"), JOLIET_ROOT);
+            svd[88..91].copy_from_slice(b"%/E");
+            put(&mut image, lba, &svd);
+            lba += 1;
+        }
+        put(&mut image, lba, &descriptor(VD_TERMINATOR));
+
+        // Boot catalog: validation entry for x86, a BIOS default entry and one EFI section
+        let mut catalog = vec![0u8; 2048];
+        catalog[0] = 1;
+        catalog[30] = 0x55;
+        catalog[31] = 0xAA;
+        let sum = catalog[..32].chunks(2).fold(0u16, |s, w| s.wrapping_add(le_u16(w)));
+        catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+        catalog[32] = 0x88;
+        catalog[38..40].copy_from_slice(&4u16.to_le_bytes());
+        catalog[40..44].copy_from_slice(&KERNEL_DATA.to_le_bytes());
+        catalog[64] = 0x91;
+        catalog[65] = PLATFORM_EFI;
+        catalog[66..68].copy_from_slice(&1u16.to_le_bytes());
+        catalog[96] = 0x88;
+        catalog[102..104].copy_from_slice(&1u16.to_le_bytes()); // Truncated sector count like xorriso writes
+        catalog[104..108].copy_from_slice(&EFI_IMAGE.to_le_bytes());
+        put(&mut image, BOOT_CATALOG, &catalog);
+
+        let su = |entries: &[Vec<u8>]| if rock_ridge { entries.concat() } else { Vec::new() };
+        let sp = vec![b'S', b'P', 7, 1, 0xBE, 0xEF, 0];
+
+        put(&mut image, ROOT, &dir(&[
+            record(&[0], ROOT, 2048, FLAG_DIRECTORY, &su(&[sp])),
+            record(&[1], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(b"BOOT", BOOT_DIR, 2048, FLAG_DIRECTORY, &su(&[nm("boot")])),
+            record(b"README.TXT;1", KERNEL_DATA, 5, 0, &su(&[nm("ReadMe.txt")])),
+        ]));
+
+        // The long name is split across the record and a continuation area
+        put(&mut image, CONTINUATION, &nm("-6.1.0-generic"));
+        put(&mut image, BOOT_DIR, &dir(&[
+            record(&[0], BOOT_DIR, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(b"VMLINUZ.;1", KERNEL_DATA, KERNEL.len() as u32, 0, &su(&[nm("vmlinuz"), ce(CONTINUATION, 0, 19)])),
+        ]));
+
+        put(&mut image, JOLIET_ROOT, &dir(&[
+            record(&[0], JOLIET_ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], JOLIET_ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(&ucs2("boot"), JOLIET_BOOT_DIR, 2048, FLAG_DIRECTORY, &[]),
+        ]));
+        put(&mut image, JOLIET_BOOT_DIR, &dir(&[
+            record(&[0], JOLIET_BOOT_DIR, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], JOLIET_ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(&ucs2("vmlinuz-joliet;1"), KERNEL_DATA, KERNEL.len() as u32, 0, &[]),
+        ]));
+
+        put(&mut image, KERNEL_DATA, KERNEL);
+        put(&mut image, EFI_IMAGE, &efi_boot_image());
+        image
+    }
+
+    fn mount(rock_ridge: bool, joliet: bool) -> FileSystem<Cursor<Vec<u8>>> {
+        FileSystem::mount(Cursor::new(build_image(rock_ridge, joliet))).unwrap()
+    }
+
+    fn read_file(fs: &mut FileSystem<Cursor<Vec<u8>>>, path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        fs.open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn rock_ridge_names_take_precedence() {
+        let mut fs = mount(true, true);
+        assert!(fs.has_rock_ridge());
+        assert_eq!(fs.volume_id(), "TEST_ISO");
+
+        let names = fs.read_dir("/").unwrap().into_iter().map(|e| e.name).collect::<Vec<_>>();
+        assert_eq!(names, ["boot", "ReadMe.txt"]);
+        assert_eq!(read_file(&mut fs, "/boot/vmlinuz-6.1.0-generic"), KERNEL);
+        assert_eq!(read_file(&mut fs, "readme.txt"), &KERNEL[..5]);
+    }
+
+    #[test]
+    fn joliet_names_are_used_without_rock_ridge() {
+        let mut fs = mount(false, true);
+        assert!(fs.has_joliet());
+        assert_eq!(fs.volume_id(), "Joliet Volume");
+        assert_eq!(read_file(&mut fs, "/boot/vmlinuz-joliet"), KERNEL);
+    }
+
+    #[test]
+    fn plain_names_drop_version_and_trailing_dot() {
+        let mut fs = mount(false, false);
+        assert!(!fs.has_rock_ridge() && !fs.has_joliet());
+
+        let entry = fs.metadata("/BOOT/VMLINUZ").unwrap();
+        assert!(entry.is_file());
+        assert_eq!(entry.size(), KERNEL.len() as u64);
+        assert_eq!(read_file(&mut fs, "boot/../boot/vmlinuz"), KERNEL);
+        assert_eq!(fs.metadata("/BOOT/MISSING").err().unwrap().kind(), EfiErrorKind::NotFound);
+    }
+
+    #[test]
+    fn seek_within_file() {
+        let mut fs = mount(true, false);
+        let mut file = fs.open("/boot/vmlinuz-6.1.0-generic").unwrap();
+        file.seek(SeekFrom::End(-6)).unwrap();
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"kernel");
+    }
+
+    #[test]
+    fn el_torito_efi_image_is_extracted() {
+        let mut fs = mount(true, true);
+        let entries = fs.boot_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].platform_id, PLATFORM_X86);
+        assert!(entries[0].bootable);
+
+        let efi = entries.iter().find(|e| e.is_efi()).unwrap();
+        assert_eq!(efi.load_rba, EFI_IMAGE);
+
+        // The recorded sector count is 1 but the whole FAT image comes back
+        let image = fs.boot_image(efi).unwrap();
+        assert_eq!(image.len(), EFI_IMAGE_SIZE);
+
+        let mut esp = fat::FileSystem::mount(Cursor::new(image)).unwrap();
+        let mut loader = Vec::new();
+        esp.open("/EFI/BOOT/BOOTX64.EFI").unwrap().read_to_end(&mut loader).unwrap();
+        assert_eq!(loader, b"MZ");
+    }
+
+    #[test]
+    fn boot_images_larger_than_the_volume_are_rejected() {
+        let mut fs = mount(true, true);
+        let efi = fs.boot_entries().unwrap().into_iter().find(|e| e.is_efi()).unwrap();
+
+        let huge_count = BootEntry { sector_count: u16::MAX, ..efi.clone() };
+        assert_eq!(fs.boot_image(&huge_count).err().unwrap().kind(), EfiErrorKind::VolumeCorrupted);
+
+        // A FAT boot sector claiming a size past the end of the volume is ignored rather than trusted
+        let mut image = build_image(true, true);
+        let total_sectors = EFI_IMAGE as usize * 2048 + 19;
+        image[total_sectors..total_sectors + 2].copy_from_slice(&0u16.to_le_bytes());
+        image[total_sectors + 13..total_sectors + 17].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut fs = FileSystem::mount(Cursor::new(image)).unwrap();
+        assert_eq!(fs.boot_image(&efi).unwrap().len(), 512);
+    }
+
+    #[test]
+    fn directories_larger_than_the_volume_are_rejected() {
+        let mut image = build_image(false, false);
+        put(&mut image, ROOT, &vec![0u8; 2048]);
+        put(&mut image, ROOT, &dir(&[
+            record(&[0], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(b"BOOT", BOOT_DIR, u32::MAX, FLAG_DIRECTORY, &[]),
+        ]));
+
+        let mut fs = FileSystem::mount(Cursor::new(image)).unwrap();
+        assert_eq!(fs.read_dir("/BOOT").err().unwrap().kind(), EfiErrorKind::VolumeCorrupted);
+    }
+
+    #[test]
+    fn multi_extent_directories_are_read_whole() {
+        let mut image = build_image(false, false);
+        let first = (image.len() / 2048) as u32;
+        image.resize(image.len() + 2 * 2048, 0);
+
+        put(&mut image, ROOT, &vec![0u8; 2048]);
+        put(&mut image, ROOT, &dir(&[
+            record(&[0], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(b"SPLIT", first, 2048, FLAG_DIRECTORY | FLAG_MULTI_EXTENT, &[]),
+            record(b"SPLIT", first + 1, 100, FLAG_DIRECTORY, &[]),
+        ]));
+        put(&mut image, first, &dir(&[
+            record(&[0], first, 2048, FLAG_DIRECTORY, &[]),
+            record(&[1], ROOT, 2048, FLAG_DIRECTORY, &[]),
+            record(b"A.TXT;1", KERNEL_DATA, 4, 0, &[]),
+        ]));
+        put(&mut image, first + 1, &record(b"B.TXT;1", KERNEL_DATA, 7, 0, &[]));
+
+        let mut fs = FileSystem::mount(Cursor::new(image)).unwrap();
+        let names = fs.read_dir("/SPLIT").unwrap().into_iter().map(|e| e.name).collect::<Vec<_>>();
+        assert_eq!(names, ["A.TXT", "B.TXT"]);
+        assert_eq!(read_file(&mut fs, "/split/b.txt"), &KERNEL[..7]);
+    }
+
+    #[test]
+    fn non_iso_data_is_rejected() {
+        let result = FileSystem::mount(Cursor::new(vec![0u8; 64 * 1024]));
+        assert_eq!(result.err().unwrap().kind(), EfiErrorKind::VolumeCorrupted);
+    }
+}
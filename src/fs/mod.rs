@@ -0,0 +1,7 @@
+//! File systems implemented in Rust on top of the crate's `io::Read`, `io::Write` and `io::Seek` traits.
+//!
+//! Unlike the firmware's Simple File System protocol these work over anything that implements
+//! those traits: a `disk::BlockIo` device, a RAM disk buffer or a disk image file on the host.
+
+pub mod fat;
+pub mod iso9660;
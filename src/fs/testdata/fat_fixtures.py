@@ -0,0 +1,281 @@
+#!/usr/bin/env python3
+"""Builds the FAT test fixtures used by src/fs/fat.rs.
+
+The images are stored sparse (see `sparsify`) since a FAT32 volume needs at least 65525
+clusters and is therefore ~34 MiB, almost all of it zeroes.
+
+    fat_fixtures.py --from-images fat12.img fat16.img fat32.img
+        Sparsifies images made with mkfs.fat and mtools by make_fat_fixtures.sh. This is the
+        preferred way of producing the fixtures.
+
+    fat_fixtures.py --tree DIR
+        Writes the directory tree the fixtures contain to DIR.
+
+    fat_fixtures.py --write
+        Writes the images with the FAT writer below instead, for when dosfstools and mtools
+        aren't available. It follows the on-disk layout of the FAT spec and the conventions of
+        mkfs.fat and mtools (NTRes case flags for lower case 8.3 names, a volume label entry in
+        the root, FSInfo and backup boot sector on FAT32) and shares no code with fat.rs.
+
+Both produce the same directory tree, which the tests in fat.rs check for (see `CONTENTS`).
+"""
+
+import struct
+import sys
+
+SECTOR = 512
+LABEL = b"FIXTURE    "
+VOLUME_ID = 0x1234ABCD
+# 2024-01-02 03:04:06
+FAT_TIME = (3 << 11) | (4 << 5) | (6 // 2)
+FAT_DATE = ((2024 - 1980) << 9) | (1 << 5) | 2
+
+
+def pattern(n):
+    return bytes(i * 7 % 251 for i in range(n))
+
+
+# (path, contents). Directories are created as needed.
+CONTENTS = [
+    ("hello.txt", b"Hello, FAT!\n"),
+    ("A long file name with spaces.txt", b"long name\n" * 3),
+    ("EFI/BOOT/BOOTX64.EFI", b"MZ" + pattern(6000)),
+    ("docs/Nested Directory/notes.md", b"# Notes\n\nNested two levels deep.\n"),
+] + [("many/file number %02d.txt" % i, b"file %d\n" % i) for i in range(25)]
+
+# (name, total sectors, sectors per cluster, reserved sectors, root entries, FAT bits)
+LAYOUTS = [
+    ("fat12", 2048, 4, 1, 512, 12),
+    ("fat16", 16384, 2, 1, 512, 16),
+    ("fat32", 69632, 1, 32, 0, 32),
+]
+
+
+def sparsify(image):
+    """Header of the total size as a u64, then (u64 offset, u32 length, data) for each run of non-zero sectors"""
+    out = bytearray(struct.pack("<Q", len(image)))
+    sector = 0
+    count = len(image) // SECTOR
+    while sector < count:
+        if not any(image[sector * SECTOR:(sector + 1) * SECTOR]):
+            sector += 1
+            continue
+        start = sector
+        while sector < count and any(image[sector * SECTOR:(sector + 1) * SECTOR]):
+            sector += 1
+        data = image[start * SECTOR:sector * SECTOR]
+        out += struct.pack("<QI", start * SECTOR, len(data)) + data
+    return bytes(out)
+
+
+class Fat:
+    def __init__(self, total, spc, reserved, root_entries, bits):
+        self.total, self.spc, self.reserved, self.root_entries, self.bits = total, spc, reserved, root_entries, bits
+        self.root_sectors = (root_entries * 32 + SECTOR - 1) // SECTOR
+
+        # Grow the FAT until it's large enough for the clusters left over after it
+        self.fat_sectors = 1
+        while True:
+            clusters = (total - reserved - self.root_sectors - 2 * self.fat_sectors) // spc
+            needed = ((clusters + 2) * bits // 8 + SECTOR) // SECTOR
+            if needed <= self.fat_sectors:
+                break
+            self.fat_sectors = needed
+        self.clusters = clusters
+        self.data_start = reserved + 2 * self.fat_sectors + self.root_sectors
+        self.cluster_size = spc * SECTOR
+        self.image = bytearray(total * SECTOR)
+        self.fat = [0] * (clusters + 2)
+        self.fat[0] = {12: 0xFF8, 16: 0xFFF8, 32: 0x0FFFFFF8}[bits]
+        self.fat[1] = self.eoc()
+        self.next_cluster = 2
+        self.root_cluster = self.alloc(1)[0] if bits == 32 else 0
+        self.dirs = {"": (self.root_cluster, [])}  # path -> (first cluster, entries)
+
+    def eoc(self):
+        return {12: 0xFFF, 16: 0xFFFF, 32: 0x0FFFFFFF}[self.bits]
+
+    def alloc(self, count):
+        chain = list(range(self.next_cluster, self.next_cluster + count))
+        self.next_cluster += count
+        for a, b in zip(chain, chain[1:]):
+            self.fat[a] = b
+        self.fat[chain[-1]] = self.eoc()
+        return chain
+
+    def cluster_offset(self, cluster):
+        return (self.data_start + (cluster - 2) * self.spc) * SECTOR
+
+    def write_chain(self, chain, data):
+        for i, cluster in enumerate(chain):
+            part = data[i * self.cluster_size:(i + 1) * self.cluster_size]
+            off = self.cluster_offset(cluster)
+            self.image[off:off + len(part)] = part
+
+    def short_name(self, name, siblings):
+        """Returns the 11 byte 8.3 name, the NTRes case flags and whether a long name is needed"""
+        base, _, ext = name.rpartition(".") if "." in name else (name, "", "")
+        valid = lambda s, n: 0 < len(s) <= n and all(c.isalnum() and c.isascii() for c in s)
+        fits = valid(base, 8) and (ext == "" or valid(ext, 3))
+        if fits and (base == base.upper() or base == base.lower()) and (ext == ext.upper() or ext == ext.lower()):
+            flags = (0x08 if base != base.upper() else 0) | (0x10 if ext != ext.upper() else 0)
+            return (base.upper().ljust(8) + ext.upper().ljust(3)).encode(), flags, False
+
+        clean = lambda s: "".join(c for c in s.upper() if c.isascii() and c.isalnum())
+        base, ext = clean(base), clean(ext)[:3]
+        n = 1
+        while True:
+            tail = "~%d" % n
+            short = (base[:8 - len(tail)] + tail).ljust(8) + ext.ljust(3)
+            if short.encode() not in siblings:
+                return short.encode(), 0, True
+            n += 1
+
+    def add_entry(self, dir_path, name, attr, cluster, size):
+        entries = self.dirs[dir_path][1]
+        siblings = [e[:11] for e in entries]
+        short, flags, needs_long = self.short_name(name, siblings)
+        if needs_long:
+            checksum = 0
+            for b in short:
+                checksum = (((checksum & 1) << 7) + (checksum >> 1) + b) & 0xFF
+            units = list(struct.unpack("<%dH" % len(name), name.encode("utf-16-le")))
+            parts = (len(units) + 12) // 13
+            units += [0x0000] if len(units) % 13 else []
+            units += [0xFFFF] * (parts * 13 - len(units))
+            for ordinal in range(parts, 0, -1):
+                chunk = units[(ordinal - 1) * 13:ordinal * 13]
+                entry = bytearray(32)
+                entry[0] = ordinal | (0x40 if ordinal == parts else 0)
+                entry[11] = 0x0F
+                entry[13] = checksum
+                for i, offset in enumerate([1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]):
+                    struct.pack_into("<H", entry, offset, chunk[i])
+                entries.append(bytes(entry))
+        entries.append(self.short_entry(short, attr, cluster, size, flags))
+
+    def short_entry(self, short, attr, cluster, size, flags=0):
+        entry = bytearray(32)
+        entry[:11] = short
+        entry[11] = attr
+        entry[12] = flags
+        struct.pack_into("<HHHHHHHI", entry, 14, FAT_TIME, FAT_DATE, FAT_DATE, cluster >> 16, FAT_TIME, FAT_DATE, cluster & 0xFFFF, size)
+        return bytes(entry)
+
+    def mkdir(self, path):
+        if path in self.dirs:
+            return
+        parent, _, name = path.rpartition("/")
+        if parent:
+            self.mkdir(parent)
+        cluster = self.alloc(1)[0]  # Grown in `finish` if the entries don't fit
+        parent_cluster = self.dirs[parent][0]
+        self.dirs[path] = (cluster, [
+            self.short_entry(b".          ", 0x10, cluster, 0),
+            self.short_entry(b"..         ", 0x10, 0 if parent_cluster == self.root_cluster else parent_cluster, 0),
+        ])
+        self.add_entry(parent, name, 0x10, cluster, 0)
+
+    def add_file(self, path, data):
+        parent, _, name = path.rpartition("/")
+        if parent:
+            self.mkdir(parent)
+        chain = self.alloc((len(data) + self.cluster_size - 1) // self.cluster_size)
+        self.write_chain(chain, data)
+        self.add_entry(parent, name, 0x20, chain[0], len(data))
+
+    def finish(self):
+        # Directories with more entries than fit in their first cluster get more clusters at the end of the chain
+        for path, (first, entries) in self.dirs.items():
+            data = b"".join(entries)
+            if path == "" and self.bits != 32:
+                off = (self.reserved + 2 * self.fat_sectors) * SECTOR
+                assert len(data) <= self.root_entries * 32
+                self.image[off:off + len(data)] = data
+                continue
+            chain = [first]
+            while len(chain) * self.cluster_size < len(data):
+                extra = self.alloc(1)[0]
+                self.fat[chain[-1]] = extra
+                chain.append(extra)
+            self.write_chain(chain, data)
+
+        fat = bytearray(self.fat_sectors * SECTOR)
+        for i, value in enumerate(self.fat):
+            if self.bits == 32:
+                struct.pack_into("<I", fat, i * 4, value)
+            elif self.bits == 16:
+                struct.pack_into("<H", fat, i * 2, value)
+            else:
+                off = i * 3 // 2
+                word = struct.unpack_from("<H", fat, off)[0]
+                word = (word & 0x000F) | (value << 4) if i % 2 else (word & 0xF000) | value
+                struct.pack_into("<H", fat, off, word)
+        for n in range(2):
+            off = (self.reserved + n * self.fat_sectors) * SECTOR
+            self.image[off:off + len(fat)] = fat
+
+        boot = bytearray(SECTOR)
+        boot[:3] = b"\xEB\x3C\x90" if self.bits != 32 else b"\xEB\x58\x90"
+        boot[3:11] = b"mkfs.fat"
+        struct.pack_into("<HBHBHHBHHHII", boot, 11, SECTOR, self.spc, self.reserved, 2, self.root_entries,
+                         self.total if self.total < 0x10000 else 0, 0xF8,
+                         self.fat_sectors if self.bits != 32 else 0, 32, 64, 0,
+                         0 if self.total < 0x10000 else self.total)
+        if self.bits == 32:
+            struct.pack_into("<IHHIHH", boot, 36, self.fat_sectors, 0, 0, self.root_cluster, 1, 6)
+            ext = 64
+        else:
+            ext = 36
+        boot[ext] = 0x80
+        boot[ext + 2] = 0x29
+        struct.pack_into("<I", boot, ext + 3, VOLUME_ID)
+        boot[ext + 7:ext + 18] = LABEL
+        boot[ext + 18:ext + 26] = {12: b"FAT12   ", 16: b"FAT16   ", 32: b"FAT32   "}[self.bits]
+        boot[510:512] = b"\x55\xAA"
+        self.image[:SECTOR] = boot
+
+        if self.bits == 32:
+            info = bytearray(SECTOR)
+            struct.pack_into("<I", info, 0, 0x41615252)
+            struct.pack_into("<III", info, 484, 0x61417272, self.clusters - (self.next_cluster - 2), self.next_cluster)
+            struct.pack_into("<I", info, 508, 0xAA550000)
+            self.image[SECTOR:2 * SECTOR] = info
+            self.image[6 * SECTOR:8 * SECTOR] = self.image[:2 * SECTOR]
+
+        return bytes(self.image)
+
+
+def write_images():
+    for name, total, spc, reserved, root_entries, bits in LAYOUTS:
+        fat = Fat(total, spc, reserved, root_entries, bits)
+        fat.dirs[""][1].append(fat.short_entry(LABEL, 0x08, 0, 0))  # mkfs.fat -n also adds a label entry
+        for path, data in CONTENTS:
+            fat.add_file(path, data)
+        with open(name + ".img.sparse", "wb") as f:
+            f.write(sparsify(fat.finish()))
+
+
+def from_images(paths):
+    for path in paths:
+        with open(path, "rb") as f:
+            image = f.read()
+        with open(path + ".sparse", "wb") as f:
+            f.write(sparsify(image))
+
+
+if __name__ == "__main__":
+    if sys.argv[1:2] == ["--write"]:
+        write_images()
+    elif sys.argv[1:2] == ["--from-images"]:
+        from_images(sys.argv[2:])
+    elif sys.argv[1:2] == ["--tree"]:
+        # Used by make_fat_fixtures.sh to populate mkfs.fat images with the same tree
+        import os
+        for path, data in CONTENTS:
+            path = os.path.join(sys.argv[2], path)
+            os.makedirs(os.path.dirname(path), exist_ok=True)
+            with open(path, "wb") as f:
+                f.write(data)
+    else:
+        sys.exit(__doc__)
@@ -0,0 +1,20 @@
+#!/bin/sh
+# Regenerates the FAT fixtures with mkfs.fat (dosfstools) and mtools. Run from this directory.
+set -e
+
+tree=$(mktemp -d)
+trap 'rm -rf "$tree" fat12.img fat16.img fat32.img' EXIT
+./fat_fixtures.py --tree "$tree"
+
+make_image() {
+    rm -f "$1.img"
+    mkfs.fat -C -S 512 -s "$2" -F "$3" -n FIXTURE -i 1234ABCD "$1.img" "$4"
+    mcopy -s -i "$1.img" "$tree"/* ::/
+}
+
+# Sizes in KiB, chosen so that each gets the intended FAT type from its cluster count
+make_image fat12 4 12 1024
+make_image fat16 2 16 8192
+make_image fat32 1 32 34816
+
+./fat_fixtures.py --from-images fat12.img fat16.img fat32.img
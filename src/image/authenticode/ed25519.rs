@@ -0,0 +1,396 @@
+//! Ed25519 signature verification (RFC 8032).
+//!
+//! Follows the structure of TweetNaCl: field elements are sixteen 16-bit limbs held in `i64`s
+//! and points are in extended coordinates. Only verification is provided, so nothing here
+//! needs to be constant time.
+
+use super::sha2::Sha512;
+
+type Fe = [i64; 16];
+type Point = [Fe; 4]; // X, Y, Z, T
+
+const ZERO: Fe = [0; 16];
+const ONE: Fe = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+// The square root of -1 mod p
+const SQRT_M1: [u8; 32] = [
+    0xb0, 0xa0, 0x0e, 0x4a, 0x27, 0x1b, 0xee, 0xc4, 0x78, 0xe4, 0x2f, 0xad, 0x06, 0x18, 0x43, 0x2f,
+    0xa7, 0xd7, 0xfb, 0x3d, 0x99, 0x00, 0x4d, 0x2b, 0x0b, 0xdf, 0xc1, 0x4f, 0x80, 0x24, 0x83, 0x2b,
+];
+
+// The encoding of the base point, i.e. y = 4/5 with x positive
+const BASE: [u8; 32] = [
+    0x58, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+    0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+];
+
+// The order of the base point, little endian
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+/// Verifies `signature` over `message` with `public_key`.
+///
+/// Rejects non-canonical `S` values so that signatures can't be made malleable.
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let consts = Constants::new();
+
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&signature[32..]);
+    if !is_canonical_scalar(&s) {
+        return false;
+    }
+
+    let neg_a = match unpack_neg(&consts, public_key) {
+        Some(p) => p,
+        None => return false,
+    };
+
+    let mut hasher = Sha512::new();
+    hasher.update(&signature[..32]);
+    hasher.update(public_key);
+    hasher.update(message);
+    let h = reduce(&hasher.finish());
+
+    // R must equal [S]B - [h]A
+    let mut p = scalar_mult(&consts, &neg_a, &h);
+    let q = scalar_mult(&consts, &consts.base, &s);
+    add(&consts, &mut p, &q);
+
+    pack(&p)[..] == signature[..32]
+}
+
+struct Constants {
+    d2: Fe,
+    d: Fe,
+    sqrt_m1: Fe,
+    base: Point,
+}
+
+impl Constants {
+    fn new() -> Self {
+        // d = -121665/121666
+        let mut num = ZERO;
+        num[0] = 121_665;
+        let mut den = ZERO;
+        den[0] = 121_666;
+        let d = mul(&sub(&ZERO, &num), &invert(&den));
+
+        let mut consts = Self { d2: add_fe(&d, &d), d, sqrt_m1: unpack(&SQRT_M1), base: [ZERO; 4] };
+        let neg_base = unpack_neg(&consts, &BASE).expect("base point decodes");
+        consts.base = [sub(&ZERO, &neg_base[0]), neg_base[1], neg_base[2], sub(&ZERO, &neg_base[3])];
+        consts
+    }
+}
+
+fn carry(o: &mut Fe) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+// Swaps p and q if b is 1
+fn select(p: &mut Fe, q: &mut Fe, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack_fe(n: &Fe) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        let mut m = ZERO;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        select(&mut t, &mut m, 1 - b);
+    }
+
+    let mut o = [0u8; 32];
+    for i in 0..16 {
+        o[2 * i] = (t[i] & 0xff) as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn unpack(n: &[u8; 32]) -> Fe {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn equal(a: &Fe, b: &Fe) -> bool {
+    pack_fe(a) == pack_fe(b)
+}
+
+fn parity(a: &Fe) -> u8 {
+    pack_fe(a)[0] & 1
+}
+
+fn add_fe(a: &Fe, b: &Fe) -> Fe {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn sub(a: &Fe, b: &Fe) -> Fe {
+    let mut o = ZERO;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul(a: &Fe, b: &Fe) -> Fe {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+
+    let mut o = ZERO;
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+fn square(a: &Fe) -> Fe {
+    mul(a, a)
+}
+
+// a^(p-2)
+fn invert(a: &Fe) -> Fe {
+    let mut c = *a;
+    for i in (0..254).rev() {
+        c = square(&c);
+        if i != 2 && i != 4 {
+            c = mul(&c, a);
+        }
+    }
+    c
+}
+
+// a^((p-5)/8)
+fn pow2523(a: &Fe) -> Fe {
+    let mut c = *a;
+    for i in (0..251).rev() {
+        c = square(&c);
+        if i != 1 {
+            c = mul(&c, a);
+        }
+    }
+    c
+}
+
+fn add(consts: &Constants, p: &mut Point, q: &Point) {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add_fe(&p[0], &p[1]), &add_fe(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &consts.d2);
+    let d = mul(&p[2], &q[2]);
+    let d = add_fe(&d, &d);
+    let e = sub(&b, &a);
+    let f = sub(&d, &c);
+    let g = add_fe(&d, &c);
+    let h = add_fe(&b, &a);
+
+    p[0] = mul(&e, &f);
+    p[1] = mul(&h, &g);
+    p[2] = mul(&g, &f);
+    p[3] = mul(&e, &h);
+}
+
+fn swap(p: &mut Point, q: &mut Point, b: i64) {
+    for i in 0..4 {
+        select(&mut p[i], &mut q[i], b);
+    }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = invert(&p[2]);
+    let tx = mul(&p[0], &zi);
+    let ty = mul(&p[1], &zi);
+    let mut r = pack_fe(&ty);
+    r[31] ^= parity(&tx) << 7;
+    r
+}
+
+fn scalar_mult(consts: &Constants, q: &Point, s: &[u8; 32]) -> Point {
+    let mut p = [ZERO, ONE, ONE, ZERO];
+    let mut q = *q;
+    for i in (0..256).rev() {
+        let b = ((s[i / 8] >> (i & 7)) & 1) as i64;
+        swap(&mut p, &mut q, b);
+        let p_copy = p;
+        add(consts, &mut q, &p_copy);
+        add(consts, &mut p, &p_copy);
+        swap(&mut p, &mut q, b);
+    }
+    p
+}
+
+// Decodes a point and negates it. None if the encoding isn't a point on the curve.
+fn unpack_neg(consts: &Constants, bytes: &[u8; 32]) -> Option<Point> {
+    let z = ONE;
+    let y = unpack(bytes);
+    let num = square(&y);
+    let den = mul(&num, &consts.d);
+    let num = sub(&num, &z);
+    let den = add_fe(&z, &den);
+
+    let den2 = square(&den);
+    let den4 = square(&den2);
+    let den6 = mul(&den4, &den2);
+    let mut t = mul(&mul(&den6, &num), &den);
+
+    t = pow2523(&t);
+    t = mul(&mul(&mul(&t, &num), &den), &den);
+    let mut x = mul(&t, &den);
+
+    let chk = mul(&square(&x), &den);
+    if !equal(&chk, &num) {
+        x = mul(&x, &consts.sqrt_m1);
+    }
+
+    let chk = mul(&square(&x), &den);
+    if !equal(&chk, &num) {
+        return None;
+    }
+
+    if parity(&x) == (bytes[31] >> 7) {
+        x = sub(&ZERO, &x);
+    }
+
+    let t = mul(&x, &y);
+    Some([x, y, z, t])
+}
+
+// Reduces a 512-bit little endian number mod L
+fn reduce(bytes: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for i in 0..64 {
+        x[i] = bytes[i] as i64;
+    }
+
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut r = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+// True if s < L
+fn is_canonical_scalar(s: &[u8; 32]) -> bool {
+    for i in (0..32).rev() {
+        let l = L[i] as u8;
+        if s[i] != l {
+            return s[i] < l;
+        }
+    }
+    false // Equal to L
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex<const N: usize>(text: &str) -> [u8; N] {
+        let mut out = [0u8; N];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn constants_are_right() {
+        let consts = Constants::new();
+        let m1 = sub(&ZERO, &ONE);
+        assert!(equal(&square(&consts.sqrt_m1), &m1));
+        assert_eq!(pack(&consts.base), BASE);
+    }
+
+    #[test]
+    fn verifies_rfc8032_vectors() {
+        let pk = hex::<32>("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let sig = hex::<64>("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b");
+        assert!(verify(&pk, b"", &sig));
+
+        let pk = hex::<32>("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        let sig = hex::<64>("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00");
+        assert!(verify(&pk, &[0x72], &sig));
+        assert!(!verify(&pk, &[0x73], &sig));
+
+        let mut bad = sig;
+        bad[5] ^= 1;
+        assert!(!verify(&pk, &[0x72], &bad));
+    }
+
+    #[test]
+    fn rejects_non_canonical_s() {
+        let pk = hex::<32>("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let mut sig = hex::<64>("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b");
+
+        // S + L is the same scalar mod L and would verify without the check
+        let mut carry = 0u16;
+        for i in 0..32 {
+            let sum = sig[32 + i] as u16 + L[i] as u16 + carry;
+            sig[32 + i] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!verify(&pk, b"", &sig));
+    }
+}
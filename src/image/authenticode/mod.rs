@@ -0,0 +1,199 @@
+//! Authenticode hashing of PE images and checks against it.
+//!
+//! `digest` computes the same SHA-256 hash that Secure Boot uses (the one in `db`/`dbx` and in
+//! the image's own signature). It can then be matched against an allowlist or checked against
+//! a detached ed25519 signature over the digest. All of it is plain Rust, so it works whether
+//! or not Secure Boot is enabled.
+//!
+//! To check an image loaded through `image::load_image`, wrap the reader in a `RecordingReader`
+//! and verify `RecordingReader::recorded()` after loading and before `image::start_image`.
+
+mod sha2;
+mod ed25519;
+
+pub use self::sha2::{Sha256, Sha512};
+
+use super::{Len, pe::{PeImage, PeError}};
+use crate::{io::{self, Read}, Result};
+use alloc::vec::Vec;
+
+/// An Authenticode SHA-256 digest
+pub type Digest = [u8; 32];
+
+/// Computes the Authenticode SHA-256 digest of an image.
+///
+/// As the PE spec requires this leaves out the CheckSum field, the certificate table's entry
+/// in the data directory and the certificate table itself. Sections are hashed in file order.
+pub fn digest(image: &PeImage) -> core::result::Result<Digest, PeError> {
+    let data = image.as_bytes();
+    let size_of_headers = image.size_of_headers() as usize;
+    let checksum = image.checksum_offset();
+
+    let mut hasher = Sha256::new();
+    hasher.update(&data[..checksum]);
+    match image.certificate_directory_offset() {
+        Some(cert_dir) => {
+            hasher.update(&data[checksum + 4..cert_dir]);
+            hasher.update(&data[cert_dir + 8..size_of_headers]);
+        },
+        None => hasher.update(&data[checksum + 4..size_of_headers]),
+    }
+
+    let mut sections = image.sections().iter().filter(|s| s.size_of_raw_data > 0).collect::<Vec<_>>();
+    sections.sort_by_key(|s| s.pointer_to_raw_data);
+
+    let mut hashed = size_of_headers;
+    for section in sections {
+        hasher.update(image.section_data(section));
+        hashed += section.size_of_raw_data as usize;
+    }
+
+    // Anything after the sections other than the certificate table, e.g. debug data
+    let cert_size = image.certificate_table()?.map(|t| t.len()).unwrap_or(0);
+    if data.len() > hashed + cert_size {
+        hasher.update(&data[hashed..data.len() - cert_size]);
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Fails with `PeError::NotAllowed` unless the image's digest is in `allowlist`
+pub fn check_allowlist(image: &PeImage, allowlist: &[Digest]) -> core::result::Result<(), PeError> {
+    let digest = digest(image)?;
+    if allowlist.contains(&digest) {
+        Ok(())
+    } else {
+        Err(PeError::NotAllowed)
+    }
+}
+
+/// Verifies a detached ed25519 `signature` made over the image's 32-byte Authenticode digest
+pub fn verify_signature(image: &PeImage, public_key: &[u8; 32], signature: &[u8; 64]) -> core::result::Result<(), PeError> {
+    let digest = digest(image)?;
+    if ed25519::verify(public_key, &digest, signature) {
+        Ok(())
+    } else {
+        Err(PeError::BadSignature)
+    }
+}
+
+/// Passes reads through to the inner reader and keeps a copy of everything read.
+///
+/// Meant to be handed to `image::load_image` so the same bytes the firmware loaded
+/// can be verified afterwards.
+pub struct RecordingReader<R> {
+    inner: R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read + Len> RecordingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, bytes: Vec::new() }
+    }
+
+    /// Everything read so far
+    pub fn recorded(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_inner(self) -> (R, Vec<u8>) {
+        (self.inner, self.bytes)
+    }
+}
+
+impl<R: Read + Len> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: Read + Len> Len for RecordingReader<R> {
+    fn len(&mut self) -> Result<Option<u64>> {
+        self.inner.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::pe::{WIN_CERT_TYPE_PKCS_SIGNED_DATA, test_image::ImageBuilder};
+
+    #[test]
+    fn sha2_matches_known_answers() {
+        assert_eq!(Sha256::digest(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert_eq!(Sha256::digest(&[b'a'; 1000])[28..], [0xb9, 0x73, 0x7e, 0xa3]);
+
+        let mut sha512 = Sha512::new();
+        sha512.update(&[b'a'; 1000]);
+        assert_eq!(sha512.finish()[60..], [0x47, 0x70, 0x9c, 0x97]);
+    }
+
+    #[test]
+    fn digest_ignores_checksum_and_signature() {
+        let mut builder = ImageBuilder::new();
+        let unsigned = builder.build();
+        let unsigned_digest = digest(&PeImage::parse(&unsigned).unwrap()).unwrap();
+
+        builder.certificate = Some((WIN_CERT_TYPE_PKCS_SIGNED_DATA, vec![0xAA; 100]));
+        let mut signed = builder.build();
+        signed[0x40 + 24 + 64] = 0x55; // CheckSum field
+        assert_eq!(digest(&PeImage::parse(&signed).unwrap()).unwrap(), unsigned_digest);
+
+        let mut tampered = unsigned.clone();
+        let text = PeImage::parse(&unsigned).unwrap().section(".text").unwrap().pointer_to_raw_data as usize;
+        tampered[text] = 0x90;
+        assert_ne!(digest(&PeImage::parse(&tampered).unwrap()).unwrap(), unsigned_digest);
+    }
+
+    #[test]
+    fn digest_matches_a_reference_computation() {
+        // Hash the unsigned image by hand with the excluded ranges cut out
+        let data = ImageBuilder::new().build();
+        let checksum = 0x40 + 24 + 64;
+        let cert_dir = 0x40 + 24 + 112 + 4 * 8;
+        let expected = Sha256::digest(&[&data[..checksum], &data[checksum + 4..cert_dir], &data[cert_dir + 8..]].concat());
+        assert_eq!(digest(&PeImage::parse(&data).unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn checks_allowlist_and_signatures() {
+        let data = ImageBuilder::new().build();
+        let image = PeImage::parse(&data).unwrap();
+        let good = digest(&image).unwrap();
+        assert!(check_allowlist(&image, &[[0; 32], good]).is_ok());
+        assert_eq!(check_allowlist(&image, &[[0; 32]]), Err(PeError::NotAllowed));
+
+        // The RFC 8032 test key. The signature is over this image's digest and was made with the
+        // matching secret key outside of this crate.
+        let public_key = [
+            0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+            0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+        ];
+        let signature = [
+            0x1e, 0xb0, 0x4c, 0x6b, 0x0a, 0xda, 0x81, 0x78, 0x23, 0xfd, 0x99, 0x7b, 0xe5, 0x4f, 0x96, 0xb3,
+            0x1a, 0x9e, 0x9c, 0xae, 0xf6, 0xfd, 0xdc, 0x98, 0x40, 0x8b, 0x2c, 0xf9, 0x3b, 0xd7, 0xe8, 0xc5,
+            0x62, 0xd6, 0xab, 0xb2, 0x6a, 0xa3, 0x81, 0xee, 0x0e, 0x3a, 0x0a, 0x3f, 0x4f, 0xdf, 0x84, 0x50,
+            0x1d, 0x28, 0x4c, 0xdd, 0x2d, 0xc3, 0x1e, 0xfa, 0x91, 0x2f, 0x75, 0xb8, 0xff, 0x4f, 0x0c, 0x09,
+        ];
+        assert!(verify_signature(&image, &public_key, &signature).is_ok());
+        assert_eq!(verify_signature(&image, &public_key, &[0; 64]), Err(PeError::BadSignature));
+
+        let mut tampered = data.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(verify_signature(&PeImage::parse(&tampered).unwrap(), &public_key, &signature), Err(PeError::BadSignature));
+    }
+
+    #[test]
+    fn recording_reader_keeps_what_was_read() {
+        let data = ImageBuilder::new().build();
+        let mut reader = RecordingReader::new(&data[..]);
+        assert_eq!(reader.len().unwrap(), Some(data.len() as u64));
+
+        let mut buf = vec![0u8; data.len()];
+        io::fill_buf(&mut reader, &mut buf).unwrap();
+        assert_eq!(reader.recorded(), &data[..]);
+    }
+}
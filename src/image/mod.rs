@@ -0,0 +1,549 @@
+pub mod pe;
+#[cfg(feature = "authenticode")]
+pub mod authenticode;
+
+use crate::{Result, EfiError, io::{self, Read}, system_table, image_handle, EfiErrorKind};
+use ffi::{
+    media::{EFI_LOAD_FILE_PROTOCOL, EFI_LOAD_FILE_PROTOCOL_GUID}, 
+    loaded_image::{EFI_LOADED_IMAGE_PROTOCOL, EFI_LOADED_IMAGE_PROTOCOL_GUID},
+    device_path::{EFI_DEVICE_PATH_PROTOCOL, EFI_DEVICE_PATH_PROTOCOL_GUID},
+    EFI_HANDLE,
+    EFI_STATUS,
+    EFI_SUCCESS,
+    EFI_BUFFER_TOO_SMALL,
+    EFI_INVALID_PARAMETER,
+    EFI_DEVICE_ERROR,
+    EFI_SECURITY_VIOLATION,
+    boot_services::{EFI_INTERFACE_TYPE, EFI_OPEN_PROTOCOL_BY_HANDLE_PROTOCOL, EFI_MEMORY_TYPE},
+    UINTN,
+    CHAR16,
+    BOOLEAN,
+    VOID,
+    TRUE,
+    FALSE,
+};
+use crate::device_path::{DevicePath, create_file_path_node, append_path};
+use core::{self, ptr, mem, slice, cmp, cell::Cell};
+use alloc::{vec::Vec, string::String};
+
+
+// TODO: we should create a virtualfs (filesystem) and put all our images there.
+// Each image will have its own file path. Then we can call LoadImage on these file paths.
+// And there will be a way to enumerate through all these images as well
+
+/// A trait that provides the length of the object that implements it.
+/// An example can be a file implementing this interface to expose a way to get its length.
+pub trait Len { // TODO: Move this a more general module like 'io' or something.
+    fn len(&mut self) -> Result<Option<u64>>; // TODO: was forced to use &mut self because some reaers like HTTP reader mutated when the read lenght (e.g. do a PUT request on their underlying HTTP stream and thus mutating it). Is interior mutability the answer?
+}
+
+/// Tells the firmware where a load request comes from. Some firmware treats the two
+/// differently, e.g. when picking which Load File protocol to use or applying Secure Boot policy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BootPolicy {
+    /// The image was selected by the boot manager, e.g. from a boot option
+    BootSelection,
+    /// The image is loaded programmatically by an application
+    #[default]
+    Programmatic,
+}
+
+impl From<BootPolicy> for BOOLEAN {
+    fn from(policy: BootPolicy) -> Self {
+        match policy {
+            BootPolicy::BootSelection => TRUE,
+            BootPolicy::Programmatic => FALSE,
+        }
+    }
+}
+
+// efi_ffi doesn't define the signatures of UnloadImage and Exit yet
+type EfiImageUnload = extern "win64" fn(image_handle: EFI_HANDLE) -> EFI_STATUS;
+type EfiExit = extern "win64" fn(image_handle: EFI_HANDLE, exit_status: EFI_STATUS, exit_data_size: UINTN, exit_data: *const CHAR16) -> EFI_STATUS;
+
+// TODO: this whole shit about wrapping raw paths into DevicePath type is unsafe. Address this unsafety
+pub fn load_image_from_path(path: &mut DevicePath) -> Result<LoadedImage> {
+    load_image_from_path_with_policy(path, BootPolicy::Programmatic)
+}
+
+/// Same as `load_image_from_path` but lets you choose the boot policy
+pub fn load_image_from_path_with_policy(path: &mut DevicePath, boot_policy: BootPolicy) -> Result<LoadedImage> {
+    load_raw(boot_policy, path.as_ptr(), &[])
+}
+
+/// Loads an image that is already in memory, e.g. one that was downloaded or decompressed.
+///
+/// `parent_device_path` is recorded as the path the image came from. The firmware also uses it
+/// when measuring the image and when applying Secure Boot policy.
+///
+/// If Secure Boot rejects the image this fails with `EfiErrorKind::SecurityViolation`
+/// (the image failed verification) or `EfiErrorKind::AccessDenied` (the platform forbids loading
+/// it altogether).
+pub fn load_image_from_buffer(buffer: &[u8], parent_device_path: Option<&DevicePath>, boot_policy: BootPolicy) -> Result<LoadedImage> {
+    if buffer.is_empty() {
+        return Err(EfiErrorKind::InvalidParameter.into());
+    }
+
+    let path = parent_device_path.map(|p| p.as_ptr()).unwrap_or(ptr::null());
+    load_raw(boot_policy, path, buffer)
+}
+
+fn load_raw(boot_policy: BootPolicy, path: *const EFI_DEVICE_PATH_PROTOCOL, buffer: &[u8]) -> Result<LoadedImage> {
+    let bs = (*system_table()).BootServices;
+    let current_image_handle = image_handle();
+    let source = if buffer.is_empty() { ptr::null() } else { buffer.as_ptr() as *const VOID };
+
+    let loaded_img_handle = unsafe {
+        let mut loaded_img_handle: EFI_HANDLE = ptr::null_mut();
+        let status = ((*bs).LoadImage)(boot_policy.into(), current_image_handle, path, source, buffer.len(), &mut loaded_img_handle);
+
+        // On a security violation the image is loaded regardless and a handle handed out, but it
+        // may never be started. The spec leaves it to us to unload it.
+        if status == EFI_SECURITY_VIOLATION && !loaded_img_handle.is_null() {
+            let unload: EfiImageUnload = mem::transmute((*bs).UnloadImage);
+            unload(loaded_img_handle);
+        }
+
+        ret_on_err!(status);
+        loaded_img_handle
+    };
+
+    Ok(LoadedImage::new(loaded_img_handle))
+}
+
+//TODO: Provide a way for the user to specify load options as well
+/// Loads image read from the given reader.
+///
+/// If the reader can't tell its length (e.g. a chunked HTTP response) the whole image is read
+/// into memory first. Such images are limited to `MAX_UNKNOWN_LENGTH_IMAGE_SIZE` bytes.
+pub fn load_image<R: Read + Len>(reader: &mut R) -> Result<LoadedImage> {
+    load_image_with_progress(reader, |_, _| {})
+}
+
+/// Same as `load_image` but calls `progress` with the number of bytes read so far and the
+/// total size, if known, as the image is read. Suitable for driving a progress bar.
+pub fn load_image_with_progress<R: Read + Len, P: FnMut(u64, Option<u64>)>(reader: &mut R, mut progress: P) -> Result<LoadedImage> {
+    let loader = Loader::new(reader, &mut progress);
+    let bs = (*system_table()).BootServices;
+
+    let (mut image_path, device_handle) = unsafe {
+        // Install our load file protocol and get a newly generated handle to it
+        let mut device_handle: EFI_HANDLE = ptr::null_mut();
+        ret_on_err!(((*bs).InstallProtocolInterface)(&mut device_handle, &EFI_LOAD_FILE_PROTOCOL_GUID, EFI_INTERFACE_TYPE::EFI_NATIVE_INTERFACE, mem::transmute(&loader.proto)));
+
+        // Open loaded image protocol on the currently running image in order to obtain its device handle
+        let current_image_handle = image_handle();
+        let loaded_image: *mut EFI_LOADED_IMAGE_PROTOCOL = ptr::null_mut();
+        ret_on_err!(((*bs).OpenProtocol)(current_image_handle, &EFI_LOADED_IMAGE_PROTOCOL_GUID, mem::transmute(&loaded_image), current_image_handle, ptr::null(), EFI_OPEN_PROTOCOL_BY_HANDLE_PROTOCOL)); // TODO: should we use GET_PROTOCOL instead of BY_HANDLE_PROTOCOL? Not clear from UEFI documentation.
+
+
+        if loaded_image.is_null() { // If above call returned null protocol that means no such protocol is associated with the handle (which is odd)
+            return Err(EfiErrorKind::LoadError.into()); // TODO: Need proper error here
+        }
+
+        ret_on_err!(((*bs).CloseProtocol)(current_image_handle, &EFI_LOADED_IMAGE_PROTOCOL_GUID, current_image_handle, ptr::null()));
+
+        // Open device path protocol on the device handle of the currently running image
+        let current_image_device_path: *mut EFI_DEVICE_PATH_PROTOCOL  = ptr::null_mut();
+        ret_on_err!(((*bs).OpenProtocol)((*loaded_image).DeviceHandle, &EFI_DEVICE_PATH_PROTOCOL_GUID, mem::transmute(&current_image_device_path), current_image_handle, ptr::null(), EFI_OPEN_PROTOCOL_BY_HANDLE_PROTOCOL)); // TODO: should we use GET_PROTOCOL instead of BY_HANDLE_PROTOCOL? Not clear from UEFI documentation.
+
+        if current_image_device_path.is_null() { // If above call returned null protocol that means no such protocol is associated with the handle (which is odd)
+            return Err(EfiErrorKind::LoadError.into()); // TODO: Need proper error here
+        }
+
+        ret_on_err!(((*bs).CloseProtocol)((*loaded_image).DeviceHandle, &EFI_DEVICE_PATH_PROTOCOL_GUID, current_image_handle, ptr::null()));
+
+        // Create a new device path and associate it with the our load file protocol. This path will be used for loading the image in LoadImage EFI call later
+        let dummy_image_file_name = "image_file";
+        let file_path_node = create_file_path_node(dummy_image_file_name)?.into_path();
+        let current_image_device_path = DevicePath::from_ptr(current_image_device_path)?;
+        let image_path = append_path(&current_image_device_path, &file_path_node)?; // TODO: Is this appraoch okay? Should we create a more proper path than this?
+        ret_on_err!(((*bs).InstallProtocolInterface)(&mut device_handle, &EFI_DEVICE_PATH_PROTOCOL_GUID, EFI_INTERFACE_TYPE::EFI_NATIVE_INTERFACE, mem::transmute(image_path.as_ptr())));
+
+        (image_path, device_handle)
+    };
+
+    let loaded_image = load_image_from_path(&mut image_path);
+
+    unsafe {
+        // Uninstall the load file and device path protocols since our protocol handle is about to go out of scope
+        // TODO: how will the device_handle be deallocated?
+        ret_on_err!(((*bs).UninstallProtocolInterface)(device_handle, &EFI_LOAD_FILE_PROTOCOL_GUID, mem::transmute(&loader.proto)));
+    }
+
+    loaded_image
+}
+
+/// Starts an image previously loaded using load_image.
+///
+/// The status the image exited with is in the returned `ExitData` rather than turned into an error,
+/// so that a failing image's exit message isn't lost. Note that the firmware's own failures to start
+/// the image (e.g. a Secure Boot violation) come back the same way.
+pub fn start_image(image: &LoadedImage ) -> Result<ExitData> {
+    let bs = (*system_table()).BootServices;
+
+    unsafe {
+        let mut exit_data_size: UINTN = 0;
+        let mut exit_data_ptr = ptr::null_mut() as *const CHAR16;
+        let status = ((*bs).StartImage)(image.handle, &mut exit_data_size, &mut exit_data_ptr);
+        image.started.set(true);
+        Ok(ExitData::from_raw_parts(exit_data_ptr, exit_data_size, status)) // The pointer is null if the image returned from its entry point without calling Exit()
+    }
+}
+
+/// Exits the current image, handing `status` and `message` to whoever started it.
+///
+/// The parent frees the exit data with FreePool so the message is copied into pool memory first.
+/// The firmware ignores exit data when `status` is `EFI_SUCCESS`, so the message is dropped then.
+/// Only returns if the firmware refuses to exit, in which case it returns the reason.
+pub fn exit(status: EFI_STATUS, message: Option<&str>) -> EfiError {
+    let bs = (*system_table()).BootServices;
+
+    let mut exit_data: *const VOID = ptr::null();
+    let mut exit_data_size = 0;
+    if let Some(message) = message.filter(|_| status != EFI_SUCCESS) {
+        let utf16 = message.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+        exit_data_size = utf16.len() * 2;
+        unsafe {
+            let alloc_status = ((*bs).AllocatePool)(EFI_MEMORY_TYPE::EfiBootServicesData, exit_data_size, &mut exit_data);
+            if alloc_status != EFI_SUCCESS {
+                return EfiError::from(alloc_status);
+            }
+            ptr::copy_nonoverlapping(utf16.as_ptr(), exit_data as *mut u16, utf16.len());
+        }
+    }
+
+    unsafe {
+        let exit_fn: EfiExit = mem::transmute((*bs).Exit);
+        let exit_status = exit_fn(image_handle(), status, exit_data_size, exit_data as *const CHAR16);
+
+        // Still here so Exit failed and nobody else is going to free the data
+        if !exit_data.is_null() {
+            ((*bs).FreePool)(exit_data);
+        }
+
+        EfiError::from(exit_status)
+    }
+}
+
+/// How much is read between two calls to the progress callback
+const PROGRESS_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest image `load_image` reads into memory when the reader doesn't know its length.
+/// Anything bigger fails with `EfiErrorKind::OutOfResources` instead of exhausting memory.
+pub const MAX_UNKNOWN_LENGTH_IMAGE_SIZE: usize = 256 * 1024 * 1024;
+
+#[repr(C)] // repr C needed so that we can safely transmute back to this struct in load_file_callback below
+struct Loader<'a, R: 'a + Read + Len> {
+    proto: EFI_LOAD_FILE_PROTOCOL,
+    reader: &'a mut R,
+    progress: &'a mut dyn FnMut(u64, Option<u64>),
+    cached_len: Option<u64>,
+    buffered: Option<Vec<u8>>, // The whole image when the reader didn't know its length
+}
+
+impl<'a, R: 'a + Read + Len> Loader<'a, R> {
+    fn new(reader: &'a mut R, progress: &'a mut dyn FnMut(u64, Option<u64>)) -> Self {
+        Self { proto: EFI_LOAD_FILE_PROTOCOL { LoadFile: load_file_callback::<'a, R> }, reader, progress, cached_len: None, buffered: None }
+    }
+}
+
+extern "win64" fn load_file_callback<'a, R: 'a + Read + Len>(
+    this: *const EFI_LOAD_FILE_PROTOCOL, 
+    file_path: *const EFI_DEVICE_PATH_PROTOCOL,
+    _boot_policy: BOOLEAN,
+    buffer_size: *mut UINTN, 
+    buffer_ptr: *mut VOID
+) -> EFI_STATUS {
+    // TODO: Properly handle boot_policy also
+
+    if this.is_null() || file_path.is_null() || buffer_size.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+
+    let loader: &mut Loader<'a, R> = unsafe { mem::transmute(this) }; // Should be safe to do this transmute since Loader is marked repr C
+
+    // Get file length once and cache it in loader.
+    // We cache because for many readers it may be expesive to get the length.
+    // E.g. in HTTP it will result in a HEAD call each time.
+    // If the reader doesn't know the length either we have no choice but to read it all into memory.
+    if loader.cached_len.is_none() {
+        loader.cached_len = match loader.reader.len() {
+            Ok(Some(l)) => Some(l),
+            Ok(None) => match read_to_end_with_progress(&mut loader.reader, MAX_UNKNOWN_LENGTH_IMAGE_SIZE, &mut *loader.progress) {
+                Ok(bytes) => {
+                    let len = bytes.len() as u64;
+                    loader.buffered = Some(bytes);
+                    Some(len)
+                },
+                Err(e) => return e.into(),
+            },
+            Err(e) => return e.into()
+        }
+    };
+
+    let file_len = loader.cached_len.expect("len should not have been none here");
+    let incoming_buf_size = unsafe { *buffer_size };
+
+    // If buffer_ptr is null the firmware just wants to know the size of the file
+    // So we just set that and return
+    if buffer_ptr.is_null() {
+        // There's a chance *buffer_size can overflow
+        // because file_len is u64 while *buffer_size
+        // is UINTN (which is an alias for usize).
+        // So here we just error out if it overflows.
+        if (core::usize::MAX as u64) < file_len {
+            return EFI_DEVICE_ERROR;
+        }
+
+        unsafe { *buffer_size  = file_len as UINTN };
+
+        // The UEFI spec 2.4 DOES NOT say that we should return EFI_BUFFER_TOO_SMALL
+        // when the incoming buffer_ptr is null. However, if you return any other status,
+        // such as EFI_SUCCESS, it doesn't work. The firmware never tries to downlaod file
+        // in that case.
+        return EFI_BUFFER_TOO_SMALL;
+    } 
+
+    // As per UEFI spec, if the provided buf is smaller 
+    // than the file we have, we must return EFI_BUFFER_TOO_SMALL
+    if (incoming_buf_size as u64) < file_len { 
+        return EFI_BUFFER_TOO_SMALL
+    }
+
+    // Everything good. Let's read the data.
+    let buf = unsafe { slice::from_raw_parts_mut(buffer_ptr as *mut u8, *buffer_size) };
+    if let Some(ref bytes) = loader.buffered {
+        buf[..bytes.len()].copy_from_slice(bytes);
+        unsafe { *buffer_size = bytes.len() };
+        return EFI_SUCCESS;
+    }
+
+    match fill_buf_with_progress(&mut loader.reader, buf, file_len, &mut *loader.progress) {
+        Ok(bytes_read) => {
+            unsafe { *buffer_size = bytes_read };
+            EFI_SUCCESS
+        },
+        Err(_) => EFI_DEVICE_ERROR,
+    }
+}
+
+/// Reads the reader till EOF, reporting progress after every chunk.
+/// Fails with `OutOfResources` if there's more than `max_len` bytes to read.
+fn read_to_end_with_progress<R: Read>(reader: &mut R, max_len: usize, progress: &mut dyn FnMut(u64, Option<u64>)) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    loop {
+        let start = bytes.len();
+        bytes.resize(start + PROGRESS_CHUNK_SIZE, 0);
+        let read = io::fill_buf(reader, &mut bytes[start..])?;
+        bytes.truncate(start + read);
+        if bytes.len() > max_len {
+            return Err(EfiErrorKind::OutOfResources.into());
+        }
+
+        progress(bytes.len() as u64, None);
+
+        if read < PROGRESS_CHUNK_SIZE {
+            return Ok(bytes);
+        }
+    }
+}
+
+/// Same as `io::fill_buf` but reads in chunks, reporting progress against `total` after each one
+fn fill_buf_with_progress<R: Read>(reader: &mut R, buf: &mut [u8], total: u64, progress: &mut dyn FnMut(u64, Option<u64>)) -> io::Result<usize> {
+    let mut bytes_read = 0;
+    for chunk in buf.chunks_mut(PROGRESS_CHUNK_SIZE) {
+        let read = io::fill_buf(reader, chunk)?;
+        bytes_read += read;
+        progress(bytes_read as u64, Some(total));
+
+        if read < chunk.len() { // EOF
+            break;
+        }
+    }
+
+    Ok(bytes_read)
+}
+
+
+/// An image loaded into memory but not necessarily started
+#[derive(Debug)]
+pub struct LoadedImage {
+    handle: EFI_HANDLE,
+    unload_on_drop: bool,
+    started: Cell<bool>,
+}
+
+impl LoadedImage {
+    fn new(handle: EFI_HANDLE) -> Self {
+        Self { handle, unload_on_drop: false, started: Cell::new(false) }
+    }
+
+    pub fn handle(&self) -> EFI_HANDLE {
+        self.handle
+    }
+
+    /// Makes dropping this value unload the image so a failed chainload doesn't leak it.
+    ///
+    /// This only covers images that were never started. An application that ran has already
+    /// been unloaded by the firmware and its handle may since have been given to another image,
+    /// so once `start_image` has been called dropping leaves the handle alone. Use `unload`
+    /// to get rid of a started driver.
+    pub fn unload_on_drop(mut self, unload: bool) -> Self {
+        self.unload_on_drop = unload;
+        self
+    }
+
+    /// Unloads the image, freeing its memory. Drivers get the chance to refuse through their unload function.
+    pub fn unload(mut self) -> Result<()> {
+        self.unload_on_drop = false; // Whatever happens below, don't try again in drop
+        unload_image(self.handle)
+    }
+}
+
+impl Drop for LoadedImage {
+    fn drop(&mut self) {
+        if self.unload_on_drop && !self.started.get() {
+            let _ = unload_image(self.handle); // Nothing we can do about a failure here
+        }
+    }
+}
+
+fn unload_image(handle: EFI_HANDLE) -> Result<()> {
+    let bs = (*system_table()).BootServices;
+    unsafe {
+        let unload: EfiImageUnload = mem::transmute((*bs).UnloadImage);
+        ret_on_err!(unload(handle));
+    }
+    Ok(())
+}
+
+/// The data returned by a running image when it exits.
+/// Contains a UCS-2 string part followed by an optional binary data.
+/// The interpretation of the binary data part is up to the application
+#[derive(Debug)]
+pub struct ExitData {
+    ptr: *const CHAR16,
+    size_in_bytes: UINTN,
+    str_end: usize,
+    status: EFI_STATUS,
+}
+
+impl ExitData {
+    fn from_raw_parts(ptr: *const CHAR16, size_in_bytes: UINTN, status: EFI_STATUS) -> Self {
+        let buf = Self::create_slice(ptr, size_in_bytes);
+        let str_end = buf.iter().position(|c| *c == 0).unwrap_or(buf.len()); // End of str part is the first ocurrence of null terminator or failing that the end of the buf itself
+        Self { ptr, size_in_bytes, str_end, status } 
+    }
+
+    /// The status the image exited with
+    pub fn status(&self) -> EFI_STATUS {
+        self.status
+    }
+
+    /// The exit status as a `Result`. Warnings count as success.
+    pub fn result(&self) -> Result<()> {
+        ret_on_err!(self.status);
+        Ok(())
+    }
+
+    /// String part of exit data converted to a `String`. Unpaired surrogates are replaced.
+    pub fn message(&self) -> String {
+        String::from_utf16_lossy(self.str_part())
+    }
+    
+    /// String part of exit data. It is a UCS-2 string with NO null terminator.
+    pub fn str_part(&self) -> &[u16] {
+        let buf = self.as_slice();
+        &buf[..self.str_end]
+    }
+
+    /// Binary part of exit data.
+    pub fn binary_part(&self) -> &[u16] {
+        let buf = self.as_slice();
+        let bin_start = cmp::min(self.str_end + 1, buf.len()); // min because we don't want to overshoot the buf end 
+        &buf[bin_start..]
+    }
+
+    /// The whole buffer with string and binary parts together
+    pub fn as_slice(&self) -> &[u16] {
+        Self::create_slice(self.ptr, self.size_in_bytes)
+    }
+
+    fn create_slice<'a>(ptr: *const CHAR16, size_in_bytes: UINTN) -> &'a [u16] {
+        if ptr.is_null() {
+            return &[]; // Image didn't pass any exit data
+        }
+
+        unsafe { slice::from_raw_parts(ptr, size_in_bytes / 2) } // Dividing by two since slice::from_raw_parts' second arg is size in elements not bytes
+    }
+}
+
+impl Drop for ExitData {
+    fn drop(&mut self) { // The exit data ptr is allocated by the image we loaded but must be deallocated by us as per UEFI spec
+        if self.ptr.is_null() {
+            return;
+        }
+
+        let bs = (*system_table()).BootServices;
+        unsafe { ((*bs).FreePool)(self.ptr as *const VOID) }; // TODO: Can't do anything if this fails except. So we should log here
+    }
+}
+
+impl<'a> Len for &'a[u8] {
+    fn len(&mut self) -> Result<Option<u64>> {
+        Ok(Some(<[u8]>::len(self) as u64))
+    }
+}
+
+impl Len for io::Cursor<Vec<u8>> {
+    fn len(&mut self) -> Result<Option<u64>> {
+        Ok(Some(self.get_ref().len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_unknown_length_in_chunks() {
+        let data = vec![0xAB; PROGRESS_CHUNK_SIZE * 2 + 10];
+        let mut reports = Vec::new();
+        let bytes = read_to_end_with_progress(&mut &data[..], data.len(), &mut |read, total| reports.push((read, total))).unwrap();
+
+        assert_eq!(bytes, data);
+        assert_eq!(reports, [(PROGRESS_CHUNK_SIZE as u64, None), (PROGRESS_CHUNK_SIZE as u64 * 2, None), (data.len() as u64, None)]);
+    }
+
+    #[test]
+    fn unknown_length_reads_are_bounded() {
+        let data = vec![0xAB; PROGRESS_CHUNK_SIZE * 3];
+        let err = read_to_end_with_progress(&mut &data[..], PROGRESS_CHUNK_SIZE * 2, &mut |_, _| {}).unwrap_err();
+        assert_eq!(err.kind(), EfiErrorKind::OutOfResources);
+
+        let bytes = read_to_end_with_progress(&mut &data[..PROGRESS_CHUNK_SIZE * 2], PROGRESS_CHUNK_SIZE * 2, &mut |_, _| {}).unwrap();
+        assert_eq!(bytes.len(), PROGRESS_CHUNK_SIZE * 2);
+
+        // A limit that isn't a multiple of the chunk size is exact too
+        let max_len = PROGRESS_CHUNK_SIZE + 10;
+        let err = read_to_end_with_progress(&mut &data[..max_len + 1], max_len, &mut |_, _| {}).unwrap_err();
+        assert_eq!(err.kind(), EfiErrorKind::OutOfResources);
+
+        let bytes = read_to_end_with_progress(&mut &data[..max_len], max_len, &mut |_, _| {}).unwrap();
+        assert_eq!(bytes.len(), max_len);
+    }
+
+    #[test]
+    fn reports_progress_against_total() {
+        let data = vec![0xCD; PROGRESS_CHUNK_SIZE + 1];
+        let total = data.len() as u64;
+        let mut buf = vec![0; data.len()];
+        let mut reports = Vec::new();
+        let read = fill_buf_with_progress(&mut &data[..], &mut buf, total, &mut |read, total| reports.push((read, total))).unwrap();
+
+        assert_eq!(read, data.len());
+        assert_eq!(buf, data);
+        assert_eq!(reports, [(PROGRESS_CHUNK_SIZE as u64, Some(total)), (total, Some(total))]);
+    }
+}
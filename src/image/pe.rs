@@ -0,0 +1,681 @@
+//! Parsing and validation of PE/COFF images.
+//!
+//! Lets you look inside an image before handing it to `LoadImage`, e.g. to reject an image
+//! built for another architecture or one that isn't signed with a message that says so.
+//! Works purely on bytes and doesn't need the firmware.
+
+use crate::{EfiError, EfiErrorKind};
+use failure::Fail;
+use alloc::{vec::Vec, string::String};
+use core::{str, convert::TryFrom};
+
+const DOS_MAGIC: &[u8] = b"MZ";
+const PE_SIGNATURE: &[u8] = b"PE\0\0";
+const PE32_MAGIC: u16 = 0x10B;
+const PE32_PLUS_MAGIC: u16 = 0x20B;
+
+const COFF_HEADER_SIZE: usize = 20;
+const SECTION_HEADER_SIZE: usize = 40;
+const DATA_DIRECTORY_SIZE: usize = 8;
+
+const SECURITY_DIRECTORY: usize = 4;
+const DLL_CHARACTERISTICS_NX_COMPAT: u16 = 0x0100;
+
+pub const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+pub const WIN_CERT_TYPE_EFI_GUID: u16 = 0x0EF1;
+
+/// Why an image was rejected
+#[derive(Debug, Fail, PartialEq)]
+pub enum PeError {
+    #[fail(display = "Not a PE image")]
+    NotPe,
+    #[fail(display = "The image is truncated or its headers point outside of it")]
+    Truncated,
+    #[fail(display = "The image's {} is malformed", _0)]
+    Malformed(&'static str),
+    #[fail(display = "The image is for {:?} but this platform is {:?}", found, expected)]
+    WrongMachine { expected: Machine, found: Machine },
+    #[fail(display = "The image has subsystem {:?} which is not a UEFI subsystem", _0)]
+    NotEfi(Subsystem),
+    #[fail(display = "The image is not signed")]
+    Unsigned,
+    #[fail(display = "The image's hash is not in the allowlist")]
+    NotAllowed,
+    #[fail(display = "The image's signature does not verify")]
+    BadSignature,
+}
+
+impl From<PeError> for EfiError {
+    fn from(error: PeError) -> Self {
+        let kind = match error {
+            PeError::Unsigned | PeError::NotAllowed | PeError::BadSignature => EfiErrorKind::SecurityViolation,
+            PeError::WrongMachine { .. } | PeError::NotEfi(_) => EfiErrorKind::Unsupported,
+            _ => EfiErrorKind::LoadError,
+        };
+        error.context(kind).into()
+    }
+}
+
+/// The architecture an image was built for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Machine {
+    I386,
+    X64,
+    Arm,
+    Aarch64,
+    Ia64,
+    RiscV64,
+    LoongArch64,
+    Unknown(u16),
+}
+
+impl Machine {
+    /// The machine type images must have to run on the platform this crate was built for
+    pub fn native() -> Self {
+        if cfg!(target_arch = "x86_64") {
+            Machine::X64
+        } else if cfg!(target_arch = "aarch64") {
+            Machine::Aarch64
+        } else if cfg!(target_arch = "x86") {
+            Machine::I386
+        } else {
+            Machine::Unknown(0)
+        }
+    }
+}
+
+impl From<u16> for Machine {
+    fn from(value: u16) -> Self {
+        match value {
+            0x014C => Machine::I386,
+            0x8664 => Machine::X64,
+            0x01C2 => Machine::Arm, // The spec wants Thumb-2 images to use the mixed ARM/Thumb type
+            0xAA64 => Machine::Aarch64,
+            0x0200 => Machine::Ia64,
+            0x5064 => Machine::RiscV64,
+            0x6264 => Machine::LoongArch64,
+            other => Machine::Unknown(other),
+        }
+    }
+}
+
+/// The subsystem in the optional header. Decides how the firmware treats the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subsystem {
+    EfiApplication,
+    EfiBootServiceDriver,
+    EfiRuntimeDriver,
+    EfiRom,
+    Other(u16),
+}
+
+impl Subsystem {
+    pub fn is_efi(self) -> bool {
+        !matches!(self, Subsystem::Other(_))
+    }
+}
+
+impl From<u16> for Subsystem {
+    fn from(value: u16) -> Self {
+        match value {
+            10 => Subsystem::EfiApplication,
+            11 => Subsystem::EfiBootServiceDriver,
+            12 => Subsystem::EfiRuntimeDriver,
+            13 => Subsystem::EfiRom,
+            other => Subsystem::Other(other),
+        }
+    }
+}
+
+/// An entry in the section table
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub pointer_to_raw_data: u32,
+    pub size_of_raw_data: u32,
+    pub characteristics: u32,
+}
+
+/// An entry in the data directory of the optional header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+/// An entry in the certificate table (a `WIN_CERTIFICATE`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Certificate<'a> {
+    pub revision: u16,
+    pub certificate_type: u16,
+    pub data: &'a [u8],
+}
+
+/// A line of the `.sbat` section. See the shim project's SBAT.md for what the fields mean.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SbatEntry {
+    pub component: String,
+    pub generation: u32,
+    pub vendor: String,
+    pub package: String,
+    pub version: String,
+    pub url: String,
+}
+
+/// A parsed PE/COFF image borrowing the bytes it was parsed from
+#[derive(Debug)]
+pub struct PeImage<'a> {
+    data: &'a [u8],
+    machine: Machine,
+    characteristics: u16,
+    is_pe32_plus: bool,
+    entry_point: u32,
+    image_base: u64,
+    size_of_image: u32,
+    size_of_headers: u32,
+    subsystem: Subsystem,
+    dll_characteristics: u16,
+    #[cfg(feature = "authenticode")]
+    checksum_offset: usize,
+    #[cfg(feature = "authenticode")]
+    data_directories_offset: usize,
+    data_directories: Vec<DataDirectory>,
+    sections: Vec<Section>,
+}
+
+impl<'a> PeImage<'a> {
+    /// Parses the headers and checks that everything they point to lies inside `data`
+    pub fn parse(data: &'a [u8]) -> Result<Self, PeError> {
+        if !data.starts_with(DOS_MAGIC) {
+            return Err(PeError::NotPe);
+        }
+
+        let pe_offset = le_u32(data, 0x3C)? as usize;
+        if slice(data, pe_offset, PE_SIGNATURE.len())? != PE_SIGNATURE {
+            return Err(PeError::NotPe);
+        }
+
+        let coff = pe_offset + PE_SIGNATURE.len();
+        let machine = Machine::from(le_u16(data, coff)?);
+        let number_of_sections = le_u16(data, coff + 2)? as usize;
+        let size_of_optional_header = le_u16(data, coff + 16)? as usize;
+        let characteristics = le_u16(data, coff + 18)?;
+
+        let opt = coff + COFF_HEADER_SIZE;
+        let optional_header = slice(data, opt, size_of_optional_header)?;
+        let is_pe32_plus = match le_u16(optional_header, 0)? {
+            PE32_PLUS_MAGIC => true,
+            PE32_MAGIC => false,
+            _ => return Err(PeError::Malformed("optional header")),
+        };
+
+        let (image_base, rva_count_offset) = if is_pe32_plus {
+            (le_u64(optional_header, 24)?, 108)
+        } else {
+            (le_u32(optional_header, 28)? as u64, 92)
+        };
+
+        let entry_point = le_u32(optional_header, 16)?;
+        let size_of_image = le_u32(optional_header, 56)?;
+        let size_of_headers = le_u32(optional_header, 60)?;
+        let subsystem = Subsystem::from(le_u16(optional_header, 68)?);
+        let dll_characteristics = le_u16(optional_header, 70)?;
+
+        let number_of_rva_and_sizes = le_u32(optional_header, rva_count_offset)? as usize;
+        let data_directories_offset = rva_count_offset + 4;
+        let data_directories_end = number_of_rva_and_sizes.checked_mul(DATA_DIRECTORY_SIZE)
+            .and_then(|len| len.checked_add(data_directories_offset))
+            .ok_or(PeError::Malformed("data directory"))?;
+        if data_directories_end > optional_header.len() {
+            return Err(PeError::Malformed("data directory"));
+        }
+
+        let data_directories = (0..number_of_rva_and_sizes)
+            .map(|i| {
+                let offset = data_directories_offset + i * DATA_DIRECTORY_SIZE;
+                Ok(DataDirectory { virtual_address: le_u32(optional_header, offset)?, size: le_u32(optional_header, offset + 4)? })
+            })
+            .collect::<Result<Vec<_>, PeError>>()?;
+
+        let section_table = slice(data, opt + size_of_optional_header, number_of_sections * SECTION_HEADER_SIZE)?;
+        let sections = section_table.chunks(SECTION_HEADER_SIZE).map(parse_section).collect::<Result<Vec<_>, PeError>>()?;
+
+        for section in &sections {
+            slice(data, section.pointer_to_raw_data as usize, section.size_of_raw_data as usize)?;
+        }
+
+        // The headers have to cover the section table, and with it the CheckSum field and the
+        // data directory that Authenticode hashing skips over
+        let headers_end = opt + size_of_optional_header + section_table.len();
+        if (size_of_headers as usize) < headers_end || size_of_headers as usize > data.len() || entry_point >= size_of_image.max(1) {
+            return Err(PeError::Malformed("optional header"));
+        }
+
+        Ok(Self {
+            data,
+            machine,
+            characteristics,
+            is_pe32_plus,
+            entry_point,
+            image_base,
+            size_of_image,
+            size_of_headers,
+            subsystem,
+            dll_characteristics,
+            #[cfg(feature = "authenticode")]
+            checksum_offset: opt + 64,
+            #[cfg(feature = "authenticode")]
+            data_directories_offset: opt + data_directories_offset,
+            data_directories,
+            sections,
+        })
+    }
+
+    /// Checks that the image can run here: it must be for the native machine type and have a
+    /// UEFI subsystem. With `require_signed` it must also carry a certificate table.
+    ///
+    /// This doesn't verify signatures. The firmware still does that when Secure Boot is on.
+    pub fn validate(&self, require_signed: bool) -> Result<(), PeError> {
+        let expected = Machine::native();
+        if self.machine != expected {
+            return Err(PeError::WrongMachine { expected, found: self.machine });
+        }
+
+        if !self.subsystem.is_efi() {
+            return Err(PeError::NotEfi(self.subsystem));
+        }
+
+        if require_signed && !self.is_signed() {
+            return Err(PeError::Unsigned);
+        }
+
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn machine(&self) -> Machine {
+        self.machine
+    }
+
+    pub fn subsystem(&self) -> Subsystem {
+        self.subsystem
+    }
+
+    /// The COFF header characteristics
+    pub fn characteristics(&self) -> u16 {
+        self.characteristics
+    }
+
+    pub fn dll_characteristics(&self) -> u16 {
+        self.dll_characteristics
+    }
+
+    /// True if the image says it works with non-executable data pages
+    pub fn is_nx_compatible(&self) -> bool {
+        self.dll_characteristics & DLL_CHARACTERISTICS_NX_COMPAT != 0
+    }
+
+    pub fn is_pe32_plus(&self) -> bool {
+        self.is_pe32_plus
+    }
+
+    /// RVA of the entry point
+    pub fn entry_point(&self) -> u32 {
+        self.entry_point
+    }
+
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    pub fn size_of_image(&self) -> u32 {
+        self.size_of_image
+    }
+
+    pub fn size_of_headers(&self) -> u32 {
+        self.size_of_headers
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// The bytes of a section as stored in the file
+    pub fn section_data(&self, section: &Section) -> &'a [u8] {
+        // Bounds were checked in parse()
+        let start = section.pointer_to_raw_data as usize;
+        &self.data[start..start + section.size_of_raw_data as usize]
+    }
+
+    pub fn data_directories(&self) -> &[DataDirectory] {
+        &self.data_directories
+    }
+
+    /// The entries of the `.sbat` section if the image has one
+    pub fn sbat(&self) -> Option<Result<Vec<SbatEntry>, PeError>> {
+        let section = self.section(".sbat")?;
+        let data = self.section_data(section);
+        let end = data.iter().position(|b| *b == 0).unwrap_or(data.len()); // Padded with NULs up to the file alignment
+        Some(str::from_utf8(&data[..end]).map_err(|_| PeError::Malformed(".sbat section")).and_then(parse_sbat))
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.certificates().map(|c| !c.is_empty()).unwrap_or(false)
+    }
+
+    /// The entries of the certificate table. Empty if the image isn't signed.
+    pub fn certificates(&self) -> Result<Vec<Certificate<'a>>, PeError> {
+        let table = match self.certificate_table()? {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut certificates = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= table.len() {
+            let length = le_u32(table, offset)? as usize;
+            if length < 8 || offset + length > table.len() {
+                return Err(PeError::Malformed("certificate table"));
+            }
+
+            certificates.push(Certificate {
+                revision: le_u16(table, offset + 4)?,
+                certificate_type: le_u16(table, offset + 6)?,
+                data: &table[offset + 8..offset + length],
+            });
+
+            offset += (length + 7) & !7; // Entries are 8 byte aligned
+        }
+
+        Ok(certificates)
+    }
+
+    /// File offset of the CheckSum field of the optional header
+    #[cfg(feature = "authenticode")]
+    pub (crate) fn checksum_offset(&self) -> usize {
+        self.checksum_offset
+    }
+
+    /// File offset of the certificate table's entry in the data directory, if there is one
+    #[cfg(feature = "authenticode")]
+    pub (crate) fn certificate_directory_offset(&self) -> Option<usize> {
+        if self.data_directories.len() > SECURITY_DIRECTORY {
+            Some(self.data_directories_offset + SECURITY_DIRECTORY * DATA_DIRECTORY_SIZE)
+        } else {
+            None
+        }
+    }
+
+    /// The raw certificate table. Unlike other directories its address is a file offset, not an RVA.
+    pub fn certificate_table(&self) -> Result<Option<&'a [u8]>, PeError> {
+        match self.data_directories.get(SECURITY_DIRECTORY) {
+            Some(dir) if dir.size > 0 => slice(self.data, dir.virtual_address as usize, dir.size as usize).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn parse_section(header: &[u8]) -> Result<Section, PeError> {
+    let raw_name = &header[..8];
+    let end = raw_name.iter().position(|b| *b == 0).unwrap_or(raw_name.len());
+    let name = str::from_utf8(&raw_name[..end]).map_err(|_| PeError::Malformed("section table"))?;
+
+    Ok(Section {
+        name: name.into(),
+        virtual_size: le_u32(header, 8)?,
+        virtual_address: le_u32(header, 12)?,
+        size_of_raw_data: le_u32(header, 16)?,
+        pointer_to_raw_data: le_u32(header, 20)?,
+        characteristics: le_u32(header, 36)?,
+    })
+}
+
+fn parse_sbat(text: &str) -> Result<Vec<SbatEntry>, PeError> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            let fields = line.split(',').collect::<Vec<_>>();
+            if fields.len() < 2 {
+                return Err(PeError::Malformed(".sbat section"));
+            }
+
+            let field = |i: usize| String::from(fields.get(i).cloned().unwrap_or(""));
+            Ok(SbatEntry {
+                component: field(0),
+                generation: fields[1].parse().map_err(|_| PeError::Malformed(".sbat section"))?,
+                vendor: field(2),
+                package: field(3),
+                version: field(4),
+                url: field(5),
+            })
+        })
+        .collect()
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], PeError> {
+    let end = offset.checked_add(len).ok_or(PeError::Truncated)?;
+    data.get(offset..end).ok_or(PeError::Truncated)
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Result<u16, PeError> {
+    let bytes = slice(data, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Result<u32, PeError> {
+    let bytes = slice(data, offset, 4)?;
+    Ok(u32::from_le_bytes(<[u8; 4]>::try_from(bytes).unwrap()))
+}
+
+fn le_u64(data: &[u8], offset: usize) -> Result<u64, PeError> {
+    let bytes = slice(data, offset, 8)?;
+    Ok(u64::from_le_bytes(<[u8; 8]>::try_from(bytes).unwrap()))
+}
+
+/// Builds minimal but well formed PE32+ images for the tests here and in `authenticode`
+#[cfg(test)]
+pub (crate) mod test_image {
+    use super::Machine;
+    use alloc::vec::Vec;
+
+    pub const FILE_ALIGNMENT: usize = 0x200;
+
+    /// The machine value `PeImage::validate` accepts on the host running the tests
+    pub fn native_machine() -> u16 {
+        match Machine::native() {
+            Machine::I386 => 0x014C,
+            Machine::X64 => 0x8664,
+            Machine::Aarch64 => 0xAA64,
+            _ => 0,
+        }
+    }
+
+    /// A machine value `PeImage::validate` rejects on the host running the tests
+    pub fn foreign_machine() -> u16 {
+        if Machine::native() == Machine::Aarch64 { 0x8664 } else { 0xAA64 }
+    }
+
+    pub struct ImageBuilder {
+        pub machine: u16,
+        pub subsystem: u16,
+        pub dll_characteristics: u16,
+        pub sections: Vec<(&'static str, Vec<u8>)>,
+        pub certificate: Option<(u16, Vec<u8>)>,
+    }
+
+    impl ImageBuilder {
+        pub fn new() -> Self {
+            Self {
+                machine: 0x8664,
+                subsystem: 10,
+                dll_characteristics: 0x0100,
+                sections: vec![(".text", vec![0xC3; 16])],
+                certificate: None,
+            }
+        }
+
+        pub fn build(&self) -> Vec<u8> {
+            let pe_offset = 0x40;
+            let opt = pe_offset + 4 + 20;
+            let opt_size = 112 + 16 * 8;
+            let section_table = opt + opt_size;
+            let headers = section_table + self.sections.len() * 40;
+            let size_of_headers = align(headers, FILE_ALIGNMENT);
+
+            let mut image = vec![0u8; size_of_headers];
+            image[..2].copy_from_slice(b"MZ");
+            put32(&mut image, 0x3C, pe_offset as u32);
+            image[pe_offset..pe_offset + 4].copy_from_slice(b"PE\0\0");
+            put16(&mut image, pe_offset + 4, self.machine);
+            put16(&mut image, pe_offset + 6, self.sections.len() as u16);
+            put16(&mut image, pe_offset + 20, opt_size as u16);
+            put16(&mut image, pe_offset + 22, 0x0022); // Executable, large address aware
+
+            put16(&mut image, opt, 0x20B);
+            put32(&mut image, opt + 16, 0x1000);
+            image[opt + 24..opt + 32].copy_from_slice(&0x4000_0000u64.to_le_bytes());
+            put32(&mut image, opt + 32, 0x1000);
+            put32(&mut image, opt + 36, FILE_ALIGNMENT as u32);
+            put32(&mut image, opt + 56, 0x1000 * (self.sections.len() as u32 + 1));
+            put32(&mut image, opt + 60, size_of_headers as u32);
+            put16(&mut image, opt + 68, self.subsystem);
+            put16(&mut image, opt + 70, self.dll_characteristics);
+            put32(&mut image, opt + 108, 16);
+
+            for (i, (name, data)) in self.sections.iter().enumerate() {
+                let header = section_table + i * 40;
+                let raw_size = align(data.len(), FILE_ALIGNMENT);
+                let raw_pointer = image.len();
+                image[header..header + name.len()].copy_from_slice(name.as_bytes());
+                put32(&mut image, header + 8, data.len() as u32);
+                put32(&mut image, header + 12, 0x1000 * (i as u32 + 1));
+                put32(&mut image, header + 16, raw_size as u32);
+                put32(&mut image, header + 20, raw_pointer as u32);
+                put32(&mut image, header + 36, 0x6000_0020);
+                image.extend_from_slice(data);
+                image.resize(raw_pointer + raw_size, 0);
+            }
+
+            if let Some((cert_type, data)) = &self.certificate {
+                let table = image.len();
+                let length = 8 + data.len();
+                image.extend_from_slice(&(length as u32).to_le_bytes());
+                image.extend_from_slice(&0x0200u16.to_le_bytes());
+                image.extend_from_slice(&cert_type.to_le_bytes());
+                image.extend_from_slice(data);
+                image.resize(table + align(length, 8), 0);
+                put32(&mut image, opt + 112 + 4 * 8, table as u32);
+                let table_size = image.len() - table;
+                put32(&mut image, opt + 112 + 4 * 8 + 4, table_size as u32);
+            }
+
+            image
+        }
+    }
+
+    pub fn align(value: usize, alignment: usize) -> usize {
+        (value + alignment - 1) / alignment * alignment
+    }
+
+    fn put16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_image::{ImageBuilder, native_machine, foreign_machine};
+
+    #[test]
+    fn parses_headers_and_sections() {
+        let mut builder = ImageBuilder::new();
+        builder.sections.push((".sbat", b"sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\ngrub,3,Free Software Foundation,grub,2.06,https://www.gnu.org/software/grub/\n".to_vec()));
+        let data = builder.build();
+
+        let image = PeImage::parse(&data).unwrap();
+        assert_eq!(image.machine(), Machine::X64);
+        assert_eq!(image.subsystem(), Subsystem::EfiApplication);
+        assert!(image.is_pe32_plus());
+        assert!(image.is_nx_compatible());
+        assert_eq!(image.entry_point(), 0x1000);
+        assert_eq!(image.image_base(), 0x4000_0000);
+        assert_eq!(image.sections().len(), 2);
+        assert_eq!(image.section_data(image.section(".text").unwrap())[..16], [0xC3; 16]);
+        assert_eq!(image.data_directories().len(), 16);
+
+        let sbat = image.sbat().unwrap().unwrap();
+        assert_eq!(sbat.len(), 2);
+        assert_eq!(sbat[1].component, "grub");
+        assert_eq!(sbat[1].generation, 3);
+        assert_eq!(sbat[1].version, "2.06");
+    }
+
+    #[test]
+    fn reads_the_certificate_table() {
+        let mut builder = ImageBuilder::new();
+        builder.machine = native_machine();
+        assert!(!PeImage::parse(&builder.build()).unwrap().is_signed());
+
+        builder.certificate = Some((WIN_CERT_TYPE_PKCS_SIGNED_DATA, vec![0x30, 0x82, 0x01]));
+        let data = builder.build();
+        let image = PeImage::parse(&data).unwrap();
+        let certificates = image.certificates().unwrap();
+        assert_eq!(certificates.len(), 1);
+        assert_eq!(certificates[0].certificate_type, WIN_CERT_TYPE_PKCS_SIGNED_DATA);
+        assert_eq!(certificates[0].data, &[0x30, 0x82, 0x01]);
+        assert!(image.validate(true).is_ok());
+    }
+
+    #[test]
+    fn validation_explains_rejections() {
+        let mut builder = ImageBuilder::new();
+        builder.machine = foreign_machine();
+        let data = builder.build();
+        assert_eq!(PeImage::parse(&data).unwrap().validate(false), Err(PeError::WrongMachine { expected: Machine::native(), found: Machine::from(foreign_machine()) }));
+
+        let mut builder = ImageBuilder::new();
+        builder.machine = native_machine();
+        builder.subsystem = 3; // Windows console
+        let data = builder.build();
+        assert_eq!(PeImage::parse(&data).unwrap().validate(false), Err(PeError::NotEfi(Subsystem::Other(3))));
+
+        builder.subsystem = 10;
+        let data = builder.build();
+        assert_eq!(PeImage::parse(&data).unwrap().validate(true), Err(PeError::Unsigned));
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        assert_eq!(PeImage::parse(b"not an image").unwrap_err(), PeError::NotPe);
+
+        let data = ImageBuilder::new().build();
+        assert_eq!(PeImage::parse(&data[..0x100]).unwrap_err(), PeError::Truncated); // Section data cut off
+
+        let mut bad_pe_offset = data.clone();
+        bad_pe_offset[0x3C..0x40].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert_eq!(PeImage::parse(&bad_pe_offset).unwrap_err(), PeError::Truncated);
+
+        let mut too_many_directories = data.clone();
+        too_many_directories[0xC4..0xC8].copy_from_slice(&u32::MAX.to_le_bytes()); // NumberOfRvaAndSizes
+        assert_eq!(PeImage::parse(&too_many_directories).unwrap_err(), PeError::Malformed("data directory"));
+
+        let mut headers_too_small = data.clone();
+        headers_too_small[0x94..0x98].copy_from_slice(&0u32.to_le_bytes()); // SizeOfHeaders
+        assert_eq!(PeImage::parse(&headers_too_small).unwrap_err(), PeError::Malformed("optional header"));
+    }
+}
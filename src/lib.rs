@@ -12,7 +12,13 @@ pub mod io;
 pub mod net;
 pub mod image;
 pub mod device_path;
+pub mod disk;
+pub mod fs;
 pub mod events;
+pub mod sync;
+pub mod tui;
+#[cfg(feature = "task")]
+pub mod task;
 pub mod time;
 mod allocator;
 mod boot_services;
@@ -92,6 +98,24 @@ impl From<EFI_STATUS> for EfiError {
     }
 }
 
+// Lossy since io errors don't carry an EFI_STATUS, but it lets the
+// parsers that work over io::Read/Write/Seek use '?' on I/O results
+impl From<io::Error> for EfiError {
+    fn from(error: io::Error) -> Self {
+        let kind = match error.kind() {
+            io::ErrorKind::UnexpectedEof => EfiErrorKind::EndOfFile,
+            io::ErrorKind::NotFound => EfiErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => EfiErrorKind::AccessDenied,
+            io::ErrorKind::InvalidInput => EfiErrorKind::InvalidParameter,
+            io::ErrorKind::InvalidData => EfiErrorKind::VolumeCorrupted,
+            io::ErrorKind::TimedOut => EfiErrorKind::Timeout,
+            io::ErrorKind::WriteZero => EfiErrorKind::VolumeFull,
+            _ => EfiErrorKind::DeviceError,
+        };
+        EfiError::from(kind)
+    }
+}
+
 impl From<EfiError> for EFI_STATUS {
     fn from(error: EfiError) -> Self {
         error.kind().into()
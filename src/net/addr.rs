@@ -1,5 +1,5 @@
 use ffi::{EFI_IPv4_ADDRESS, EFI_IPv6_ADDRESS, EFI_IP_ADDRESS};
-use core::{mem, fmt, iter, slice, option, cmp::Ordering};
+use core::{fmt, iter, slice, option, cmp::Ordering};
 use crate::io;
 use alloc::{string::String, vec::{self, Vec}};
 use super::dns::lookup_host;
@@ -242,9 +242,13 @@ pub struct Ipv6Addr(EFI_IPv6_ADDRESS);
 
 impl Ipv6Addr {
     pub fn new(a: u16, b: u16, c: u16, d: u16, e: u16, f: u16, g: u16, h: u16) -> Self {
-        Ipv6Addr(EFI_IPv6_ADDRESS {
-            Addr: unsafe { mem::transmute([a, b, c, d, e, f, g, h]) } // Transmuting from an 8 elem array of u16 to 16 elem array of UINT8
-        })
+        // Segments are stored in network byte order, so no transmuting here
+        let mut addr = [0u8; 16];
+        for (i, segment) in [a, b, c, d, e, f, g, h].iter().enumerate() {
+            addr[i * 2..i * 2 + 2].copy_from_slice(&segment.to_be_bytes());
+        }
+
+        Ipv6Addr(EFI_IPv6_ADDRESS { Addr: addr })
     }
 
     pub fn localhost() -> Ipv6Addr {
@@ -395,11 +399,10 @@ impl Ipv6Addr {
         }
     }
 
-    // TODO: Implement this. Note you can't just transmute from segments() because of endianness
-    // /// Returns the sixteen eight-bit integers the IPv6 address consists of.
-    // pub fn octets(&self) -> [u8; 16] {
-    //     self.inner.s6_addr
-    // }
+    /// Returns the sixteen eight-bit integers the IPv6 address consists of.
+    pub fn octets(&self) -> [u8; 16] {
+        self.0.Addr
+    }
 }
 
 impl From<EFI_IPv6_ADDRESS> for Ipv6Addr {
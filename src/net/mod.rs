@@ -13,8 +13,13 @@ use crate::{
     to_res,
     io::{self, Read, Write},
     events::{self, TimerSchedule, TimerState, EventTpl, Wait},
+    sync::TplCell,
     boot_services::locate_handles,
 };
+#[cfg(feature = "task")]
+use crate::{task, events::AsRawEvt};
+#[cfg(feature = "task")]
+use ffi::tcp4::EFI_TCP4_COMPLETION_TOKEN;
 use self::pxebc::DhcpConfig;
 use ffi::{
     TRUE,
@@ -51,7 +56,8 @@ use ffi::{
         EFI_TCP4_CONFIG_DATA,
         EFI_TCP4_ACCESS_POINT,
         EFI_TCP4_OPTION,
-        EFI_TCP4_FRAGMENT_DATA 
+        EFI_TCP4_FRAGMENT_DATA,
+        PacketUnion,
     },
     udp4::{
         EFI_UDP4_SERVICE_BINDING_PROTOCOL_GUID,
@@ -95,11 +101,40 @@ fn for_ip4_only<A: ToSocketAddrs, F: FnMut(SocketAddrV4) -> Result<S>, S>(addr:
     Err(EfiErrorKind::DeviceError.into())
 }
 
+#[cfg(feature = "task")]
+fn first_ip4<A: ToSocketAddrs>(addr: A) -> Result<SocketAddrV4> {
+    addr.to_socket_addrs()
+        .map_err(|_| EfiError::from(EfiErrorKind::DeviceError))?
+        .filter_map(|a| if let SocketAddr::V4(a) = a { Some(a) } else { None })
+        .next()
+        .ok_or_else(|| EfiErrorKind::DeviceError.into()) // TODO: Same as in for_ip4_only. Should say something about Ipv6 not being supported yet
+}
+
 impl TcpStream {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
         Ok(Self {tcp4_stream: for_ip4_only(addr, |addr| Tcp4Stream::connect(addr))? })
     }
 
+    /// Same as `connect` but lets other tasks run while the connection is being established.
+    /// Only the first IPv4 address `addr` resolves to is tried.
+    #[cfg(feature = "task")]
+    pub async fn connect_async<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let addr = first_ip4(addr)?;
+        Ok(Self { tcp4_stream: Tcp4Stream::connect_async(addr).await? })
+    }
+
+    /// Reads into `buf` without blocking other tasks. Returns the number of bytes read.
+    #[cfg(feature = "task")]
+    pub async fn read_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.tcp4_stream.read_buf_async(buf).await
+    }
+
+    /// Writes `buf` without blocking other tasks. Returns the number of bytes written.
+    #[cfg(feature = "task")]
+    pub async fn write_async(&mut self, buf: &[u8]) -> Result<usize> {
+        self.tcp4_stream.write_buf_async(buf).await
+    }
+
     pub fn peer_addr(&self) -> Result<SocketAddr> {
         self.tcp4_stream.peer_addr().map(|a| SocketAddr::V4(a))
     }
@@ -141,18 +176,19 @@ extern "win64" fn empty_cb(_event: EFI_EVENT, _context: *const VOID) -> EFI_STAT
     EFI_SUCCESS
 }
 
-static mut OP_DONE: bool = false;
+// Set from a notify function at TPL_NOTIFY hence the TplCell
+static OP_DONE: TplCell<bool> = TplCell::new(EventTpl::Notify, false);
 extern "win64" fn common_cb(_event: EFI_EVENT, _context: *const VOID) -> EFI_STATUS {
-    unsafe { OP_DONE = true };
+    OP_DONE.set(true);
     EFI_SUCCESS
 }
 
 fn reset_op_done() {
-    unsafe { OP_DONE = false }
+    OP_DONE.set(false)
 }
 
 fn op_done() -> bool {
-    unsafe { OP_DONE }
+    OP_DONE.get()
 }
 
 impl Tcp4Stream {
@@ -171,6 +207,30 @@ impl Tcp4Stream {
     }
 
     fn connect(addr: SocketAddrV4) -> Result<Self> {
+        let mut stream = Self::open(addr)?;
+        unsafe {
+            ret_on_err!(((*stream.protocol).Connect)(stream.protocol, &mut stream.connect_token));
+            stream.wait_for_evt(&stream.connect_token.CompletionToken.Event)?;
+            ret_on_err!(stream.connect_token.CompletionToken.Status);
+        }
+        stream.is_connected = true;
+
+        Ok(stream)
+    }
+
+    #[cfg(feature = "task")]
+    async fn connect_async(addr: SocketAddrV4) -> Result<Self> {
+        let mut stream = Self::open(addr)?;
+        ret_on_err!(unsafe { ((*stream.protocol).Connect)(stream.protocol, &mut stream.connect_token) });
+        task::EventSignaled::new(stream.connect_token.CompletionToken.Event).await?;
+        ret_on_err!(stream.connect_token.CompletionToken.Status);
+        stream.is_connected = true;
+
+        Ok(stream)
+    }
+
+    /// Creates and configures the TCP instance for a connection to `addr` without connecting yet
+    fn open(addr: SocketAddrV4) -> Result<Self> {
         // TODO: this function is too ugly right now. Refactor/clean it up.
         let ip: EFI_IPv4_ADDRESS = (*addr.ip()).into();
         
@@ -240,11 +300,6 @@ impl Tcp4Stream {
         let (subnet_addr, subnet_mask, gateway_addr) = form_default_route(&dhcp_config)?;
         unsafe {
             ret_on_err!(((*stream.protocol).Routes)(stream.protocol, FALSE, &subnet_addr, &subnet_mask, &gateway_addr));
-
-            ret_on_err!(((*stream.protocol).Connect)(stream.protocol, &mut stream.connect_token));
-            stream.wait_for_evt(&stream.connect_token.CompletionToken.Event)?;
-            ret_on_err!(stream.connect_token.CompletionToken.Status);
-            stream.is_connected = true;
         }
 
         // TODO: We should try to close all events that have been created if we're returning early
@@ -332,6 +387,97 @@ impl Tcp4Stream {
     }
 }
 
+#[cfg(feature = "task")]
+impl Tcp4Stream {
+    async fn read_buf_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let fragment_data = EFI_TCP4_FRAGMENT_DATA {
+            FragmentLength: buf.len() as UINT32,
+            FragmentBuffer: buf.as_ptr() as *const VOID
+        };
+
+        let recv_data = EFI_TCP4_RECEIVE_DATA {
+            UrgentFlag: FALSE,
+            DataLength: buf.len() as UINT32,
+            FragmentCount: 1,
+            FragmentTable: [fragment_data]
+        };
+
+        // The stream's own receive token signals OP_DONE from a notify function which can't be awaited.
+        // So this uses a token of its own with a plain event.
+        let event = task::TokenEvent::new()?;
+        let recv_token = EFI_TCP4_IO_TOKEN {
+            CompletionToken: EFI_TCP4_COMPLETION_TOKEN { Event: unsafe { event.as_raw() }, ..Default::default() },
+            Packet: PacketUnion { RxData: &recv_data },
+        };
+        ret_on_err!(unsafe { ((*self.protocol).Receive)(self.protocol, &recv_token) });
+
+        let protocol = self.protocol;
+        let pending = CancelOnDrop::new(&recv_token.CompletionToken as *const _ as *const VOID, |token| unsafe {
+            ((*protocol).Cancel)(protocol, token as *const EFI_TCP4_COMPLETION_TOKEN);
+        });
+        task::wait(&event).await?;
+        pending.disarm();
+
+        to_res(recv_data.DataLength as usize, recv_token.CompletionToken.Status)
+    }
+
+    async fn write_buf_async(&mut self, buf: &[u8]) -> Result<usize> {
+        let fragment_data = EFI_TCP4_FRAGMENT_DATA {
+            FragmentLength: buf.len() as UINT32,
+            FragmentBuffer: buf.as_ptr() as *const VOID
+        };
+
+        let send_data = EFI_TCP4_TRANSMIT_DATA {
+            Push: FALSE,
+            Urgent: FALSE,
+            DataLength: buf.len() as UINT32,
+            FragmentCount: 1,
+            FragmentTable: [fragment_data]
+        };
+
+        self.send_token.Packet.TxData =  &send_data;
+        ret_on_err!(unsafe { ((*self.protocol).Transmit)(self.protocol, &self.send_token) });
+
+        let protocol = self.protocol;
+        let pending = CancelOnDrop::new(&self.send_token.CompletionToken as *const _ as *const VOID, |token| unsafe {
+            ((*protocol).Cancel)(protocol, token as *const EFI_TCP4_COMPLETION_TOKEN);
+        });
+        task::EventSignaled::new(self.send_token.CompletionToken.Event).await?;
+        pending.disarm();
+
+        to_res(buf.len(), self.send_token.CompletionToken.Status)
+    }
+}
+
+/// Cancels a token that's still queued in a protocol when an async operation is dropped before completing.
+/// Otherwise the protocol would go on writing to the dropped future's memory.
+#[cfg(feature = "task")]
+struct CancelOnDrop<F: FnMut(*const VOID)> {
+    token: *const VOID,
+    cancel: Option<F>,
+}
+
+#[cfg(feature = "task")]
+impl<F: FnMut(*const VOID)> CancelOnDrop<F> {
+    fn new(token: *const VOID, cancel: F) -> Self {
+        Self { token, cancel: Some(cancel) }
+    }
+
+    /// The operation completed. Nothing to cancel.
+    fn disarm(mut self) {
+        self.cancel = None;
+    }
+}
+
+#[cfg(feature = "task")]
+impl<F: FnMut(*const VOID)> Drop for CancelOnDrop<F> {
+    fn drop(&mut self) {
+        if let Some(ref mut cancel) = self.cancel {
+            cancel(self.token);
+        }
+    }
+}
+
 impl Drop for Tcp4Stream {
     fn drop(&mut self) {
         // TODO: add the code to panic when any of the below calls fail. (Could be difficult) but maybe we can trace something when we do that.
@@ -424,6 +570,12 @@ impl UdpSocket {
         self.udp4_socket.recv_buf(buf)
     }
 
+    /// Receives a datagram without blocking other tasks. The read timeout doesn't apply.
+    #[cfg(feature = "task")]
+    pub async fn recv_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.udp4_socket.recv_buf_async(buf).await
+    }
+
     // TODO: need to make self non-mut just like in the std lib
     pub fn send(&mut self, buf: &[u8]) -> Result<usize> {
         self.udp4_socket.send_buf(buf, None)
@@ -736,6 +888,32 @@ impl Udp4Socket {
         }
     }
 
+    #[cfg(feature = "task")]
+    async fn recv_buf_async(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Own token with an awaitable event for the same reason as in Tcp4Stream::read_buf_async
+        let event = task::TokenEvent::new()?;
+        let recv_token = EFI_UDP4_COMPLETION_TOKEN { Event: unsafe { event.as_raw() }, ..Default::default() };
+        ret_on_err!(unsafe { ((*self.protocol).Receive)(self.protocol, &recv_token) });
+
+        let protocol = self.protocol;
+        let pending = CancelOnDrop::new(&recv_token as *const _ as *const VOID, |token| unsafe {
+            ((*protocol).Cancel)(protocol, token as *const EFI_UDP4_COMPLETION_TOKEN);
+        });
+        task::wait(&event).await?;
+        pending.disarm();
+        ret_on_err!(recv_token.Status);
+
+        unsafe {
+            let read_data = (*recv_token.Packet.RxData).FragmentTable[0].FragmentBuffer as *const u8;
+            let read_len = (*recv_token.Packet.RxData).FragmentTable[0].FragmentLength as usize;
+            if buf.len() < read_len {
+                return Err(EfiError::from(::ffi::EFI_INVALID_PARAMETER));
+            }
+            ptr::copy(read_data, buf.as_mut_ptr(), read_len);
+            Ok(read_len)
+        }
+    }
+
     fn send_buf(&mut self, buf: &[u8], session_data: Option<&EFI_UDP4_SESSION_DATA>) -> Result<usize> {
         let fragment_data = EFI_UDP4_FRAGMENT_DATA {
             FragmentLength: buf.len() as UINT32,
@@ -0,0 +1,162 @@
+//! Synchronization with event notify functions.
+//!
+//! Boot services run on one processor so the only concurrency is notify functions interrupting
+//! code running at a lower task priority level (TPL). Raising the TPL to that of the notify
+//! function holds it off, which is all that's needed for exclusive access to shared state.
+//!
+//! ```ignore
+//! static RECEIVED: TplCell<bool> = TplCell::new(EventTpl::Notify, false);
+//!
+//! // In the notify function
+//! RECEIVED.set(true);
+//!
+//! // Elsewhere
+//! if RECEIVED.replace(false) { ... }
+//! ```
+
+use crate::{system_table, events::EventTpl};
+use ffi::boot_services::{EFI_TPL, TPL_HIGH_LEVEL};
+use core::{
+    mem,
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+// efi_ffi doesn't define the signatures of RaiseTPL and RestoreTPL yet
+type EfiRaiseTpl = extern "win64" fn(new_tpl: EFI_TPL) -> EFI_TPL;
+type EfiRestoreTpl = extern "win64" fn(old_tpl: EFI_TPL);
+
+fn raise_tpl(tpl: EFI_TPL) -> EFI_TPL {
+    let bs = system_table().BootServices;
+    unsafe {
+        let raise: EfiRaiseTpl = mem::transmute((*bs).RaiseTPL);
+        raise(tpl)
+    }
+}
+
+fn restore_tpl(tpl: EFI_TPL) {
+    let bs = system_table().BootServices;
+    unsafe {
+        let restore: EfiRestoreTpl = mem::transmute((*bs).RestoreTPL);
+        restore(tpl)
+    }
+}
+
+/// Keeps the TPL raised while it's alive. Notify functions at or below that TPL are held off until it is dropped.
+///
+/// Raising to a TPL below the current one is a no-op rather than a violation of the spec,
+/// so guards can be created from notify functions too.
+pub struct TplGuard {
+    old_tpl: Option<EFI_TPL>,
+    _not_send: PhantomData<*const ()>, // Must be dropped in the same context it was created in
+}
+
+impl TplGuard {
+    pub fn raise(tpl: EventTpl) -> Self {
+        let tpl = tpl as EFI_TPL;
+
+        // The only way to find out the current TPL is to raise it
+        let current_tpl = raise_tpl(TPL_HIGH_LEVEL);
+        restore_tpl(current_tpl);
+
+        let old_tpl = if current_tpl < tpl { Some(raise_tpl(tpl)) } else { None };
+        Self { old_tpl, _not_send: PhantomData }
+    }
+}
+
+impl Drop for TplGuard {
+    fn drop(&mut self) {
+        if let Some(old_tpl) = self.old_tpl {
+            restore_tpl(old_tpl);
+        }
+    }
+}
+
+/// A mutex for data shared with notify functions.
+///
+/// `tpl` must be at least the TPL of every notify function that accesses the data.
+/// Locking it again while it's already locked panics since that can only happen from
+/// the same context (or from a notify function with a TPL higher than `tpl`).
+pub struct TplMutex<T> {
+    tpl: EventTpl,
+    locked: Cell<bool>,
+    value: UnsafeCell<T>,
+}
+
+// Exclusive access is ensured by raising the TPL and there's only the one processor
+unsafe impl<T: Send> Sync for TplMutex<T> {}
+unsafe impl<T: Send> Send for TplMutex<T> {}
+
+impl<T> TplMutex<T> {
+    pub const fn new(tpl: EventTpl, value: T) -> Self {
+        Self { tpl, locked: Cell::new(false), value: UnsafeCell::new(value) }
+    }
+
+    pub fn lock(&self) -> TplMutexGuard<'_, T> {
+        let tpl_guard = TplGuard::raise(self.tpl);
+        if self.locked.replace(true) {
+            panic!("TplMutex locked twice");
+        }
+
+        TplMutexGuard { mutex: self, _tpl_guard: tpl_guard }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct TplMutexGuard<'a, T> {
+    mutex: &'a TplMutex<T>,
+    _tpl_guard: TplGuard, // Restores the TPL when it drops, which is after `drop` below has unlocked the mutex
+}
+
+impl<'a, T> Deref for TplMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for TplMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for TplMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.set(false);
+    }
+}
+
+/// A `Cell` for `Copy` values shared with notify functions. Every access briefly raises the TPL to `tpl`.
+pub struct TplCell<T: Copy> {
+    tpl: EventTpl,
+    value: Cell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for TplCell<T> {}
+
+impl<T: Copy> TplCell<T> {
+    pub const fn new(tpl: EventTpl, value: T) -> Self {
+        Self { tpl, value: Cell::new(value) }
+    }
+
+    pub fn get(&self) -> T {
+        let _guard = TplGuard::raise(self.tpl);
+        self.value.get()
+    }
+
+    pub fn set(&self, value: T) {
+        let _guard = TplGuard::raise(self.tpl);
+        self.value.set(value)
+    }
+
+    pub fn replace(&self, value: T) -> T {
+        let _guard = TplGuard::raise(self.tpl);
+        self.value.replace(value)
+    }
+}
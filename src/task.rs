@@ -0,0 +1,443 @@
+//! A minimal single-threaded async executor driven by UEFI events.
+//!
+//! Futures that wait on firmware activity (timers, network tokens etc.) register their event
+//! with the executor when they return `Pending`. Once no task can make progress the executor
+//! blocks in `WaitForEvent` on all registered events and wakes the task whose event fired.
+//!
+//! ```ignore
+//! task::block_on(async {
+//!     let (download, _) = efi::join!(fetch_image(&mut stream), countdown(5));
+//!     download
+//! })?;
+//! ```
+//!
+//! Wakers can be called from event notify functions as well. They only set a flag and signal
+//! an event, both of which are allowed at any TPL up to `TPL_NOTIFY`.
+
+use crate::{
+    Result,
+    system_table,
+    events::{self, AsRawEvt, TimerSchedule, TimerState, EventTpl},
+};
+use ffi::{
+    EFI_EVENT,
+    EFI_SUCCESS,
+    EFI_NOT_READY,
+    UINTN,
+    boot_services::{EFI_TPL, TPL_CALLBACK},
+};
+use core::{
+    ptr,
+    future::Future,
+    pin::Pin,
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use alloc::{boxed::Box, rc::{Rc, Weak}, sync::Arc, task::Wake, vec::Vec};
+
+/// Runs `future` to completion on a new executor
+pub fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    Executor::new()?.block_on(future)
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Runs any number of tasks on the current (and only) processor
+pub struct Executor {
+    tasks: Vec<(Task, Arc<TaskWaker>)>,
+    wake_event: EFI_EVENT, // Signaled by wakers so that a wait in progress returns
+}
+
+impl Executor {
+    pub fn new() -> Result<Self> {
+        Ok(Self { tasks: Vec::new(), wake_event: create_plain_event()? })
+    }
+
+    /// Adds a task that runs alongside whatever is passed to `block_on` or `run`
+    pub fn spawn<F: Future<Output = ()> + 'static>(&mut self, future: F) {
+        let waker = Arc::new(TaskWaker { woken: AtomicBool::new(true), event: self.wake_event });
+        self.tasks.push((Box::pin(future), waker));
+    }
+
+    /// Runs spawned tasks until all of them have finished
+    pub fn run(&mut self) -> Result<()> {
+        while !self.tasks.is_empty() {
+            if !self.poll_tasks() {
+                self.wait()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `future` and any spawned tasks until `future` completes.
+    /// Spawned tasks that haven't finished by then stay in the executor.
+    pub fn block_on<F: Future>(&mut self, future: F) -> Result<F::Output> {
+        let mut future = Box::pin(future);
+        let main_waker = Arc::new(TaskWaker { woken: AtomicBool::new(true), event: self.wake_event });
+        let waker = Waker::from(main_waker.clone());
+
+        loop {
+            if main_waker.take() {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                    return Ok(output);
+                }
+            }
+
+            if !self.poll_tasks() && !main_waker.is_woken() {
+                self.wait()?;
+            }
+        }
+    }
+
+    /// Polls every woken task once. Returns whether any of them were woken.
+    fn poll_tasks(&mut self) -> bool {
+        let mut any_woken = false;
+        let mut i = 0;
+        while i < self.tasks.len() {
+            let (ref mut task, ref task_waker) = self.tasks[i];
+            if task_waker.take() {
+                any_woken = true;
+                let waker = Waker::from(task_waker.clone());
+                if task.as_mut().poll(&mut Context::from_waker(&waker)).is_ready() {
+                    drop(self.tasks.swap_remove(i));
+                    continue;
+                }
+            }
+            i += 1;
+        }
+
+        any_woken
+    }
+
+    /// Blocks until either a registered event fires or a waker is called
+    fn wait(&self) -> Result<()> {
+        let registrations = REACTOR.live_registrations();
+        let mut raw_events = registrations.iter().map(|r| r.event).collect::<Vec<_>>();
+        raw_events.push(self.wake_event);
+
+        let bs = system_table().BootServices;
+        let mut signaled_index: UINTN = 0;
+        unsafe {
+            ret_on_err!(((*bs).WaitForEvent)(raw_events.len(), raw_events.as_ptr(), &mut signaled_index));
+        }
+
+        // WaitForEvent clears the signaled state so the future has to be told explicitly
+        if let Some(registration) = registrations.get(signaled_index) {
+            registration.fired.set(true);
+            registration.waker.borrow().wake_by_ref();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.tasks.clear(); // Tasks may still refer to the wake event through their wakers. Drop them first.
+        let bs = system_table().BootServices;
+        unsafe {
+            ((*bs).CloseEvent)(self.wake_event); // Can't do anything if it fails
+        }
+    }
+}
+
+struct TaskWaker {
+    woken: AtomicBool,
+    event: EFI_EVENT,
+}
+
+impl TaskWaker {
+    fn take(&self) -> bool {
+        self.woken.swap(false, Ordering::AcqRel)
+    }
+
+    fn is_woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+}
+
+// The event is only ever signaled which firmware allows from any context
+unsafe impl Send for TaskWaker {}
+unsafe impl Sync for TaskWaker {}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        let bs = system_table().BootServices;
+        unsafe {
+            ((*bs).SignalEvent)(self.event);
+        }
+    }
+}
+
+/// An event some future is waiting on
+struct Registration {
+    event: EFI_EVENT,
+    waker: RefCell<Waker>,
+    fired: Cell<bool>,
+}
+
+struct Reactor {
+    registrations: RefCell<Vec<Weak<Registration>>>,
+}
+
+// Only touched from the executor and the futures it polls, never from notify functions.
+// Boot services run on a single processor so that's enough.
+unsafe impl Sync for Reactor {}
+
+static REACTOR: Reactor = Reactor { registrations: RefCell::new(Vec::new()) };
+
+impl Reactor {
+    fn register(&self, event: EFI_EVENT, waker: &Waker) -> Rc<Registration> {
+        let registration = Rc::new(Registration { event, waker: RefCell::new(waker.clone()), fired: Cell::new(false) });
+        self.registrations.borrow_mut().push(Rc::downgrade(&registration));
+        registration
+    }
+
+    /// Registrations whose futures are still around. Forgets the others.
+    fn live_registrations(&self) -> Vec<Rc<Registration>> {
+        let mut registrations = self.registrations.borrow_mut();
+        registrations.retain(|r| r.strong_count() > 0);
+        registrations.iter().filter_map(|r| r.upgrade()).collect()
+    }
+}
+
+/// Completes once a raw event is signaled
+pub(crate) struct EventSignaled {
+    event: EFI_EVENT,
+    registration: Option<Rc<Registration>>,
+}
+
+impl EventSignaled {
+    pub(crate) fn new(event: EFI_EVENT) -> Self {
+        Self { event, registration: None }
+    }
+}
+
+impl Future for EventSignaled {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(ref registration) = self.registration {
+            if registration.fired.get() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        let bs = system_table().BootServices;
+        match unsafe { ((*bs).CheckEvent)(self.event) } {
+            EFI_SUCCESS => return Poll::Ready(Ok(())),
+            EFI_NOT_READY => (),
+            s => return Poll::Ready(Err(s.into())),
+        }
+
+        match self.registration {
+            Some(ref registration) => { registration.waker.replace(cx.waker().clone()); },
+            None => self.registration = Some(REACTOR.register(self.event, cx.waker())),
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Waits for `event` to be signaled without blocking other tasks.
+///
+/// Like `Wait::wait` this doesn't work for events created with `NotifyType::Signal`.
+pub fn wait<E: AsRawEvt>(event: &E) -> impl Future<Output = Result<()>> + '_ {
+    EventSignaled::new(unsafe { event.as_raw() })
+}
+
+/// Completes after `duration` has passed
+pub async fn sleep(duration: Duration) -> Result<()> {
+    let timer = events::Timer::create(duration, TimerSchedule::Relative, TimerState::Active, EventTpl::Callback)?;
+    wait(&timer).await
+}
+
+/// Ticks at a fixed rate, e.g. to drive a countdown
+pub struct Interval {
+    timer: events::Timer,
+}
+
+impl Interval {
+    /// Starts ticking right away. The first tick comes after `period`.
+    pub fn new(period: Duration) -> Result<Self> {
+        Ok(Self { timer: events::Timer::create(period, TimerSchedule::Periodic, TimerState::Active, EventTpl::Callback)? })
+    }
+
+    /// Completes on the next tick. Ticks missed while not waiting collapse into one.
+    pub async fn tick(&mut self) -> Result<()> {
+        wait(&self.timer).await
+    }
+}
+
+/// An event without a notify function that can be handed to a protocol as a completion event
+/// and then awaited. Closed on drop.
+pub(crate) struct TokenEvent(EFI_EVENT);
+
+impl TokenEvent {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(TokenEvent(create_plain_event()?))
+    }
+}
+
+impl AsRawEvt for TokenEvent {
+    unsafe fn as_raw(&self) -> EFI_EVENT {
+        self.0
+    }
+}
+
+impl Drop for TokenEvent {
+    fn drop(&mut self) {
+        let bs = system_table().BootServices;
+        unsafe {
+            ((*bs).CloseEvent)(self.0); // Can't do anything if it fails
+        }
+    }
+}
+
+fn create_plain_event() -> Result<EFI_EVENT> {
+    let bs = system_table().BootServices;
+    let mut event: EFI_EVENT = ptr::null();
+    unsafe {
+        ret_on_err!(((*bs).CreateEvent)(0, TPL_CALLBACK as EFI_TPL, None, ptr::null(), &mut event));
+    }
+    Ok(event)
+}
+
+/// Holds a future's output once it has completed so it can be polled alongside unfinished ones
+#[doc(hidden)]
+pub enum MaybeDone<F: Future> {
+    Pending(F),
+    Done(F::Output),
+    Taken,
+}
+
+impl<F: Future> MaybeDone<F> {
+    /// Polls the future if it's still pending. Returns whether it's done.
+    fn poll_done(self: Pin<&mut Self>, cx: &mut Context) -> bool {
+        // Safe because the inner future is never moved out while pinned. It is only dropped in place when replaced.
+        let this = unsafe { self.get_unchecked_mut() };
+        if let MaybeDone::Pending(ref mut future) = this {
+            match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+                Poll::Ready(output) => *this = MaybeDone::Done(output),
+                Poll::Pending => return false,
+            }
+        }
+        true
+    }
+
+    fn take(self: Pin<&mut Self>) -> F::Output {
+        let this = unsafe { self.get_unchecked_mut() };
+        match core::mem::replace(this, MaybeDone::Taken) {
+            MaybeDone::Done(output) => output,
+            _ => panic!("output taken before the future completed"),
+        }
+    }
+}
+
+/// Runs two futures concurrently and completes with both outputs once both are done.
+/// See also the `join!` macro.
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join { a: MaybeDone::Pending(a), b: MaybeDone::Pending(b) }
+}
+
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // Safe because neither field is moved while pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let mut b = unsafe { Pin::new_unchecked(&mut this.b) };
+
+        let a_done = a.as_mut().poll_done(cx);
+        let b_done = b.as_mut().poll_done(cx);
+        if a_done && b_done {
+            Poll::Ready((a.take(), b.take()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Awaits several futures concurrently and evaluates to a tuple of their outputs.
+/// Only usable inside async code.
+///
+/// ```ignore
+/// let (a, b, c) = efi::join!(fut_a, fut_b, fut_c);
+/// ```
+#[macro_export]
+macro_rules! join {
+    ($a:expr, $b:expr $(,)?) => {
+        $crate::task::join($a, $b).await
+    };
+    ($a:expr, $b:expr, $c:expr $(,)?) => {{
+        let (a, (b, c)) = $crate::task::join($a, $crate::task::join($b, $c)).await;
+        (a, b, c)
+    }};
+    ($a:expr, $b:expr, $c:expr, $d:expr $(,)?) => {{
+        let ((a, b), (c, d)) = $crate::task::join($crate::task::join($a, $b), $crate::task::join($c, $d)).await;
+        (a, b, c, d)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pending for the given number of polls, waking itself each time
+    struct Yield(usize, usize);
+
+    impl Future for Yield {
+        type Output = usize;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+            if self.0 == 0 {
+                return Poll::Ready(self.1);
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    fn poll_to_end<F: Future>(future: F) -> (F::Output, usize) {
+        let mut future = Box::pin(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut polls = 1;
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return (output, polls);
+            }
+            polls += 1;
+        }
+    }
+
+    #[test]
+    fn join_waits_for_the_slowest_future() {
+        let (output, polls) = poll_to_end(join(Yield(3, 1), Yield(1, 2)));
+        assert_eq!(output, (1, 2));
+        assert_eq!(polls, 4);
+    }
+
+    #[test]
+    fn join_macro_flattens_outputs() {
+        let ((three, four), _) = poll_to_end(async {
+            (join!(Yield(2, 1), Yield(0, 2), Yield(1, 3)), join!(Yield(0, 1), Yield(0, 2), Yield(0, 3), Yield(5, 4)))
+        });
+        assert_eq!(three, (1, 2, 3));
+        assert_eq!(four, (1, 2, 3, 4));
+    }
+}
@@ -0,0 +1,629 @@
+//! Text-mode UI widgets: frames, menus, yes/no dialogs, progress bars and a status line.
+//!
+//! Everything is drawn through `Console` using box-drawing characters and laid out against the
+//! rows and columns of the current text mode. Widgets that take over the screen (menus and dialogs)
+//! hide the cursor while they run and, when done, blank the area they covered and restore the
+//! console's colors, cursor position and cursor visibility. Redrawing what was underneath is up to the caller.
+//!
+//! ```ignore
+//! let mut console = console();
+//! StatusLine::new().set(&mut console, "Up/Down select  Enter boot  Esc firmware setup")?;
+//!
+//! let mut menu = Menu::new("Boot")
+//!     .item("Windows Boot Manager", Target::Windows)
+//!     .item("Linux", Target::Linux);
+//!
+//! if let Some(target) = menu.run(&mut console)? {
+//!     if ConfirmDialog::new("Boot", "Boot the selected entry?").run(&mut console)? {
+//!         boot(*target)?;
+//!     }
+//! }
+//! ```
+
+use crate::console::{Console, ForeColor, BackColor, Key, Position};
+use crate::Result;
+use alloc::{vec::Vec, string::String};
+use core::{cmp, iter, mem};
+
+const HORIZONTAL: char = '\u{2500}';
+const VERTICAL: char = '\u{2502}';
+const DOWN_RIGHT: char = '\u{250C}';
+const DOWN_LEFT: char = '\u{2510}';
+const UP_RIGHT: char = '\u{2514}';
+const UP_LEFT: char = '\u{2518}';
+const ARROW_UP: char = '\u{2191}';
+const ARROW_DOWN: char = '\u{2193}';
+const FULL_BLOCK: char = '\u{2588}';
+const LIGHT_SHADE: char = '\u{2591}';
+
+const PROGRESS_BAR_MAX_WIDTH: usize = 60;
+
+/// A foreground and background color pair
+#[derive(Debug, Copy, Clone)]
+pub struct Colors {
+    pub fore: ForeColor,
+    pub back: BackColor,
+}
+
+impl Colors {
+    pub fn new(fore: ForeColor, back: BackColor) -> Self {
+        Self { fore, back }
+    }
+}
+
+/// The colors widgets are drawn in
+#[derive(Debug, Copy, Clone)]
+pub struct Theme {
+    /// Frames, text and unselected items
+    pub window: Colors,
+    /// The selected menu item or dialog button
+    pub selected: Colors,
+    /// The status line
+    pub status: Colors,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            window: Colors::new(ForeColor::White, BackColor::Blue),
+            selected: Colors::new(ForeColor::Black, BackColor::LightGray),
+            status: Colors::new(ForeColor::Black, BackColor::LightGray),
+        }
+    }
+}
+
+/// A rectangular area of the screen in character cells
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub row: usize,
+    pub col: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(row: usize, col: usize, width: usize, height: usize) -> Self {
+        Self { row, col, width, height }
+    }
+
+    /// A rect of the given size centered on a screen of `columns` by `rows`, shrunk if it doesn't fit
+    pub fn centered(width: usize, height: usize, columns: usize, rows: usize) -> Self {
+        let width = cmp::min(width, columns);
+        let height = cmp::min(height, rows);
+        Self { row: (rows - height) / 2, col: (columns - width) / 2, width, height }
+    }
+
+    /// The area inside a frame drawn around this rect
+    pub fn inner(&self) -> Rect {
+        Rect {
+            row: self.row + 1,
+            col: self.col + 1,
+            width: self.width.saturating_sub(2),
+            height: self.height.saturating_sub(2),
+        }
+    }
+}
+
+/// A box drawn with line characters, with an optional title in its top edge
+pub struct Frame {
+    rect: Rect,
+    title: Option<String>,
+    colors: Colors,
+}
+
+impl Frame {
+    pub fn new(rect: Rect) -> Self {
+        Self { rect, title: None, colors: Theme::default().window }
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn colors(mut self, colors: Colors) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    pub fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    /// Draws the frame and blanks the area inside it
+    pub fn draw(&self, console: &mut Console) -> Result<()> {
+        let Rect { row, col, width, height } = self.rect;
+        if width < 2 || height < 2 {
+            return Ok(());
+        }
+
+        set_colors(console, self.colors)?;
+
+        let mut top = String::new();
+        top.push(DOWN_RIGHT);
+        match self.title {
+            Some(ref title) if width > 4 => {
+                let title = fit(&format!(" {} ", title), width - 4, false);
+                let rest = width - 3 - title.chars().count();
+                top.push(HORIZONTAL);
+                top.push_str(&title);
+                top.extend(iter::repeat_n(HORIZONTAL, rest));
+            },
+            _ => top.extend(iter::repeat_n(HORIZONTAL, width - 2)),
+        }
+        top.push(DOWN_LEFT);
+        put(console, row, col, &top)?;
+
+        let mut middle = String::new();
+        middle.push(VERTICAL);
+        middle.extend(iter::repeat_n(' ', width - 2));
+        middle.push(VERTICAL);
+        for r in row + 1..row + height - 1 {
+            put(console, r, col, &middle)?;
+        }
+
+        let mut bottom = String::new();
+        bottom.push(UP_RIGHT);
+        bottom.extend(iter::repeat_n(HORIZONTAL, width - 2));
+        bottom.push(UP_LEFT);
+        put(console, row + height - 1, col, &bottom)
+    }
+}
+
+/// A framed list of items, one of which is selected with the arrow keys
+pub struct Menu<T> {
+    title: String,
+    items: Vec<(String, T)>,
+    selected: usize,
+    theme: Theme,
+}
+
+impl<T> Menu<T> {
+    pub fn new(title: &str) -> Self {
+        Self { title: title.into(), items: Vec::new(), selected: 0, theme: Theme::default() }
+    }
+
+    pub fn item(mut self, label: &str, value: T) -> Self {
+        self.items.push((label.into(), value));
+        self
+    }
+
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Sets the item selected when the menu is shown
+    pub fn select(mut self, index: usize) -> Self {
+        self.selected = index;
+        self
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Shows the menu until an item is picked with Enter, which returns it, or Escape is pressed, which returns `None`.
+    ///
+    /// Up/Down, PageUp/PageDown and Home/End move the selection, scrolling the menu if it has more
+    /// items than fit on the screen. The selection is remembered for the next time the menu is run.
+    pub fn run(&mut self, console: &mut Console) -> Result<Option<&T>> {
+        if self.items.is_empty() {
+            return Ok(None);
+        }
+
+        let saved = SavedState::save(console)?;
+        let picked = self.run_inner(console, saved.colors);
+        saved.restore(console)?;
+
+        Ok(picked?.map(move |i| &self.items[i].1))
+    }
+
+    fn run_inner(&mut self, console: &mut Console, background: Colors) -> Result<Option<usize>> {
+        let (columns, rows) = console.size()?;
+
+        let label_width = self.items.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0);
+        let width = cmp::max(label_width + 2, self.title.chars().count() + 4) + 2;
+        let rect = Rect::centered(width, self.items.len() + 2, columns, rows.saturating_sub(2)); // Leave room for a status line
+        if rect.height < 3 || rect.width < 3 {
+            return Ok(None);
+        }
+
+        let frame = Frame::new(rect).title(&self.title).colors(self.theme.window);
+        frame.draw(console)?;
+
+        let visible = rect.height - 2;
+        self.selected = cmp::min(self.selected, self.items.len() - 1);
+        let mut top = scroll_to(self.selected, 0, visible);
+
+        let picked = loop {
+            self.draw_items(console, rect, top)?;
+
+            let key_press = console.read_key()?;
+            self.selected = match key_press.key {
+                Key::Enter => break Some(self.selected),
+                Key::Escape => break None,
+                key => move_selection(self.selected, self.items.len(), visible, key),
+            };
+            top = scroll_to(self.selected, top, visible);
+        };
+
+        clear(console, rect, background)?;
+        Ok(picked)
+    }
+
+    fn draw_items(&self, console: &mut Console, rect: Rect, top: usize) -> Result<()> {
+        let inner = rect.inner();
+        for i in 0..inner.height {
+            let index = top + i;
+            let label = self.items.get(index).map(|(label, _)| label.as_str()).unwrap_or("");
+            let colors = if index == self.selected { self.theme.selected } else { self.theme.window };
+            set_colors(console, colors)?;
+            put(console, inner.row + i, inner.col, &fit(&format!(" {}", label), inner.width, true))?;
+        }
+
+        // Scroll indicators on the right edge of the frame
+        set_colors(console, self.theme.window)?;
+        let edge = rect.col + rect.width - 1;
+        let up = if top > 0 { ARROW_UP } else { DOWN_LEFT };
+        let down = if top + inner.height < self.items.len() { ARROW_DOWN } else { UP_LEFT };
+        put(console, rect.row, edge, up.encode_utf8(&mut [0; 4]))?;
+        put(console, rect.row + rect.height - 1, edge, down.encode_utf8(&mut [0; 4]))
+    }
+}
+
+/// A framed message with Yes and No buttons
+pub struct ConfirmDialog {
+    title: String,
+    message: String,
+    default: bool,
+    theme: Theme,
+}
+
+impl ConfirmDialog {
+    pub fn new(title: &str, message: &str) -> Self {
+        Self { title: title.into(), message: message.into(), default: false, theme: Theme::default() }
+    }
+
+    /// Sets whether Yes rather than No is selected when the dialog is shown
+    pub fn default_yes(mut self, yes: bool) -> Self {
+        self.default = yes;
+        self
+    }
+
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Shows the dialog and returns whether Yes was chosen.
+    ///
+    /// Left/Right or Tab switch between the buttons and Enter picks the selected one.
+    /// Y and N pick a button directly. Escape is the same as picking No.
+    pub fn run(&self, console: &mut Console) -> Result<bool> {
+        let saved = SavedState::save(console)?;
+        let answer = self.run_inner(console, saved.colors);
+        saved.restore(console)?;
+        answer
+    }
+
+    fn run_inner(&self, console: &mut Console, background: Colors) -> Result<bool> {
+        const YES: &str = "< Yes >";
+        const NO: &str = "< No >";
+
+        let (columns, rows) = console.size()?;
+        let lines = wrap(&self.message, columns.saturating_sub(8));
+
+        let text_width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+        let buttons_width = YES.len() + 2 + NO.len();
+        let width = cmp::max(cmp::max(text_width, buttons_width), self.title.chars().count() + 2) + 4;
+        let rect = Rect::centered(width, lines.len() + 4, columns, rows);
+        if rect.height < 3 || rect.width < 3 {
+            return Ok(false);
+        }
+
+        Frame::new(rect).title(&self.title).colors(self.theme.window).draw(console)?;
+
+        let inner = rect.inner();
+        for (i, line) in lines.iter().take(inner.height.saturating_sub(2)).enumerate() {
+            put(console, inner.row + i, inner.col, &fit(&format!(" {}", line), inner.width, false))?;
+        }
+
+        let buttons_row = inner.row + inner.height - 1;
+        let yes_col = inner.col + inner.width.saturating_sub(buttons_width) / 2;
+        let no_col = yes_col + YES.len() + 2;
+
+        let mut yes = self.default;
+        let answer = loop {
+            set_colors(console, if yes { self.theme.selected } else { self.theme.window })?;
+            put(console, buttons_row, yes_col, YES)?;
+            set_colors(console, if yes { self.theme.window } else { self.theme.selected })?;
+            put(console, buttons_row, no_col, NO)?;
+
+            match console.read_key()?.key {
+                Key::Left | Key::Right | Key::Tab => yes = !yes,
+                Key::Enter => break yes,
+                Key::Escape | Key::Char('n') | Key::Char('N') => break false,
+                Key::Char('y') | Key::Char('Y') => break true,
+                _ => {},
+            }
+        };
+
+        clear(console, rect, background)?;
+        Ok(answer)
+    }
+}
+
+/// A framed bar showing how much of an operation is done
+///
+/// Unlike menus and dialogs it leaves the cursor and colors as they are while it's shown
+/// so that it can be updated from inside other code. Call `finish` to remove it.
+pub struct ProgressBar {
+    label: String,
+    total: u64,
+    rect: Rect,
+    theme: Theme,
+}
+
+impl ProgressBar {
+    pub fn new(label: &str, total: u64) -> Self {
+        Self { label: label.into(), total, rect: Rect::new(0, 0, 0, 0), theme: Theme::default() }
+    }
+
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Draws the bar, empty, in the middle of the screen
+    pub fn show(&mut self, console: &mut Console) -> Result<()> {
+        let (columns, rows) = console.size()?;
+        let width = cmp::min(PROGRESS_BAR_MAX_WIDTH, columns.saturating_sub(4));
+        self.rect = Rect::centered(width, 3, columns, rows);
+        if self.rect.width < 3 {
+            return Ok(());
+        }
+
+        Frame::new(self.rect).title(&self.label).colors(self.theme.window).draw(console)?;
+        self.update(console, 0)
+    }
+
+    /// Redraws the bar with `done` out of the total filled in
+    pub fn update(&mut self, console: &mut Console, done: u64) -> Result<()> {
+        let inner = self.rect.inner();
+        if inner.width < 8 || inner.height == 0 {
+            return Ok(());
+        }
+
+        let bar_width = inner.width - 7; // One space of padding on either side and " 100%"
+        let filled = filled_cells(done, self.total, bar_width);
+
+        let mut line = String::from(" ");
+        line.extend(iter::repeat_n(FULL_BLOCK, filled));
+        line.extend(iter::repeat_n(LIGHT_SHADE, bar_width - filled));
+        line.push_str(&format!("{:>4}%", percent(done, self.total)));
+
+        set_colors(console, self.theme.window)?;
+        put(console, inner.row, inner.col, &line)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Changes the total, e.g. when it only becomes known after the operation has started
+    pub fn set_total(&mut self, total: u64) {
+        self.total = total;
+    }
+
+    /// Removes the bar from the screen, blanking it in `colors`
+    pub fn finish(self, console: &mut Console, colors: Colors) -> Result<()> {
+        clear(console, self.rect, colors)
+    }
+}
+
+/// A line of text across the bottom row of the screen, e.g. for key hints
+pub struct StatusLine {
+    colors: Colors,
+}
+
+impl StatusLine {
+    pub fn new() -> Self {
+        Self { colors: Theme::default().status }
+    }
+
+    pub fn with_colors(mut self, colors: Colors) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    /// Replaces the status line with `text`, truncating it if it's longer than the screen is wide
+    pub fn set(&self, console: &mut Console, text: &str) -> Result<()> {
+        let saved = SavedState::save(console)?;
+        let (columns, rows) = console.size()?;
+        if rows > 0 && columns > 1 {
+            set_colors(console, self.colors)?;
+            // Writing the very last cell of the screen makes the console scroll so stop one short of it
+            put(console, rows - 1, 0, &fit(text, columns - 1, true))?;
+        }
+        saved.restore(console)
+    }
+}
+
+impl Default for StatusLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What widgets change on the console and put back when they're done
+struct SavedState {
+    colors: Colors,
+    cursor_pos: Position,
+    cursor_visible: bool,
+}
+
+impl SavedState {
+    fn save(console: &mut Console) -> Result<Self> {
+        let saved = Self { colors: Colors::new(console.fore_color(), console.back_color()), cursor_pos: console.cursor_pos(), cursor_visible: console.cursor_visible() };
+        console.disable_cursor()?;
+        Ok(saved)
+    }
+
+    fn restore(self, console: &mut Console) -> Result<()> {
+        set_colors(console, self.colors)?;
+        console.set_cursor_pos(self.cursor_pos)?;
+        if self.cursor_visible {
+            console.enable_cursor()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn set_colors(console: &mut Console, colors: Colors) -> Result<()> {
+    console.set_fore_color(colors.fore)?;
+    console.set_back_color(colors.back)
+}
+
+fn put(console: &mut Console, row: usize, col: usize, s: &str) -> Result<()> {
+    console.set_cursor_pos(Position { row: row as u32, col: col as u32 })?;
+    console.write_str(s)
+}
+
+fn clear(console: &mut Console, rect: Rect, colors: Colors) -> Result<()> {
+    set_colors(console, colors)?;
+    let blank = fit("", rect.width, true);
+    for row in rect.row..rect.row + rect.height {
+        put(console, row, rect.col, &blank)?;
+    }
+
+    Ok(())
+}
+
+/// Truncates `text` to `width` chars and, if `pad` is set, pads it with spaces to exactly that width
+fn fit(text: &str, width: usize, pad: bool) -> String {
+    let mut fitted = text.chars().take(width).collect::<String>();
+    if pad {
+        let len = fitted.chars().count();
+        fitted.extend(iter::repeat_n(' ', width - len));
+    }
+    fitted
+}
+
+/// Breaks `text` into lines of at most `width` chars at spaces, splitting words longer than that
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    if width == 0 {
+        return lines;
+    }
+
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_len = 0;
+        for word in paragraph.split(' ').filter(|w| !w.is_empty()) {
+            let mut word = word.chars().collect::<Vec<char>>();
+            while !word.is_empty() {
+                let space = if line_len > 0 { 1 } else { 0 };
+                if line_len + space + word.len() <= width {
+                    if space == 1 {
+                        line.push(' ');
+                    }
+                    line_len += space + word.len();
+                    line.extend(word.drain(..));
+                } else if line_len > 0 {
+                    lines.push(mem::take(&mut line));
+                    line_len = 0;
+                } else {
+                    line.extend(word.drain(..width));
+                    lines.push(mem::take(&mut line));
+                }
+            }
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// The item selected after `key` is pressed in a menu of `len` items showing `page` of them at a time
+fn move_selection(selected: usize, len: usize, page: usize, key: Key) -> usize {
+    let last = len.saturating_sub(1);
+    match key {
+        Key::Up => selected.checked_sub(1).unwrap_or(last), // Wrap around at either end
+        Key::Down => if selected >= last { 0 } else { selected + 1 },
+        Key::PageUp => selected.saturating_sub(page),
+        Key::PageDown => cmp::min(selected + page, last),
+        Key::Home => 0,
+        Key::End => last,
+        _ => selected,
+    }
+}
+
+/// The first visible item once the menu has scrolled as little as possible to show `selected`
+fn scroll_to(selected: usize, top: usize, visible: usize) -> usize {
+    if selected < top {
+        selected
+    } else if selected >= top + visible {
+        selected + 1 - visible
+    } else {
+        top
+    }
+}
+
+fn filled_cells(done: u64, total: u64, width: usize) -> usize {
+    if total == 0 {
+        return width;
+    }
+
+    (cmp::min(done, total) as u128 * width as u128 / total as u128) as usize
+}
+
+fn percent(done: u64, total: u64) -> u64 {
+    if total == 0 {
+        return 100;
+    }
+
+    (cmp::min(done, total) as u128 * 100 / total as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn menu_selection_wraps_and_pages() {
+        assert_eq!(move_selection(0, 5, 3, Key::Up), 4);
+        assert_eq!(move_selection(4, 5, 3, Key::Down), 0);
+        assert_eq!(move_selection(1, 5, 3, Key::PageDown), 4);
+        assert_eq!(move_selection(4, 5, 3, Key::PageDown), 4);
+        assert_eq!(move_selection(2, 5, 3, Key::PageUp), 0);
+        assert_eq!(move_selection(2, 5, 3, Key::End), 4);
+        assert_eq!(move_selection(2, 5, 3, Key::Char('x')), 2);
+
+        assert_eq!(scroll_to(4, 0, 3), 2);
+        assert_eq!(scroll_to(3, 2, 3), 2);
+        assert_eq!(scroll_to(0, 2, 3), 0);
+    }
+
+    #[test]
+    fn wrap_breaks_at_spaces_and_splits_long_words() {
+        assert_eq!(wrap("Erase the disk and reinstall?", 12), vec!["Erase the", "disk and", "reinstall?"]);
+        assert_eq!(wrap("abcdefgh ij", 5), vec!["abcde", "fgh", "ij"]);
+        assert_eq!(wrap("one\ntwo", 10), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn progress_is_clamped_and_handles_zero_total() {
+        assert_eq!(filled_cells(50, 100, 40), 20);
+        assert_eq!(filled_cells(150, 100, 40), 40);
+        assert_eq!(filled_cells(0, 0, 40), 40);
+        assert_eq!(percent(1, 3), 33);
+        assert_eq!(percent(u64::MAX, u64::MAX), 100);
+
+        let centered = Rect::centered(60, 3, 80, 25);
+        assert_eq!(centered, Rect::new(11, 10, 60, 3));
+        assert_eq!(centered.inner(), Rect::new(12, 11, 58, 1));
+        assert_eq!(Rect::centered(100, 3, 80, 25).width, 80);
+    }
+}
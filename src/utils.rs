@@ -1,7 +1,7 @@
 // TODO: Write a proc macro called derive(TupleWrapper) which automaticlly impls Wrapper trait for any tuple struct wrapping types
-use ffi::CHAR16;
+use ffi::{CHAR16, EFI_GUID};
 use core::{self, mem, slice, fmt};
-use crate::{EfiError, EfiErrorKind};
+use crate::{EfiError, EfiErrorKind, Guid};
 use alloc::str;
 
 pub trait Wrapper {
@@ -76,4 +76,55 @@ impl<'a> fmt::Display for NullTerminatedAsciiStr<'a> {
         write!(f, "{}", display)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+/// Reads a GUID stored in its on-disk mixed-endian form
+/// (first three fields little endian, last eight bytes as is)
+pub fn guid_from_bytes(bytes: &[u8; 16]) -> Guid {
+    let mut data4 = [0u8; 8];
+    data4.copy_from_slice(&bytes[8..]);
+    EFI_GUID(
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        data4,
+    )
+}
+
+/// The inverse of `guid_from_bytes`
+pub fn guid_to_bytes(guid: &Guid) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..4].copy_from_slice(&guid.0.to_le_bytes());
+    bytes[4..6].copy_from_slice(&guid.1.to_le_bytes());
+    bytes[6..8].copy_from_slice(&guid.2.to_le_bytes());
+    bytes[8..].copy_from_slice(&guid.3);
+    bytes
+}
+
+// EFI_GUID doesn't derive Clone. Orphan rules again.
+pub fn clone_guid(guid: &Guid) -> Guid {
+    EFI_GUID(guid.0, guid.1, guid.2, guid.3)
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The standard CRC32 (IEEE 802.3) used all over UEFI, e.g. in GPT headers.
+/// Computed in software rather than via the CalculateCrc32 boot service so that it works on the host too.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| CRC32_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}